@@ -8,7 +8,8 @@
 
 import unittest
 
-from amaranth          import Signal, Module, Elaboratable, Memory, Record
+from amaranth          import Signal, Module, Elaboratable, Memory, Record, C
+from usb_protocol.types import USBPacketID
 
 from luna.gateware.stream import StreamInterface
 from luna.gateware.test   import LunaGatewareTestCase, usb_domain_test_case
@@ -34,12 +35,20 @@ class USBAnalyzer(Elaboratable):
         Asserted iff the analyzer is stopped and not capturing packets.
     overrun: Signal(), output
         Asserted iff the analyzer has received more data than it can store in its internal buffer.
-        Occurs if :attr:``stream`` is not being read quickly enough.
+        Occurs if :attr:``stream`` is not being read quickly enough. Packets are dropped until
+        the buffer is half empty, at which point a dropped-packets marker record is emitted.
     capturing: Signal(), output
         Asserted iff the analyzer is currently capturing a packet.
     discarding: Signal(), output
         Asserted iff the analyzer is discarding the contents of its internal buffer.
 
+    filter_in: Signal(16), input
+        Mask of IN endpoints to capture; bit N selects endpoint N.
+    filter_out: Signal(16), input
+        Mask of OUT endpoints to capture, including SETUP and PING tokens.
+        Data and handshake packets are kept iff their transaction's token was.
+        SOF and other special packets are only kept while both masks are all ones.
+
 
     Parameters
     ----------
@@ -57,6 +66,11 @@ class USBAnalyzer(Elaboratable):
     # Support a maximum payload size of 1024B, plus a 1-byte PID and a 2-byte CRC16.
     MAX_PACKET_SIZE_BYTES = 1024 + 1 + 2
 
+    # After an overrun, we emit a marker record with a header that can't be a packet
+    # length, followed by the number of packets dropped as a 32-bit big-endian value.
+    DROPPED_MARKER = 0xFFFF
+    DROPPED_RECORD_SIZE_BYTES = HEADER_SIZE_BYTES + 4
+
     def __init__(self, *, utmi_interface, mem_depth=65536):
         """
         Parameters:
@@ -83,6 +97,10 @@ class USBAnalyzer(Elaboratable):
         self.capturing      = Signal()
         self.discarding     = Signal()
 
+        # Endpoint filter; captures all traffic by default.
+        self.filter_in      = Signal(16, reset=0xFFFF)
+        self.filter_out     = Signal(16, reset=0xFFFF)
+
         # Diagnostic I/O.
         self.sampling       = Signal()
 
@@ -106,6 +124,18 @@ class USBAnalyzer(Elaboratable):
 
         # Current receive status.
         packet_size     = Signal(16)
+        rx_active_prev  = Signal()
+
+        # Number of packets dropped since the last dropped-packets marker.
+        dropped_count   = Signal(32)
+
+        # Fields of the current packet used by the endpoint filter.
+        pid             = Signal(4)
+        token           = Signal(16)
+        endpoint        = token[7:11]
+
+        # Whether the current transaction's token passed the filter.
+        token_match     = Signal(reset=1)
 
         #
         # Read FIFO logic.
@@ -164,13 +194,40 @@ class USBAnalyzer(Elaboratable):
             m.d.usb += fifo_count.eq(fifo_count - 1)
 
 
+        #
+        # Endpoint filter.
+        #
+        keep_packet = Signal()
+        filter_all  = (self.filter_in == 0xFFFF) & (self.filter_out == 0xFFFF)
+        is_token    = ((pid[0:2] == 0b01) & (pid != USBPacketID.SOF)) | (pid == USBPacketID.PING)
+
+        with m.Switch(pid):
+            with m.Case(USBPacketID.IN):
+                m.d.comb += keep_packet.eq(self.filter_in.bit_select(endpoint, 1))
+            with m.Case(USBPacketID.OUT, USBPacketID.SETUP, USBPacketID.PING):
+                m.d.comb += keep_packet.eq(self.filter_out.bit_select(endpoint, 1))
+
+            # Data and handshake packets belong to the preceding token.
+            with m.Case("--11", "--10"):
+                m.d.comb += keep_packet.eq(token_match)
+
+            # SOF and special packets don't address an endpoint.
+            with m.Case():
+                m.d.comb += keep_packet.eq(filter_all)
+
+
+        # Track the start of each packet, so we can count the ones we drop.
+        m.d.usb += rx_active_prev.eq(self.utmi.rx_active)
+        packet_started = self.utmi.rx_active & ~rx_active_prev
+
+
         #
         # Core analysis FSM.
         #
         with m.FSM(domain="usb") as f:
             m.d.comb += [
                 self.idle      .eq(f.ongoing("AWAIT_START") | f.ongoing("AWAIT_PACKET")),
-                self.stopped   .eq(f.ongoing("AWAIT_START") | f.ongoing("OVERRUN")),
+                self.stopped   .eq(f.ongoing("AWAIT_START")),
                 self.overrun   .eq(f.ongoing("OVERRUN")),
                 self.capturing .eq(f.ongoing("CAPTURE_PACKET")),
                 self.discarding.eq(self.stopped & self.capture_enable),
@@ -215,10 +272,24 @@ class USBAnalyzer(Elaboratable):
                         packet_size     .eq(packet_size + 1)
                     ]
 
-                    # If this would be filling up our data memory,
-                    # move to the OVERRUN state.
+                    # Latch the PID and token fields for the endpoint filter.
+                    with m.Switch(packet_size):
+                        with m.Case(0):
+                            m.d.usb += pid.eq(self.utmi.rx_data[0:4])
+                        with m.Case(1):
+                            m.d.usb += token[0:8].eq(self.utmi.rx_data)
+                        with m.Case(2):
+                            m.d.usb += token[8:16].eq(self.utmi.rx_data)
+
+                    # If this would be filling up our data memory, drop the packet
+                    # and move to the OVERRUN state.
                     with m.If(fifo_count == self.mem_size - 1 - self.HEADER_SIZE_BYTES):
                         m.next = "OVERRUN"
+                        m.d.usb += [
+                            write_location  .eq(header_location),
+                            fifo_count      .eq(fifo_count - packet_size),
+                            dropped_count   .eq(dropped_count + 1),
+                        ]
 
                 # If we've stopped receiving, move to the "finalize" state.
                 with m.If(~self.utmi.rx_active):
@@ -231,9 +302,21 @@ class USBAnalyzer(Elaboratable):
                         m.d.usb += [
                             write_location.eq(header_location)
                         ]
+
+                    # If the packet doesn't pass the endpoint filter, drop it
+                    # along with any of its bytes already in the FIFO.
+                    with m.Elif(~keep_packet):
+                        m.next = "AWAIT_PACKET"
+                        m.d.usb += [
+                            write_location.eq(header_location),
+                            fifo_count.eq(fifo_count - packet_size),
+                        ]
                     with m.Else():
                         m.next = "EOP_1"
 
+                    with m.If((packet_size != 0) & is_token):
+                        m.d.usb += token_match.eq(keep_packet)
+
             # EOP: handle the end of the relevant packet.
             with m.State("EOP_1"):
 
@@ -271,12 +354,48 @@ class USBAnalyzer(Elaboratable):
                 pass
 
 
+            # OVERRUN -- drop packets until the host has drained half of our buffer.
             with m.State("OVERRUN"):
-                # TODO: we should probably set an overrun flag and then emit an EOP, here?
 
                 # If capture is stopped by the host, reset back to the ready state.
                 with m.If(~self.capture_enable):
                     m.next = "AWAIT_START"
+                    m.d.usb += dropped_count.eq(0)
+
+                # Count each packet we miss.
+                with m.Elif(packet_started):
+                    m.d.usb += dropped_count.eq(dropped_count + 1)
+
+                # Once there's room again, tell the host how many packets were lost;
+                # but don't resume capture mid-packet.
+                with m.Elif((fifo_count < self.mem_size // 2) & ~self.utmi.rx_active):
+                    m.next = "DROPPED_0"
+
+
+            # DROPPED_n -- write the dropped-packets marker record, one byte at a time.
+            marker = [
+                C(self.DROPPED_MARKER >> 8, 8),
+                C(self.DROPPED_MARKER & 0xFF, 8),
+                dropped_count[24:32],
+                dropped_count[16:24],
+                dropped_count[8:16],
+                dropped_count[0:8],
+            ]
+            for index, value in enumerate(marker):
+                with m.State(f"DROPPED_{index}"):
+                    m.d.comb += [
+                        mem_write_port.addr  .eq(write_location),
+                        mem_write_port.data  .eq(value),
+                        mem_write_port.en    .eq(1),
+                        fifo_new_data        .eq(1)
+                    ]
+                    m.d.usb += write_location.eq(write_location + 1)
+
+                    if index + 1 < len(marker):
+                        m.next = f"DROPPED_{index + 1}"
+                    else:
+                        m.next = "AWAIT_PACKET"
+                        m.d.usb += dropped_count.eq(0)
 
 
         return m
@@ -401,6 +520,106 @@ class USBAnalyzerTest(LunaGatewareTestCase):
         self.assertEqual((yield self.dut.stream.valid), 0)
 
 
+    def send_packet(self, data):
+        yield self.utmi.rx_active.eq(1)
+        yield self.utmi.rx_valid.eq(1)
+        yield
+
+        for datum in data:
+            yield from self.advance_stream(datum)
+
+        yield self.utmi.rx_active.eq(0)
+        yield from self.advance_cycles(5)
+
+
+    def read_stream(self, length, timeout=1000):
+        data = []
+
+        yield self.dut.stream.ready.eq(1)
+        yield
+
+        for _ in range(timeout):
+            if len(data) == length:
+                break
+            if (yield self.dut.stream.valid):
+                data.append((yield self.dut.stream.payload))
+            yield
+
+        yield self.dut.stream.ready.eq(0)
+        yield
+        return data
+
+
+    @usb_domain_test_case
+    def test_overrun(self):
+        # Enable capture
+        yield self.analyzer.capture_enable.eq(1)
+        yield
+
+        # Fill our 128 byte buffer with ten 12 byte records; the eleventh
+        # packet doesn't fit, and the twelfth arrives while we're overrun.
+        for _ in range(12):
+            yield from self.send_packet(range(10))
+        self.assertEqual((yield self.dut.overrun), 1)
+
+        # Drain the buffer; we should get all of the packets that fit,
+        # followed by a marker reporting the two dropped packets.
+        expected_data = ([0x00, 0x0a] + list(range(10))) * 10
+        expected_data += [0xff, 0xff, 0x00, 0x00, 0x00, 0x02]
+        data = yield from self.read_stream(len(expected_data))
+        self.assertEqual(data, expected_data)
+        self.assertEqual((yield self.dut.overrun), 0)
+
+        # Capture should have resumed.
+        yield from self.send_packet([0xd2])
+        data = yield from self.read_stream(3)
+        self.assertEqual(data, [0x00, 0x01, 0xd2])
+        self.assertEqual((yield self.dut.stream.valid), 0)
+
+
+    @usb_domain_test_case
+    def test_endpoint_filter(self):
+        # Only capture OUT traffic to endpoint 1.
+        yield self.analyzer.filter_in.eq(0)
+        yield self.analyzer.filter_out.eq(0b10)
+
+        # Enable capture
+        yield self.analyzer.capture_enable.eq(1)
+        yield
+
+        # OUT transaction to endpoint 2, which should be dropped.
+        yield from self.send_packet([0xe1, 0x00, 0x01])
+        yield from self.send_packet([0xc3, 0xaa])
+        yield from self.send_packet([0xd2])
+
+        # SOF, which should be dropped as we're filtering.
+        yield from self.send_packet([0xa5, 0x00, 0x00])
+
+        # OUT transaction to endpoint 1, which should be kept.
+        yield from self.send_packet([0xe1, 0x80, 0x00])
+        yield from self.send_packet([0xc3, 0x55])
+        yield from self.send_packet([0xd2])
+
+        # Try to read back the capture data, byte by byte.
+        self.assertEqual((yield self.dut.stream.valid), 1)
+        self.assertEqual((yield self.dut.stream.payload), 0)
+        yield self.dut.stream.ready.eq(1)
+        yield
+
+        # Validate that only the endpoint 1 transaction was captured.
+        expected_data = [
+            0x00, 0x03, 0xe1, 0x80, 0x00,
+            0x00, 0x02, 0xc3, 0x55,
+            0x00, 0x01, 0xd2,
+        ]
+        for datum in expected_data:
+            self.assertEqual((yield self.dut.stream.payload), datum)
+            yield
+
+        # We should now be out of data -- verify that there's no longer data available.
+        self.assertEqual((yield self.dut.stream.valid), 0)
+
+
 
 
 class USBAnalyzerStackTest(LunaGatewareTestCase):
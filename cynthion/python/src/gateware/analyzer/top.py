@@ -62,10 +62,31 @@ class USBAnalyzerState(Elaboratable):
         return m
 
 
+class USBAnalyzerFilter(Elaboratable):
+    """ Endpoint filter masks; bit N of each mask selects endpoint N. """
+
+    def __init__(self):
+        self.in_mask = Signal(16, reset=0xFFFF)
+        self.out_mask = Signal(16, reset=0xFFFF)
+        self.next_in_mask = Signal(16)
+        self.next_out_mask = Signal(16)
+        self.write = Signal()
+
+    def elaborate(self, platform):
+        m = Module()
+        with m.If(self.write):
+            m.d.usb += [
+                self.in_mask.eq(self.next_in_mask),
+                self.out_mask.eq(self.next_out_mask),
+            ]
+        return m
+
+
 class USBAnalyzerVendorRequests(IntEnum):
     GET_STATE = 0
     SET_STATE = 1
     GET_SPEEDS = 2
+    SET_FILTER = 3
 
 
 class USBAnalyzerSupportedSpeeds(IntFlag):
@@ -77,8 +98,9 @@ class USBAnalyzerSupportedSpeeds(IntFlag):
 
 class USBAnalyzerVendorRequestHandler(ControlRequestHandler):
 
-    def __init__(self, state):
+    def __init__(self, state, endpoint_filter):
         self.state = state
+        self.filter = endpoint_filter
         super().__init__()
 
     def elaborate(self, platform):
@@ -112,6 +134,8 @@ class USBAnalyzerVendorRequestHandler(ControlRequestHandler):
                                 m.next = 'SET_STATE'
                             with m.Case(USBAnalyzerVendorRequests.GET_SPEEDS):
                                 m.next = 'GET_SPEEDS'
+                            with m.Case(USBAnalyzerVendorRequests.SET_FILTER):
+                                m.next = 'SET_FILTER'
                             with m.Case():
                                 m.next = 'UNHANDLED'
 
@@ -132,6 +156,31 @@ class USBAnalyzerVendorRequestHandler(ControlRequestHandler):
                         USBAnalyzerSupportedSpeeds.USB_SPEED_HIGH
                     self.handle_simple_data_request(m, transmitter, supported_speeds, length=1)
 
+                # SET_FILTER -- The host is setting the endpoint filter
+                #
+                # wValue is the mask of OUT endpoints and wIndex the mask of IN
+                # endpoints to capture; 0xFFFF for both captures all traffic.
+                with m.State('SET_FILTER'):
+
+                    # Reject filters that would capture nothing, or that carry a data stage.
+                    invalid = ((setup.value == 0) & (setup.index == 0)) | (setup.length != 0)
+
+                    with m.If(interface.data_requested | interface.status_requested):
+                        with m.If(invalid):
+                            m.d.comb += handshake_generator.stall.eq(1)
+                            m.next = 'IDLE'
+                        with m.Elif(interface.status_requested):
+                            m.d.comb += self.send_zlp()
+
+                    # Apply the filter once the status stage has been ACK'd.
+                    with m.If(interface.handshakes_in.ack):
+                        m.d.comb += [
+                            self.filter.next_out_mask .eq(setup.value),
+                            self.filter.next_in_mask  .eq(setup.index),
+                            self.filter.write         .eq(1),
+                        ]
+                        m.next = 'IDLE'
+
                 # UNHANDLED -- we've received a request we're not prepared to handle
                 with m.State('UNHANDLED'):
 
@@ -194,6 +243,9 @@ class USBAnalyzerApplet(Elaboratable):
         # State register
         m.submodules.state = state = USBAnalyzerState()
 
+        # Endpoint filter register
+        m.submodules.filter = endpoint_filter = USBAnalyzerFilter()
+
         # Generate our clock domains.
         clocking = LunaECP5DomainGenerator()
         m.submodules.clocking = clocking
@@ -238,7 +290,7 @@ class USBAnalyzerApplet(Elaboratable):
         control_endpoint = usb.add_standard_control_endpoint(descriptors)
 
         # Add our vendor request handler to the control endpoint.
-        vendor_request_handler = USBAnalyzerVendorRequestHandler(state)
+        vendor_request_handler = USBAnalyzerVendorRequestHandler(state, endpoint_filter)
         control_endpoint.add_request_handler(vendor_request_handler)
 
         # Add a stream endpoint to our device.
@@ -255,6 +307,10 @@ class USBAnalyzerApplet(Elaboratable):
             # Connect enable signal to host-controlled state register.
             analyzer.capture_enable     .eq(state.current[0]),
 
+            # Connect the endpoint filter to the host-controlled filter register.
+            analyzer.filter_in          .eq(endpoint_filter.in_mask),
+            analyzer.filter_out         .eq(endpoint_filter.out_mask),
+
             # Flush endpoint when analyzer is idle with capture disabled.
             stream_ep.flush             .eq(analyzer.idle & ~analyzer.capture_enable),
 
@@ -3,10 +3,18 @@
 mod error;
 pub use error::ErrorKind;
 
+#[macro_use]
+pub(crate) mod ratelimit;
+
+pub mod registers;
+pub use registers::RegisterSnapshot;
+
 use smolusb::setup::*;
+use smolusb::{ConnectError, SmolError, SmolResult, EP_MAX_PACKET_SIZE};
+use smolusb::event::{InterruptSource, UsbEvent};
 use smolusb::traits::{
-    ReadControl, ReadEndpoint, UnsafeUsbDriverOperations, UsbDriver, UsbDriverOperations,
-    WriteEndpoint, WriteRefEndpoint,
+    EndpointStatus, InterruptControl, PollEvent, ReadControl, ReadEndpoint, UnsafeUsbDriverOperations, UsbDriver,
+    UsbDriverOperations, WriteEndpoint, WriteRefEndpoint,
 };
 
 use crate::pac;
@@ -14,6 +22,9 @@ use pac::interrupt::Interrupt;
 
 use log::{trace, warn};
 
+/// Maximum number of spins the write paths wait for the IN FIFO to drain
+pub const IN_IDLE_MAX_SPINS: usize = 1_000_000;
+
 /// Macro to generate hal wrappers for pac::USBx peripherals
 ///
 /// For example:
@@ -189,13 +200,541 @@ macro_rules! impl_usb {
                 pub fn ep_control_address(&self) -> u8 {
                     self.ep_control.address.read().address().bits()
                 }
+
+                /// Returns a snapshot of the controller and endpoint
+                /// handler registers.
+                ///
+                /// Only reads registers without side effects so it is
+                /// safe to call at any time, including from interrupt
+                /// handlers.
+                pub fn dump_registers(&self) -> registers::RegisterSnapshot {
+                    use registers::*;
+
+                    let controller = &self.controller;
+                    let ep_control = &self.ep_control;
+                    let ep_in = &self.ep_in;
+                    let ep_out = &self.ep_out;
+
+                    RegisterSnapshot {
+                        controller: ControllerRegisters {
+                            connect: controller.connect.read().bits() as u8,
+                            speed: controller.speed.read().bits() as u8,
+                            low_speed_only: controller.low_speed_only.read().bits() as u8,
+                            full_speed_only: controller.full_speed_only.read().bits() as u8,
+                            ev: EventRegisters {
+                                status: controller.ev_status.read().bits() as u8,
+                                pending: controller.ev_pending.read().bits() as u8,
+                                enable: controller.ev_enable.read().bits() as u8,
+                            },
+                        },
+                        ep_control: EpControlRegisters {
+                            epno: ep_control.epno.read().bits() as u8,
+                            have: ep_control.have.read().bits() as u8,
+                            pend: ep_control.pend.read().bits() as u8,
+                            address: ep_control.address.read().bits() as u8,
+                            ev: EventRegisters {
+                                status: ep_control.ev_status.read().bits() as u8,
+                                pending: ep_control.ev_pending.read().bits() as u8,
+                                enable: ep_control.ev_enable.read().bits() as u8,
+                            },
+                        },
+                        ep_in: EpInRegisters {
+                            epno: ep_in.epno.read().bits() as u8,
+                            stall: ep_in.stall.read().bits() as u8,
+                            idle: ep_in.idle.read().bits() as u8,
+                            have: ep_in.have.read().bits() as u8,
+                            pend: ep_in.pend.read().bits() as u8,
+                            pid: ep_in.pid.read().bits() as u8,
+                            ev: EventRegisters {
+                                status: ep_in.ev_status.read().bits() as u8,
+                                pending: ep_in.ev_pending.read().bits() as u8,
+                                enable: ep_in.ev_enable.read().bits() as u8,
+                            },
+                        },
+                        ep_out: EpOutRegisters {
+                            data_ep: ep_out.data_ep.read().bits() as u8,
+                            epno: ep_out.epno.read().bits() as u8,
+                            enable: ep_out.enable.read().bits() as u8,
+                            stall: ep_out.stall.read().bits() as u8,
+                            have: ep_out.have.read().bits() as u8,
+                            pend: ep_out.pend.read().bits() as u8,
+                            address: ep_out.address.read().bits() as u8,
+                            pid: ep_out.pid.read().bits() as u8,
+                            ev: EventRegisters {
+                                status: ep_out.ev_status.read().bits() as u8,
+                                pending: ep_out.ev_pending.read().bits() as u8,
+                                enable: ep_out.ev_enable.read().bits() as u8,
+                            },
+                        },
+                    }
+                }
+
+                /// Returns the endpoint number of the packet currently held in the OUT FIFO.
+                ///
+                /// The OUT handler has two endpoint number registers:
+                ///
+                ///   * `ep_out.epno` is written by firmware to select the endpoint to
+                ///     prime, stall or reset. It only reflects the last selection made
+                ///     and says nothing about where data came from.
+                ///   * `ep_out.data_ep` is set by the controller to the endpoint number
+                ///     on which the data in the FIFO was received.
+                ///
+                /// Only `data_ep` is valid for identifying an OUT completion, so always
+                /// use this accessor in `USBx_EP_OUT` interrupt handlers.
+                ///
+                /// By contrast `ep_control.epno` and `ep_in.epno` do hold the endpoint
+                /// number for SETUP and IN completions respectively.
+                #[inline(always)]
+                pub fn ep_out_endpoint_number(&self) -> u8 {
+                    self.ep_out.data_ep.read().data_ep().bits()
+                }
+
+                /// Wait for the IN FIFO to drain and the last transmission to complete.
+                ///
+                /// Gives up with `ErrorKind::Timeout` after `max_spins`
+                /// polls, e.g. if the host stops reading. eptri has a
+                /// single IN FIFO so `endpoint_number` is only used for
+                /// diagnostics.
+                #[inline(always)]
+                pub fn wait_in_idle(&self, endpoint_number: u8, max_spins: usize) -> Result<(), ErrorKind> {
+                    for _ in 0..max_spins {
+                        if self.in_is_idle(endpoint_number) {
+                            return Ok(());
+                        }
+                    }
+                    warn_ratelimited!("  TX IN{} timed out waiting for idle", endpoint_number);
+                    Err(ErrorKind::Timeout)
+                }
+
+                /// Returns true if a write to the given IN endpoint would
+                /// clobber data the host has not collected yet.
+                ///
+                /// eptri has a single IN FIFO so this is also true while
+                /// data written to another endpoint is waiting in it.
+                #[inline(always)]
+                pub fn in_endpoint_busy(&self, endpoint_number: u8) -> bool {
+                    self.is_in_flight(endpoint_number) || self.ep_in.have.read().have().bit()
+                }
+
+                /// Write iterator to multiple packets, waiting at least
+                /// `delay_cycles` between packets.
+                ///
+                /// Emulates a slow device. Unlike `write_packets` this
+                /// blocks until every packet but the last has been
+                /// collected by the host.
+                pub fn write_packets_delayed<I>(
+                    &self,
+                    endpoint_number: u8,
+                    mut iter: I,
+                    packet_size: usize,
+                    delay_cycles: u32,
+                ) -> SmolResult<usize>
+                where
+                    I: Iterator<Item = u8>,
+                {
+                    // endpoint is NAK'd, leave the write to the caller
+                    if self.is_endpoint_nak(endpoint_number, Direction::DeviceToHost) {
+                        warn_ratelimited!("  TX IN{} NAK", endpoint_number);
+                        return Err(SmolError::WouldBlock);
+                    }
+
+                    if packet_size == 0 {
+                        return Err(SmolError::InvalidArgument);
+                    }
+
+                    // the host has not collected this endpoint's previous write yet
+                    if self.in_has_data(endpoint_number) {
+                        return Err(SmolError::WouldBlock);
+                    }
+
+                    // eptri has a single IN FIFO, discard data another
+                    // endpoint left in it
+                    if self.ep_in.have.read().have().bit() {
+                        warn_ratelimited!("  clear tx");
+                        self.reset_in_fifo_and_in_flight();
+                    }
+
+                    let mut total_bytes_written: usize = 0;
+                    let mut last_sent: Option<u32> = None;
+                    loop {
+                        // fill the fifo with the next packet
+                        let mut bytes_written: usize = 0;
+                        for byte in iter.by_ref().take(packet_size) {
+                            self.ep_in.data.write(|w| unsafe { w.data().bits(byte) });
+                            bytes_written += 1;
+                        }
+                        total_bytes_written += bytes_written;
+
+                        // hold the packet back until the delay has passed
+                        if let Some(last_sent) = last_sent {
+                            while (riscv::register::mcycle::read() as u32).wrapping_sub(last_sent) < delay_cycles {}
+                        }
+
+                        // prime IN endpoint
+                        self.ep_in
+                            .epno
+                            .write(|w| unsafe { w.epno().bits(endpoint_number) });
+
+                        // a short packet or ZLP ends the transfer
+                        if bytes_written < packet_size {
+                            break;
+                        }
+
+                        // wait for transmission to complete
+                        if self.wait_in_idle(endpoint_number, IN_IDLE_MAX_SPINS).is_err() {
+                            self.reset_in_fifo_and_in_flight();
+                            return Err(SmolError::Timeout);
+                        }
+                        last_sent = Some(riscv::register::mcycle::read() as u32);
+                    }
+                    self.set_in_flight(endpoint_number, true);
+
+                    Ok(total_bytes_written)
+                }
+
+                /// Make the given endpoint respond to the host with NAK.
+                ///
+                /// This is a software NAK: the eptri controller has no NAK
+                /// register so the state is kept in a per-controller bitmap
+                /// and the write and prime paths consult it. The controller
+                /// NAKs any IN token for an endpoint that has not been primed
+                /// via `ep_in.epno` and any OUT token for an endpoint that has
+                /// not been primed via `ep_out.prime`, NAK is therefore
+                /// implemented by withholding priming:
+                ///
+                ///   * IN: writes to the endpoint return `SmolError::WouldBlock`
+                ///     without touching the FIFO while NAK is set.
+                ///   * OUT: `ep_out_prime_receive` will not prime the endpoint while
+                ///     NAK is set. Clearing NAK re-primes the endpoint without
+                ///     resetting the OUT FIFO, or leaves it to be re-primed once
+                ///     read if the FIFO still holds a packet for the endpoint.
+                ///
+                /// An OUT endpoint that is already primed when NAK is set will still
+                /// accept one more packet.
+                ///
+                /// Unlike a stall, a NAK'd endpoint is not an error condition and the
+                /// host will keep retrying the transfer.
+                ///
+                /// Returns `SmolError::InvalidArgument` for endpoint 0, withholding
+                /// priming there would drop the status stage of control transfers.
+                pub fn set_endpoint_nak(&self, endpoint_number: u8, direction: Direction, nak: bool) -> SmolResult<()> {
+                    if endpoint_number & 0xf == 0 {
+                        warn!("  usb::set_endpoint_nak() endpoint 0 can't be NAK'd");
+                        return Err(SmolError::InvalidArgument);
+                    }
+
+                    let mask = 1_u16 << (endpoint_number & 0xf);
+                    self.update_endpoint_nak_bitmap(direction, mask, nak);
+
+                    // a packet received before the endpoint was NAK'd may
+                    // still be waiting in the FIFO, so don't reset it
+                    if !nak && direction == Direction::HostToDevice && !self.out_has_data(endpoint_number) {
+                        self.ep_out_prime(endpoint_number);
+                    }
+
+                    trace!("  usb::set_endpoint_nak({}, {:?}, {})", endpoint_number, direction, nak);
+
+                    Ok(())
+                }
+
+                /// Returns true if the given endpoint is set to NAK.
+                #[inline(always)]
+                pub fn is_endpoint_nak(&self, endpoint_number: u8, direction: Direction) -> bool {
+                    let mask = 1_u16 << (endpoint_number & 0xf);
+                    (self.endpoint_nak_bitmap(direction) & mask) != 0
+                }
+
+                #[inline(always)]
+                fn endpoint_nak_bitmap(&self, direction: Direction) -> u16 {
+                    #[cfg(not(target_has_atomic))]
+                    {
+                        riscv::interrupt::free(|| unsafe {
+                            match direction {
+                                Direction::DeviceToHost => $USBX_CONTROLLER::EP_IN_NAK,
+                                Direction::HostToDevice => $USBX_CONTROLLER::EP_OUT_NAK,
+                            }
+                        })
+                    }
+                    #[cfg(target_has_atomic)]
+                    {
+                        use core::sync::atomic::Ordering;
+                        match direction {
+                            Direction::DeviceToHost => $USBX_CONTROLLER::EP_IN_NAK.load(Ordering::Relaxed),
+                            Direction::HostToDevice => $USBX_CONTROLLER::EP_OUT_NAK.load(Ordering::Relaxed),
+                        }
+                    }
+                }
+
+                /// Set or clear the bits in `mask` of the given direction's
+                /// NAK bitmap as a single atomic update.
+                #[inline(always)]
+                fn update_endpoint_nak_bitmap(&self, direction: Direction, mask: u16, set: bool) {
+                    #[cfg(not(target_has_atomic))]
+                    {
+                        riscv::interrupt::free(|| unsafe {
+                            let bitmap = match direction {
+                                Direction::DeviceToHost => &mut $USBX_CONTROLLER::EP_IN_NAK,
+                                Direction::HostToDevice => &mut $USBX_CONTROLLER::EP_OUT_NAK,
+                            };
+                            if set {
+                                *bitmap |= mask;
+                            } else {
+                                *bitmap &= !mask;
+                            }
+                        });
+                    }
+                    #[cfg(target_has_atomic)]
+                    {
+                        use core::sync::atomic::Ordering;
+                        let bitmap = match direction {
+                            Direction::DeviceToHost => &$USBX_CONTROLLER::EP_IN_NAK,
+                            Direction::HostToDevice => &$USBX_CONTROLLER::EP_OUT_NAK,
+                        };
+                        if set {
+                            bitmap.fetch_or(mask, Ordering::Relaxed);
+                        } else {
+                            bitmap.fetch_and(!mask, Ordering::Relaxed);
+                        }
+                    }
+                }
+
+                /// Select, prime and enable the given OUT endpoint
+                /// without resetting the OUT FIFO.
+                #[inline(always)]
+                fn ep_out_prime(&self, endpoint_number: u8) {
+                    // select endpoint
+                    self.ep_out
+                        .epno
+                        .write(|w| unsafe { w.epno().bits(endpoint_number) });
+
+                    // prime endpoint
+                    self.ep_out.prime.write(|w| w.prime().bit(true));
+
+                    // enable it
+                    self.ep_out.enable.write(|w| w.enable().bit(true));
+                }
+
+                /// Handle a `USBx_EP_IN` event by clearing the in-flight
+                /// flag of the IN endpoint whose write the host has just
+                /// collected.
+                ///
+                /// Must be called for every `USBx_EP_IN` event, before
+                /// the next write selects another endpoint. Returns the
+                /// endpoint number.
+                #[inline(always)]
+                pub fn ep_in_send_complete(&self) -> u8 {
+                    let endpoint_number = self.ep_in.epno.read().bits() as u8;
+                    self.set_in_flight(endpoint_number, false);
+                    endpoint_number
+                }
+
+                /// Mark a write to the given IN endpoint as in flight
+                /// until the host has collected it.
+                #[inline(always)]
+                fn set_in_flight(&self, endpoint_number: u8, in_flight: bool) {
+                    let mask = 1_u16 << (endpoint_number & 0xf);
+                    self.update_in_flight_bitmap(mask, in_flight);
+                }
+
+                #[inline(always)]
+                fn in_flight_bitmap(&self) -> u16 {
+                    #[cfg(not(target_has_atomic))]
+                    {
+                        riscv::interrupt::free(|| unsafe { $USBX_CONTROLLER::EP_IN_IN_FLIGHT })
+                    }
+                    #[cfg(target_has_atomic)]
+                    {
+                        use core::sync::atomic::Ordering;
+                        $USBX_CONTROLLER::EP_IN_IN_FLIGHT.load(Ordering::Relaxed)
+                    }
+                }
+
+                /// Set or clear the bits in `mask` of the in-flight bitmap as a
+                /// single atomic update, so a concurrent `ep_in_send_complete`
+                /// from the interrupt handler can't be lost.
+                #[inline(always)]
+                fn update_in_flight_bitmap(&self, mask: u16, in_flight: bool) {
+                    #[cfg(not(target_has_atomic))]
+                    {
+                        riscv::interrupt::free(|| unsafe {
+                            if in_flight {
+                                $USBX_CONTROLLER::EP_IN_IN_FLIGHT |= mask;
+                            } else {
+                                $USBX_CONTROLLER::EP_IN_IN_FLIGHT &= !mask;
+                            }
+                        });
+                    }
+                    #[cfg(target_has_atomic)]
+                    {
+                        use core::sync::atomic::Ordering;
+                        if in_flight {
+                            $USBX_CONTROLLER::EP_IN_IN_FLIGHT.fetch_or(mask, Ordering::Relaxed);
+                        } else {
+                            $USBX_CONTROLLER::EP_IN_IN_FLIGHT.fetch_and(!mask, Ordering::Relaxed);
+                        }
+                    }
+                }
+
+                /// Returns true if data received on the given OUT
+                /// endpoint has been discarded since the last call to
+                /// [`clear_overflow`](Self::clear_overflow).
+                ///
+                /// Data is discarded when a packet does not fit in the
+                /// buffer passed to `read` or `read_control`, SETUP
+                /// packets count towards endpoint 0.
+                #[inline(always)]
+                pub fn has_overflowed(&self, endpoint_number: u8) -> bool {
+                    self.overflow_count(endpoint_number) != 0
+                }
+
+                /// Returns the number of bytes discarded from the given
+                /// OUT endpoint since the last call to
+                /// [`clear_overflow`](Self::clear_overflow).
+                #[inline(always)]
+                pub fn overflow_count(&self, endpoint_number: u8) -> u32 {
+                    let index = (endpoint_number & 0xf) as usize;
+                    #[cfg(not(target_has_atomic))]
+                    {
+                        riscv::interrupt::free(|| unsafe { $USBX_CONTROLLER::EP_OUT_OVERFLOW[index] })
+                    }
+                    #[cfg(target_has_atomic)]
+                    {
+                        use core::sync::atomic::Ordering;
+                        $USBX_CONTROLLER::EP_OUT_OVERFLOW[index].load(Ordering::Relaxed)
+                    }
+                }
+
+                /// Clear the overflow flag and count of the given OUT endpoint.
+                #[inline(always)]
+                pub fn clear_overflow(&self, endpoint_number: u8) {
+                    let index = (endpoint_number & 0xf) as usize;
+                    #[cfg(not(target_has_atomic))]
+                    {
+                        riscv::interrupt::free(|| unsafe {
+                            $USBX_CONTROLLER::EP_OUT_OVERFLOW[index] = 0;
+                        });
+                    }
+                    #[cfg(target_has_atomic)]
+                    {
+                        use core::sync::atomic::Ordering;
+                        $USBX_CONTROLLER::EP_OUT_OVERFLOW[index].store(0, Ordering::Relaxed);
+                    }
+                }
+
+                /// Add `overflow` discarded bytes to the given OUT endpoint's count.
+                #[inline(always)]
+                fn record_overflow(&self, endpoint_number: u8, overflow: usize) {
+                    let index = (endpoint_number & 0xf) as usize;
+                    let overflow = overflow as u32;
+                    #[cfg(not(target_has_atomic))]
+                    {
+                        riscv::interrupt::free(|| unsafe {
+                            let count = &mut $USBX_CONTROLLER::EP_OUT_OVERFLOW[index];
+                            *count = count.saturating_add(overflow);
+                        });
+                    }
+                    #[cfg(target_has_atomic)]
+                    {
+                        use core::sync::atomic::Ordering;
+                        let _ = $USBX_CONTROLLER::EP_OUT_OVERFLOW[index]
+                            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                                Some(count.saturating_add(overflow))
+                            });
+                    }
+                }
+
+                /// Write iterator as packets of at most `max_packet_size`
+                /// bytes, see [`WriteEndpoint::write_all`] and
+                /// [`WriteEndpoint::write_packets`].
+                ///
+                /// If `zlp` is set the transfer is terminated with a zero
+                /// length packet when it ends on a packet boundary.
+                fn write_in_packets<I>(
+                    &self,
+                    endpoint_number: u8,
+                    iter: I,
+                    max_packet_size: usize,
+                    zlp: bool,
+                ) -> SmolResult<usize>
+                where
+                    I: Iterator<Item = u8>,
+                {
+                    // endpoint is NAK'd, leave the write to the caller
+                    if self.is_endpoint_nak(endpoint_number, Direction::DeviceToHost) {
+                        warn_ratelimited!("  TX IN{} NAK", endpoint_number);
+                        return Err(SmolError::WouldBlock);
+                    }
+
+                    if max_packet_size == 0 {
+                        return Err(SmolError::InvalidArgument);
+                    }
+
+                    // the host has not collected this endpoint's previous write yet
+                    if self.in_has_data(endpoint_number) {
+                        return Err(SmolError::WouldBlock);
+                    }
+
+                    // eptri has a single IN FIFO, discard data another
+                    // endpoint left in it
+                    if self.ep_in.have.read().have().bit() {
+                        warn_ratelimited!("  clear tx");
+                        self.reset_in_fifo_and_in_flight();
+                    }
+
+                    // write data as packets of at most max_packet_size
+                    let mut bytes_written: usize = 0;
+                    for byte in iter {
+                        self.ep_in.data.write(|w| unsafe { w.data().bits(byte) });
+                        bytes_written += 1;
+                        // end of packet - transmit it
+                        if bytes_written % max_packet_size == 0 {
+                            // prime IN endpoint
+                            self.ep_in
+                                .epno
+                                .write(|w| unsafe { w.epno().bits(endpoint_number) });
+                            // wait for transmission to complete
+                            if self.wait_in_idle(endpoint_number, IN_IDLE_MAX_SPINS).is_err() {
+                                self.reset_in_fifo_and_in_flight();
+                                return Err(SmolError::Timeout);
+                            }
+                        }
+                    }
+
+                    // the last full packet has already been sent
+                    if !zlp && bytes_written != 0 && bytes_written % max_packet_size == 0 {
+                        return Ok(bytes_written);
+                    }
+
+                    // finally, prime IN endpoint to transmit either the
+                    // remaining partial packet or a terminating ZLP
+                    self.ep_in
+                        .epno
+                        .write(|w| unsafe { w.epno().bits(endpoint_number) });
+                    self.set_in_flight(endpoint_number, true);
+
+                    if bytes_written > 60 {
+                        debug_ratelimited!("  TX {} bytes", bytes_written);
+                    }
+
+                    Ok(bytes_written)
+                }
+
+                /// Discard the IN FIFO along with any in-flight writes.
+                #[inline(always)]
+                fn reset_in_fifo_and_in_flight(&self) {
+                    self.ep_in.reset.write(|w| w.reset().bit(true));
+                    self.update_in_flight_bitmap(u16::MAX, false);
+                }
             }
 
             // - trait: UsbDriverOperations -----------------------------------
 
             impl UsbDriverOperations for $USBX {
                 /// Set the interface up for new connections
-                fn connect(&self) -> u8 {
+                ///
+                /// eptri can't sense VBUS so this only fails if the
+                /// controller doesn't come up connected.
+                fn connect(&self) -> Result<u8, ConnectError> {
                     // disconnect device controller
                     self.controller.connect.write(|w| w.connect().bit(false));
 
@@ -204,14 +743,17 @@ macro_rules! impl_usb {
 
                     // reset FIFOs
                     self.ep_control.reset.write(|w| w.reset().bit(true));
-                    self.ep_in.reset.write(|w| w.reset().bit(true));
+                    self.reset_in_fifo_and_in_flight();
                     self.ep_out.reset.write(|w| w.reset().bit(true));
 
                     // connect device controller
                     self.controller.connect.write(|w| w.connect().bit(true));
+                    if !self.controller.connect.read().connect().bit() {
+                        return Err(ConnectError::Disconnected);
+                    }
 
                     // 0: High, 1: Full, 2: Low, 3:SuperSpeed (incl SuperSpeed+)
-                    self.controller.speed.read().speed().bits()
+                    Ok(self.controller.speed.read().speed().bits())
                 }
 
                 fn disconnect(&self) {
@@ -226,7 +768,7 @@ macro_rules! impl_usb {
 
                     // reset FIFOs
                     self.ep_control.reset.write(|w| w.reset().bit(true));
-                    self.ep_in.reset.write(|w| w.reset().bit(true));
+                    self.reset_in_fifo_and_in_flight();
                     self.ep_out.reset.write(|w| w.reset().bit(true));
                 }
 
@@ -240,7 +782,7 @@ macro_rules! impl_usb {
 
                     // reset FIFOs
                     self.ep_control.reset.write(|w| w.reset().bit(true));
-                    self.ep_in.reset.write(|w| w.reset().bit(true));
+                    self.reset_in_fifo_and_in_flight();
                     self.ep_out.reset.write(|w| w.reset().bit(true));
 
                     // re-enable endpoint events
@@ -267,7 +809,7 @@ macro_rules! impl_usb {
 
                     // reset FIFOs
                     self.ep_control.reset.write(|w| w.reset().bit(true));
-                    self.ep_in.reset.write(|w| w.reset().bit(true));
+                    self.reset_in_fifo_and_in_flight();
                     self.ep_out.reset.write(|w| w.reset().bit(true));
 
                     // reset SETUP handler state
@@ -289,11 +831,15 @@ macro_rules! impl_usb {
 
                 /// Acknowledge the status stage of an incoming control request.
                 fn ack_status_stage(&self, packet: &SetupPacket) {
-                    match Direction::from(packet.request_type) {
-                        // If this is an IN request, read a zero-length packet (ZLP) from the host..
-                        Direction::DeviceToHost => self.ep_out_prime_receive(0),
-                        // ... otherwise, send a ZLP.
-                        Direction::HostToDevice => self.write(0, [].into_iter()),
+                    match (Direction::from(packet.request_type), packet.length) {
+                        // If this is an IN request with a data stage, read a zero-length packet (ZLP) from the host..
+                        (Direction::DeviceToHost, 1..) => self.ep_out_prime_receive(0),
+                        // ... otherwise, including requests without a data stage, send a ZLP.
+                        _ => {
+                            if let Err(e) = self.write(0, [].into_iter()) {
+                                warn!("  usb::ack_status_stage() failed to send ZLP: {:?}", e);
+                            }
+                        }
                     }
                 }
 
@@ -302,7 +848,11 @@ macro_rules! impl_usb {
                         // If this is an IN request, read a zero-length packet (ZLP) from the host..
                         Direction::DeviceToHost => self.ep_out_prime_receive(endpoint_number),
                         // ... otherwise, send a ZLP.
-                        Direction::HostToDevice => self.write(endpoint_number, [].into_iter()),
+                        Direction::HostToDevice => {
+                            if let Err(e) = self.write(endpoint_number, [].into_iter()) {
+                                warn!("  usb::ack({}) failed to send ZLP: {:?}", endpoint_number, e);
+                            }
+                        }
                     }
                 }
 
@@ -367,6 +917,55 @@ macro_rules! impl_usb {
                     // this smacks of a deeper problem ...
                     log::debug!("  usb::clear_feature_endpoint_halt: 0x{:x}", endpoint_address);
                 }
+
+                /// Reset the FIFO of the given endpoint.
+                ///
+                /// eptri has a single FIFO per direction so this
+                /// discards whatever it holds regardless of endpoint
+                /// number. The control FIFO is left untouched.
+                fn reset_endpoint(&self, endpoint_number: u8, direction: Direction) {
+                    match direction {
+                        Direction::HostToDevice => {
+                            // eptri has a single OUT FIFO so it is only reset
+                            // if it holds a packet for `endpoint_number`
+                            if self.ep_out_endpoint_number() == endpoint_number & 0xf {
+                                self.ep_out.reset.write(|w| w.reset().bit(true));
+                            }
+                        }
+                        Direction::DeviceToHost => {
+                            self.flush_in(endpoint_number);
+                        }
+                    }
+                    trace!("  usb::reset_endpoint({}, {:?})", endpoint_number, direction);
+                }
+
+                /// eptri has a single IN FIFO so it is only reset if it
+                /// holds data for `endpoint_number`, other endpoints'
+                /// writes are left in place.
+                fn flush_in(&self, endpoint_number: u8) {
+                    let endpoint_number = endpoint_number & 0xf;
+                    if self.ep_in.epno.read().epno().bits() == endpoint_number {
+                        self.ep_in.reset.write(|w| w.reset().bit(true));
+                    }
+                    self.set_in_flight(endpoint_number, false);
+                    trace!("  usb::flush_in({})", endpoint_number);
+                }
+
+                /// Implemented with [`Self::set_endpoint_nak`].
+                fn pause_endpoint(&self, endpoint_number: u8, direction: Direction) -> SmolResult<()> {
+                    self.set_endpoint_nak(endpoint_number, direction, true)
+                }
+
+                fn resume_endpoint(&self, endpoint_number: u8, direction: Direction) -> SmolResult<()> {
+                    self.set_endpoint_nak(endpoint_number, direction, false)
+                }
+
+                /// eptri has no frame number register and does not raise
+                /// an event for SOF packets so there is nothing to count
+                /// either.
+                fn frame_number(&self) -> Option<u16> {
+                    None
+                }
             }
 
             // - trait: UnsafeUsbDriverOperations -----------------------------
@@ -383,6 +982,37 @@ macro_rules! impl_usb {
                 #[cfg(target_has_atomic)]
                 pub static TX_ACK_ACTIVE: core::sync::atomic::AtomicBool =
                     core::sync::atomic::AtomicBool::new(false);
+
+                // Bitmaps of endpoints that should NAK, indexed by endpoint number.
+                #[cfg(not(target_has_atomic))]
+                pub static mut EP_IN_NAK: u16 = 0;
+                #[cfg(not(target_has_atomic))]
+                pub static mut EP_OUT_NAK: u16 = 0;
+                #[cfg(target_has_atomic)]
+                pub static EP_IN_NAK: core::sync::atomic::AtomicU16 =
+                    core::sync::atomic::AtomicU16::new(0);
+                #[cfg(target_has_atomic)]
+                pub static EP_OUT_NAK: core::sync::atomic::AtomicU16 =
+                    core::sync::atomic::AtomicU16::new(0);
+
+                // Bitmap of IN endpoints with a write the host has not
+                // collected yet, indexed by endpoint number.
+                #[cfg(not(target_has_atomic))]
+                pub static mut EP_IN_IN_FLIGHT: u16 = 0;
+                #[cfg(target_has_atomic)]
+                pub static EP_IN_IN_FLIGHT: core::sync::atomic::AtomicU16 =
+                    core::sync::atomic::AtomicU16::new(0);
+
+                // Number of bytes discarded by OUT endpoint reads since
+                // the count was last cleared, indexed by endpoint number.
+                #[cfg(not(target_has_atomic))]
+                pub static mut EP_OUT_OVERFLOW: [u32; 16] = [0; 16];
+                #[cfg(target_has_atomic)]
+                pub static EP_OUT_OVERFLOW: [core::sync::atomic::AtomicU32; 16] = {
+                    #[allow(clippy::declare_interior_mutable_const)]
+                    const ZERO: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+                    [ZERO; 16]
+                };
             }
 
             impl UnsafeUsbDriverOperations for $USBX {
@@ -431,6 +1061,82 @@ macro_rules! impl_usb {
                 }
             }
 
+            /// eptri has a single IN and a single OUT FIFO shared by
+            /// all endpoints. Selecting an IN endpoint primes it, so IN
+            /// data is attributed to an endpoint via its in-flight flag
+            /// and OUT data via `data_ep` rather than `epno`.
+            impl EndpointStatus for $USBX {
+                #[inline(always)]
+                fn in_has_data(&self, endpoint_number: u8) -> bool {
+                    self.is_in_flight(endpoint_number) && self.ep_in.have.read().have().bit()
+                }
+
+                #[inline(always)]
+                fn in_is_idle(&self, _endpoint_number: u8) -> bool {
+                    self.ep_in.idle.read().idle().bit() && !self.ep_in.have.read().have().bit()
+                }
+
+                #[inline(always)]
+                fn out_has_data(&self, endpoint_number: u8) -> bool {
+                    self.ep_out.have.read().have().bit()
+                        && self.ep_out_endpoint_number() == endpoint_number
+                }
+            }
+
+            impl InterruptControl for $USBX {
+                fn set_interrupt_source(&self, source: InterruptSource, enabled: bool) {
+                    let interrupt = match source {
+                        InterruptSource::Controller => Interrupt::$USBX_CONTROLLER,
+                        InterruptSource::EndpointControl => Interrupt::$USBX_EP_CONTROL,
+                        InterruptSource::EndpointIn => Interrupt::$USBX_EP_IN,
+                        InterruptSource::EndpointOut => Interrupt::$USBX_EP_OUT,
+                    };
+                    if enabled {
+                        self.enable_interrupt(interrupt);
+                    } else {
+                        self.disable_interrupt(interrupt);
+                    }
+                }
+
+                fn is_interrupt_source_enabled(&self, source: InterruptSource) -> bool {
+                    match source {
+                        InterruptSource::Controller => self.controller.ev_enable.read().enable().bit(),
+                        InterruptSource::EndpointControl => self.ep_control.ev_enable.read().enable().bit(),
+                        InterruptSource::EndpointIn => self.ep_in.ev_enable.read().enable().bit(),
+                        InterruptSource::EndpointOut => self.ep_out.ev_enable.read().enable().bit(),
+                    }
+                }
+            }
+
+            impl PollEvent for $USBX {
+                /// Checks the event pending registers directly so this
+                /// works without enabling the `MachineExternal`
+                /// interrupt.
+                fn poll_event(&self) -> Option<UsbEvent> {
+                    if self.controller.ev_pending.read().pending().bit() {
+                        self.clear_pending(Interrupt::$USBX_CONTROLLER);
+                        Some(UsbEvent::BusReset)
+                    } else if self.ep_control.ev_pending.read().pending().bit() {
+                        let endpoint = self.ep_control.epno.read().bits() as u8;
+                        self.clear_pending(Interrupt::$USBX_EP_CONTROL);
+                        Some(UsbEvent::ReceiveControl(endpoint))
+                    } else if self.ep_out.ev_pending.read().pending().bit() {
+                        let endpoint = self.ep_out_endpoint_number();
+                        self.clear_pending(Interrupt::$USBX_EP_OUT);
+                        Some(UsbEvent::ReceivePacket(endpoint))
+                    } else if self.ep_in.ev_pending.read().pending().bit() {
+                        let endpoint = self.ep_in_send_complete();
+                        self.clear_pending(Interrupt::$USBX_EP_IN);
+                        unsafe {
+                            self.clear_tx_ack_active();
+                        }
+                        Some(UsbEvent::SendComplete(endpoint))
+                    } else {
+                        None
+                    }
+                }
+            }
+
             // - trait: Read/Write traits -------------------------------------
 
             impl ReadControl for $USBX {
@@ -451,8 +1157,9 @@ macro_rules! impl_usb {
                     if overflow == 0 {
                         trace!("  RX CONTROL {} bytes read", bytes_read);
                     } else {
-                        warn!("  RX CONTROL {} bytes read + {} bytes overflow",
+                        warn_ratelimited!("  RX CONTROL {} bytes read + {} bytes overflow",
                               bytes_read, overflow);
+                        self.record_overflow(0, overflow);
                     }
 
                     bytes_read
@@ -460,26 +1167,49 @@ macro_rules! impl_usb {
             }
 
             impl ReadEndpoint for $USBX {
+                /// Not supported: the eptri OUT interface has a single
+                /// receive FIFO shared by all endpoints.
+                fn set_double_buffered(&self, endpoint_number: u8, enabled: bool) -> bool {
+                    if enabled {
+                        warn!("  OUT{} double-buffering not supported, using single buffer",
+                              endpoint_number);
+                    }
+                    false
+                }
+
                 /// Prepare OUT endpoint to receive a single packet.
                 #[inline(always)]
                 fn ep_out_prime_receive(&self, endpoint_number: u8) {
+                    // endpoint is NAK'd, leave it unprimed
+                    if self.is_endpoint_nak(endpoint_number, Direction::HostToDevice) {
+                        trace!("  RX OUT{} NAK", endpoint_number);
+                        return;
+                    }
+
                     // clear receive buffer
                     self.ep_out.reset.write(|w| w.reset().bit(true));
 
-                    // select endpoint
-                    self.ep_out
-                        .epno
-                        .write(|w| unsafe { w.epno().bits(endpoint_number) });
-
-                    // prime endpoint
-                    self.ep_out.prime.write(|w| w.prime().bit(true));
+                    self.ep_out_prime(endpoint_number);
+                }
 
-                    // enable it
-                    self.ep_out.enable.write(|w| w.enable().bit(true));
+                /// The enable bit is cleared once a packet has been
+                /// received so a FIFO holding data also counts as
+                /// primed. NAK'd endpoints are deliberately left
+                /// unprimed and also count as primed.
+                #[inline(always)]
+                fn is_ep_out_primed(&self, endpoint_number: u8) -> bool {
+                    self.is_endpoint_nak(endpoint_number, Direction::HostToDevice)
+                        || self.ep_out.enable.read().enable().bit()
+                        || self.ep_out.have.read().have().bit()
                 }
 
                 #[inline(always)]
                 fn read(&self, endpoint_number: u8, buffer: &mut [u8]) -> usize {
+                    self.read_with_overflow(endpoint_number, buffer).0
+                }
+
+                #[inline(always)]
+                fn read_with_overflow(&self, endpoint_number: u8, buffer: &mut [u8]) -> (usize, usize) {
                     /*let mut bytes_read = 0;
                     let mut overflow = 0;
                     while self.ep_out.have.read().have().bit() {
@@ -516,95 +1246,153 @@ macro_rules! impl_usb {
                     if overflow == 0 {
                         trace!("  RX OUT{} {} bytes read", endpoint_number, bytes_read);
                     } else {
-                        warn!("  RX OUT{} {} bytes read + {} bytes overflow",
+                        warn_ratelimited!("  RX OUT{} {} bytes read + {} bytes overflow",
                               endpoint_number, bytes_read, overflow);
+                        self.record_overflow(endpoint_number, overflow);
                     }
 
-                    bytes_read
+                    (bytes_read, overflow)
                 }
             }
 
             impl WriteEndpoint for $USBX {
-                fn write_packets<'a, I>(&self, endpoint_number: u8, iter: I, packet_size: usize)
+                #[inline(always)]
+                fn write<I>(&self, endpoint_number: u8, iter: I) -> SmolResult<usize>
                 where
-                    I: Iterator<Item = u8>
+                    I: Iterator<Item = u8>,
                 {
-                    // reset output fifo if needed
-                    // TODO rather return an error
+                    // endpoint is NAK'd, leave the write to the caller
+                    if self.is_endpoint_nak(endpoint_number, Direction::DeviceToHost) {
+                        warn_ratelimited!("  TX IN{} NAK", endpoint_number);
+                        return Err(SmolError::WouldBlock);
+                    }
+
+                    // the host has not collected this endpoint's previous write yet
+                    if self.in_has_data(endpoint_number) {
+                        return Err(SmolError::WouldBlock);
+                    }
+
+                    // eptri has a single IN FIFO, discard data another
+                    // endpoint left in it
                     if self.ep_in.have.read().have().bit() {
-                        warn!("  clear tx");
-                        self.ep_in.reset.write(|w| w.reset().bit(true));
+                        warn_ratelimited!("  clear tx");
+                        self.reset_in_fifo_and_in_flight();
                     }
 
-                    // write data as multiple packets
+                    // write data, refusing to send an oversized packet
                     let mut bytes_written: usize = 0;
                     for byte in iter {
+                        if bytes_written == EP_MAX_PACKET_SIZE {
+                            self.reset_in_fifo_and_in_flight();
+                            return Err(SmolError::FifoOverflow);
+                        }
                         self.ep_in.data.write(|w| unsafe { w.data().bits(byte) });
                         bytes_written += 1;
-                        // end of chunk - transmit packet
-                        if bytes_written % packet_size == 0 {
-                            // prime IN endpoint
-                            self.ep_in
-                                .epno
-                                .write(|w| unsafe { w.epno().bits(endpoint_number) });
-                            // wait for transmission to complete
-                            while self.ep_in.have.read().have().bit() { }
-                            //unsafe { riscv::asm::delay(10000); }
-                        }
                     }
 
-                    // finally prime IN endpoint
+                    // finally, prime IN endpoint
                     self.ep_in
                         .epno
                         .write(|w| unsafe { w.epno().bits(endpoint_number) });
+                    self.set_in_flight(endpoint_number, true);
+
+                    Ok(bytes_written)
                 }
 
-                #[inline(always)]
-                fn write<I>(&self, endpoint_number: u8, iter: I)
+                fn write_packets<I>(&self, endpoint_number: u8, iter: I, packet_size: usize) -> SmolResult<usize>
+                where
+                    I: Iterator<Item = u8>
+                {
+                    self.write_in_packets(endpoint_number, iter, packet_size, false)
+                }
+
+                fn write_all<I>(&self, endpoint_number: u8, iter: I, max_packet_size: usize) -> SmolResult<usize>
                 where
                     I: Iterator<Item = u8>,
                 {
-                    // reset output fifo if needed
-                    // TODO rather return an error
-                    if self.ep_in.have.read().have().bit() {
-                        warn!("  clear tx");
-                        self.ep_in.reset.write(|w| w.reset().bit(true));
+                    self.write_in_packets(endpoint_number, iter, max_packet_size, true)
+                }
+
+                fn try_write<I>(&self, endpoint_number: u8, iter: I) -> SmolResult<usize>
+                where
+                    I: Iterator<Item = u8>,
+                {
+                    // endpoint is NAK'd, leave the write to the caller
+                    if self.is_endpoint_nak(endpoint_number, Direction::DeviceToHost) {
+                        warn_ratelimited!("  TX IN{} NAK", endpoint_number);
+                        return Err(SmolError::WouldBlock);
+                    }
+
+                    if self.in_endpoint_busy(endpoint_number) {
+                        return if self.is_in_flight(endpoint_number) {
+                            // host has not collected the previous write yet
+                            Err(SmolError::WouldBlock)
+                        } else {
+                            // output fifo still holds data from a previous write
+                            Err(SmolError::TxInProgress)
+                        };
                     }
 
-                    // write data
                     let mut bytes_written: usize = 0;
                     for byte in iter {
+                        if bytes_written == EP_MAX_PACKET_SIZE {
+                            self.reset_in_fifo();
+                            return Err(SmolError::FifoOverflow);
+                        }
                         self.ep_in.data.write(|w| unsafe { w.data().bits(byte) });
                         bytes_written += 1;
                     }
 
-                    // finally, prime IN endpoint
+                    // prime IN endpoint
                     self.ep_in
                         .epno
                         .write(|w| unsafe { w.epno().bits(endpoint_number) });
+                    self.set_in_flight(endpoint_number, true);
 
-                    if bytes_written > 60 {
-                        log::debug!("  TX {} bytes", bytes_written);
-                    }
+                    Ok(bytes_written)
+                }
+
+                fn is_in_flight(&self, endpoint_number: u8) -> bool {
+                    let mask = 1_u16 << (endpoint_number & 0xf);
+                    (self.in_flight_bitmap() & mask) != 0
+                }
+
+                fn reset_in_fifo(&self) {
+                    self.reset_in_fifo_and_in_flight();
                 }
             }
 
             impl WriteRefEndpoint for $USBX {
                 #[inline(always)]
-                fn write_ref<'a, I>(&self, endpoint_number: u8, iter: I)
+                fn write_ref<'a, I>(&self, endpoint_number: u8, iter: I) -> SmolResult<usize>
                 where
                     I: Iterator<Item = &'a u8>,
                 {
-                    // reset output fifo if needed
-                    // TODO rather return an error
+                    // endpoint is NAK'd, leave the write to the caller
+                    if self.is_endpoint_nak(endpoint_number, Direction::DeviceToHost) {
+                        warn_ratelimited!("  TX IN{} NAK", endpoint_number);
+                        return Err(SmolError::WouldBlock);
+                    }
+
+                    // the host has not collected this endpoint's previous write yet
+                    if self.in_has_data(endpoint_number) {
+                        return Err(SmolError::WouldBlock);
+                    }
+
+                    // eptri has a single IN FIFO, discard data another
+                    // endpoint left in it
                     if self.ep_in.have.read().have().bit() {
-                        warn!("  clear tx");
-                        self.ep_in.reset.write(|w| w.reset().bit(true));
+                        warn_ratelimited!("  clear tx");
+                        self.reset_in_fifo_and_in_flight();
                     }
 
-                    // write data
+                    // write data, refusing to send an oversized packet
                     let mut bytes_written: usize = 0;
                     for byte in iter {
+                        if bytes_written == EP_MAX_PACKET_SIZE {
+                            self.reset_in_fifo_and_in_flight();
+                            return Err(SmolError::FifoOverflow);
+                        }
                         self.ep_in.data.write(|w| unsafe { w.data().bits(*byte) });
                         bytes_written += 1;
                     }
@@ -613,8 +1401,11 @@ macro_rules! impl_usb {
                     self.ep_in
                         .epno
                         .write(|w| unsafe { w.epno().bits(endpoint_number) });
+                    self.set_in_flight(endpoint_number, true);
 
                     trace!("  TX {} bytes", bytes_written);
+
+                    Ok(bytes_written)
                 }
             }
 
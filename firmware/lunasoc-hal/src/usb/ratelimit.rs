@@ -0,0 +1,97 @@
+//! Rate-limited logging for the USB hot path
+//!
+//! Logging every stale FIFO reset or overflow from interrupt context
+//! floods the UART and perturbs the very timing being debugged. The
+//! `*_ratelimited!` macros emit at most one message per call site per
+//! interval and report the number of messages suppressed in between:
+//!
+//!     warn_ratelimited!("  clear tx");
+//!     // => "  clear tx (x42 suppressed)"
+//!
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::pac;
+
+/// Minimum interval between two messages from the same call site
+pub const RATELIMIT_INTERVAL_MS: u32 = 100;
+
+/// Per call-site rate limiter
+///
+/// Only uses atomic loads and stores so it works on targets without
+/// the atomic extension. A message racing with an interrupt may be
+/// miscounted, which is harmless.
+pub struct RateLimiter {
+    interval: u32,
+    last: AtomicU32,
+    suppressed: AtomicU32,
+}
+
+impl RateLimiter {
+    pub const fn new(interval_ms: u32) -> Self {
+        Self {
+            interval: (pac::clock::sysclk() / 1000) * interval_ms,
+            last: AtomicU32::new(0),
+            suppressed: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns the number of messages suppressed since the last one
+    /// if a message may be logged now, otherwise `None`.
+    pub fn check(&self) -> Option<u32> {
+        let now = riscv::register::mcycle::read() as u32;
+        let last = self.last.load(Ordering::Relaxed);
+        let suppressed = self.suppressed.load(Ordering::Relaxed);
+
+        if last != 0 && now.wrapping_sub(last) < self.interval {
+            self.suppressed
+                .store(suppressed.saturating_add(1), Ordering::Relaxed);
+            return None;
+        }
+
+        // never store 0 so the next message is rate-limited
+        self.last.store(now | 1, Ordering::Relaxed);
+        self.suppressed.store(0, Ordering::Relaxed);
+
+        Some(suppressed)
+    }
+}
+
+/// Log a message at the given level, rate-limited per call site
+///
+/// Compiles to nothing if `$level` is above the `log` crate's static
+/// maximum level, e.g. with moondancer's `no-log` feature.
+macro_rules! log_ratelimited {
+    ($level:expr, $($arg:tt)+) => {{
+        static LIMITER: $crate::usb::ratelimit::RateLimiter =
+            $crate::usb::ratelimit::RateLimiter::new(
+                $crate::usb::ratelimit::RATELIMIT_INTERVAL_MS
+            );
+        if $level <= log::STATIC_MAX_LEVEL {
+            match LIMITER.check() {
+                Some(0) => log::log!($level, $($arg)+),
+                Some(suppressed) => log::log!(
+                    $level,
+                    "{} (x{} suppressed)",
+                    format_args!($($arg)+),
+                    suppressed
+                ),
+                None => (),
+            }
+        }
+    }};
+}
+
+/// `warn!` rate-limited per call site
+macro_rules! warn_ratelimited {
+    ($($arg:tt)+) => {
+        log_ratelimited!(log::Level::Warn, $($arg)+)
+    };
+}
+
+/// `debug!` rate-limited per call site
+macro_rules! debug_ratelimited {
+    ($($arg:tt)+) => {
+        log_ratelimited!(log::Level::Debug, $($arg)+)
+    };
+}
@@ -0,0 +1,152 @@
+//! Snapshot of the eptri USB controller registers
+//!
+//! Taken with `Usb::dump_registers()` for diagnosing enumeration
+//! problems. Only registers that can be read without side effects are
+//! included, the `data` FIFO registers are skipped because reading
+//! them pops a byte.
+//!
+//! The `stall` and `pid` fields of `ep_in` and `ep_out` refer to the
+//! endpoint currently selected by the matching `epno` register.
+
+use core::fmt;
+
+/// Size of a serialized [`RegisterSnapshot`]
+pub const REGISTER_SNAPSHOT_SIZE: usize = 34;
+
+/// Event registers common to each eptri interface
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EventRegisters {
+    pub status: u8,
+    pub pending: u8,
+    pub enable: u8,
+}
+
+/// `USBx` device controller registers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerRegisters {
+    pub connect: u8,
+    pub speed: u8,
+    pub low_speed_only: u8,
+    pub full_speed_only: u8,
+    pub ev: EventRegisters,
+}
+
+/// `USBx_EP_CONTROL` SETUP handler registers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EpControlRegisters {
+    pub epno: u8,
+    pub have: u8,
+    pub pend: u8,
+    pub address: u8,
+    pub ev: EventRegisters,
+}
+
+/// `USBx_EP_IN` IN handler registers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EpInRegisters {
+    pub epno: u8,
+    pub stall: u8,
+    pub idle: u8,
+    pub have: u8,
+    pub pend: u8,
+    pub pid: u8,
+    pub ev: EventRegisters,
+}
+
+/// `USBx_EP_OUT` OUT handler registers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EpOutRegisters {
+    pub data_ep: u8,
+    pub epno: u8,
+    pub enable: u8,
+    pub stall: u8,
+    pub have: u8,
+    pub pend: u8,
+    pub address: u8,
+    pub pid: u8,
+    pub ev: EventRegisters,
+}
+
+/// The readable registers of one eptri USB interface
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterSnapshot {
+    pub controller: ControllerRegisters,
+    pub ep_control: EpControlRegisters,
+    pub ep_in: EpInRegisters,
+    pub ep_out: EpOutRegisters,
+}
+
+impl EventRegisters {
+    fn as_bytes(&self) -> [u8; 3] {
+        [self.status, self.pending, self.enable]
+    }
+}
+
+impl RegisterSnapshot {
+    /// Returns the snapshot as bytes in register order for transfer
+    /// to the host
+    pub fn as_bytes(&self) -> [u8; REGISTER_SNAPSHOT_SIZE] {
+        let c = &self.controller;
+        let ctl = &self.ep_control;
+        let i = &self.ep_in;
+        let o = &self.ep_out;
+
+        let mut bytes = [0; REGISTER_SNAPSHOT_SIZE];
+        let fields = [c.connect, c.speed, c.low_speed_only, c.full_speed_only]
+            .into_iter()
+            .chain(c.ev.as_bytes())
+            .chain([ctl.epno, ctl.have, ctl.pend, ctl.address])
+            .chain(ctl.ev.as_bytes())
+            .chain([i.epno, i.stall, i.idle, i.have, i.pend, i.pid])
+            .chain(i.ev.as_bytes())
+            .chain([
+                o.data_ep, o.epno, o.enable, o.stall, o.have, o.pend, o.address, o.pid,
+            ])
+            .chain(o.ev.as_bytes());
+        for (dest, byte) in bytes.iter_mut().zip(fields) {
+            *dest = byte;
+        }
+        bytes
+    }
+}
+
+// - trait: Display -----------------------------------------------------------
+
+impl fmt::Display for EventRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ev_status:{:#04x} ev_pending:{:#04x} ev_enable:{:#04x}",
+            self.status, self.pending, self.enable
+        )
+    }
+}
+
+impl fmt::Display for RegisterSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let c = &self.controller;
+        writeln!(
+            f,
+            "controller  connect:{} speed:{} low_speed_only:{} full_speed_only:{} {}",
+            c.connect, c.speed, c.low_speed_only, c.full_speed_only, c.ev
+        )?;
+        let ctl = &self.ep_control;
+        writeln!(
+            f,
+            "ep_control  epno:{} have:{} pend:{} address:{} {}",
+            ctl.epno, ctl.have, ctl.pend, ctl.address, ctl.ev
+        )?;
+        let i = &self.ep_in;
+        writeln!(
+            f,
+            "ep_in       epno:{} stall:{} idle:{} have:{} pend:{} pid:{} {}",
+            i.epno, i.stall, i.idle, i.have, i.pend, i.pid, i.ev
+        )?;
+        let o = &self.ep_out;
+        write!(
+            f,
+            "ep_out      data_ep:{} epno:{} enable:{} stall:{} have:{} pend:{} address:{} pid:{} {}",
+            o.data_ep, o.epno, o.enable, o.stall, o.have, o.pend, o.address, o.pid, o.ev
+        )
+    }
+}
@@ -25,6 +25,9 @@ PRODUCT_ID = cynthion.shared.usb.bProductId.example
 BULK_ENDPOINT_NUMBER = 1
 COMMAND_ENDPOINT_NUMBER = 2
 
+# Seed of the PRBS pattern used by the verification tests, must match the firmware
+VERIFY_PRBS_SEED = 0x5eedc0de
+
 # Set the total amount of data to be used in our speed test.
 TEST_DATA_SIZE = 2 * 1024 * 1024 # 2MB
 
@@ -82,9 +85,11 @@ TRANSFER_QUEUE_DEPTH = 8
 
 # Test commands
 class TestCommand(IntEnum):
-    Stop = 0x01,
-    In   = 0x23,
-    Out  = 0x42,
+    Stop      = 0x01,
+    In        = 0x23,
+    VerifyIn  = 0x24,
+    Out       = 0x42,
+    VerifyOut = 0x43,
 
 # Error messages
 _messages = {
@@ -97,20 +102,60 @@ _messages = {
 }
 
 
+# - Test Data -----------------------------------------------------------------
+
+def prbs(length, seed):
+    """ Returns the same xorshift32 PRBS pattern as moondancer::test_data::fill_prbs() """
+
+    state = seed or 0x2545f491
+    data = bytearray()
+    while len(data) < length:
+        state ^= (state << 13) & 0xffffffff
+        state ^= state >> 17
+        state ^= (state << 5) & 0xffffffff
+        data += state.to_bytes(4, "little")
+    return data[:length]
+
+
 # - Bulk Speed Test -----------------------------------------------------------
 
-def run_speed_test(direction=usb1.ENDPOINT_IN):
-    """ Runs a simple IN speed test, and reports throughput. """
+def run_speed_test(direction=usb1.ENDPOINT_IN, verify=False):
+    """ Runs a simple speed test, and reports throughput.
 
-    test_data = bytearray([x % 256 for x in range(512)])
+    If verify is set the exchanged data is checked against a PRBS pattern
+    and any mismatches are reported.
+    """
+
+    if verify:
+        test_data = prbs(512, VERIFY_PRBS_SEED)
+    else:
+        test_data = bytearray([x % 256 for x in range(512)])
     total_data_exchanged = 0
     failed_out = False
 
+    # IN verification state
+    mismatch_count = 0
+    first_error_offset = None
+
     def _should_terminate():
         """ Returns true iff our test should terminate. """
         return (total_data_exchanged > TEST_DATA_SIZE) or failed_out
 
 
+    def _verify_in(buffer, stream_offset):
+        """ Checks received IN data against the repeated test pattern. """
+        nonlocal mismatch_count, first_error_offset
+
+        for packet_offset in range(0, len(buffer), len(test_data)):
+            packet = buffer[packet_offset:packet_offset + len(test_data)]
+            for n, byte in enumerate(packet):
+                if byte != test_data[n]:
+                    mismatch_count += 1
+                    if first_error_offset is None:
+                        first_error_offset = stream_offset + packet_offset + n
+                    break
+
+
     def _transfer_completed(transfer: usb1.USBTransfer):
         """ Callback executed when an async transfer completes. """
         nonlocal total_data_exchanged, failed_out
@@ -120,8 +165,13 @@ def run_speed_test(direction=usb1.ENDPOINT_IN):
         # If the transfer completed.
         if status in (usb1.TRANSFER_COMPLETED,):
 
+            # Verify the data received in this transfer...
+            length = transfer.getActualLength()
+            if verify and direction == usb1.ENDPOINT_IN:
+                _verify_in(transfer.getBuffer()[:length], total_data_exchanged)
+
             # Count the data exchanged in this packet...
-            total_data_exchanged += transfer.getActualLength()
+            total_data_exchanged += length
             logging.debug(f"usb1.TRANSFER_COMPLETED: {total_data_exchanged} bytes")
 
             if direction == usb1.ENDPOINT_IN:
@@ -172,9 +222,10 @@ def run_speed_test(direction=usb1.ENDPOINT_IN):
 
         # Tell Cynthion to start transmitting/receiving
         if direction == usb1.ENDPOINT_IN:
-            device.bulkWrite(COMMAND_ENDPOINT_NUMBER, [TestCommand.In])
+            command = TestCommand.VerifyIn if verify else TestCommand.In
         else:
-            device.bulkWrite(COMMAND_ENDPOINT_NUMBER, [TestCommand.Out])
+            command = TestCommand.VerifyOut if verify else TestCommand.Out
+        device.bulkWrite(COMMAND_ENDPOINT_NUMBER, [command])
 
         # Run our transfers until we get enough data.
         while not _should_terminate():
@@ -201,6 +252,19 @@ def run_speed_test(direction=usb1.ENDPOINT_IN):
         bytes_per_second = total_data_exchanged / elapsed
         logging.info(f"Exchanged {total_data_exchanged / 1000000}MB total at {bytes_per_second / 1000000}MB/s.")
 
+        if verify:
+            # Cynthion reports its OUT verification results once stopped
+            report = device.bulkRead(usb1.ENDPOINT_IN | COMMAND_ENDPOINT_NUMBER, 8, timeout=1000)
+            if direction == usb1.ENDPOINT_OUT:
+                mismatch_count = int.from_bytes(report[0:4], "little")
+                offset = int.from_bytes(report[4:8], "little")
+                first_error_offset = None if offset == 0xffffffff else offset
+
+            if mismatch_count:
+                logging.error(f"Verification failed: {mismatch_count} mismatched packets, first error at offset {first_error_offset}.")
+            else:
+                logging.info("Verification passed.")
+
 
 
 
@@ -220,6 +284,14 @@ if __name__ == "__main__":
 
         logging.info("Running OUT speed test...")
         run_speed_test(direction=usb1.ENDPOINT_OUT)
+        time.sleep(1)
+
+        logging.info("Running IN verification test...")
+        run_speed_test(direction=usb1.ENDPOINT_IN, verify=True)
+        time.sleep(1)
+
+        logging.info("Running OUT verification test...")
+        run_speed_test(direction=usb1.ENDPOINT_OUT, verify=True)
 
     except Exception as e:
         logging.error(f"USB Bulk speed test failed: {e}")
@@ -1,7 +1,6 @@
 #![no_std]
 #![no_main]
 
-use heapless::mpmc::MpMcQueue as Queue;
 use log::{debug, error, info, warn};
 
 use libgreat::{GreatError, GreatResult};
@@ -9,28 +8,32 @@ use libgreat::{GreatError, GreatResult};
 use smolusb::descriptor::*;
 use smolusb::device::UsbDevice;
 use smolusb::event::UsbEvent;
-use smolusb::traits::{ReadEndpoint, UnsafeUsbDriverOperations, UsbDriverOperations};
+use smolusb::traits::{
+    ReadEndpoint, UnsafeUsbDriverOperations, UsbDriverOperations, WriteRefEndpoint,
+};
 
-use moondancer::event::InterruptEvent;
+use moondancer::command::{Command, Response};
+use moondancer::event::{EventQueue, InterruptEvent};
+use moondancer::fmt::HexSlice;
+use moondancer::interrupt::PendingInterrupts;
 use moondancer::{hal, pac};
 
 // - constants ----------------------------------------------------------------
 
-const MAX_CONTROL_RESPONSE_SIZE: usize = 8;
+/// Largest control OUT data stage accepted, IN responses are unbuffered
+const MAX_CONTROL_OUT_SIZE: usize = 8;
+
+/// Seed of the PRBS pattern used by the verification tests
+const VERIFY_PRBS_SEED: u32 = 0x5eed_c0de;
 
 // - global static state ------------------------------------------------------
 
-static EVENT_QUEUE: Queue<InterruptEvent, 32> = Queue::new();
+static EVENT_QUEUE: EventQueue<InterruptEvent, 32> = EventQueue::new();
 
 #[inline(always)]
 fn dispatch_event(event: InterruptEvent) {
-    match EVENT_QUEUE.enqueue(event) {
-        Ok(()) => (),
-        Err(_) => {
-            error!("MachineExternal - event queue overflow");
-            panic!("MachineExternal - event queue overflow");
-        }
-    }
+    // overflows are counted by the queue and reported when the test stops
+    let _ = EVENT_QUEUE.enqueue(event);
 }
 
 // - MachineExternal interrupt handler ----------------------------------------
@@ -61,7 +64,7 @@ fn MachineExternal() {
 
     // USB0_EP_OUT UsbReceiveData
     } else if usb0.is_pending(pac::Interrupt::USB0_EP_OUT) {
-        let endpoint = usb0.ep_out.data_ep.read().bits() as u8;
+        let endpoint = usb0.ep_out_endpoint_number();
 
         // discard packets from Bulk OUT transfer endpoint
         /*if endpoint == 1 {
@@ -81,7 +84,7 @@ fn MachineExternal() {
 
     // USB0_EP_IN UsbTransferComplete
     } else if usb0.is_pending(pac::Interrupt::USB0_EP_IN) {
-        let endpoint = usb0.ep_in.epno.read().bits() as u8;
+        let endpoint = usb0.ep_in_send_complete();
         usb0.clear_pending(pac::Interrupt::USB0_EP_IN);
 
         // TODO something a little bit safer would be nice
@@ -96,8 +99,7 @@ fn MachineExternal() {
 
     // - Unknown Interrupt --
     } else {
-        let pending = pac::csr::interrupt::reg_pending();
-        dispatch_event(InterruptEvent::UnknownInterrupt(pending));
+        dispatch_event(InterruptEvent::UnknownInterrupt(PendingInterrupts::read()));
     }
 }
 
@@ -136,7 +138,7 @@ fn main_loop() -> GreatResult<()> {
     info!("Logging initialized");
 
     // usb0: Target
-    let mut usb0 = UsbDevice::<_, MAX_CONTROL_RESPONSE_SIZE>::new(
+    let mut usb0 = UsbDevice::<_, MAX_CONTROL_OUT_SIZE>::new(
         hal::Usb0::new(
             peripherals.USB0,
             peripherals.USB0_EP_CONTROL,
@@ -150,7 +152,10 @@ fn main_loop() -> GreatResult<()> {
     );
     usb0.set_device_qualifier_descriptor(USB_DEVICE_QUALIFIER_DESCRIPTOR);
     usb0.set_other_speed_configuration_descriptor(USB_OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0);
-    let speed = usb0.connect();
+    let speed = usb0.connect().map_err(|e| {
+        error!("Failed to connect usb0 device: {}", e);
+        GreatError::ConnectionRefused
+    })?;
     debug!("Connected usb0 device: {:?}", speed);
 
     // enable interrupts
@@ -171,29 +176,29 @@ fn main_loop() -> GreatResult<()> {
 
     info!("Peripherals initialized, entering main loop.");
 
-    let mut test_command = TestCommand::Stop;
+    let mut test_command = Command::Stop;
     let mut test_stats = TestStats::new();
 
-    // 4 MB/s
     let test_data = {
         let mut test_data = [0_u8; moondancer::EP_MAX_PACKET_SIZE];
-        for n in 0..moondancer::EP_MAX_PACKET_SIZE {
-            test_data[n] = (n % 256) as u8;
-        }
+        moondancer::test_data::fill_ramp(&mut test_data);
         test_data
     };
 
-    // prime the usb OUT endpoints we'll be using
-    usb0.hal_driver.ep_out_prime_receive(1);
-    usb0.hal_driver.ep_out_prime_receive(2);
+    // the host sends and expects this pattern repeatedly during verification tests
+    let verify_data = {
+        let mut verify_data = [0_u8; moondancer::EP_MAX_PACKET_SIZE];
+        moondancer::test_data::fill_prbs(&mut verify_data, VERIFY_PRBS_SEED);
+        verify_data
+    };
+
+    // OUT endpoints are primed by UsbDevice once the host has set a configuration
 
     let mut counter = 0;
 
     let mut rx_buffer: [u8; moondancer::EP_MAX_PACKET_SIZE] = [0; moondancer::EP_MAX_PACKET_SIZE];
 
     loop {
-        let mut queue_length = 0;
-
         while let Some(event) = EVENT_QUEUE.dequeue() {
             use moondancer::{event::InterruptEvent::*, UsbInterface::Target};
             use smolusb::event::UsbEvent::*;
@@ -226,51 +231,51 @@ fn main_loop() -> GreatResult<()> {
                 // Usb0 received packet
                 Usb(Target, ReceivePacket(endpoint)) => {
                     let bytes_read = usb0.hal_driver.read(endpoint, &mut rx_buffer);
+                    let _ = usb0.ep_out_prime_receive(endpoint);
                     if endpoint == 1 {
                         leds.output.write(|w| unsafe { w.output().bits(0b11_1000) });
+                        if test_command == Command::VerifyOut {
+                            test_stats.verify_out(&verify_data, &rx_buffer[..bytes_read]);
+                        }
                         if counter % 100 == 0 {
                             log::trace!(
-                                "{:?} .. {:?}",
-                                &rx_buffer[0..8],
-                                &rx_buffer[(bytes_read - 8)..]
+                                "{} .. {}",
+                                HexSlice::new(&rx_buffer[0..8]),
+                                HexSlice::new(&rx_buffer[(bytes_read - 8)..])
                             );
                         }
                         counter += 1;
-                        usb0.hal_driver.ep_out_prime_receive(1);
                     } else if endpoint == 2 {
                         info!("received command data from host: {} bytes", bytes_read);
-                        let command = rx_buffer[0].into();
-                        match (bytes_read, &command) {
-                            (1, TestCommand::In) => {
-                                info!("starting test: IN");
+                        match Command::parse(&rx_buffer[..bytes_read]) {
+                            Ok(command) if command.is_start() => {
+                                info!("starting test: {:?}", command);
                                 test_stats.reset();
-                                test_command = TestCommand::In;
+                                EVENT_QUEUE.reset_stats();
+                                test_command = command;
                             }
-                            (1, TestCommand::Out) => {
-                                info!("starting test: OUT");
-                                test_stats.reset();
-                                test_command = TestCommand::Out;
-                            }
-                            (1, command) => {
-                                info!("stopping test: {:?}", command);
-                                info!("  max write time: {}", test_stats.max_write_time);
-                                info!("  min write time: {}", test_stats.min_write_time);
-                                info!("  max flush time: {}", test_stats.max_flush_time);
-                                info!("  min flush time: {}", test_stats.min_flush_time);
+                            Ok(_) => {
+                                info!("stopping test: {:?}", test_command);
+                                test_stats.log_times();
                                 info!("  write count: {}", test_stats.write_count);
                                 info!("  reset count: {}", test_stats.reset_count);
-                                test_command = TestCommand::Stop;
+                                info!("  max queue length: {}", EVENT_QUEUE.high_water_mark());
+                                info!("  dropped events: {}", EVENT_QUEUE.dropped());
+                                if test_command.is_verify() {
+                                    test_stats.log_verification();
+                                    if let Err(e) = usb0.hal_driver.write_ref(
+                                        0x2,
+                                        test_stats.verification_report().to_bytes().iter(),
+                                    ) {
+                                        warn!("failed to send verification report: {:?}", e);
+                                    }
+                                }
+                                test_command = Command::Stop;
                             }
-                            (bytes_read, _) => {
-                                error!(
-                                    "received invalid command from host: {:?} (read {} bytes)",
-                                    command, bytes_read,
-                                );
+                            Err(e) => {
+                                error!("received invalid command from host: {}", e);
                             }
                         }
-                        usb0.hal_driver.ep_out_prime_receive(2);
-                    } else {
-                        usb0.hal_driver.ep_out_prime_receive(endpoint);
                     }
                 }
 
@@ -284,27 +289,27 @@ fn main_loop() -> GreatResult<()> {
                     error!("MachineExternal Error - {}", message);
                 }
 
+                // Unexpected interrupt
+                UnknownInterrupt(pending) => {
+                    error!("MachineExternal Unknown interrupt - {}", pending);
+                }
+
                 // Unhandled event
                 _ => {
                     error!("Unhandled event: {:?}", event);
                 }
             }
-
-            queue_length += 1;
         }
 
         // perform tests
         match test_command {
-            TestCommand::In => test_in_speed(leds, &usb0.hal_driver, &test_data, &mut test_stats),
-            TestCommand::Out => (),
+            Command::In => test_in_speed(leds, &usb0.hal_driver, &test_data, &mut test_stats),
+            Command::VerifyIn => {
+                test_in_speed(leds, &usb0.hal_driver, &verify_data, &mut test_stats)
+            }
+            Command::Out => (),
             _ => (),
         }
-
-        // queue diagnostics
-        if queue_length > test_stats.max_queue_length {
-            test_stats.max_queue_length = queue_length;
-            debug!("max_queue_length: {}", test_stats.max_queue_length);
-        }
     }
 }
 
@@ -346,10 +351,7 @@ fn test_in_speed(
 
     // wait for fifo endpoint to be idle
     let (_, t_flush) = moondancer::profile!(
-        let mut timeout = 100;
-        while !usb0.ep_in.idle.read().idle().bit() && timeout > 0 {
-            timeout -= 1;
-        }
+        let _ = usb0.wait_in_idle(0x1, 100);
     );
 
     // write data to endpoint fifo
@@ -366,28 +368,14 @@ fn test_in_speed(
 
 // - types --------------------------------------------------------------------
 
-#[derive(Debug, PartialEq)]
-#[repr(u8)]
-enum TestCommand {
-    Stop,
-    In = 0x23,
-    Out = 0x42,
-    Error = 0xff,
-}
-
-impl From<u8> for TestCommand {
-    fn from(value: u8) -> Self {
-        match value {
-            0x23 => TestCommand::In,
-            0x42 => TestCommand::Out,
-            0xff => TestCommand::Error,
-            _ => TestCommand::Stop,
-        }
-    }
-}
-
+/// Test statistics
+///
+/// The verification tests exchange a repeated PRBS pattern instead of a
+/// ramp. On stop the device reports the number of mismatched OUT packets
+/// and the offset of the first mismatched byte on endpoint 0x82, see
+/// `TestStats::verification_report`. IN data is verified by the host.
 struct TestStats {
-    max_queue_length: usize,
+    start_time: u64,
 
     max_write_time: usize,
     min_write_time: usize,
@@ -396,23 +384,101 @@ struct TestStats {
 
     write_count: usize,
     reset_count: usize,
+
+    verified_bytes: usize,
+    mismatch_count: usize,
+    first_error_offset: Option<usize>,
 }
 
 impl TestStats {
     const fn new() -> Self {
         Self {
-            max_queue_length: 0,
+            start_time: 0,
             max_write_time: 0,
             min_write_time: usize::MAX,
             max_flush_time: 0,
             min_flush_time: usize::MAX,
             write_count: 0,
             reset_count: 0,
+            verified_bytes: 0,
+            mismatch_count: 0,
+            first_error_offset: None,
         }
     }
 
     fn reset(&mut self) {
         *self = Self::new();
+        self.start_time = riscv::register::mcycle::read64();
+    }
+
+    fn log_times(&self) {
+        use moondancer::cycles_to_ns;
+
+        let elapsed = riscv::register::mcycle::read64() - self.start_time;
+        let bytes = (self.write_count * moondancer::EP_MAX_PACKET_SIZE) as u64;
+
+        info!(
+            "  max write time: {} cycles ({} ns)",
+            self.max_write_time,
+            cycles_to_ns(self.max_write_time as u64)
+        );
+        info!(
+            "  min write time: {} cycles ({} ns)",
+            self.min_write_time,
+            cycles_to_ns(self.min_write_time as u64)
+        );
+        info!(
+            "  max flush time: {} cycles ({} ns)",
+            self.max_flush_time,
+            cycles_to_ns(self.max_flush_time as u64)
+        );
+        info!(
+            "  min flush time: {} cycles ({} ns)",
+            self.min_flush_time,
+            cycles_to_ns(self.min_flush_time as u64)
+        );
+        info!(
+            "  throughput: {} bytes/s ({} bytes in {} us)",
+            moondancer::clock::bytes_per_second(bytes, elapsed),
+            bytes,
+            moondancer::cycles_to_us(elapsed)
+        );
+    }
+
+    /// Check a received OUT packet against the repeated `pattern`
+    fn verify_out(&mut self, pattern: &[u8], data: &[u8]) {
+        let offset = self.verified_bytes % pattern.len();
+        let mismatch = match pattern.get(offset..offset + data.len()) {
+            Some(expected) => data.iter().zip(expected).position(|(a, b)| a != b),
+            // packet straddles the end of the pattern
+            None => data
+                .iter()
+                .zip(pattern.iter().cycle().skip(offset))
+                .position(|(a, b)| a != b),
+        };
+
+        if let Some(position) = mismatch {
+            self.mismatch_count += 1;
+            if self.first_error_offset.is_none() {
+                self.first_error_offset = Some(self.verified_bytes + position);
+            }
+        }
+        self.verified_bytes += data.len();
+    }
+
+    fn log_verification(&self) {
+        info!("  verified bytes: {}", self.verified_bytes);
+        info!("  mismatched packets: {}", self.mismatch_count);
+        if let Some(offset) = self.first_error_offset {
+            info!("  first error offset: {}", offset);
+        }
+    }
+
+    fn verification_report(&self) -> Response {
+        Response {
+            mismatch_count: self.mismatch_count as u32,
+            first_error_offset: self.first_error_offset.map(|offset| offset as u32),
+        }
     }
 
     #[inline(always)]
@@ -440,14 +506,14 @@ impl TestStats {
 use moondancer::usb::{DEVICE_SERIAL_STRING, DEVICE_VERSION_NUMBER};
 
 static USB_DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
-    descriptor_version: 0x0200,
+    descriptor_version: le16(0x0200),
     device_class: 0x00,
     device_subclass: 0x00,
     device_protocol: 0x00,
     max_packet_size: 64,
-    vendor_id: cynthion::shared::usb::bVendorId::example,
-    product_id: cynthion::shared::usb::bProductId::example,
-    device_version_number: DEVICE_VERSION_NUMBER,
+    vendor_id: le16(cynthion::shared::usb::bVendorId::example),
+    product_id: le16(cynthion::shared::usb::bProductId::example),
+    device_version_number: le16(DEVICE_VERSION_NUMBER),
     manufacturer_string_index: 1,
     product_string_index: 2,
     serial_string_index: 3,
@@ -456,7 +522,7 @@ static USB_DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
 };
 
 static USB_DEVICE_QUALIFIER_DESCRIPTOR: DeviceQualifierDescriptor = DeviceQualifierDescriptor {
-    descriptor_version: 0x0200,
+    descriptor_version: le16(0x0200),
     device_class: 0x00,
     device_subclass: 0x00,
     device_protocol: 0x00,
@@ -470,8 +536,8 @@ static USB_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDe
     ConfigurationDescriptorHeader {
         configuration_value: 1,
         configuration_string_index: 1,
-        attributes: 0x80, // 0b1000_0000 = bus-powered
-        max_power: 50,    // 50 * 2 mA = 100 mA
+        attributes: ConfigurationAttributes::new().bits(),
+        max_power: 50, // 50 * 2 mA = 100 mA
         ..ConfigurationDescriptorHeader::new()
     },
     &[InterfaceDescriptor::new(
@@ -487,22 +553,29 @@ static USB_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDe
         &[
             EndpointDescriptor {
                 endpoint_address: 0x01, // OUT
-                attributes: 0x02,       // Bulk
-                max_packet_size: 512,
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(512),
                 interval: 0,
                 ..EndpointDescriptor::new()
             },
             EndpointDescriptor {
                 endpoint_address: 0x02, // OUT - host commands
-                attributes: 0x02,       // Bulk
-                max_packet_size: 8,
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(8),
                 interval: 0,
                 ..EndpointDescriptor::new()
             },
             EndpointDescriptor {
                 endpoint_address: 0x81, // IN
-                attributes: 0x02,       // Bulk
-                max_packet_size: 512,
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(512),
+                interval: 0,
+                ..EndpointDescriptor::new()
+            },
+            EndpointDescriptor {
+                endpoint_address: 0x82, // IN - command responses
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(8),
                 interval: 0,
                 ..EndpointDescriptor::new()
             },
@@ -516,8 +589,8 @@ static USB_OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
             descriptor_type: DescriptorType::OtherSpeedConfiguration as u8,
             configuration_value: 1,
             configuration_string_index: 1,
-            attributes: 0x80, // 0b1000_0000 = bus-powered
-            max_power: 50,    // 50 * 2 mA = 100 mA
+            attributes: ConfigurationAttributes::new().bits(),
+            max_power: 50, // 50 * 2 mA = 100 mA
             ..ConfigurationDescriptorHeader::new()
         },
         &[InterfaceDescriptor::new(
@@ -533,22 +606,29 @@ static USB_OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
             &[
                 EndpointDescriptor {
                     endpoint_address: 0x01, // OUT
-                    attributes: 0x02,       // Bulk
-                    max_packet_size: 64,
+                    attributes: EndpointAttributes::bulk().bits(),
+                    max_packet_size: le16(64),
                     interval: 0,
                     ..EndpointDescriptor::new()
                 },
                 EndpointDescriptor {
                     endpoint_address: 0x02, // OUT - host commands
-                    attributes: 0x02,       // Bulk
-                    max_packet_size: 8,
+                    attributes: EndpointAttributes::bulk().bits(),
+                    max_packet_size: le16(8),
                     interval: 0,
                     ..EndpointDescriptor::new()
                 },
                 EndpointDescriptor {
                     endpoint_address: 0x81, // IN
-                    attributes: 0x02,       // Bulk
-                    max_packet_size: 64,
+                    attributes: EndpointAttributes::bulk().bits(),
+                    max_packet_size: le16(64),
+                    interval: 0,
+                    ..EndpointDescriptor::new()
+                },
+                EndpointDescriptor {
+                    endpoint_address: 0x82, // IN - command responses
+                    attributes: EndpointAttributes::bulk().bits(),
+                    max_packet_size: le16(8),
                     interval: 0,
                     ..EndpointDescriptor::new()
                 },
@@ -1,43 +1,93 @@
 #![no_std]
 #![no_main]
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use log::{debug, error, info, warn};
 
 use smolusb::class::cdc;
-use smolusb::device::{Speed, UsbDevice};
+use smolusb::class::cdc::{acm, AcmDevice};
+use smolusb::device::{ControlOutcome, UnknownRequestPolicy, UsbDevice};
 use smolusb::event::UsbEvent;
 use smolusb::setup::SetupPacket;
 use smolusb::traits::{
     ReadControl, ReadEndpoint, UnsafeUsbDriverOperations, UsbDriverOperations, WriteEndpoint,
     WriteRefEndpoint,
 };
+use smolusb::RecoveryPolicy;
 
+use moondancer::fmt::HexSlice;
+use moondancer::ring::ByteRing;
 use moondancer::{hal, pac};
 use pac::csr::interrupt;
 
 // - constants ----------------------------------------------------------------
 
-const MAX_CONTROL_RESPONSE_SIZE: usize = 8;
+/// Largest control OUT data stage accepted, IN responses are unbuffered
+const MAX_CONTROL_OUT_SIZE: usize = 8;
+
+/// Control errors tolerated before a device reconnects to recover
+const MAX_CONTROL_ERRORS: usize = 8;
+
+/// Control endpoint plus the two data endpoints
+const MAX_ENDPOINTS: usize = 3;
+
+// the devices' endpoint state and the event queues must fit the descriptors
+const _: () = assert!(acm::CONFIGURATION_DESCRIPTOR_0.endpoint_slots() <= MAX_ENDPOINTS);
+const _: () =
+    assert!(acm::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0.endpoint_slots() <= MAX_ENDPOINTS);
+const _: () = assert!(cdc::CONFIGURATION_DESCRIPTOR_0.endpoint_slots() <= MAX_ENDPOINTS);
+const _: () =
+    assert!(cdc::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0.endpoint_slots() <= MAX_ENDPOINTS);
+const _: () = assert!(MAX_ENDPOINTS <= moondancer::EP_MAX_ENDPOINTS);
+
+/// Size of the receive packet buffers
+const MAX_PACKET_SIZE: usize = moondancer::EP_MAX_PACKET_SIZE;
+
+/// Both devices use endpoint 2 for their serial data IN and OUT endpoints
+const DATA_ENDPOINT: u8 = acm::DATA_OUT_ENDPOINT;
+
+/// Size of the serial data receive rings
+const RX_RING_SIZE: usize = 2048;
 
 // - types --------------------------------------------------------------------
 
 /// The UsbDataPacket struct represents a single packet of data
 /// received from a USB port.
-pub struct UsbDataPacket {
+pub struct UsbDataPacket<const N: usize = MAX_PACKET_SIZE> {
     pub interface: moondancer::UsbInterface,
     pub endpoint: u8,
     pub bytes_read: usize,
-    pub buffer: [u8; moondancer::EP_MAX_PACKET_SIZE],
+    pub buffer: [u8; N],
+}
+
+impl<const N: usize> UsbDataPacket<N> {
+    pub const fn new(interface: moondancer::UsbInterface, endpoint: u8) -> Self {
+        Self {
+            interface,
+            endpoint,
+            bytes_read: 0,
+            buffer: [0; N],
+        }
+    }
 }
 
 // - global static state ------------------------------------------------------
 
-use heapless::mpmc::MpMcQueue as Queue;
-use moondancer::event::InterruptEvent;
+use moondancer::event::{EventQueue, InterruptEvent};
+
+static EVENT_QUEUE: EventQueue<InterruptEvent, { moondancer::EP_MAX_ENDPOINTS }> =
+    EventQueue::new();
+static USB_RECEIVE_PACKET_QUEUE: EventQueue<UsbDataPacket, { moondancer::EP_MAX_ENDPOINTS }> =
+    EventQueue::new();
+
+// serial data is a byte stream so it skips the packet queue
+static USB0_RX_RING: ByteRing<RX_RING_SIZE> = ByteRing::new();
+static USB1_RX_RING: ByteRing<RX_RING_SIZE> = ByteRing::new();
 
-static EVENT_QUEUE: Queue<InterruptEvent, { moondancer::EP_MAX_ENDPOINTS }> = Queue::new();
-static USB_RECEIVE_PACKET_QUEUE: Queue<UsbDataPacket, { moondancer::EP_MAX_ENDPOINTS }> =
-    Queue::new();
+// set when a data endpoint was left unprimed because its ring was full
+static USB0_RX_PAUSED: AtomicBool = AtomicBool::new(false);
+static USB1_RX_PAUSED: AtomicBool = AtomicBool::new(false);
 
 #[inline(always)]
 fn dispatch_event(event: InterruptEvent) {
@@ -59,6 +109,27 @@ fn dispatch_receive_packet(usb_receive_packet: UsbDataPacket) {
     }
 }
 
+/// Read a serial data packet into `ring`
+///
+/// Returns true if the ring has room for another packet and the
+/// endpoint can be re-primed.
+#[inline(always)]
+fn receive_stream<D: ReadEndpoint>(
+    usb: &D,
+    endpoint: u8,
+    ring: &ByteRing<RX_RING_SIZE>,
+    paused: &AtomicBool,
+) -> bool {
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    let bytes_read = usb.read(endpoint, &mut buffer);
+    // overflow is counted by the ring and reported from the main loop
+    let _ = ring.push_slice(&buffer[..bytes_read]);
+
+    let reprime = ring.free() >= MAX_PACKET_SIZE;
+    paused.store(!reprime, Ordering::Relaxed);
+    reprime
+}
+
 // - MachineExternal interrupt handler ----------------------------------------
 
 #[allow(non_snake_case)]
@@ -89,21 +160,26 @@ fn MachineExternal() {
             UsbEvent::ReceiveControl(endpoint),
         ));
     } else if usb0.is_pending(pac::Interrupt::USB0_EP_IN) {
+        usb0.ep_in_send_complete();
         usb0.clear_pending(pac::Interrupt::USB0_EP_IN);
         // TODO something a little bit safer would be nice
         unsafe {
             usb0.clear_tx_ack_active();
         }
         dispatch_event(InterruptEvent::Interrupt(pac::Interrupt::USB0_EP_IN));
+    } else if usb0.is_pending(pac::Interrupt::USB0_EP_OUT)
+        && usb0.ep_out_endpoint_number() == DATA_ENDPOINT
+    {
+        // read serial data into the receive ring
+        let reprime = receive_stream(&usb0, DATA_ENDPOINT, &USB0_RX_RING, &USB0_RX_PAUSED);
+        usb0.clear_pending(pac::Interrupt::USB0_EP_OUT);
+        if reprime {
+            usb0.ep_out_prime_receive(DATA_ENDPOINT);
+        }
     } else if usb0.is_pending(pac::Interrupt::USB0_EP_OUT) {
         // read data from endpoint
-        let endpoint = usb0.ep_out.data_ep.read().bits() as u8;
-        let mut receive_packet = UsbDataPacket {
-            interface: Target,
-            endpoint,
-            bytes_read: 0,
-            buffer: [0_u8; moondancer::EP_MAX_PACKET_SIZE],
-        };
+        let endpoint = usb0.ep_out_endpoint_number();
+        let mut receive_packet = UsbDataPacket::new(Target, endpoint);
         receive_packet.bytes_read = usb0.read(endpoint, &mut receive_packet.buffer);
 
         // clear pending IRQ after data is read
@@ -124,21 +200,26 @@ fn MachineExternal() {
             UsbEvent::ReceiveControl(endpoint),
         ));
     } else if usb1.is_pending(pac::Interrupt::USB1_EP_IN) {
+        usb1.ep_in_send_complete();
         usb1.clear_pending(pac::Interrupt::USB1_EP_IN);
         // TODO something a little bit safer would be nice
         unsafe {
             usb1.clear_tx_ack_active();
         }
         dispatch_event(InterruptEvent::Interrupt(pac::Interrupt::USB1_EP_IN));
+    } else if usb1.is_pending(pac::Interrupt::USB1_EP_OUT)
+        && usb1.ep_out_endpoint_number() == DATA_ENDPOINT
+    {
+        // read serial data into the receive ring
+        let reprime = receive_stream(&usb1, DATA_ENDPOINT, &USB1_RX_RING, &USB1_RX_PAUSED);
+        usb1.clear_pending(pac::Interrupt::USB1_EP_OUT);
+        if reprime {
+            usb1.ep_out_prime_receive(DATA_ENDPOINT);
+        }
     } else if usb1.is_pending(pac::Interrupt::USB1_EP_OUT) {
         // read data from endpoint
-        let endpoint = usb1.ep_out.data_ep.read().bits() as u8;
-        let mut receive_packet = UsbDataPacket {
-            interface: Aux,
-            endpoint,
-            bytes_read: 0,
-            buffer: [0_u8; moondancer::EP_MAX_PACKET_SIZE],
-        };
+        let endpoint = usb1.ep_out_endpoint_number();
+        let mut receive_packet = UsbDataPacket::new(Aux, endpoint);
         receive_packet.bytes_read = usb1.read(endpoint, &mut receive_packet.buffer);
 
         // clear pending IRQ after data is read
@@ -149,7 +230,7 @@ fn MachineExternal() {
 
     // - Unknown Interrupt --
     } else {
-        dispatch_event(InterruptEvent::UnknownInterrupt(pending));
+        dispatch_event(InterruptEvent::UnknownInterrupt(pending.into()));
     }
 }
 
@@ -175,27 +256,34 @@ fn main() -> ! {
     info!("logging initialized");
 
     // usb0: Target
-    let mut usb0 = UsbDevice::<_, MAX_CONTROL_RESPONSE_SIZE>::new(
+    let mut usb0 = UsbDevice::<_, MAX_CONTROL_OUT_SIZE, MAX_ENDPOINTS>::new(
         hal::Usb0::new(
             peripherals.USB0,
             peripherals.USB0_EP_CONTROL,
             peripherals.USB0_EP_IN,
             peripherals.USB0_EP_OUT,
         ),
-        cdc::DEVICE_DESCRIPTOR,
-        cdc::CONFIGURATION_DESCRIPTOR_0,
-        cdc::USB_STRING_DESCRIPTOR_0,
-        cdc::USB_STRING_DESCRIPTORS,
+        acm::DEVICE_DESCRIPTOR,
+        acm::CONFIGURATION_DESCRIPTOR_0,
+        acm::USB_STRING_DESCRIPTOR_0,
+        acm::USB_STRING_DESCRIPTORS,
     );
-    usb0.set_device_qualifier_descriptor(cdc::DEVICE_QUALIFIER_DESCRIPTOR);
-    usb0.set_other_speed_configuration_descriptor(cdc::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0);
-    usb0.cb_vendor_request = Some(handle_vendor_request);
-    usb0.cb_string_request = Some(handle_string_request);
-    let speed = usb0.connect();
-    info!("Connected USB0 device: {:?}", Speed::from(speed));
+    usb0.set_device_qualifier_descriptor(acm::DEVICE_QUALIFIER_DESCRIPTOR);
+    usb0.set_other_speed_configuration_descriptor(acm::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0);
+    usb0.set_recovery_policy(RecoveryPolicy::reconnect_after(MAX_CONTROL_ERRORS));
+    // reconnecting disables the device's events
+    usb0.cb_recovery = Some(|device| device.hal_driver.enable_interrupts());
+    let mut usb0_acm = AcmDevice::new(acm::COMMUNICATIONS_INTERFACE, acm::NOTIFICATION_ENDPOINT);
+    match usb0.connect() {
+        Ok(speed) => info!("Connected USB0 device: {:?}", speed),
+        Err(e) => {
+            error!("Failed to connect USB0 device: {}", e);
+            panic!("Failed to connect USB0 device: {}", e)
+        }
+    }
 
     // usb1: Aux
-    let mut usb1 = UsbDevice::<_, MAX_CONTROL_RESPONSE_SIZE>::new(
+    let mut usb1 = UsbDevice::<_, MAX_CONTROL_OUT_SIZE, MAX_ENDPOINTS>::new(
         hal::Usb1::new(
             peripherals.USB1,
             peripherals.USB1_EP_CONTROL,
@@ -210,9 +298,19 @@ fn main() -> ! {
     usb1.set_device_qualifier_descriptor(cdc::DEVICE_QUALIFIER_DESCRIPTOR);
     usb1.set_other_speed_configuration_descriptor(cdc::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0);
     usb1.cb_vendor_request = Some(handle_vendor_request);
-    usb1.cb_string_request = Some(handle_string_request);
-    let speed = usb1.connect();
-    info!("Connected USB1 device: {:?}", Speed::from(speed));
+    // we're pretending to be a ch34x so play along with whatever the
+    // host driver asks for rather than stalling it
+    usb1.set_unknown_request_policy(UnknownRequestPolicy::IgnoreUnknown);
+    usb1.set_recovery_policy(RecoveryPolicy::reconnect_after(MAX_CONTROL_ERRORS));
+    // reconnecting disables the device's events
+    usb1.cb_recovery = Some(|device| device.hal_driver.enable_interrupts());
+    match usb1.connect() {
+        Ok(speed) => info!("Connected USB1 device: {:?}", speed),
+        Err(e) => {
+            error!("Failed to connect USB1 device: {}", e);
+            panic!("Failed to connect USB1 device: {}", e)
+        }
+    }
 
     // enable interrupts
     unsafe {
@@ -235,15 +333,18 @@ fn main() -> ! {
         usb1.hal_driver.enable_interrupts();
     }
 
-    // prime the usb OUT endpoints we'll be using
-    usb0.hal_driver.ep_out_prime_receive(1);
-    usb0.hal_driver.ep_out_prime_receive(2);
-    usb1.hal_driver.ep_out_prime_receive(1);
-    usb1.hal_driver.ep_out_prime_receive(2);
+    // OUT endpoints are primed by UsbDevice once the host has set a configuration
 
     info!("Peripherals initialized, entering main loop.");
 
+    // reconnects made by each device's recovery policy
+    let mut recoveries = [0_usize; 2];
+
     loop {
+        // forward serial data between the two devices
+        forward_stream(&USB0_RX_RING, &USB0_RX_PAUSED, &usb0.hal_driver, &usb1.hal_driver);
+        forward_stream(&USB1_RX_RING, &USB1_RX_PAUSED, &usb1.hal_driver, &usb0.hal_driver);
+
         if let Some(UsbDataPacket {
             interface,
             endpoint,
@@ -258,32 +359,48 @@ fn main() -> ! {
                 (Target, endpoint, bytes_read, buffer) => {
                     if endpoint != 0 {
                         debug!(
-                            "Received {} bytes on usb0 endpoint: {} - {:?}",
+                            "Received {} bytes on usb0 endpoint: {} - {}",
                             bytes_read,
                             endpoint,
-                            &buffer[0..8],
+                            HexSlice::new(&buffer[0..8]).with_ascii(),
                         );
-                        usb1.hal_driver
-                            .write_ref(endpoint, buffer.iter().take(bytes_read).into_iter());
-                        info!("Sent {} bytes to usb1 endpoint: {}", bytes_read, endpoint);
+                        match usb1
+                            .hal_driver
+                            .write_ref(endpoint, buffer.iter().take(bytes_read).into_iter())
+                        {
+                            Ok(_) => {
+                                info!("Sent {} bytes to usb1 endpoint: {}", bytes_read, endpoint)
+                            }
+                            Err(e) => {
+                                warn!("Failed to send to usb1 endpoint {}: {:?}", endpoint, e)
+                            }
+                        }
                     }
-                    usb0.hal_driver.ep_out_prime_receive(endpoint);
+                    let _ = usb0.ep_out_prime_receive(endpoint);
                 }
 
                 // usb1 receive packet handler
                 (Aux, endpoint, bytes_read, buffer) => {
                     if endpoint != 0 {
                         debug!(
-                            "Received {} bytes on usb1 endpoint: {} - {:?}",
+                            "Received {} bytes on usb1 endpoint: {} - {}",
                             bytes_read,
                             endpoint,
-                            &buffer[0..8],
+                            HexSlice::new(&buffer[0..8]).with_ascii(),
                         );
-                        usb0.hal_driver
-                            .write_ref(endpoint, buffer.iter().take(bytes_read).into_iter());
-                        info!("Sent {} bytes to usb0 endpoint: {}", bytes_read, endpoint);
+                        match usb0
+                            .hal_driver
+                            .write_ref(endpoint, buffer.iter().take(bytes_read).into_iter())
+                        {
+                            Ok(_) => {
+                                info!("Sent {} bytes to usb0 endpoint: {}", bytes_read, endpoint)
+                            }
+                            Err(e) => {
+                                warn!("Failed to send to usb0 endpoint {}: {:?}", endpoint, e)
+                            }
+                        }
                     }
-                    usb1.hal_driver.ep_out_prime_receive(endpoint);
+                    let _ = usb1.ep_out_prime_receive(endpoint);
                 }
 
                 // unhandled
@@ -292,7 +409,7 @@ fn main() -> ! {
         }
 
         if let Some(event) = EVENT_QUEUE.dequeue() {
-            use moondancer::event::InterruptEvent::Usb;
+            use moondancer::event::InterruptEvent::{UnknownInterrupt, Usb};
             use moondancer::UsbInterface::{Aux, Target};
             use smolusb::event::UsbEvent::*;
 
@@ -305,14 +422,22 @@ fn main() -> ! {
                     debug!("\n\nUsb(Target, {:?})", event);
                     match usb0.dispatch_control(event) {
                         Ok(Some(control_event)) => {
-                            // handle any events control couldn't
-                            warn!("Unhandled control event on Target: {:?}", control_event);
+                            // handle any cdc-acm class requests
+                            if let Some(control_event) =
+                                usb0_acm.handle_control_event(&usb0.hal_driver, control_event)
+                            {
+                                warn!("Unhandled control event on Target: {:?}", control_event);
+                            }
                         }
                         Ok(None) => {
                             // control event was handled by UsbDevice
                         }
                         Err(e) => {
                             error!("Error handling control event on Target: {:?}", e);
+                            if usb0.recoveries() != recoveries[0] {
+                                recoveries[0] = usb0.recoveries();
+                                warn!("Target reconnected, recoveries: {}", recoveries[0]);
+                            }
                         }
                     }
                 }
@@ -333,10 +458,19 @@ fn main() -> ! {
                         }
                         Err(e) => {
                             error!("Error handling control event on Aux: {:?}", e);
+                            if usb1.recoveries() != recoveries[1] {
+                                recoveries[1] = usb1.recoveries();
+                                warn!("Aux reconnected, recoveries: {}", recoveries[1]);
+                            }
                         }
                     }
                 }
 
+                // Unexpected interrupt
+                UnknownInterrupt(pending) => {
+                    error!("MachineExternal Unknown interrupt - {}", pending);
+                }
+
                 // unhandled
                 _ => (),
             }
@@ -344,31 +478,66 @@ fn main() -> ! {
     }
 }
 
+// - serial data forwarding ---------------------------------------------------
+
+/// Drain the data received by `source` into `ring` and write it to `sink`
+///
+/// Re-primes the source's data endpoint if it was paused and the ring
+/// has room for another packet again.
+fn forward_stream<S, D>(ring: &ByteRing<RX_RING_SIZE>, paused: &AtomicBool, source: &S, sink: &D)
+where
+    S: ReadEndpoint,
+    D: WriteRefEndpoint,
+{
+    let dropped = ring.dropped();
+    if dropped > 0 {
+        warn!("Serial data receive ring overflow, dropped {} bytes", dropped);
+        ring.reset_stats();
+    }
+
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    let bytes_read = ring.pop_slice(&mut buffer);
+    if bytes_read > 0 {
+        match sink.write_ref(DATA_ENDPOINT, buffer.iter().take(bytes_read)) {
+            Ok(_) => debug!(
+                "Forwarded {} bytes to endpoint: {}",
+                bytes_read, DATA_ENDPOINT
+            ),
+            Err(e) => warn!(
+                "Dropped {} bytes for endpoint {}: {:?}",
+                bytes_read, DATA_ENDPOINT, e
+            ),
+        }
+    }
+
+    if paused.load(Ordering::Relaxed) && ring.free() >= MAX_PACKET_SIZE {
+        paused.store(false, Ordering::Relaxed);
+        source.ep_out_prime_receive(DATA_ENDPOINT);
+    }
+}
+
 // - vendor request handlers --------------------------------------------------
 
 fn handle_vendor_request<'a, D>(
-    device: &UsbDevice<'a, D, MAX_CONTROL_RESPONSE_SIZE>,
+    device: &UsbDevice<'a, D, MAX_CONTROL_OUT_SIZE, MAX_ENDPOINTS>,
     _setup_packet: &SetupPacket,
     request: u8,
-) where
+) -> ControlOutcome
+where
     D: ReadControl + ReadEndpoint + WriteEndpoint + WriteRefEndpoint + UsbDriverOperations,
 {
     let request = cdc::ch34x::VendorRequest::from(request);
     debug!("  CDC-SERIAL vendor_request: {:?}", request);
 
-    // we can just spoof these
-    device.hal_driver.write(0, [0, 0].into_iter());
-}
-
-fn handle_string_request<'a, D>(
-    device: &UsbDevice<'a, D, MAX_CONTROL_RESPONSE_SIZE>,
-    _setup_packet: &SetupPacket,
-    index: u8,
-) where
-    D: ReadControl + ReadEndpoint + WriteEndpoint + WriteRefEndpoint + UsbDriverOperations,
-{
-    debug!("  CDC-SERIAL string_request: {}", index);
-
-    // we can just spoof this too
-    device.hal_driver.write(0, [].into_iter());
+    match request {
+        // ignored by the device's `IgnoreUnknown` policy
+        cdc::ch34x::VendorRequest::Unknown => ControlOutcome::Stall,
+        _ => {
+            // we can just spoof the ones we know about
+            match device.hal_driver.write(0, [0, 0].into_iter()) {
+                Ok(_) => ControlOutcome::Handled,
+                Err(_) => ControlOutcome::Stall,
+            }
+        }
+    }
 }
@@ -0,0 +1,354 @@
+#![no_std]
+#![no_main]
+
+use log::{debug, error, info, warn};
+
+use smolusb::class::cdc::{acm, AcmDevice};
+use smolusb::class::hid::{self, HidDevice};
+use smolusb::descriptor::*;
+use smolusb::device::UsbDevice;
+use smolusb::traits::{ReadEndpoint, UsbDriverOperations, WriteRefEndpoint};
+use smolusb::RecoveryPolicy;
+
+use moondancer::event::{EventQueue, InterruptEvent};
+use moondancer::fmt::HexSlice;
+use moondancer::ring::ByteRing;
+use moondancer::{hal, pac};
+use pac::csr::interrupt;
+
+// - constants ----------------------------------------------------------------
+
+/// Largest control OUT data stage accepted, large enough for a HID
+/// SET_REPORT carrying a full output report
+const MAX_CONTROL_OUT_SIZE: usize = hid::MAX_REPORT_SIZE;
+
+/// Control errors tolerated before the device reconnects to recover
+const MAX_CONTROL_ERRORS: usize = 8;
+
+/// Control endpoint, the three CDC-ACM endpoints and the HID endpoint
+const MAX_ENDPOINTS: usize = 4;
+
+// the device's endpoint state must fit the descriptors
+const _: () = assert!(CONFIGURATION_DESCRIPTOR_0.endpoint_slots() <= MAX_ENDPOINTS);
+const _: () = assert!(OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0.endpoint_slots() <= MAX_ENDPOINTS);
+const _: () = assert!(MAX_ENDPOINTS <= moondancer::EP_MAX_ENDPOINTS);
+
+/// Size of the receive packet buffer
+const MAX_PACKET_SIZE: usize = moondancer::EP_MAX_PACKET_SIZE;
+
+/// HID interface, following the two CDC-ACM interfaces
+const HID_INTERFACE: u8 = 2;
+/// HID interrupt IN endpoint for input reports
+const HID_IN_ENDPOINT: u8 = 0x83;
+/// Size of the vendor report descriptor's input and output reports
+const HID_REPORT_SIZE: usize = 8;
+
+/// Size of the HID output report ring
+const HID_RING_SIZE: usize = 256;
+
+// - global static state ------------------------------------------------------
+
+static EVENT_QUEUE: EventQueue<InterruptEvent, 32> = EventQueue::new();
+
+// output reports received by SET_REPORT, waiting to be echoed back
+static HID_OUTPUT_RING: ByteRing<HID_RING_SIZE> = ByteRing::new();
+
+#[inline(always)]
+fn dispatch_event(event: InterruptEvent) {
+    match EVENT_QUEUE.enqueue(event) {
+        Ok(()) => (),
+        Err(_) => {
+            error!("MachineExternal - event queue overflow");
+        }
+    }
+}
+
+// - MachineExternal interrupt handler ----------------------------------------
+
+#[allow(non_snake_case)]
+#[no_mangle]
+fn MachineExternal() {
+    match moondancer::util::get_usb_interrupt_event() {
+        InterruptEvent::UnhandledInterrupt(pending) => {
+            dispatch_event(InterruptEvent::UnknownInterrupt(pending));
+        }
+        event => {
+            dispatch_event(event);
+        }
+    }
+}
+
+// - main entry point ---------------------------------------------------------
+
+#[cfg(feature = "vexriscv")]
+#[riscv_rt::pre_init]
+unsafe fn pre_main() {
+    pac::cpu::vexriscv::flush_icache();
+    #[cfg(feature = "vexriscv_dcache")]
+    pac::cpu::vexriscv::flush_dcache();
+}
+
+#[riscv_rt::entry]
+fn main() -> ! {
+    let peripherals = pac::Peripherals::take().unwrap();
+    let leds = &peripherals.LEDS;
+    leds.output.write(|w| unsafe { w.output().bits(0x0) });
+
+    // initialize logging
+    let serial = hal::Serial::new(peripherals.UART);
+    moondancer::log::init(serial);
+    info!("logging initialized");
+
+    // usb0: Target
+    let mut usb0 = UsbDevice::<_, MAX_CONTROL_OUT_SIZE, MAX_ENDPOINTS>::new(
+        hal::Usb0::new(
+            peripherals.USB0,
+            peripherals.USB0_EP_CONTROL,
+            peripherals.USB0_EP_IN,
+            peripherals.USB0_EP_OUT,
+        ),
+        DEVICE_DESCRIPTOR,
+        CONFIGURATION_DESCRIPTOR_0,
+        USB_STRING_DESCRIPTOR_0,
+        USB_STRING_DESCRIPTORS,
+    );
+    usb0.set_device_qualifier_descriptor(acm::DEVICE_QUALIFIER_DESCRIPTOR);
+    usb0.set_other_speed_configuration_descriptor(OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0);
+    usb0.set_recovery_policy(RecoveryPolicy::reconnect_after(MAX_CONTROL_ERRORS));
+    // reconnecting disables the device's events
+    usb0.cb_recovery = Some(|device| device.hal_driver.enable_interrupts());
+
+    // the two functions sharing the device
+    let mut acm = AcmDevice::new(acm::COMMUNICATIONS_INTERFACE, acm::NOTIFICATION_ENDPOINT);
+    let mut hid = HidDevice::new(
+        HID_INTERFACE,
+        HID_IN_ENDPOINT,
+        hid::VENDOR_REPORT_DESCRIPTOR,
+    );
+    hid.cb_set_report = Some(handle_set_report);
+
+    match usb0.connect() {
+        Ok(speed) => info!("Connected USB0 device: {:?}", speed),
+        Err(e) => {
+            error!("Failed to connect USB0 device: {}", e);
+            panic!("Failed to connect USB0 device: {}", e)
+        }
+    }
+
+    // enable interrupts
+    unsafe {
+        // set mstatus register: interrupt enable
+        riscv::interrupt::enable();
+
+        // set mie register: machine external interrupts enable
+        riscv::register::mie::set_mext();
+
+        // write csr: enable usb0 interrupts and events
+        interrupt::enable(pac::Interrupt::USB0);
+        interrupt::enable(pac::Interrupt::USB0_EP_CONTROL);
+        interrupt::enable(pac::Interrupt::USB0_EP_IN);
+        interrupt::enable(pac::Interrupt::USB0_EP_OUT);
+        usb0.hal_driver.enable_interrupts();
+    }
+
+    // OUT endpoints are primed by UsbDevice once the host has set a configuration
+
+    info!("Peripherals initialized, entering main loop.");
+
+    // reconnects made by the device's recovery policy
+    let mut recoveries = 0;
+
+    loop {
+        // echo output reports back to the host as input reports
+        let mut report = [0; HID_REPORT_SIZE];
+        if HID_OUTPUT_RING.len() >= HID_REPORT_SIZE {
+            HID_OUTPUT_RING.pop_slice(&mut report);
+            match hid.send_report(&usb0.hal_driver, &report) {
+                Ok(_) => debug!("Sent HID input report: {:?}", report),
+                Err(e) => warn!("Failed to send HID input report: {:?}", e),
+            }
+        }
+
+        if let Some(event) = EVENT_QUEUE.dequeue() {
+            use moondancer::event::InterruptEvent::{UnknownInterrupt, Usb};
+            use moondancer::UsbInterface::Target;
+            use smolusb::event::UsbEvent::*;
+
+            match event {
+                // Usb0 received a control event
+                Usb(Target, event @ BusReset)
+                | Usb(Target, event @ ReceiveControl(0))
+                | Usb(Target, event @ ReceivePacket(0))
+                | Usb(Target, event @ SendComplete(0)) => {
+                    debug!("\n\nUsb(Target, {:?})", event);
+                    // the control endpoint's packet size depends on the negotiated speed
+                    hid.set_control_max_packet_size(usb0.ep0_max_packet_size());
+                    match usb0.dispatch_control(event) {
+                        Ok(Some(control_event)) => {
+                            // offer the event to each function in turn
+                            let control_event = acm
+                                .handle_control_event(&usb0.hal_driver, control_event)
+                                .and_then(|control_event| {
+                                    hid.handle_control_event(&usb0.hal_driver, control_event)
+                                });
+                            if let Some(control_event) = control_event {
+                                warn!("Unhandled control event on Target: {:?}", control_event);
+                                usb0.unknown_control_request(&control_event.setup_packet);
+                            }
+                        }
+                        Ok(None) => {
+                            // control event was handled by UsbDevice
+                        }
+                        Err(e) => {
+                            error!("Error handling control event on Target: {:?}", e);
+                            if usb0.recoveries() != recoveries {
+                                recoveries = usb0.recoveries();
+                                warn!("Target reconnected, recoveries: {}", recoveries);
+                            }
+                        }
+                    }
+                }
+
+                // Usb0 received serial data, echo it back
+                Usb(Target, ReceivePacket(endpoint)) if endpoint == acm::DATA_OUT_ENDPOINT => {
+                    let mut buffer = [0; MAX_PACKET_SIZE];
+                    let bytes_read = usb0.hal_driver.read(endpoint, &mut buffer);
+                    debug!(
+                        "Received {} bytes on serial data endpoint: {}",
+                        bytes_read,
+                        HexSlice::new(&buffer[..bytes_read.min(8)]).with_ascii(),
+                    );
+                    if let Err(e) = usb0
+                        .hal_driver
+                        .write_ref(acm::DATA_IN_ENDPOINT, buffer.iter().take(bytes_read))
+                    {
+                        warn!("Failed to echo serial data: {:?}", e);
+                    }
+                    let _ = usb0.ep_out_prime_receive(endpoint);
+                }
+
+                // Unexpected interrupt
+                UnknownInterrupt(pending) => {
+                    error!("MachineExternal Unknown interrupt - {}", pending);
+                }
+
+                // unhandled
+                _ => (),
+            }
+        }
+    }
+}
+
+// - HID report handlers ------------------------------------------------------
+
+/// Called by the HID function with the data of each SET_REPORT request
+fn handle_set_report(report_type: u8, _report_id: u8, data: &[u8]) {
+    const OUTPUT_REPORT: u8 = 0x02;
+
+    if report_type != OUTPUT_REPORT || data.len() != HID_REPORT_SIZE {
+        warn!("Ignoring HID report: {} {:?}", report_type, data);
+        return;
+    }
+    if HID_OUTPUT_RING.push_slice(data).is_err() {
+        warn!("HID output report ring overflow");
+    }
+}
+
+// - descriptors --------------------------------------------------------------
+
+const HID_CLASS_DESCRIPTOR: [u8; 9] =
+    hid::class_descriptor(hid::VENDOR_REPORT_DESCRIPTOR.len() as u16);
+
+pub const DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
+    product_id: le16(0x0003), // pid.codes test PID
+    ..acm::DEVICE_DESCRIPTOR
+};
+
+/// The CDC-ACM function's two interfaces followed by the HID interface
+const INTERFACE_DESCRIPTORS_0: [InterfaceDescriptor; 3] = [
+    acm::CONFIGURATION_DESCRIPTOR_0.tail[0],
+    acm::CONFIGURATION_DESCRIPTOR_0.tail[1],
+    InterfaceDescriptor::new(
+        InterfaceDescriptorHeader {
+            interface_number: HID_INTERFACE,
+            alternate_setting: 0,
+            interface_class: 0x03, // HID
+            interface_subclass: 0x00,
+            interface_protocol: 0x00,
+            interface_string_index: 4,
+            ..InterfaceDescriptorHeader::new()
+        },
+        &[EndpointDescriptor {
+            endpoint_address: HID_IN_ENDPOINT, // IN
+            attributes: EndpointAttributes::interrupt().bits(),
+            max_packet_size: le16(HID_REPORT_SIZE as u16),
+            interval: 4, // 2^(4-1) * 125us = 1ms
+            ..EndpointDescriptor::new()
+        }],
+    )
+    .with_class_descriptors(&HID_CLASS_DESCRIPTOR),
+];
+
+pub const CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDescriptor::new(
+    ConfigurationDescriptorHeader {
+        descriptor_type: DescriptorType::Configuration as u8,
+        configuration_value: 1,
+        configuration_string_index: 1,
+        attributes: ConfigurationAttributes::new().bits(),
+        max_power: 50, // 50 * 2 mA = 100 mA
+        ..ConfigurationDescriptorHeader::new()
+    },
+    &INTERFACE_DESCRIPTORS_0,
+);
+
+const OTHER_SPEED_INTERFACE_DESCRIPTORS_0: [InterfaceDescriptor; 3] = [
+    acm::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0.tail[0],
+    acm::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0.tail[1],
+    InterfaceDescriptor::new(
+        InterfaceDescriptorHeader {
+            interface_number: HID_INTERFACE,
+            alternate_setting: 0,
+            interface_class: 0x03, // HID
+            interface_subclass: 0x00,
+            interface_protocol: 0x00,
+            interface_string_index: 4,
+            ..InterfaceDescriptorHeader::new()
+        },
+        &[EndpointDescriptor {
+            endpoint_address: HID_IN_ENDPOINT, // IN
+            attributes: EndpointAttributes::interrupt().bits(),
+            max_packet_size: le16(HID_REPORT_SIZE as u16),
+            interval: 1, // 1ms
+            ..EndpointDescriptor::new()
+        }],
+    )
+    .with_class_descriptors(&HID_CLASS_DESCRIPTOR),
+];
+
+pub const OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
+    ConfigurationDescriptor::new(
+        ConfigurationDescriptorHeader {
+            descriptor_type: DescriptorType::OtherSpeedConfiguration as u8,
+            configuration_value: 1,
+            configuration_string_index: 1,
+            attributes: ConfigurationAttributes::new().bits(),
+            max_power: 50, // 50 * 2 mA = 100 mA
+            ..ConfigurationDescriptorHeader::new()
+        },
+        &OTHER_SPEED_INTERFACE_DESCRIPTORS_0,
+    );
+
+pub const USB_STRING_DESCRIPTOR_0: StringDescriptorZero =
+    StringDescriptorZero::new(&[LanguageId::EnglishUnitedStates]);
+
+pub const USB_STRING_DESCRIPTOR_1: StringDescriptor = StringDescriptor::new("Great Scott Gadgets");
+pub const USB_STRING_DESCRIPTOR_2: StringDescriptor = StringDescriptor::new("CDC-ACM Serial");
+pub const USB_STRING_DESCRIPTOR_3: StringDescriptor = StringDescriptor::new("100");
+pub const USB_STRING_DESCRIPTOR_4: StringDescriptor = StringDescriptor::new("HID Reports");
+
+pub const USB_STRING_DESCRIPTORS: &[&StringDescriptor] = &[
+    &USB_STRING_DESCRIPTOR_1,
+    &USB_STRING_DESCRIPTOR_2,
+    &USB_STRING_DESCRIPTOR_3,
+    &USB_STRING_DESCRIPTOR_4,
+];
@@ -5,7 +5,6 @@
 use core::any::Any;
 use core::{array, iter, slice};
 
-use heapless::mpmc::MpMcQueue as Queue;
 use log::{debug, error, info, trace, warn};
 
 use smolusb::class;
@@ -21,7 +20,7 @@ use smolusb::traits::{
 use libgreat::gcp::{iter_to_response, GreatResponse, LIBGREAT_MAX_COMMAND_SIZE};
 use libgreat::{GreatError, GreatResult};
 
-use moondancer::event::InterruptEvent;
+use moondancer::event::{EventQueue, InterruptEvent};
 use moondancer::usb::vendor::{VendorRequest, VendorValue};
 use moondancer::{hal, pac};
 
@@ -29,7 +28,7 @@ use pac::csr::interrupt;
 
 // - MachineExternal interrupt handler ----------------------------------------
 
-static EVENT_QUEUE: Queue<InterruptEvent, 128> = Queue::new();
+static EVENT_QUEUE: EventQueue<InterruptEvent, 128> = EventQueue::new();
 
 #[inline(always)]
 fn dispatch_event(event: InterruptEvent) {
@@ -176,7 +175,10 @@ impl<'a> Firmware<'a> {
             .write(|w| unsafe { w.output().bits(1 << 2) });
 
         // connect usb1
-        let speed = self.usb1.connect();
+        let speed = self.usb1.connect().map_err(|e| {
+            error!("Failed to connect usb1 device: {}", e);
+            GreatError::ConnectionRefused
+        })?;
         info!("Connected usb1 device: {:?}", speed);
 
         // enable interrupts
@@ -245,6 +247,9 @@ impl<'a> Firmware<'a> {
                     ErrorMessage(message) => {
                         error!("MachineExternal Error - {}", message);
                     }
+                    UnknownInterrupt(pending) => {
+                        error!("MachineExternal Unknown interrupt - {}", pending);
+                    }
 
                     // - usb1 event handlers --
 
@@ -354,6 +359,21 @@ impl<'a> Firmware<'a> {
                     }
                 }
             }
+            (RequestType::Vendor, VendorRequest::ReadStatistics) => match direction {
+                Direction::DeviceToHost => self.dispatch_read_statistics(&setup_packet)?,
+                Direction::HostToDevice => {
+                    error!("handle_control stall: statistics request must be device to host");
+                    self.usb1.hal_driver.stall_control_request();
+                }
+            },
+            #[cfg(feature = "control-capture")]
+            (RequestType::Vendor, VendorRequest::ReadControlCapture) => match direction {
+                Direction::DeviceToHost => self.dispatch_read_control_capture(&setup_packet)?,
+                Direction::HostToDevice => {
+                    error!("handle_control stall: control capture request must be device to host");
+                    self.usb1.hal_driver.stall_control_request();
+                }
+            },
             (RequestType::Vendor, VendorRequest::Unknown(vendor_request)) => {
                 error!(
                     "handle_control_event Unknown vendor request '{}'",
@@ -411,6 +431,64 @@ impl<'a> Firmware<'a> {
     }
 }
 
+// - statistics ---------------------------------------------------------------
+
+impl<'a> Firmware<'a> {
+    /// Respond with the statistics block described in
+    /// [`moondancer::usb::statistics`].
+    fn dispatch_read_statistics(&mut self, setup_packet: &SetupPacket) -> GreatResult<()> {
+        let reset = setup_packet.value != 0;
+
+        {
+            let endpoints = self.usb1.endpoints.borrow();
+            let block = moondancer::usb::statistics::iter(
+                &endpoints,
+                EVENT_QUEUE.dropped(),
+                EVENT_QUEUE.high_water_mark(),
+            );
+            self.usb1
+                .hal_driver
+                .write_packets(0, block.take(setup_packet.length as usize), 64)
+                .map_err(|_| GreatError::IoError)?;
+        }
+
+        if reset {
+            self.usb1.endpoints.borrow_mut().reset_stats();
+            EVENT_QUEUE.reset_stats();
+        }
+
+        Ok(())
+    }
+}
+
+// - control capture ----------------------------------------------------------
+
+#[cfg(feature = "control-capture")]
+impl<'a> Firmware<'a> {
+    /// Respond with the control requests captured since the last read,
+    /// serialized as described in [`smolusb::control_capture`]
+    ///
+    /// Only whole records that fit in `wLength` are returned, the rest
+    /// are left for the next read. A non-zero `wValue` enables the
+    /// capture, zero disables it. While enabled these requests are
+    /// captured too.
+    fn dispatch_read_control_capture(&mut self, setup_packet: &SetupPacket) -> GreatResult<()> {
+        let mut buffer = [0; 512];
+        let length = (setup_packet.length as usize).min(buffer.len());
+
+        let mut capture = self.usb1.capture.borrow_mut();
+        let bytes_written = capture.drain_into(&mut buffer[..length]);
+        capture.set_enabled(setup_packet.value != 0);
+
+        self.usb1
+            .hal_driver
+            .write_packets(0, buffer[..bytes_written].iter().copied(), 64)
+            .map_err(|_| GreatError::IoError)?;
+
+        Ok(())
+    }
+}
+
 // - libgreat command dispatch ------------------------------------------------
 
 impl<'a> Firmware<'a> {
@@ -425,7 +503,11 @@ impl<'a> Firmware<'a> {
             }
         };
 
-        trace!("dispatch_libgreat_request {:?}.0x{:x}", class_id, verb_number);
+        trace!(
+            "dispatch_libgreat_request {:?}.0x{:x}",
+            class_id,
+            verb_number
+        );
 
         // dispatch command
         let response_buffer: [u8; LIBGREAT_MAX_COMMAND_SIZE] = [0; LIBGREAT_MAX_COMMAND_SIZE];
@@ -473,8 +555,14 @@ impl<'a> Firmware<'a> {
 
                 // TODO this is... weird...
                 self.usb1.hal_driver.stall_endpoint_in(0);
-                unsafe { riscv::asm::delay(2000); }
-                self.usb1.hal_driver.ep_in.reset.write(|w| w.reset().bit(true));
+                unsafe {
+                    riscv::asm::delay(2000);
+                }
+                self.usb1
+                    .hal_driver
+                    .ep_in
+                    .reset
+                    .write(|w| w.reset().bit(true));
             }
         }
 
@@ -490,14 +578,18 @@ impl<'a> Firmware<'a> {
                 log::debug!("dispatch_libgreat_response -> {} bytes", response.len());
             }
 
-            self.usb1.hal_driver.write_packets(0, response, 64);
+            self.usb1
+                .hal_driver
+                .write_packets(0, response, 64)
+                .map_err(|_| GreatError::IoError)?;
 
             self.libgreat_response = None;
         } else if let Some(error) = self.libgreat_response_last_error {
             warn!("dispatch_libgreat_response error result: {:?}", error);
             self.usb1
                 .hal_driver
-                .write(0, (error as u32).to_le_bytes().into_iter());
+                .write(0, (error as u32).to_le_bytes().into_iter())
+                .map_err(|_| GreatError::IoError)?;
             self.libgreat_response_last_error = None;
         } else {
             // TODO figure out what to do if we don't have a response or error
@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+use moondancer::{hal, pac};
+
+use log::info;
+use riscv_rt::entry;
+
+#[cfg(feature = "vexriscv")]
+#[riscv_rt::pre_init]
+unsafe fn pre_main() {
+    pac::cpu::vexriscv::flush_icache();
+    #[cfg(feature = "vexriscv_dcache")]
+    pac::cpu::vexriscv::flush_dcache();
+}
+
+// - main entry point ---------------------------------------------------------
+
+#[entry]
+fn main() -> ! {
+    let peripherals = pac::Peripherals::take().unwrap();
+
+    // initialize logging
+    let serial = hal::Serial::new(peripherals.UART);
+    moondancer::log::init(serial);
+
+    info!("Running self-test");
+    let report = moondancer::selftest::run();
+    info!("{:?}", report);
+
+    loop {
+        unsafe {
+            riscv::asm::wfi();
+        }
+    }
+}
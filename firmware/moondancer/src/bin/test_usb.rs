@@ -144,7 +144,10 @@ impl<'a> Firmware<'a> {
             .write(|w| unsafe { w.output().bits(1 << 2) });
 
         // connect usb1
-        let speed = self.usb1.connect();
+        let speed = self.usb1.connect().map_err(|e| {
+            error!("Failed to connect usb1 device: {}", e);
+            GreatError::ConnectionRefused
+        })?;
         info!("Connected usb1 device: {:?}", speed);
 
         // enable interrupts
@@ -210,6 +213,9 @@ impl<'a> Firmware<'a> {
                     ErrorMessage(message) => {
                         error!("MachineExternal Error - {}", message);
                     }
+                    UnknownInterrupt(pending) => {
+                        error!("MachineExternal Unknown interrupt - {}", pending);
+                    }
 
                     // - usb1 event handlers --
 
@@ -241,19 +247,18 @@ impl<'a> Firmware<'a> {
                         let bytes_read = self
                             .usb1
                             .hal_driver
-                            .read(BULK_OUT_ENDPOINT_NUMBER, &mut rx_buffer);
+                            .read_and_reprime(BULK_OUT_ENDPOINT_NUMBER, &mut rx_buffer);
                         self.handle_receive_command_packet(bytes_read, rx_buffer)?;
-                        self.usb1
-                            .hal_driver
-                            .ep_out_prime_receive(BULK_OUT_ENDPOINT_NUMBER);
                     }
 
                     // USB1_EP_OUT n - Usb1 received data on endpoint - shouldn't ever be called
                     Usb(Aux, event @ ReceivePacket(endpoint_number)) => {
                         debug!("\n\nUsb(Aux, {:?})", event);
-                        let bytes_read = self.usb1.hal_driver.read(endpoint_number, &mut rx_buffer);
+                        let bytes_read = self
+                            .usb1
+                            .hal_driver
+                            .read_and_reprime(endpoint_number, &mut rx_buffer);
                         self.handle_receive_packet(endpoint_number, bytes_read, rx_buffer)?;
-                        self.usb1.hal_driver.ep_out_prime_receive(endpoint_number);
                     }
 
                     // USB1_EP_IN n Usb1 transfer complete
@@ -340,10 +345,17 @@ impl<'a> Firmware<'a> {
                             buf
                         };
 
-                        self.usb1.hal_driver.write(0, buf.into_iter());
-                        self.usb1.hal_driver.write(0, buf.into_iter());
+                        for _ in 0..2 {
+                            self.usb1
+                                .hal_driver
+                                .write(0, buf.into_iter())
+                                .map_err(|_| GreatError::IoError)?;
+                        }
                         // end data stage with ACK ?
-                        self.usb1.hal_driver.write(0, [].into_iter());
+                        self.usb1
+                            .hal_driver
+                            .write(0, [].into_iter())
+                            .map_err(|_| GreatError::IoError)?;
                     }
 
                     // host would like to abort the current command sequence
@@ -428,14 +440,14 @@ use moondancer::usb::{DEVICE_SERIAL_STRING, DEVICE_VERSION_NUMBER};
 use smolusb::descriptor::*;
 
 pub static DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
-    descriptor_version: 0x0200,
+    descriptor_version: le16(0x0200),
     device_class: 0x00,    // Composite
     device_subclass: 0x00, // Composite
     device_protocol: 0x00, // Composite
     max_packet_size: 64,
-    vendor_id: cynthion::shared::usb::bVendorId::example,
-    product_id: cynthion::shared::usb::bProductId::example,
-    device_version_number: DEVICE_VERSION_NUMBER,
+    vendor_id: le16(cynthion::shared::usb::bVendorId::example),
+    product_id: le16(cynthion::shared::usb::bProductId::example),
+    device_version_number: le16(DEVICE_VERSION_NUMBER),
     manufacturer_string_index: 1,
     product_string_index: 2,
     serial_string_index: 3,
@@ -444,7 +456,7 @@ pub static DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
 };
 
 pub static DEVICE_QUALIFIER_DESCRIPTOR: DeviceQualifierDescriptor = DeviceQualifierDescriptor {
-    descriptor_version: 0x0200,
+    descriptor_version: le16(0x0200),
     device_class: 0x00,    // Composite
     device_subclass: 0x00, // Composite
     device_protocol: 0x00, // Composite
@@ -458,8 +470,8 @@ pub static CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDe
         descriptor_type: DescriptorType::Configuration as u8,
         configuration_value: 1,
         configuration_string_index: 4,
-        attributes: 0x80, // 0b1000_0000 = bus-powered
-        max_power: 250,   // 250 * 2 mA = 500 mA ?
+        attributes: ConfigurationAttributes::new().bits(),
+        max_power: 250, // 250 * 2 mA = 500 mA ?
         ..ConfigurationDescriptorHeader::new()
     },
     &[InterfaceDescriptor::new(
@@ -475,15 +487,15 @@ pub static CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDe
         &[
             EndpointDescriptor {
                 endpoint_address: cynthion::shared::libgreat::endpoints::bulk_in_address, // IN
-                attributes: 0x02,                                                         // Bulk
-                max_packet_size: 512,
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(512),
                 interval: 0,
                 ..EndpointDescriptor::new()
             },
             EndpointDescriptor {
                 endpoint_address: cynthion::shared::libgreat::endpoints::bulk_out_address, // OUT
-                attributes: 0x02,                                                          // Bulk
-                max_packet_size: 512,
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(512),
                 interval: 0,
                 ..EndpointDescriptor::new()
             },
@@ -497,8 +509,8 @@ pub static OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
             descriptor_type: DescriptorType::OtherSpeedConfiguration as u8,
             configuration_value: 1,
             configuration_string_index: 7,
-            attributes: 0x80, // 0b1000_0000 = bus-powered
-            max_power: 250,   // 250 * 2 mA = 500 mA ?
+            attributes: ConfigurationAttributes::new().bits(),
+            max_power: 250, // 250 * 2 mA = 500 mA ?
             ..ConfigurationDescriptorHeader::new()
         },
         &[InterfaceDescriptor::new(
@@ -514,15 +526,15 @@ pub static OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
             &[
                 EndpointDescriptor {
                     endpoint_address: cynthion::shared::libgreat::endpoints::bulk_in_address, // IN
-                    attributes: 0x02, // Bulk
-                    max_packet_size: 64,
+                    attributes: EndpointAttributes::bulk().bits(),
+                    max_packet_size: le16(64),
                     interval: 0,
                     ..EndpointDescriptor::new()
                 },
                 EndpointDescriptor {
                     endpoint_address: cynthion::shared::libgreat::endpoints::bulk_out_address, // OUT
-                    attributes: 0x02, // Bulk
-                    max_packet_size: 64,
+                    attributes: EndpointAttributes::bulk().bits(),
+                    max_packet_size: le16(64),
                     interval: 0,
                     ..EndpointDescriptor::new()
                 },
@@ -0,0 +1,36 @@
+//! SoC clock frequency and cycle count conversions
+
+use crate::pac;
+
+/// Core clock frequency in Hz
+///
+/// The SoC does not expose its clock configuration at runtime so this
+/// is the frequency the gateware was built for.
+pub const CLOCK_HZ: u32 = pac::clock::sysclk();
+
+/// Convert a cycle count, as returned by `mcycle` or `profile!`, to nanoseconds
+#[inline(always)]
+pub const fn cycles_to_ns(cycles: u64) -> u64 {
+    cycles.saturating_mul(1_000_000_000) / CLOCK_HZ as u64
+}
+
+/// Convert a cycle count, as returned by `mcycle` or `profile!`, to microseconds
+#[inline(always)]
+pub const fn cycles_to_us(cycles: u64) -> u64 {
+    cycles.saturating_mul(1_000_000) / CLOCK_HZ as u64
+}
+
+/// Convert microseconds to a cycle count
+#[inline(always)]
+pub const fn us_to_cycles(us: u64) -> u64 {
+    us.saturating_mul(CLOCK_HZ as u64) / 1_000_000
+}
+
+/// Throughput in bytes per second for `bytes` transferred over `cycles`
+#[inline(always)]
+pub const fn bytes_per_second(bytes: u64, cycles: u64) -> u64 {
+    if cycles == 0 {
+        return 0;
+    }
+    bytes.saturating_mul(CLOCK_HZ as u64) / cycles
+}
@@ -0,0 +1,176 @@
+//! Test command protocol
+//!
+//! Test firmware such as `bulk_speed_test` is driven by the host over a
+//! pair of bulk endpoints. Each packet the host writes to the command
+//! OUT endpoint holds a single [`Command`] byte. Commands that produce
+//! a result, e.g. stopping a verification test, answer with an
+//! [`RESPONSE_LENGTH`] byte [`Response`] on the matching IN endpoint.
+//!
+//! The host side lives in `scripts/bulk_speed_test.py`.
+
+/// Length of a command packet
+pub const COMMAND_LENGTH: usize = 1;
+
+/// Length of an encoded [`Response`]
+pub const RESPONSE_LENGTH: usize = 8;
+
+// - Command ------------------------------------------------------------------
+
+/// Commands sent by the host on the command OUT endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Command {
+    /// Stop the running test and report its results
+    Stop = 0x01,
+    /// Send test data to the host as fast as possible
+    In = 0x23,
+    /// As `In` but send the verification pattern
+    VerifyIn = 0x24,
+    /// Receive test data from the host as fast as possible
+    Out = 0x42,
+    /// As `Out` but check the data against the verification pattern
+    VerifyOut = 0x43,
+}
+
+impl Command {
+    /// Decode a packet received on the command OUT endpoint
+    pub fn parse(packet: &[u8]) -> Result<Self, CommandError> {
+        match packet {
+            [] => Err(CommandError::Empty),
+            [byte] => Self::try_from(*byte),
+            _ => Err(CommandError::InvalidLength(packet.len())),
+        }
+    }
+
+    /// Returns true for commands that start a test
+    pub fn is_start(&self) -> bool {
+        *self != Command::Stop
+    }
+
+    /// Returns true for commands that start a verification test
+    pub fn is_verify(&self) -> bool {
+        matches!(self, Command::VerifyIn | Command::VerifyOut)
+    }
+}
+
+impl TryFrom<u8> for Command {
+    type Error = CommandError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Command::Stop),
+            0x23 => Ok(Command::In),
+            0x24 => Ok(Command::VerifyIn),
+            0x42 => Ok(Command::Out),
+            0x43 => Ok(Command::VerifyOut),
+            _ => Err(CommandError::Unknown(value)),
+        }
+    }
+}
+
+impl From<Command> for u8 {
+    fn from(command: Command) -> Self {
+        command as u8
+    }
+}
+
+/// Reasons a command packet was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    /// A zero length packet was received
+    Empty,
+    /// The packet was longer than [`COMMAND_LENGTH`]
+    InvalidLength(usize),
+    /// The command byte is not a known [`Command`]
+    Unknown(u8),
+}
+
+impl core::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(&self, f)
+    }
+}
+
+// - Response -----------------------------------------------------------------
+
+/// Result of a verification test, sent on the command IN endpoint when
+/// the test is stopped
+///
+/// Encoded as the mismatched packet count followed by the offset of
+/// the first mismatched byte, both as little-endian u32's. The offset
+/// is `0xffffffff` if there were no mismatches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Response {
+    pub mismatch_count: u32,
+    pub first_error_offset: Option<u32>,
+}
+
+impl Response {
+    pub fn to_bytes(&self) -> [u8; RESPONSE_LENGTH] {
+        let first_error_offset = self.first_error_offset.unwrap_or(u32::MAX);
+
+        let mut bytes = [0; RESPONSE_LENGTH];
+        bytes[..4].copy_from_slice(&self.mismatch_count.to_le_bytes());
+        bytes[4..].copy_from_slice(&first_error_offset.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; RESPONSE_LENGTH]) -> Self {
+        let mismatch_count = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let first_error_offset = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        Self {
+            mismatch_count,
+            first_error_offset: (first_error_offset != u32::MAX).then_some(first_error_offset),
+        }
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        for (byte, command) in [
+            (0x01, Command::Stop),
+            (0x23, Command::In),
+            (0x24, Command::VerifyIn),
+            (0x42, Command::Out),
+            (0x43, Command::VerifyOut),
+        ] {
+            assert_eq!(Command::parse(&[byte]), Ok(command));
+            assert_eq!(u8::from(command), byte);
+            assert_eq!(command.is_start(), command != Command::Stop);
+        }
+        assert!(Command::VerifyIn.is_verify());
+        assert!(!Command::Out.is_verify());
+    }
+
+    #[test]
+    fn test_malformed_commands() {
+        assert_eq!(Command::parse(&[]), Err(CommandError::Empty));
+        assert_eq!(
+            Command::parse(&[0x23, 0x00]),
+            Err(CommandError::InvalidLength(2))
+        );
+        assert_eq!(Command::parse(&[0x00]), Err(CommandError::Unknown(0x00)));
+        assert_eq!(Command::parse(&[0xff]), Err(CommandError::Unknown(0xff)));
+    }
+
+    #[test]
+    fn test_response_encoding() {
+        let response = Response {
+            mismatch_count: 2,
+            first_error_offset: Some(0x1234),
+        };
+        let bytes = response.to_bytes();
+        assert_eq!(bytes, [2, 0, 0, 0, 0x34, 0x12, 0, 0]);
+        assert_eq!(Response::from_bytes(bytes), response);
+
+        let response = Response::default();
+        assert_eq!(response.to_bytes(), [0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(Response::from_bytes(response.to_bytes()), response);
+    }
+}
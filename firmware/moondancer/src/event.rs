@@ -1,5 +1,9 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use heapless::mpmc::MpMcQueue;
 use smolusb::event::UsbEvent;
 
+use crate::interrupt::PendingInterrupts;
 use crate::pac;
 use crate::UsbInterface;
 
@@ -9,8 +13,10 @@ use crate::UsbInterface;
 pub enum InterruptEvent {
     // interrupt events
     Interrupt(pac::Interrupt),
-    UnknownInterrupt(usize),
-    UnhandledInterrupt(usize),
+    /// An interrupt the handler did not expect, with the decoded
+    /// pending-interrupt bitmap it saw
+    UnknownInterrupt(PendingInterrupts),
+    UnhandledInterrupt(PendingInterrupts),
 
     // timer events
     Timer(usize),
@@ -24,26 +30,167 @@ pub enum InterruptEvent {
     DebugMessage(&'static str),
 }
 
+// - EventQueue ---------------------------------------------------------------
+
+/// A [`MpMcQueue`] wrapper that keeps track of dropped items and the
+/// maximum number of items queued at any one time.
+///
+/// Unlike the bare queue, overflow is not an error for the caller to
+/// handle but is counted so it can be reported later.
+pub struct EventQueue<T, const N: usize> {
+    queue: MpMcQueue<T, N>,
+    length: AtomicUsize,
+    high_water_mark: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl<T, const N: usize> EventQueue<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            queue: MpMcQueue::new(),
+            length: AtomicUsize::new(0),
+            high_water_mark: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds an item to the end of the queue.
+    ///
+    /// Returns the item back if the queue is full, in which case it is
+    /// counted as dropped.
+    pub fn enqueue(&self, item: T) -> Result<(), T> {
+        match self.queue.enqueue(item) {
+            Ok(()) => {
+                let length = Self::increment(&self.length);
+                if length > self.high_water_mark.load(Ordering::Relaxed) {
+                    self.high_water_mark.store(length, Ordering::Relaxed);
+                }
+                Ok(())
+            }
+            Err(item) => {
+                Self::increment(&self.dropped);
+                Err(item)
+            }
+        }
+    }
+
+    /// Returns the item in the front of the queue, or `None` if the queue is empty.
+    pub fn dequeue(&self) -> Option<T> {
+        let item = self.queue.dequeue();
+        if item.is_some() {
+            Self::decrement(&self.length);
+        }
+        item
+    }
+
+    /// Returns the number of items currently in the queue.
+    pub fn len(&self) -> usize {
+        self.length.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of items dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the maximum number of items that have been in the queue at once.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark.load(Ordering::Relaxed)
+    }
+
+    /// Resets the dropped count and high-water mark.
+    pub fn reset_stats(&self) {
+        self.dropped.store(0, Ordering::Relaxed);
+        self.high_water_mark.store(self.len(), Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    fn increment(counter: &AtomicUsize) -> usize {
+        #[cfg(target_has_atomic)]
+        {
+            counter.fetch_add(1, Ordering::Relaxed) + 1
+        }
+        #[cfg(not(target_has_atomic))]
+        {
+            riscv::interrupt::free(|| {
+                let value = counter.load(Ordering::Relaxed) + 1;
+                counter.store(value, Ordering::Relaxed);
+                value
+            })
+        }
+    }
+
+    #[inline(always)]
+    fn decrement(counter: &AtomicUsize) -> usize {
+        #[cfg(target_has_atomic)]
+        {
+            counter.fetch_sub(1, Ordering::Relaxed) - 1
+        }
+        #[cfg(not(target_has_atomic))]
+        {
+            riscv::interrupt::free(|| {
+                let value = counter.load(Ordering::Relaxed) - 1;
+                counter.store(value, Ordering::Relaxed);
+                value
+            })
+        }
+    }
+}
+
 // - smolusb::Event conversion ------------------------------------------------
 
-impl TryFrom<InterruptEvent> for UsbEvent {
+impl From<(UsbInterface, UsbEvent)> for InterruptEvent {
+    /// Convert a `[smolusb::Event]` on `interface` to an `[InterruptEvent]`
+    fn from((interface, event): (UsbInterface, UsbEvent)) -> Self {
+        InterruptEvent::Usb(interface, event)
+    }
+}
+
+impl TryFrom<InterruptEvent> for (UsbInterface, UsbEvent) {
     type Error = ();
 
-    /// Convert an `[InterruptEvent]` to a `[smolusb::Event]`
+    /// Convert an `[InterruptEvent]` to a `[smolusb::Event]` and the
+    /// interface it occurred on
+    ///
+    /// A bare `Interrupt` is only converted for the USB device
+    /// interrupts, which signal a bus reset. The endpoint interrupts
+    /// need the endpoint number from the peripheral's registers and
+    /// are sent as `Usb` events by the interrupt handler.
     fn try_from(event: InterruptEvent) -> Result<Self, Self::Error> {
         match event {
-            InterruptEvent::Usb(_interface, event) => Ok(event),
+            InterruptEvent::Usb(interface, event) => Ok((interface, event)),
+            InterruptEvent::Interrupt(pac::Interrupt::USB0) => {
+                Ok((UsbInterface::Target, UsbEvent::BusReset))
+            }
+            InterruptEvent::Interrupt(pac::Interrupt::USB1) => {
+                Ok((UsbInterface::Aux, UsbEvent::BusReset))
+            }
+            InterruptEvent::Interrupt(pac::Interrupt::USB2) => {
+                Ok((UsbInterface::Control, UsbEvent::BusReset))
+            }
             _ => Err(()),
         }
     }
 }
 
+impl TryFrom<InterruptEvent> for UsbEvent {
+    type Error = ();
+
+    /// Convert an `[InterruptEvent]` to a `[smolusb::Event]`
+    fn try_from(event: InterruptEvent) -> Result<Self, Self::Error> {
+        <(UsbInterface, UsbEvent)>::try_from(event).map(|(_interface, event)| event)
+    }
+}
+
 impl InterruptEvent {
     /// Convert a `[smolusb::Event]` to an `[InterruptEvent]`
     pub fn from_smolusb_event(interface: UsbInterface, event: UsbEvent) -> InterruptEvent {
-        match event {
-            event => InterruptEvent::Usb(interface, event),
-        }
+        InterruptEvent::from((interface, event))
     }
 }
 
@@ -55,13 +202,15 @@ impl core::convert::From<InterruptEvent> for [u8; 3] {
         use UsbEvent::*;
         match message {
             InterruptEvent::Usb(interface, event) => match event {
-                BusReset => [event.into(), interface as u8, 0],
+                BusReset => [event.into(), interface.port_index(), 0],
                 ReceiveControl(endpoint_number) => {
-                    [event.into(), interface as u8, endpoint_number]
+                    [event.into(), interface.port_index(), endpoint_number]
                 }
-                ReceiveSetupPacket(endpoint_number, _setup_packet) => [event.into(), interface as u8, endpoint_number],
-                ReceivePacket(endpoint_number) => [event.into(), interface as u8, endpoint_number],
-                SendComplete(endpoint_number) => [event.into(), interface as u8, endpoint_number],
+                ReceiveSetupPacket(endpoint_number, _setup_packet) => [event.into(), interface.port_index(), endpoint_number],
+                ReceivePacket(endpoint_number) => [event.into(), interface.port_index(), endpoint_number],
+                SendComplete(endpoint_number) => [event.into(), interface.port_index(), endpoint_number],
+                BabbleDetected(endpoint_number) => [event.into(), interface.port_index(), endpoint_number],
+                Suspend => [event.into(), interface.port_index(), 0],
             },
             _ => [0, 0, 0],
         }
@@ -81,11 +230,11 @@ impl core::fmt::Debug for InterruptEvent {
         match self {
             // interrupts
             InterruptEvent::Interrupt(interrupt) => write!(f, "Event({:?})", interrupt),
-            InterruptEvent::UnknownInterrupt(interrupt) => {
-                write!(f, "UnknownInterrupt({})", interrupt)
+            InterruptEvent::UnknownInterrupt(pending) => {
+                write!(f, "UnknownInterrupt({})", pending)
             }
-            InterruptEvent::UnhandledInterrupt(interrupt) => {
-                write!(f, "UnhandledInterrupt({})", interrupt)
+            InterruptEvent::UnhandledInterrupt(pending) => {
+                write!(f, "UnhandledInterrupt({})", pending)
             }
 
             // timer events
@@ -93,7 +242,7 @@ impl core::fmt::Debug for InterruptEvent {
 
             // usb events
             InterruptEvent::Usb(interface, event) => {
-                write!(f, "{:?} on {:?}", event, interface)
+                write!(f, "{:?} on {}", event, interface)
             }
 
             // misc
@@ -106,3 +255,93 @@ impl core::fmt::Debug for InterruptEvent {
         }
     }
 }
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_queue_overflow() {
+        let queue: EventQueue<u8, 4> = EventQueue::new();
+        assert!(queue.is_empty());
+
+        // fill the queue and then overflow it by two
+        for n in 0..4 {
+            assert_eq!(queue.enqueue(n), Ok(()));
+        }
+        assert_eq!(queue.enqueue(4), Err(4));
+        assert_eq!(queue.enqueue(5), Err(5));
+
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.high_water_mark(), 4);
+
+        // draining keeps the high-water mark
+        assert_eq!(queue.dequeue(), Some(0));
+        assert_eq!(queue.dequeue(), Some(1));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.high_water_mark(), 4);
+
+        // there is room again
+        assert_eq!(queue.enqueue(6), Ok(()));
+        assert_eq!(queue.dropped(), 2);
+
+        // resetting stats starts from the current length
+        queue.reset_stats();
+        assert_eq!(queue.dropped(), 0);
+        assert_eq!(queue.high_water_mark(), 3);
+    }
+
+    fn usb_event(event: InterruptEvent) -> Option<(UsbInterface, u8)> {
+        <(UsbInterface, UsbEvent)>::try_from(event)
+            .ok()
+            .map(|(interface, event)| (interface, event.into()))
+    }
+
+    #[test]
+    fn test_usb_event_conversion() {
+        // usb events round-trip with their interface
+        for interface in [
+            UsbInterface::Target,
+            UsbInterface::Aux,
+            UsbInterface::Control,
+        ] {
+            let event = InterruptEvent::from((interface, UsbEvent::SendComplete(3)));
+            assert_eq!(
+                usb_event(event),
+                Some((interface, UsbEvent::SendComplete(3).into()))
+            );
+            assert_eq!(
+                UsbEvent::try_from(event).map(u8::from),
+                Ok(UsbEvent::SendComplete(3).into())
+            );
+        }
+
+        // device interrupts are bus resets on their interface
+        let bus_reset = UsbEvent::BusReset.into();
+        for (interrupt, interface) in [
+            (pac::Interrupt::USB0, UsbInterface::Target),
+            (pac::Interrupt::USB1, UsbInterface::Aux),
+            (pac::Interrupt::USB2, UsbInterface::Control),
+        ] {
+            assert_eq!(
+                usb_event(InterruptEvent::Interrupt(interrupt)),
+                Some((interface, bus_reset))
+            );
+        }
+
+        // endpoint and non-usb interrupts have no usb event
+        for interrupt in [
+            pac::Interrupt::USB0_EP_CONTROL,
+            pac::Interrupt::USB1_EP_IN,
+            pac::Interrupt::USB2_EP_OUT,
+            pac::Interrupt::TIMER,
+        ] {
+            assert_eq!(usb_event(InterruptEvent::Interrupt(interrupt)), None);
+        }
+        assert_eq!(usb_event(InterruptEvent::Timer(1)), None);
+        assert!(UsbEvent::try_from(InterruptEvent::DebugMessage("test")).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! Formatting helpers for debug output
+
+use core::fmt;
+
+/// Formats a byte slice as compact hex for log statements
+///
+/// ```ignore
+/// debug!("received: {}", HexSlice::new(&buffer[..8]));
+/// // received: 01 02 03 04 41 42 43 44
+///
+/// debug!("received: {}", HexSlice::new(&buffer[..8]).with_ascii());
+/// // received: 01 02 03 04 41 42 43 44  |....ABCD|
+/// ```
+///
+/// `Debug` and `Display` produce the same output so the wrapper can be
+/// dropped into existing `{:?}` format strings.
+#[derive(Copy, Clone)]
+pub struct HexSlice<'a> {
+    bytes: &'a [u8],
+    ascii: bool,
+}
+
+impl<'a> HexSlice<'a> {
+    pub const fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            ascii: false,
+        }
+    }
+
+    /// Append the printable ASCII characters of the slice, like a hex dump.
+    ///
+    /// Non-printable bytes are shown as `.`.
+    pub const fn with_ascii(self) -> Self {
+        Self {
+            bytes: self.bytes,
+            ascii: true,
+        }
+    }
+}
+
+impl<'a> From<&'a [u8]> for HexSlice<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl<'a> fmt::Display for HexSlice<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, byte) in self.bytes.iter().enumerate() {
+            if index > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+
+        if self.ascii {
+            f.write_str("  |")?;
+            for &byte in self.bytes {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                write!(f, "{}", c)?;
+            }
+            f.write_str("|")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Debug for HexSlice<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::fmt::Write;
+
+    fn format(hex: HexSlice) -> heapless::String<64> {
+        let mut s = heapless::String::new();
+        write!(s, "{}", hex).unwrap();
+        s
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(format(HexSlice::new(&[])), "");
+        assert_eq!(format(HexSlice::new(&[0x01, 0xab, 0xff])), "01 ab ff");
+    }
+
+    #[test]
+    fn test_hex_ascii() {
+        let bytes = [0x00, b'A', b'b', b' ', 0x7f];
+        assert_eq!(
+            format(HexSlice::new(&bytes).with_ascii()),
+            "00 41 62 20 7f  |.Ab .|"
+        );
+    }
+}
@@ -31,6 +31,9 @@ pub mod QuirkFlag {
     pub const SetAddressManually: u16 = 0x0001;
 }
 
+/// Maximum time a deferred `set_address` waits for the status stage, in microseconds
+const SET_ADDRESS_TIMEOUT_US: u64 = 100_000;
+
 // - Moondancer --------------------------------------------------------------
 
 use crate::event::InterruptEvent; // TODO use smolusb::event::UsbEvent instead
@@ -43,6 +46,8 @@ pub struct Moondancer {
     quirk_flags: u16,
     ep_in_max_packet_size: [u16; crate::EP_MAX_ENDPOINTS],
     ep_out_max_packet_size: [u16; crate::EP_MAX_ENDPOINTS],
+    /// Delay between multi-packet IN transfer packets, in cycles
+    ep_in_packet_delay: u32,
 }
 
 impl Moondancer {
@@ -53,6 +58,7 @@ impl Moondancer {
             quirk_flags: 0,
             ep_in_max_packet_size: [0; crate::EP_MAX_ENDPOINTS],
             ep_out_max_packet_size: [0; crate::EP_MAX_ENDPOINTS],
+            ep_in_packet_delay: 0,
         }
     }
 
@@ -124,7 +130,10 @@ impl Moondancer {
         //self.usb0.controller.full_speed_only.write(|w| w.full_speed_only().bit(true));
         //self.usb0.controller.low_speed_only.write(|w| w.low_speed_only().bit(true));
 
-        let speed = self.usb0.connect();
+        let speed = self.usb0.connect().map_err(|e| {
+            error!("MD moondancer::connect failed: {}", e);
+            GreatError::ConnectionRefused
+        })?;
 
         unsafe { self.enable_usb_interrupts() };
 
@@ -181,6 +190,13 @@ impl Moondancer {
     }
 
     /// Set the device address.
+    ///
+    /// If deferred is set the address is only applied once the host
+    /// has collected the status stage ack as the controller would
+    /// otherwise ignore the ack's IN token, which is still sent to the
+    /// old address.
+    ///
+    /// Returns the address read back from the controller.
     pub fn set_address(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
         #[repr(C)]
         #[derive(FromBytes, Unaligned)]
@@ -190,15 +206,52 @@ impl Moondancer {
         }
         let args = Args::read_from(arguments).ok_or(GreatError::InvalidArgument)?;
         let address = args.address & 0x7f;
+        let deferred = args.deferred != 0;
+
+        if deferred {
+            // set tx_ack_active flag, cleared by the SendComplete interrupt
+            unsafe {
+                self.usb0.set_tx_ack_active();
+            }
+
+            // respond with ack status first before changing device address
+            self.usb0.ack(0, Direction::HostToDevice);
+
+            // wait for the response packet to get sent
+            let t1 = riscv::register::mcycle::read();
+            while unsafe { self.usb0.is_tx_ack_active() } {
+                let elapsed = riscv::register::mcycle::read().wrapping_sub(t1);
+                if crate::cycles_to_us(elapsed as u64) > SET_ADDRESS_TIMEOUT_US {
+                    unsafe {
+                        self.usb0.clear_tx_ack_active();
+                    }
+                    warn!(
+                        "MD moondancer::set_address({}) timed out waiting for status stage",
+                        address
+                    );
+                    return Err(GreatError::ConnectionTimedOut);
+                }
+            }
 
-        // TODO handle
-        let _deferred = args.deferred != 0;
+            // activate new address
+            self.usb0.set_address(address);
+        } else {
+            // activate new address
+            self.usb0.set_address(address);
 
-        // activate new address
-        self.usb0.set_address(address);
+            // ack status
+            self.usb0.ack(0, Direction::HostToDevice);
+        }
 
-        // ack status
-        self.usb0.ack(0, Direction::HostToDevice);
+        // confirm the address took effect
+        let applied_address = self.usb0.ep_control_address();
+        if applied_address != address {
+            warn!(
+                "MD moondancer::set_address({}) controller reports address {}",
+                address, applied_address
+            );
+            return Err(GreatError::IoError);
+        }
 
         trace!(
             "MD moondancer::set_address(address:{}, deferred:{})",
@@ -206,7 +259,7 @@ impl Moondancer {
             args.deferred
         );
 
-        Ok([].into_iter())
+        Ok([applied_address].into_iter())
     }
 
     /// Configure endoints.
@@ -298,6 +351,190 @@ impl Moondancer {
 
         Ok([].into_iter())
     }
+
+    /// Set or clear NAK on the given USB endpoint.
+    pub fn set_endpoint_nak(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+        #[repr(C)]
+        #[derive(FromBytes, Unaligned)]
+        struct Args {
+            endpoint_address: u8,
+            nak: u8,
+        }
+        let args = Args::read_from(arguments).ok_or(GreatError::InvalidArgument)?;
+        let endpoint_address = args.endpoint_address;
+        let endpoint_number = endpoint_address & 0x7f;
+        let direction = Direction::from_endpoint_address(endpoint_address);
+        let nak = args.nak != 0;
+
+        if endpoint_number as usize >= crate::EP_MAX_ENDPOINTS {
+            return Err(GreatError::InvalidArgument);
+        }
+
+        // endpoint 0 can't be NAK'd
+        self.usb0
+            .set_endpoint_nak(endpoint_number, direction, nak)
+            .map_err(|_| GreatError::InvalidArgument)?;
+
+        log::info!(
+            "MD moondancer::set_endpoint_nak(0x{:x}, {})",
+            args.endpoint_address,
+            nak
+        );
+
+        Ok([].into_iter())
+    }
+
+    /// Pause or resume servicing of the given USB endpoint.
+    ///
+    /// A paused endpoint NAKs the host rather than stalling: OUT
+    /// endpoints are not re-primed and IN writes fail with
+    /// `OperationWouldBlock` until the endpoint is resumed.
+    pub fn set_endpoint_paused(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+        #[repr(C)]
+        #[derive(FromBytes, Unaligned)]
+        struct Args {
+            endpoint_address: u8,
+            paused: u8,
+        }
+        let args = Args::read_from(arguments).ok_or(GreatError::InvalidArgument)?;
+        let endpoint_address = args.endpoint_address;
+        let endpoint_number = endpoint_address & 0x7f;
+        let direction = Direction::from_endpoint_address(endpoint_address);
+        let paused = args.paused != 0;
+
+        if endpoint_number as usize >= crate::EP_MAX_ENDPOINTS {
+            return Err(GreatError::InvalidArgument);
+        }
+
+        // endpoint 0 can't be paused
+        if paused {
+            self.usb0.pause_endpoint(endpoint_number, direction)
+        } else {
+            self.usb0.resume_endpoint(endpoint_number, direction)
+        }
+        .map_err(|_| GreatError::InvalidArgument)?;
+
+        log::info!(
+            "MD moondancer::set_endpoint_paused(0x{:x}, {})",
+            args.endpoint_address,
+            paused
+        );
+
+        Ok([].into_iter())
+    }
+
+    /// Stall or unstall one direction of the given USB endpoint.
+    pub fn set_endpoint_stall(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+        #[repr(C)]
+        #[derive(FromBytes, Unaligned)]
+        struct Args {
+            endpoint_address: u8,
+            stall: u8,
+        }
+        let args = Args::read_from(arguments).ok_or(GreatError::InvalidArgument)?;
+        let endpoint_address = args.endpoint_address;
+        let endpoint_number = endpoint_address & 0x7f;
+        let direction = Direction::from_endpoint_address(endpoint_address);
+        let stall = args.stall != 0;
+
+        if endpoint_number as usize >= crate::EP_MAX_ENDPOINTS {
+            return Err(GreatError::InvalidArgument);
+        }
+
+        match (direction, stall) {
+            (Direction::DeviceToHost, true) => self.usb0.stall_endpoint_in(endpoint_number),
+            (Direction::DeviceToHost, false) => self.usb0.unstall_endpoint_in(endpoint_number),
+            (Direction::HostToDevice, true) => self.usb0.stall_endpoint_out(endpoint_number),
+            (Direction::HostToDevice, false) => self.usb0.unstall_endpoint_out(endpoint_number),
+        }
+
+        log::info!(
+            "MD moondancer::set_endpoint_stall(0x{:x}, {})",
+            args.endpoint_address,
+            stall
+        );
+
+        Ok([].into_iter())
+    }
+
+    /// Reset the data toggle of one direction of the given USB endpoint
+    /// to DATA0.
+    ///
+    /// Unlike `set_endpoint_stall` this leaves the endpoint's stall state
+    /// alone. Returns the address of the endpoint that was reset.
+    pub fn reset_data_toggle(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+        #[repr(C)]
+        #[derive(FromBytes, Unaligned)]
+        struct Args {
+            endpoint_address: u8,
+        }
+        let args = Args::read_from(arguments).ok_or(GreatError::InvalidArgument)?;
+        let endpoint_address = args.endpoint_address;
+        let endpoint_number = endpoint_address & 0x7f;
+
+        if endpoint_number as usize >= crate::EP_MAX_ENDPOINTS {
+            return Err(GreatError::InvalidArgument);
+        }
+
+        self.usb0.clear_feature_endpoint_halt(endpoint_address);
+
+        log::info!("MD moondancer::reset_data_toggle(0x{:x})", endpoint_address);
+
+        Ok([endpoint_address].into_iter())
+    }
+
+    /// Set the delay between the packets of multi-packet IN transfers.
+    ///
+    /// Used to emulate a slow device, a delay of 0 disables it.
+    pub fn set_in_packet_delay(
+        &mut self,
+        arguments: &[u8],
+    ) -> GreatResult<impl Iterator<Item = u8>> {
+        #[repr(C)]
+        #[derive(FromBytes, Unaligned)]
+        struct Args {
+            delay_us: U32<LittleEndian>,
+        }
+        let args = Args::read_from(arguments).ok_or(GreatError::InvalidArgument)?;
+        let delay_us = u32::from(args.delay_us);
+
+        let delay_cycles = crate::clock::us_to_cycles(delay_us as u64);
+        self.ep_in_packet_delay =
+            u32::try_from(delay_cycles).map_err(|_| GreatError::InvalidArgument)?;
+
+        log::info!(
+            "MD moondancer::set_in_packet_delay({} us) -> {} cycles",
+            delay_us,
+            self.ep_in_packet_delay
+        );
+
+        Ok([].into_iter())
+    }
+
+    /// Get the frame number of the last SOF packet.
+    ///
+    /// Returns `NotSupported` if the controller does not report SOF packets.
+    pub fn get_frame_number(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+        let frame_number = self
+            .usb0
+            .frame_number()
+            .ok_or(GreatError::NotSupported)?;
+
+        trace!("MD moondancer::get_frame_number() -> {}", frame_number);
+
+        Ok(frame_number.to_le_bytes().into_iter())
+    }
+
+    /// Get a snapshot of the Target port's USB controller registers.
+    ///
+    /// The snapshot is also written to the log.
+    pub fn dump_registers(&self, arguments: &[u8]) -> GreatResult<impl Iterator<Item = u8>> {
+        let snapshot = self.usb0.dump_registers();
+
+        debug!("MD moondancer::dump_registers()\n{}", snapshot);
+
+        Ok(snapshot.as_bytes().into_iter())
+    }
 }
 
 // - verb implementations: data transfer --------------------------------------
@@ -368,7 +605,9 @@ impl Moondancer {
         }
         let args = Args::read_from(arguments).ok_or(GreatError::InvalidArgument)?;
 
-        self.usb0.ep_out_prime_receive(args.endpoint_number);
+        self.usb0
+            .ep_out_prime_receive_retry(args.endpoint_number, &smolusb::TransferPolicy::DEFAULT)
+            .map_err(|_| GreatError::IoError)?;
 
         debug!(
             "MD moondancer::ep_out_prime_receive({})",
@@ -412,11 +651,36 @@ impl Moondancer {
 
         // TODO we can probably just use write_packets here
         let max_packet_size = self.ep_in_max_packet_size[endpoint_number as usize] as usize;
-        if payload_length > max_packet_size {
+        let result = if payload_length > max_packet_size && self.ep_in_packet_delay > 0 {
+            self.usb0.write_packets_delayed(
+                endpoint_number,
+                payload.copied(),
+                max_packet_size,
+                self.ep_in_packet_delay,
+            )
+        } else if payload_length > max_packet_size {
             self.usb0
-                .write_packets(endpoint_number, payload.copied(), max_packet_size);
+                .write_packets(endpoint_number, payload.copied(), max_packet_size)
         } else {
-            self.usb0.write_ref(endpoint_number, payload);
+            self.usb0.write_ref(endpoint_number, payload)
+        };
+
+        if let Err(e) = result {
+            warn!(
+                "MD moondancer::write_endpoint({}) failed: {:?}",
+                endpoint_number, e
+            );
+            // nothing will be sent so don't wait for it
+            if blocking {
+                unsafe {
+                    self.usb0.clear_tx_ack_active();
+                }
+            }
+            return Err(match e {
+                smolusb::SmolError::WouldBlock => GreatError::OperationWouldBlock,
+                smolusb::SmolError::Timeout => GreatError::ConnectionTimedOut,
+                _ => GreatError::IoError,
+            });
         }
 
         // TODO better handling for blocking
@@ -544,7 +808,7 @@ pub static CLASS_DOCS: &str = "API for fine-grained control of the Target USB po
 ///
 /// Fields are `"\0"`  where C implementation has `""`
 /// Fields are `"*\0"` where C implementation has `NULL`
-pub static VERBS: [Verb; 14] = [
+pub static VERBS: [Verb; 21] = [
     // - device connection --
     Verb {
         id: 0x0,
@@ -589,8 +853,8 @@ pub static VERBS: [Verb; 14] = [
         doc: "\0", //"Set the address of the target device.\nIf deferred is set this action won't complete until the setup phase ends.\0",
         in_signature: "<BB\0",
         in_param_names: "address, deferred\0",
-        out_signature: "\0",
-        out_param_names: "*\0",
+        out_signature: "<B\0",
+        out_param_names: "address\0",
     },
     Verb {
         id: 0x5,
@@ -610,6 +874,69 @@ pub static VERBS: [Verb; 14] = [
         out_signature: "\0",
         out_param_names: "*\0",
     },
+    Verb {
+        id: 0xb,
+        name: "set_endpoint_nak\0",
+        doc: "\0", //"Set or clear NAK on the endpoint with the provided address.\0",
+        in_signature: "<BB\0",
+        in_param_names: "endpoint_address, nak\0",
+        out_signature: "\0",
+        out_param_names: "*\0",
+    },
+    Verb {
+        id: 0xc,
+        name: "set_endpoint_stall\0",
+        doc: "\0", //"Stall or unstall the endpoint with the provided address.\0",
+        in_signature: "<BB\0",
+        in_param_names: "endpoint_address, stall\0",
+        out_signature: "\0",
+        out_param_names: "*\0",
+    },
+    Verb {
+        id: 0xf,
+        name: "set_endpoint_paused\0",
+        doc: "\0", //"Pause or resume servicing of the endpoint with the provided address.\0",
+        in_signature: "<BB\0",
+        in_param_names: "endpoint_address, paused\0",
+        out_signature: "\0",
+        out_param_names: "*\0",
+    },
+    Verb {
+        id: 0x10,
+        name: "reset_data_toggle\0",
+        doc: "\0", //"Reset the data toggle of the endpoint with the provided address to DATA0.\0",
+        in_signature: "<B\0",
+        in_param_names: "endpoint_address\0",
+        out_signature: "<B\0",
+        out_param_names: "endpoint_address\0",
+    },
+    Verb {
+        id: 0xd,
+        name: "set_in_packet_delay\0",
+        doc: "\0", //"Set the delay between the packets of multi-packet IN transfers.\0",
+        in_signature: "<I\0",
+        in_param_names: "delay_us\0",
+        out_signature: "\0",
+        out_param_names: "*\0",
+    },
+    Verb {
+        id: 0xe,
+        name: "get_frame_number\0",
+        doc: "\0", //"Get the frame number of the last SOF packet received by the Target port.\0",
+        in_signature: "\0",
+        in_param_names: "*\0",
+        out_signature: "<H\0",
+        out_param_names: "frame_number\0",
+    },
+    Verb {
+        id: 0x11,
+        name: "dump_registers\0",
+        doc: "\0", //"Return a snapshot of the Target port's USB controller registers.\0",
+        in_signature: "\0",
+        in_param_names: "*\0",
+        out_signature: "<34X\0",
+        out_param_names: "registers\0",
+    },
     // - data transfer --
     Verb {
         id: 0x7,
@@ -730,6 +1057,48 @@ impl Moondancer {
                 let response = unsafe { iter_to_response(iter, response_buffer) };
                 Ok(response)
             }
+            0xb => {
+                // moondancer::set_endpoint_nak
+                let iter = self.set_endpoint_nak(arguments)?;
+                let response = unsafe { iter_to_response(iter, response_buffer) };
+                Ok(response)
+            }
+            0xc => {
+                // moondancer::set_endpoint_stall
+                let iter = self.set_endpoint_stall(arguments)?;
+                let response = unsafe { iter_to_response(iter, response_buffer) };
+                Ok(response)
+            }
+            0xf => {
+                // moondancer::set_endpoint_paused
+                let iter = self.set_endpoint_paused(arguments)?;
+                let response = unsafe { iter_to_response(iter, response_buffer) };
+                Ok(response)
+            }
+            0x10 => {
+                // moondancer::reset_data_toggle
+                let iter = self.reset_data_toggle(arguments)?;
+                let response = unsafe { iter_to_response(iter, response_buffer) };
+                Ok(response)
+            }
+            0xd => {
+                // moondancer::set_in_packet_delay
+                let iter = self.set_in_packet_delay(arguments)?;
+                let response = unsafe { iter_to_response(iter, response_buffer) };
+                Ok(response)
+            }
+            0xe => {
+                // moondancer::get_frame_number
+                let iter = self.get_frame_number(arguments)?;
+                let response = unsafe { iter_to_response(iter, response_buffer) };
+                Ok(response)
+            }
+            0x11 => {
+                // moondancer::dump_registers
+                let iter = self.dump_registers(arguments)?;
+                let response = unsafe { iter_to_response(iter, response_buffer) };
+                Ok(response)
+            }
             0x7 => {
                 // moondancer::read_endpoint
                 let iter = self.read_endpoint(arguments)?;
@@ -0,0 +1,98 @@
+//! Interrupt diagnostics
+//!
+//! Decodes the raw pending-interrupt bitmap returned by
+//! `pac::csr::interrupt::reg_pending()` into [`Interrupt`] variants.
+
+use core::fmt;
+
+use crate::pac;
+use pac::Interrupt;
+
+/// Returns the name of the peripheral that raised `interrupt`
+pub const fn name(interrupt: Interrupt) -> &'static str {
+    match interrupt {
+        Interrupt::TIMER => "timer",
+        Interrupt::UART => "uart",
+        Interrupt::GPIOA => "gpioa",
+        Interrupt::GPIOB => "gpiob",
+        Interrupt::USB0 => "usb0",
+        Interrupt::USB0_EP_CONTROL => "usb0_ep_control",
+        Interrupt::USB0_EP_IN => "usb0_ep_in",
+        Interrupt::USB0_EP_OUT => "usb0_ep_out",
+        Interrupt::USB1 => "usb1",
+        Interrupt::USB1_EP_CONTROL => "usb1_ep_control",
+        Interrupt::USB1_EP_IN => "usb1_ep_in",
+        Interrupt::USB1_EP_OUT => "usb1_ep_out",
+        Interrupt::USB2 => "usb2",
+        Interrupt::USB2_EP_CONTROL => "usb2_ep_control",
+        Interrupt::USB2_EP_IN => "usb2_ep_in",
+        Interrupt::USB2_EP_OUT => "usb2_ep_out",
+    }
+}
+
+/// Returns the currently pending interrupts
+pub fn decode_pending() -> impl Iterator<Item = Interrupt> {
+    PendingInterrupts::read().iter()
+}
+
+// - PendingInterrupts --------------------------------------------------------
+
+/// A decoded pending-interrupt bitmap
+///
+/// Displays as the names of the pending interrupts followed by any bits
+/// that do not correspond to a known interrupt, e.g.
+/// `timer | usb0_ep_out | 0x10000`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingInterrupts(usize);
+
+impl PendingInterrupts {
+    /// Read the pending-interrupt bitmap
+    pub fn read() -> Self {
+        Self(pac::csr::interrupt::reg_pending())
+    }
+
+    pub const fn bits(&self) -> usize {
+        self.0
+    }
+
+    /// Returns the pending interrupts in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = Interrupt> {
+        let bits = self.0;
+        (0..usize::BITS as u8)
+            .filter(move |n| bits & (1 << n) != 0)
+            .filter_map(|n| Interrupt::try_from(n).ok())
+    }
+
+    /// Returns the bits that do not correspond to a known interrupt
+    pub fn unknown_bits(&self) -> usize {
+        self.iter()
+            .fold(self.0, |bits, interrupt| bits & !(1 << interrupt as usize))
+    }
+}
+
+impl From<usize> for PendingInterrupts {
+    fn from(bits: usize) -> Self {
+        Self(bits)
+    }
+}
+
+impl fmt::Display for PendingInterrupts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "none");
+        }
+
+        let mut separator = "";
+        for interrupt in self.iter() {
+            write!(f, "{}{}", separator, name(interrupt))?;
+            separator = " | ";
+        }
+
+        let unknown_bits = self.unknown_bits();
+        if unknown_bits != 0 {
+            write!(f, "{}{:#x}", separator, unknown_bits)?;
+        }
+
+        Ok(())
+    }
+}
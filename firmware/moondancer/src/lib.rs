@@ -4,12 +4,19 @@
 
 // - modules ------------------------------------------------------------------
 
+pub mod clock;
+pub mod command;
 pub mod error;
 pub mod event;
+pub mod fmt;
 pub mod gcp;
+pub mod interrupt;
 pub mod log;
 pub mod macros;
 pub mod panic_log;
+pub mod ring;
+pub mod selftest;
+pub mod test_data;
 pub mod usb;
 pub mod util;
 
@@ -20,13 +27,15 @@ pub use lunasoc_pac as pac;
 
 // - re-exports ---------------------------------------------------------------
 
+pub use clock::{cycles_to_ns, cycles_to_us, CLOCK_HZ};
 pub use error::FirmwareError;
 pub use libgreat::error::GreatResult;
 pub use libgreat::firmware::BoardInformation;
+pub use smolusb::device::Speed;
 
 // - constants ----------------------------------------------------------------
 
-pub const SYSTEM_CLOCK_FREQUENCY: u32 = pac::clock::sysclk();
+pub const SYSTEM_CLOCK_FREQUENCY: u32 = CLOCK_HZ;
 
 // TODO these need to be populated at runtime
 pub const BOARD_INFORMATION: BoardInformation = BoardInformation {
@@ -42,12 +51,86 @@ pub const BOARD_INFORMATION: BoardInformation = BoardInformation {
 pub const EP_MAX_ENDPOINTS: usize = 16;
 pub const EP_MAX_PACKET_SIZE: usize = 512;
 
+/// USB PHYs available on Cynthion r0.4, see [`usb::ports`]
+pub static USB_PORTS: [usb::PortInfo; 3] = [
+    usb::PortInfo {
+        index: UsbInterface::Target.port_index(),
+        role: UsbInterface::Target,
+        speeds: &[Speed::High, Speed::Full, Speed::Low],
+    },
+    usb::PortInfo {
+        index: UsbInterface::Aux.port_index(),
+        role: UsbInterface::Aux,
+        speeds: &[Speed::High, Speed::Full, Speed::Low],
+    },
+    usb::PortInfo {
+        index: UsbInterface::Control.port_index(),
+        role: UsbInterface::Control,
+        speeds: &[Speed::High, Speed::Full, Speed::Low],
+    },
+];
+
 // - types --------------------------------------------------------------------
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum UsbInterface {
     Target = 0,  // Usb0
     Aux = 1,     // Usb1 (Host on r0.4)
     Control = 2, // Usb2 (Sideband on r0.4)
 }
+
+impl UsbInterface {
+    /// Returns the index of the interface's PHY, `n` in the `UsbN` HAL
+    /// peripheral.
+    pub const fn port_index(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns the interface attached to PHY `index`, if any.
+    pub const fn from_port_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(UsbInterface::Target),
+            1 => Some(UsbInterface::Aux),
+            2 => Some(UsbInterface::Control),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for UsbInterface {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            UsbInterface::Target => write!(f, "Target"),
+            UsbInterface::Aux => write!(f, "Aux"),
+            UsbInterface::Control => write!(f, "Control"),
+        }
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usb_interface_port_index() {
+        for interface in [
+            UsbInterface::Target,
+            UsbInterface::Aux,
+            UsbInterface::Control,
+        ] {
+            assert_eq!(
+                UsbInterface::from_port_index(interface.port_index()),
+                Some(interface)
+            );
+        }
+        assert_eq!(UsbInterface::from_port_index(3), None);
+
+        // every port's role lives on that port
+        for port in usb::ports() {
+            assert_eq!(port.role.port_index(), port.index);
+        }
+    }
+}
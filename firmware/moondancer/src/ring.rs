@@ -0,0 +1,181 @@
+//! Byte ring buffer for stream-oriented endpoints
+//!
+//! Queueing every received packet in its own `EP_MAX_PACKET_SIZE`
+//! buffer wastes RAM when the data is a byte stream, e.g. CDC serial.
+//! A [`ByteRing`] lets the interrupt handler append the bytes it
+//! receives to a single shared buffer which the main loop drains at its
+//! own pace.
+//!
+//! Message-oriented endpoints should keep using an
+//! [`EventQueue`](crate::event::EventQueue) of packets so packet
+//! boundaries are preserved.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A single-producer, single-consumer byte ring buffer
+///
+/// One side, typically the interrupt handler, may only call
+/// [`ByteRing::push_slice`] and the other side may only call
+/// [`ByteRing::pop_slice`]. Each index is only ever written by one
+/// side so the ring only needs atomic loads and stores and works on
+/// targets without the atomic extension.
+///
+/// One slot is kept free to tell a full ring from an empty one so the
+/// ring holds at most `N - 1` bytes.
+pub struct ByteRing<const N: usize> {
+    buffer: UnsafeCell<[u8; N]>,
+    /// Next slot to write, only modified by the producer
+    head: AtomicUsize,
+    /// Next slot to read, only modified by the consumer
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+// Safety: the producer and consumer never access the same slots, see
+// `push_slice` and `pop_slice`.
+unsafe impl<const N: usize> Sync for ByteRing<N> {}
+
+impl<const N: usize> ByteRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the maximum number of bytes the ring can hold.
+    pub const fn capacity(&self) -> usize {
+        N - 1
+    }
+
+    /// Returns the number of bytes waiting to be read.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        (head + N - tail) % N
+    }
+
+    /// Returns true if there are no bytes waiting to be read.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of bytes that can be pushed without overflowing.
+    pub fn free(&self) -> usize {
+        self.capacity() - self.len()
+    }
+
+    /// Returns the number of bytes dropped because the ring was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Resets the dropped count.
+    pub fn reset_stats(&self) {
+        self.dropped.store(0, Ordering::Relaxed);
+    }
+
+    /// Appends `bytes` to the ring.
+    ///
+    /// If the ring does not have room for all of `bytes` as many as fit
+    /// are appended and the number of bytes that did not fit is
+    /// returned as an error and counted as dropped.
+    pub fn push_slice(&self, bytes: &[u8]) -> Result<(), usize> {
+        let mut head = self.head.load(Ordering::Relaxed);
+        let count = bytes.len().min(self.free());
+
+        let buffer = self.buffer.get() as *mut u8;
+        for &byte in &bytes[..count] {
+            // Safety: slots between head and tail are not read by the
+            // consumer until head is published below.
+            unsafe { buffer.add(head).write_volatile(byte) };
+            head = (head + 1) % N;
+        }
+        self.head.store(head, Ordering::Release);
+
+        let overflow = bytes.len() - count;
+        if overflow == 0 {
+            Ok(())
+        } else {
+            self.dropped.store(
+                self.dropped
+                    .load(Ordering::Relaxed)
+                    .saturating_add(overflow),
+                Ordering::Relaxed,
+            );
+            Err(overflow)
+        }
+    }
+
+    /// Moves up to `buffer.len()` bytes from the ring into `buffer`.
+    ///
+    /// Returns the number of bytes read.
+    pub fn pop_slice(&self, buffer: &mut [u8]) -> usize {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        let count = buffer.len().min(self.len());
+
+        let ring = self.buffer.get() as *const u8;
+        for byte in &mut buffer[..count] {
+            // Safety: slots between tail and head are not written by the
+            // producer until tail is published below.
+            *byte = unsafe { ring.add(tail).read_volatile() };
+            tail = (tail + 1) % N;
+        }
+        self.tail.store(tail, Ordering::Release);
+
+        count
+    }
+}
+
+impl<const N: usize> Default for ByteRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_pop() {
+        let ring: ByteRing<8> = ByteRing::new();
+        let mut buffer = [0; 8];
+
+        assert_eq!(ring.capacity(), 7);
+        assert_eq!(ring.pop_slice(&mut buffer), 0);
+
+        assert_eq!(ring.push_slice(&[1, 2, 3, 4, 5]), Ok(()));
+        assert_eq!(ring.pop_slice(&mut buffer[..3]), 3);
+        assert_eq!(&buffer[..3], &[1, 2, 3]);
+
+        // wraps around the end of the buffer
+        assert_eq!(ring.push_slice(&[6, 7, 8, 9]), Ok(()));
+        assert_eq!(ring.len(), 6);
+        assert_eq!(ring.pop_slice(&mut buffer), 6);
+        assert_eq!(&buffer[..6], &[4, 5, 6, 7, 8, 9]);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let ring: ByteRing<8> = ByteRing::new();
+        let mut buffer = [0; 8];
+
+        assert_eq!(ring.push_slice(&[1, 2, 3, 4, 5]), Ok(()));
+        assert_eq!(ring.push_slice(&[6, 7, 8, 9]), Err(2));
+        assert_eq!(ring.free(), 0);
+        assert_eq!(ring.dropped(), 2);
+
+        assert_eq!(ring.pop_slice(&mut buffer), 7);
+        assert_eq!(&buffer[..7], &[1, 2, 3, 4, 5, 6, 7]);
+
+        ring.reset_stats();
+        assert_eq!(ring.dropped(), 0);
+    }
+}
@@ -0,0 +1,196 @@
+//! Bring-up self-test
+//!
+//! Exercises each USB controller's registers, FIFO resets and interrupt
+//! plumbing without needing a host connection so hardware faults can be
+//! told apart from firmware bugs during board bring-up.
+//!
+//! Results are logged over the UART and shown on the LEDs, one LED per
+//! controller that passed every check.
+//!
+//! The self-test reconfigures the controllers and must be run before
+//! they are connected, e.g. at boot.
+
+use log::{error, info};
+
+use crate::pac;
+use pac::Interrupt;
+
+/// Self-test results for a single USB controller
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UsbReport {
+    /// Endpoint registers read back what was written
+    pub registers: bool,
+    /// Resetting the IN and OUT FIFOs discards their contents
+    pub fifo_reset: bool,
+    /// Interrupt enables toggle and no interrupts are left pending
+    pub interrupts: bool,
+}
+
+impl UsbReport {
+    pub fn passed(&self) -> bool {
+        self.registers && self.fifo_reset && self.interrupts
+    }
+}
+
+/// Self-test results
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Results for `Usb0`, `Usb1` and `Usb2`
+    pub usb: [UsbReport; 3],
+}
+
+impl Report {
+    /// Returns true if every subsystem passed.
+    pub fn passed(&self) -> bool {
+        self.usb.iter().all(UsbReport::passed)
+    }
+
+    /// Returns the LED pattern for the report, bit `n` is set if `Usbn` passed.
+    pub fn led_pattern(&self) -> u8 {
+        self.usb
+            .iter()
+            .enumerate()
+            .filter(|(_, report)| report.passed())
+            .fold(0, |pattern, (n, _)| pattern | (1 << n))
+    }
+}
+
+/// Run the self-test and report the results over the UART and LEDs.
+pub fn run() -> Report {
+    let peripherals = unsafe { pac::Peripherals::steal() };
+
+    let report = riscv::interrupt::free(|| Report {
+        usb: [
+            test_usb0(&peripherals),
+            test_usb1(&peripherals),
+            test_usb2(&peripherals),
+        ],
+    });
+
+    for (n, usb) in report.usb.iter().enumerate() {
+        for (name, passed) in [
+            ("registers", usb.registers),
+            ("fifo reset", usb.fifo_reset),
+            ("interrupts", usb.interrupts),
+        ] {
+            if passed {
+                info!("SELFTEST usb{} {}: pass", n, name);
+            } else {
+                error!("SELFTEST usb{} {}: FAIL", n, name);
+            }
+        }
+    }
+
+    if report.passed() {
+        info!("SELFTEST passed");
+    } else {
+        error!("SELFTEST failed");
+    }
+
+    peripherals
+        .LEDS
+        .output
+        .write(|w| unsafe { w.output().bits(report.led_pattern()) });
+
+    report
+}
+
+// - checks -------------------------------------------------------------------
+
+macro_rules! impl_test_usb {
+    ($(
+        $test_usbx:ident: $USBX:ident, $USBX_EP_CONTROL:ident, $USBX_EP_IN:ident, $USBX_EP_OUT:ident,
+    )+) => {
+        $(
+            fn $test_usbx(peripherals: &pac::Peripherals) -> UsbReport {
+                let controller = &peripherals.$USBX;
+                let ep_control = &peripherals.$USBX_EP_CONTROL;
+                let ep_in = &peripherals.$USBX_EP_IN;
+                let ep_out = &peripherals.$USBX_EP_OUT;
+
+                // - registers --
+
+                let mut registers = true;
+                for pattern in [0x55, 0x2a, 0x00] {
+                    ep_control.address.write(|w| unsafe { w.address().bits(pattern) });
+                    ep_out.address.write(|w| unsafe { w.address().bits(pattern) });
+                    registers &= ep_control.address.read().address().bits() == pattern;
+                    registers &= ep_out.address.read().address().bits() == pattern;
+                }
+                for pattern in [0x5, 0xa, 0x0] {
+                    ep_in.epno.write(|w| unsafe { w.epno().bits(pattern) });
+                    registers &= ep_in.epno.read().epno().bits() == pattern;
+                }
+
+                // - fifo reset --
+
+                ep_in.reset.write(|w| w.reset().bit(true));
+                for byte in [0xde, 0xad, 0xbe, 0xef] {
+                    ep_in.data.write(|w| unsafe { w.data().bits(byte) });
+                }
+                let mut fifo_reset = ep_in.have.read().have().bit();
+                ep_in.reset.write(|w| w.reset().bit(true));
+                ep_out.reset.write(|w| w.reset().bit(true));
+                fifo_reset &= !ep_in.have.read().have().bit();
+                fifo_reset &= !ep_out.have.read().have().bit();
+
+                // - interrupts --
+
+                let mut interrupts = true;
+                macro_rules! toggle_ev_enable {
+                    ($peripheral:expr) => {
+                        $peripheral.ev_enable.write(|w| w.enable().bit(true));
+                        interrupts &= $peripheral.ev_enable.read().enable().bit();
+                        $peripheral.ev_enable.write(|w| w.enable().bit(false));
+                        interrupts &= !$peripheral.ev_enable.read().enable().bit();
+                        $peripheral.ev_pending.write(|w| w.pending().bit(true));
+                    };
+                }
+                toggle_ev_enable!(controller);
+                toggle_ev_enable!(ep_control);
+                toggle_ev_enable!(ep_in);
+                toggle_ev_enable!(ep_out);
+
+                for interrupt in [
+                    Interrupt::$USBX,
+                    Interrupt::$USBX_EP_CONTROL,
+                    Interrupt::$USBX_EP_IN,
+                    Interrupt::$USBX_EP_OUT,
+                ] {
+                    interrupts &= toggle_interrupt(interrupt);
+                }
+
+                UsbReport {
+                    registers,
+                    fifo_reset,
+                    interrupts,
+                }
+            }
+        )+
+    }
+}
+
+impl_test_usb! {
+    test_usb0: USB0, USB0_EP_CONTROL, USB0_EP_IN, USB0_EP_OUT,
+    test_usb1: USB1, USB1_EP_CONTROL, USB1_EP_IN, USB1_EP_OUT,
+    test_usb2: USB2, USB2_EP_CONTROL, USB2_EP_IN, USB2_EP_OUT,
+}
+
+/// Toggle `interrupt` in the interrupt mask and check that it is not
+/// left pending with its events disabled.
+fn toggle_interrupt(interrupt: Interrupt) -> bool {
+    let bit = 1 << interrupt as usize;
+    let was_enabled = pac::csr::interrupt::reg_mask() & bit != 0;
+
+    unsafe { pac::csr::interrupt::enable(interrupt) };
+    let mut passed = pac::csr::interrupt::reg_mask() & bit != 0;
+    unsafe { pac::csr::interrupt::disable(interrupt) };
+    passed &= pac::csr::interrupt::reg_mask() & bit == 0;
+    passed &= !pac::csr::interrupt::pending(interrupt);
+
+    if was_enabled {
+        unsafe { pac::csr::interrupt::enable(interrupt) };
+    }
+
+    passed
+}
@@ -0,0 +1,125 @@
+//! Reproducible test data patterns
+//!
+//! Firmware and host tooling generate the same patterns so that data
+//! received on either end can be checked for integrity:
+//!
+//!   * ramp: `buffer[n] = n % 256`
+//!   * prbs: a xorshift32 pseudo-random sequence, each 32-bit word is
+//!     emitted little-endian
+//!
+//! A ramp is easy to recognise in a capture but PRBS data catches bit
+//! errors, e.g. a stuck or swapped data line, that a ramp may mask.
+
+/// Seed used in place of zero, which would only ever produce zeroes
+const PRBS_DEFAULT_SEED: u32 = 0x2545_f491;
+
+/// Fill `buffer` with a ramp
+pub fn fill_ramp(buffer: &mut [u8]) {
+    for (n, byte) in buffer.iter_mut().enumerate() {
+        *byte = n as u8;
+    }
+}
+
+/// Returns the offset of the first byte in `buffer` that does not
+/// match the ramp
+pub fn verify_ramp(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .iter()
+        .enumerate()
+        .position(|(n, byte)| *byte != n as u8)
+}
+
+/// Fill `buffer` with the PRBS sequence for `seed`
+pub fn fill_prbs(buffer: &mut [u8], seed: u32) {
+    for (byte, value) in buffer.iter_mut().zip(Prbs::new(seed)) {
+        *byte = value;
+    }
+}
+
+/// Returns the offset of the first byte in `buffer` that does not
+/// match the PRBS sequence for `seed`
+pub fn verify_prbs(buffer: &[u8], seed: u32) -> Option<usize> {
+    buffer
+        .iter()
+        .zip(Prbs::new(seed))
+        .position(|(byte, value)| *byte != value)
+}
+
+// - Prbs ---------------------------------------------------------------------
+
+/// Endless iterator over the PRBS sequence for a seed
+///
+/// Useful for generating or verifying a sequence spanning several
+/// packets.
+#[derive(Clone, Debug)]
+pub struct Prbs {
+    state: u32,
+    word: [u8; 4],
+    index: usize,
+}
+
+impl Prbs {
+    pub fn new(seed: u32) -> Self {
+        let state = if seed == 0 { PRBS_DEFAULT_SEED } else { seed };
+        Self {
+            state,
+            word: [0; 4],
+            index: 4,
+        }
+    }
+}
+
+impl Iterator for Prbs {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index == self.word.len() {
+            let mut x = self.state;
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            self.state = x;
+            self.word = x.to_le_bytes();
+            self.index = 0;
+        }
+
+        let byte = self.word[self.index];
+        self.index += 1;
+        Some(byte)
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ramp() {
+        let mut buffer = [0; 300];
+        fill_ramp(&mut buffer);
+        assert_eq!(&buffer[254..258], &[254, 255, 0, 1]);
+        assert_eq!(verify_ramp(&buffer), None);
+
+        buffer[257] ^= 0x80;
+        assert_eq!(verify_ramp(&buffer), Some(257));
+    }
+
+    #[test]
+    fn test_prbs() {
+        let mut buffer = [0; 64];
+        fill_prbs(&mut buffer, 1);
+        // xorshift32(1) = 0x00042021
+        assert_eq!(&buffer[..4], &[0x21, 0x20, 0x04, 0x00]);
+        assert_eq!(verify_prbs(&buffer, 1), None);
+        assert_eq!(verify_prbs(&buffer, 2), Some(0));
+
+        buffer[33] ^= 0x01;
+        assert_eq!(verify_prbs(&buffer, 1), Some(33));
+
+        // a zero seed does not produce a sequence of zeroes
+        fill_prbs(&mut buffer, 0);
+        assert!(buffer.iter().any(|byte| *byte != 0));
+    }
+}
@@ -2,11 +2,43 @@
 
 use smolusb::descriptor::*;
 
+use crate::{Speed, UsbInterface};
+
 // - constants ----------------------------------------------------------------
 
 pub const DEVICE_VERSION_NUMBER: u16 = 0x0004; // Cynthion r0.4 TODO read from?
 pub const DEVICE_SERIAL_STRING: &'static str = "r0.4"; // TODO read from?
 
+// - ports --------------------------------------------------------------------
+
+/// Describes one of the board's USB PHYs
+#[derive(Copy, Clone, Debug)]
+pub struct PortInfo {
+    /// PHY index, `n` in the `UsbN` HAL peripheral
+    pub index: u8,
+    /// The role the port is intended for
+    pub role: UsbInterface,
+    /// Speeds supported by the PHY, fastest first
+    pub speeds: &'static [Speed],
+}
+
+impl PortInfo {
+    /// Returns true if the PHY supports `speed`.
+    pub fn supports(&self, speed: Speed) -> bool {
+        self.speeds.contains(&speed)
+    }
+}
+
+/// Returns the USB PHYs available on this board.
+pub fn ports() -> &'static [PortInfo] {
+    &crate::USB_PORTS
+}
+
+/// Returns the USB PHY intended for `role`, if the board has one.
+pub fn port(role: UsbInterface) -> Option<&'static PortInfo> {
+    ports().iter().find(|port| port.role == role)
+}
+
 // - vendor request -----------------------------------------------------------
 
 pub mod vendor {
@@ -19,6 +51,13 @@ pub mod vendor {
         //   30:  LIBGREAT_REQUEST_NUMBER = 0x65
         UsbCommandRequest = 0x65, // 101
 
+        /// Read the firmware statistics block, see [`super::statistics`]
+        ReadStatistics = 0x66, // 102
+
+        /// Read the control requests captured by usb1, see
+        /// [`smolusb::control_capture`]
+        ReadControlCapture = 0x67, // 103
+
         // legacy commands - see: host/greatfet/boards/legacy.py
         LegacyReadBoardId = 0x04,
         LegacyReadVersionString = 0x05,
@@ -38,6 +77,8 @@ pub mod vendor {
                 0x16 => VendorRequest::LegacyReset,
                 0x40 => VendorRequest::LegacyReadDmesg,
                 0x65 => VendorRequest::UsbCommandRequest,
+                0x66 => VendorRequest::ReadStatistics,
+                0x67 => VendorRequest::ReadControlCapture,
                 _ => VendorRequest::Unknown(value),
             }
         }
@@ -62,17 +103,88 @@ pub mod vendor {
     }
 }
 
+// - statistics ---------------------------------------------------------------
+
+/// Binary layout of the statistics block returned by
+/// [`vendor::VendorRequest::ReadStatistics`]
+///
+/// All fields are little-endian:
+///
+/// | Offset | Size | Field                                   |
+/// |--------|------|-----------------------------------------|
+/// | 0      | 1    | layout version, currently [`VERSION`]   |
+/// | 1      | 1    | number of endpoints `n`                 |
+/// | 2      | 2    | reserved                                |
+/// | 4      | 4    | event queue: dropped events             |
+/// | 8      | 4    | event queue: high-water mark            |
+/// | 12     | 20n  | per-endpoint statistics, see below      |
+///
+/// Each endpoint entry, in endpoint number order:
+///
+/// | Offset | Size | Field                                   |
+/// |--------|------|-----------------------------------------|
+/// | 0      | 4    | IN packets                              |
+/// | 4      | 4    | OUT packets                             |
+/// | 8      | 4    | OUT bytes                               |
+/// | 12     | 4    | OUT packets exceeding max packet size   |
+/// | 16     | 4    | OUT bytes discarded on overflow         |
+///
+/// A non-zero `wValue` resets all statistics once the block has been
+/// read.
+pub mod statistics {
+    use smolusb::device::EndpointState;
+
+    pub const VERSION: u8 = 2;
+    pub const HEADER_SIZE: usize = 12;
+    pub const ENDPOINT_SIZE: usize = 20;
+
+    /// Returns the size of the statistics block for `endpoint_count` endpoints.
+    pub const fn block_size(endpoint_count: usize) -> usize {
+        HEADER_SIZE + (endpoint_count * ENDPOINT_SIZE)
+    }
+
+    /// Returns an iterator over the statistics block for the given
+    /// endpoint state and event queue statistics.
+    pub fn iter<const MAX_ENDPOINTS: usize>(
+        endpoints: &EndpointState<MAX_ENDPOINTS>,
+        queue_dropped: usize,
+        queue_high_water_mark: usize,
+    ) -> impl Iterator<Item = u8> + '_ {
+        let header = [VERSION, MAX_ENDPOINTS as u8, 0, 0]
+            .into_iter()
+            .chain((queue_dropped as u32).to_le_bytes())
+            .chain((queue_high_water_mark as u32).to_le_bytes());
+
+        let entries = endpoints
+            .stats_in
+            .iter()
+            .zip(endpoints.stats_out.iter())
+            .flat_map(|(stats_in, stats_out)| {
+                stats_in
+                    .packets
+                    .to_le_bytes()
+                    .into_iter()
+                    .chain(stats_out.packets.to_le_bytes())
+                    .chain(stats_out.bytes.to_le_bytes())
+                    .chain(stats_out.babble.to_le_bytes())
+                    .chain(stats_out.overflow.to_le_bytes())
+            });
+
+        header.chain(entries)
+    }
+}
+
 // - descriptors --------------------------------------------------------------
 
 pub static DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
-    descriptor_version: 0x0200,
+    descriptor_version: le16(0x0200),
     device_class: 0x00,    // Composite
     device_subclass: 0x00, // Composite
     device_protocol: 0x00, // Composite
     max_packet_size: 64,
-    vendor_id: cynthion::shared::usb::bVendorId::cynthion,
-    product_id: cynthion::shared::usb::bProductId::cynthion,
-    device_version_number: DEVICE_VERSION_NUMBER,
+    vendor_id: le16(cynthion::shared::usb::bVendorId::cynthion),
+    product_id: le16(cynthion::shared::usb::bProductId::cynthion),
+    device_version_number: le16(DEVICE_VERSION_NUMBER),
     manufacturer_string_index: 1,
     product_string_index: 2,
     serial_string_index: 3,
@@ -81,7 +193,7 @@ pub static DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
 };
 
 pub static DEVICE_QUALIFIER_DESCRIPTOR: DeviceQualifierDescriptor = DeviceQualifierDescriptor {
-    descriptor_version: 0x0200,
+    descriptor_version: le16(0x0200),
     device_class: 0x00,    // Composite
     device_subclass: 0x00, // Composite
     device_protocol: 0x00, // Composite
@@ -95,8 +207,8 @@ pub static CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDe
         descriptor_type: DescriptorType::Configuration as u8,
         configuration_value: 1,
         configuration_string_index: 4,
-        attributes: 0x80, // 0b1000_0000 = bus-powered
-        max_power: 250,   // 250 * 2 mA = 500 mA ?
+        attributes: ConfigurationAttributes::new().bits(),
+        max_power: 250, // 250 * 2 mA = 500 mA ?
         ..ConfigurationDescriptorHeader::new()
     },
     &[InterfaceDescriptor::new(
@@ -112,15 +224,15 @@ pub static CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDe
         &[
             EndpointDescriptor {
                 endpoint_address: cynthion::shared::libgreat::endpoints::bulk_in_address, // IN
-                attributes: 0x02,                                                         // Bulk
-                max_packet_size: 512,
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(512),
                 interval: 0,
                 ..EndpointDescriptor::new()
             },
             EndpointDescriptor {
                 endpoint_address: cynthion::shared::libgreat::endpoints::bulk_out_address, // OUT
-                attributes: 0x02,                                                          // Bulk
-                max_packet_size: 512,
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(512),
                 interval: 0,
                 ..EndpointDescriptor::new()
             },
@@ -134,8 +246,8 @@ pub static OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
             descriptor_type: DescriptorType::OtherSpeedConfiguration as u8,
             configuration_value: 1,
             configuration_string_index: 7,
-            attributes: 0x80, // 0b1000_0000 = bus-powered
-            max_power: 250,   // 250 * 2 mA = 500 mA ?
+            attributes: ConfigurationAttributes::new().bits(),
+            max_power: 250, // 250 * 2 mA = 500 mA ?
             ..ConfigurationDescriptorHeader::new()
         },
         &[InterfaceDescriptor::new(
@@ -151,15 +263,15 @@ pub static OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
             &[
                 EndpointDescriptor {
                     endpoint_address: cynthion::shared::libgreat::endpoints::bulk_in_address, // IN
-                    attributes: 0x02, // Bulk
-                    max_packet_size: 64,
+                    attributes: EndpointAttributes::bulk().bits(),
+                    max_packet_size: le16(64),
                     interval: 0,
                     ..EndpointDescriptor::new()
                 },
                 EndpointDescriptor {
                     endpoint_address: cynthion::shared::libgreat::endpoints::bulk_out_address, // OUT
-                    attributes: 0x02, // Bulk
-                    max_packet_size: 64,
+                    attributes: EndpointAttributes::bulk().bits(),
+                    max_packet_size: le16(64),
                     interval: 0,
                     ..EndpointDescriptor::new()
                 },
@@ -36,13 +36,13 @@ pub fn get_usb_interrupt_event() -> InterruptEvent {
 
     // USB0_EP_OUT UsbReceiveData
     } else if usb0.is_pending(pac::Interrupt::USB0_EP_OUT) {
-        let endpoint = usb0.ep_out.data_ep.read().bits() as u8;
+        let endpoint = usb0.ep_out_endpoint_number();
         usb0.clear_pending(pac::Interrupt::USB0_EP_OUT);
         InterruptEvent::Usb(Target, UsbEvent::ReceivePacket(endpoint))
 
     // USB0_EP_IN UsbTransferComplete
     } else if usb0.is_pending(pac::Interrupt::USB0_EP_IN) {
-        let endpoint = usb0.ep_in.epno.read().bits() as u8;
+        let endpoint = usb0.ep_in_send_complete();
         usb0.clear_pending(pac::Interrupt::USB0_EP_IN);
 
         // TODO something a little bit safer would be nice
@@ -67,13 +67,13 @@ pub fn get_usb_interrupt_event() -> InterruptEvent {
 
     // USB1_EP_OUT UsbReceiveData
     } else if usb1.is_pending(pac::Interrupt::USB1_EP_OUT) {
-        let endpoint = usb1.ep_out.data_ep.read().bits() as u8;
+        let endpoint = usb1.ep_out_endpoint_number();
         usb1.clear_pending(pac::Interrupt::USB1_EP_OUT);
         InterruptEvent::Usb(Aux, UsbEvent::ReceivePacket(endpoint))
 
     // USB1_EP_IN UsbTransferComplete
     } else if usb1.is_pending(pac::Interrupt::USB1_EP_IN) {
-        let endpoint = usb1.ep_in.epno.read().bits() as u8;
+        let endpoint = usb1.ep_in_send_complete();
         usb1.clear_pending(pac::Interrupt::USB1_EP_IN);
 
         // TODO something a little safer would be nice
@@ -98,13 +98,13 @@ pub fn get_usb_interrupt_event() -> InterruptEvent {
 
     // USB2_EP_OUT UsbReceiveData
     } else if usb2.is_pending(pac::Interrupt::USB2_EP_OUT) {
-        let endpoint = usb2.ep_out.data_ep.read().bits() as u8;
+        let endpoint = usb2.ep_out_endpoint_number();
         usb2.clear_pending(pac::Interrupt::USB2_EP_OUT);
         InterruptEvent::Usb(Control, UsbEvent::ReceivePacket(endpoint))
 
     // USB2_EP_IN UsbTransferComplete
     } else if usb2.is_pending(pac::Interrupt::USB2_EP_IN) {
-        let endpoint = usb2.ep_in.epno.read().bits() as u8;
+        let endpoint = usb2.ep_in_send_complete();
         usb2.clear_pending(pac::Interrupt::USB2_EP_IN);
 
         // TODO something a little safer would be nice
@@ -116,6 +116,6 @@ pub fn get_usb_interrupt_event() -> InterruptEvent {
 
     // - unhandled interrupt --
     } else {
-        InterruptEvent::UnhandledInterrupt(pending)
+        InterruptEvent::UnhandledInterrupt(pending.into())
     }
 }
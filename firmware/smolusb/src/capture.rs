@@ -0,0 +1,214 @@
+//! Analyzer capture stream format
+//!
+//! A capture starts with a [`CaptureHeader`] followed by a sequence of
+//! length-delimited packet records:
+//!
+//! ```text
+//! capture header (header_length bytes)
+//! record length (u16, big-endian)
+//! record payload (length bytes)
+//! record length
+//! record payload
+//! ...
+//! ```
+//!
+//! The record format matches the 16 bit length prefix emitted by the
+//! analyzer gateware. Header fields are little-endian.
+//!
+//! If the analyzer's buffer overflows it emits a marker record with the
+//! length prefix [`DROPPED_MARKER`] followed by the number of packets
+//! dropped as a big-endian `u32`.
+//!
+//! Note that the analyzer gateware does not yet emit the capture
+//! header, host tooling should fall back to parsing bare records if
+//! the stream does not start with [`CAPTURE_MAGIC`].
+
+use core::mem::size_of;
+
+use zerocopy::{AsBytes, FromBytes};
+
+use crate::error::{SmolError, SmolResult};
+
+/// Identifies the start of a capture stream
+pub const CAPTURE_MAGIC: [u8; 4] = *b"CYNA";
+
+/// Version of the capture stream format
+///
+/// Increment this whenever the header or record format changes.
+pub const CAPTURE_FORMAT_VERSION: u8 = 2;
+
+/// Size of the length prefix preceding each record
+pub const RECORD_HEADER_SIZE: usize = size_of::<u16>();
+
+/// Length prefix identifying a dropped-packets marker record
+///
+/// Never a valid packet length as packets are at most 1027 bytes.
+pub const DROPPED_MARKER: u16 = 0xffff;
+
+/// Size of a dropped-packets marker record
+pub const DROPPED_RECORD_SIZE: usize = RECORD_HEADER_SIZE + size_of::<u32>();
+
+// - CaptureHeader ------------------------------------------------------------
+
+/// Header emitted at the start of a capture
+#[derive(AsBytes, FromBytes, Clone, Copy)]
+#[repr(C, packed)]
+pub struct CaptureHeader {
+    pub magic: [u8; 4],
+    pub version: u8,
+    /// Number of endpoints the capture device can distinguish
+    pub endpoint_count: u8,
+    /// Total size of the header in bytes, later versions may append fields
+    pub header_length: u16,
+    /// Resolution of record timestamps in nanoseconds, 0 if untimed
+    pub timestamp_resolution_ns: u32,
+}
+
+impl CaptureHeader {
+    pub const fn new(endpoint_count: u8, timestamp_resolution_ns: u32) -> Self {
+        Self {
+            magic: CAPTURE_MAGIC,
+            version: CAPTURE_FORMAT_VERSION,
+            endpoint_count,
+            header_length: size_of::<Self>() as u16,
+            timestamp_resolution_ns,
+        }
+    }
+
+    /// Parse a capture header from the start of a capture stream
+    ///
+    /// Fails if the magic is missing or the stream uses a newer
+    /// format version than this one. Use `header_length` to find the
+    /// first record.
+    pub fn parse(bytes: &[u8]) -> SmolResult<Self> {
+        let header = bytes
+            .get(..size_of::<Self>())
+            .and_then(Self::read_from)
+            .ok_or(SmolError::FailedConversion)?;
+
+        if header.magic != CAPTURE_MAGIC
+            || header.version > CAPTURE_FORMAT_VERSION
+            || (header.header_length as usize) < size_of::<Self>()
+        {
+            return Err(SmolError::FailedConversion);
+        }
+
+        Ok(header)
+    }
+}
+
+// - records ------------------------------------------------------------------
+
+/// Returns the length prefix for a record of `length` bytes
+pub const fn record_header(length: u16) -> [u8; RECORD_HEADER_SIZE] {
+    length.to_be_bytes()
+}
+
+/// Returns a dropped-packets marker record for `count` packets
+pub const fn dropped_record(count: u32) -> [u8; DROPPED_RECORD_SIZE] {
+    let marker = DROPPED_MARKER.to_be_bytes();
+    let count = count.to_be_bytes();
+    [marker[0], marker[1], count[0], count[1], count[2], count[3]]
+}
+
+/// A record in a capture stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Record<'a> {
+    /// A captured packet
+    Packet(&'a [u8]),
+    /// The number of packets dropped because the capture buffer overflowed
+    Dropped(u32),
+}
+
+/// Iterator over the records following the capture header
+///
+/// A truncated record at the end of `bytes` is not returned.
+pub struct Records<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Records<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let prefix = self.bytes.get(..RECORD_HEADER_SIZE)?;
+        let length = u16::from_be_bytes([prefix[0], prefix[1]]);
+
+        if length == DROPPED_MARKER {
+            let count = self.bytes.get(RECORD_HEADER_SIZE..DROPPED_RECORD_SIZE)?;
+            self.bytes = &self.bytes[DROPPED_RECORD_SIZE..];
+            return Some(Record::Dropped(u32::from_be_bytes([
+                count[0], count[1], count[2], count[3],
+            ])));
+        }
+
+        let end = RECORD_HEADER_SIZE + length as usize;
+        let payload = self.bytes.get(RECORD_HEADER_SIZE..end)?;
+        self.bytes = &self.bytes[end..];
+        Some(Record::Packet(payload))
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_header() {
+        let header = CaptureHeader::new(16, 1000 / 60);
+        let bytes = header.as_bytes();
+        assert_eq!(bytes, &[b'C', b'Y', b'N', b'A', 2, 16, 12, 0, 16, 0, 0, 0]);
+
+        let parsed = CaptureHeader::parse(bytes).unwrap();
+        assert_eq!({ parsed.endpoint_count }, 16);
+        assert_eq!({ parsed.timestamp_resolution_ns }, 16);
+
+        let mut newer = header;
+        newer.version = CAPTURE_FORMAT_VERSION + 1;
+        assert!(CaptureHeader::parse(newer.as_bytes()).is_err());
+        assert!(CaptureHeader::parse(&bytes[..8]).is_err());
+        assert!(CaptureHeader::parse(&[0; 12]).is_err());
+    }
+
+    #[test]
+    fn test_records() {
+        // two records followed by a truncated one
+        let stream = [0x00, 0x03, 0x2d, 0x00, 0x10, 0x00, 0x00, 0x00, 0x05, 0xd2];
+
+        let records: std::vec::Vec<Record> = Records::new(&stream).collect();
+
+        assert_eq!(
+            records,
+            [Record::Packet(&[0x2d, 0x00, 0x10]), Record::Packet(&[])]
+        );
+    }
+
+    #[test]
+    fn test_dropped_records() {
+        let mut stream = std::vec::Vec::new();
+        stream.extend_from_slice(&[0x00, 0x01, 0xd2]);
+        stream.extend_from_slice(&dropped_record(258));
+        stream.extend_from_slice(&[0x00, 0x01, 0x5a]);
+        // truncated marker
+        stream.extend_from_slice(&dropped_record(1)[..4]);
+
+        let records: std::vec::Vec<Record> = Records::new(&stream).collect();
+
+        assert_eq!(
+            records,
+            [
+                Record::Packet(&[0xd2]),
+                Record::Dropped(258),
+                Record::Packet(&[0x5a]),
+            ]
+        );
+    }
+}
@@ -1,3 +1,4 @@
 //! USB device and interface classes
 
 pub mod cdc;
+pub mod hid;
@@ -1,5 +1,9 @@
 use crate::descriptor::*;
 
+pub mod acm;
+
+pub use acm::AcmDevice;
+
 pub const VENDOR_ID: u16 = 0x1a86; // QinHeng Electronics
 pub const PRODUCT_ID: u16 = 0x7523; // CH341 in serial mode, usb to serial port converter
 
@@ -34,14 +38,14 @@ pub mod ch34x {
 }
 
 pub const DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
-    descriptor_version: 0x0200,
+    descriptor_version: le16(0x0200),
     device_class: 0xff,    // Vendor-specific
     device_subclass: 0x00, // Vendor-specific
     device_protocol: 0x00,
     max_packet_size: 8,
-    vendor_id: VENDOR_ID,
-    product_id: PRODUCT_ID,
-    device_version_number: 0x0264,
+    vendor_id: le16(VENDOR_ID),
+    product_id: le16(PRODUCT_ID),
+    device_version_number: le16(0x0264),
     manufacturer_string_index: 1,
     product_string_index: 2,
     serial_string_index: 3,
@@ -50,7 +54,7 @@ pub const DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
 };
 
 pub const DEVICE_QUALIFIER_DESCRIPTOR: DeviceQualifierDescriptor = DeviceQualifierDescriptor {
-    descriptor_version: 0x0200,
+    descriptor_version: le16(0x0200),
     device_class: 0xff,
     device_subclass: 0x00,
     device_protocol: 0x00,
@@ -65,8 +69,8 @@ pub const CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDes
         descriptor_type: DescriptorType::Configuration as u8,
         configuration_value: 1,
         configuration_string_index: 1,
-        attributes: 0x80, // 0b1000_0000 = bus-powered
-        max_power: 50,    // 50 * 2 mA = 100 mA
+        attributes: ConfigurationAttributes::new().bits(),
+        max_power: 50, // 50 * 2 mA = 100 mA
         ..ConfigurationDescriptorHeader::new()
     },
     &[InterfaceDescriptor::new(
@@ -82,22 +86,22 @@ pub const CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDes
         &[
             EndpointDescriptor {
                 endpoint_address: 0x82, // IN
-                attributes: 0x02,       // Bulk
-                max_packet_size: 512,   // technically 32
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(512), // technically 32
                 interval: 0,
                 ..EndpointDescriptor::new()
             },
             EndpointDescriptor {
                 endpoint_address: 0x02, // OUT
-                attributes: 0x02,       // Bulk
-                max_packet_size: 512,   // technically 32
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(512), // technically 32
                 interval: 0,
                 ..EndpointDescriptor::new()
             },
             EndpointDescriptor {
                 endpoint_address: 0x81, // IN
-                attributes: 0x03,       // Interrupt
-                max_packet_size: 8,
+                attributes: EndpointAttributes::interrupt().bits(),
+                max_packet_size: le16(8),
                 interval: 1, // 1ms
                 ..EndpointDescriptor::new()
             },
@@ -111,8 +115,8 @@ pub const OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
             descriptor_type: DescriptorType::OtherSpeedConfiguration as u8,
             configuration_value: 1,
             configuration_string_index: 1,
-            attributes: 0x80, // 0b1000_0000 = bus-powered
-            max_power: 50,    // 50 * 2 mA = 100 mA
+            attributes: ConfigurationAttributes::new().bits(),
+            max_power: 50, // 50 * 2 mA = 100 mA
             ..ConfigurationDescriptorHeader::new()
         },
         &[InterfaceDescriptor::new(
@@ -128,22 +132,22 @@ pub const OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
             &[
                 EndpointDescriptor {
                     endpoint_address: 0x82, // IN
-                    attributes: 0x02,       // Bulk
-                    max_packet_size: 64,    // technically 32
+                    attributes: EndpointAttributes::bulk().bits(),
+                    max_packet_size: le16(64), // technically 32
                     interval: 0,
                     ..EndpointDescriptor::new()
                 },
                 EndpointDescriptor {
                     endpoint_address: 0x02, // OUT
-                    attributes: 0x02,       // Bulk
-                    max_packet_size: 64,    // technically 32
+                    attributes: EndpointAttributes::bulk().bits(),
+                    max_packet_size: le16(64), // technically 32
                     interval: 0,
                     ..EndpointDescriptor::new()
                 },
                 EndpointDescriptor {
                     endpoint_address: 0x81, // IN
-                    attributes: 0x03,       // Interrupt
-                    max_packet_size: 8,
+                    attributes: EndpointAttributes::interrupt().bits(),
+                    max_packet_size: le16(8),
                     interval: 1, // 1ms
                     ..EndpointDescriptor::new()
                 },
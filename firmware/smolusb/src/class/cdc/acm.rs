@@ -0,0 +1,488 @@
+//! CDC-ACM composite device
+//!
+//! A standards-compliant CDC Abstract Control Model function consisting
+//! of a communications interface with an interrupt notification endpoint
+//! and a data interface with a pair of bulk endpoints. The two interfaces
+//! are grouped by an interface association descriptor so the device can
+//! be used as part of a composite device.
+
+use log::{debug, trace, warn};
+use zerocopy::{AsBytes, FromBytes};
+
+use crate::consts::endpoint;
+use crate::control::ControlEvent;
+use crate::descriptor::*;
+use crate::error::SmolResult;
+use crate::setup::{Direction, RequestType};
+use crate::traits::{AsByteSliceIterator, UsbDriver};
+
+pub const VENDOR_ID: u16 = 0x1209; // https://pid.codes
+pub const PRODUCT_ID: u16 = 0x0002; // pid.codes test PID
+
+/// Interface number of the communications interface
+pub const COMMUNICATIONS_INTERFACE: u8 = 0;
+/// Interface number of the data interface
+pub const DATA_INTERFACE: u8 = 1;
+
+/// Interrupt IN endpoint used for serial state notifications
+pub const NOTIFICATION_ENDPOINT: u8 = 0x81;
+/// Bulk IN endpoint of the data interface
+pub const DATA_IN_ENDPOINT: u8 = 0x82;
+/// Bulk OUT endpoint of the data interface
+pub const DATA_OUT_ENDPOINT: u8 = 0x02;
+
+// - ClassRequest -------------------------------------------------------------
+
+/// CDC PSTN subclass requests supported by the ACM model
+#[derive(Debug, PartialEq)]
+#[repr(u8)]
+pub enum ClassRequest {
+    SendEncapsulatedCommand = 0x00,
+    GetEncapsulatedResponse = 0x01,
+    SetLineCoding = 0x20,
+    GetLineCoding = 0x21,
+    SetControlLineState = 0x22,
+    SendBreak = 0x23,
+    Unknown,
+}
+
+impl From<u8> for ClassRequest {
+    fn from(value: u8) -> Self {
+        match value {
+            0x00 => ClassRequest::SendEncapsulatedCommand,
+            0x01 => ClassRequest::GetEncapsulatedResponse,
+            0x20 => ClassRequest::SetLineCoding,
+            0x21 => ClassRequest::GetLineCoding,
+            0x22 => ClassRequest::SetControlLineState,
+            0x23 => ClassRequest::SendBreak,
+            _ => ClassRequest::Unknown,
+        }
+    }
+}
+
+// - LineCoding ---------------------------------------------------------------
+
+/// Line coding structure used by SET_LINE_CODING and GET_LINE_CODING
+#[derive(AsBytes, FromBytes, Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct LineCoding {
+    /// Data terminal rate in bits per second
+    pub dte_rate: u32,
+    /// 0 = 1 stop bit, 1 = 1.5 stop bits, 2 = 2 stop bits
+    pub char_format: u8,
+    /// 0 = None, 1 = Odd, 2 = Even, 3 = Mark, 4 = Space
+    pub parity_type: u8,
+    /// 5, 6, 7, 8 or 16
+    pub data_bits: u8,
+}
+
+impl AsByteSliceIterator for LineCoding {}
+
+impl LineCoding {
+    pub const fn new() -> Self {
+        Self {
+            dte_rate: 115_200,
+            char_format: 0,
+            parity_type: 0,
+            data_bits: 8,
+        }
+    }
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// - BreakDuration ------------------------------------------------------------
+
+/// Duration of a break condition requested with SEND_BREAK
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakDuration {
+    /// End any break condition in progress
+    Off,
+    /// Hold the break condition for the given number of milliseconds
+    Millis(u16),
+    /// Hold the break condition until the host sends another SEND_BREAK
+    Indefinite,
+}
+
+impl From<u16> for BreakDuration {
+    /// Decode the `wValue` of a SEND_BREAK request
+    fn from(value: u16) -> Self {
+        match value {
+            0x0000 => BreakDuration::Off,
+            0xffff => BreakDuration::Indefinite,
+            millis => BreakDuration::Millis(millis),
+        }
+    }
+}
+
+// - SerialState --------------------------------------------------------------
+
+/// Bitmap values for the SERIAL_STATE notification
+pub mod serial_state {
+    pub const DCD: u16 = 1 << 0;
+    pub const DSR: u16 = 1 << 1;
+    pub const BREAK: u16 = 1 << 2;
+    pub const RING: u16 = 1 << 3;
+    pub const FRAMING_ERROR: u16 = 1 << 4;
+    pub const PARITY_ERROR: u16 = 1 << 5;
+    pub const OVERRUN: u16 = 1 << 6;
+}
+
+const NOTIFICATION_SERIAL_STATE: u8 = 0x20;
+
+// - AcmDevice ----------------------------------------------------------------
+
+/// Class request handler and state for a CDC-ACM function
+pub struct AcmDevice {
+    interface_number: u8,
+    notification_endpoint: u8,
+    line_coding: LineCoding,
+    control_line_state: u16,
+    serial_state: u16,
+
+    /// Called when the host requests a break condition, e.g. to assert
+    /// a break on a UART bridge's TX line
+    pub cb_send_break: Option<fn(duration: BreakDuration)>,
+}
+
+impl AcmDevice {
+    pub const fn new(interface_number: u8, notification_endpoint: u8) -> Self {
+        Self {
+            interface_number,
+            notification_endpoint,
+            line_coding: LineCoding::new(),
+            control_line_state: 0,
+            serial_state: 0,
+            cb_send_break: None,
+        }
+    }
+
+    /// Current line coding as last set by the host
+    pub fn line_coding(&self) -> LineCoding {
+        self.line_coding
+    }
+
+    /// Data Terminal Ready as last set by the host
+    pub fn dtr(&self) -> bool {
+        self.control_line_state & 0b01 != 0
+    }
+
+    /// Request To Send as last set by the host
+    pub fn rts(&self) -> bool {
+        self.control_line_state & 0b10 != 0
+    }
+
+    /// Last serial state sent to the host
+    pub fn serial_state(&self) -> u16 {
+        self.serial_state
+    }
+
+    /// Handles CDC class requests addressed to our communications interface
+    ///
+    /// Returns the control event if it was not for us.
+    pub fn handle_control_event<'a, D, const MAX_RECEIVE_SIZE: usize>(
+        &mut self,
+        driver: &D,
+        control_event: ControlEvent<'a, MAX_RECEIVE_SIZE>,
+    ) -> Option<ControlEvent<'a, MAX_RECEIVE_SIZE>>
+    where
+        D: UsbDriver,
+    {
+        let setup_packet = control_event.setup_packet;
+
+        if setup_packet.request_type() != RequestType::Class
+            || setup_packet.index as u8 != self.interface_number
+        {
+            return Some(control_event);
+        }
+
+        let request = ClassRequest::from(setup_packet.request);
+        debug!("  CDC-ACM class_request: {:?}", request);
+
+        match (request, setup_packet.direction()) {
+            (ClassRequest::GetLineCoding, Direction::DeviceToHost) => {
+                let length = core::cmp::min(
+                    setup_packet.length as usize,
+                    core::mem::size_of::<LineCoding>(),
+                );
+                match driver.write(0, self.line_coding.as_iter().copied().take(length)) {
+                    Ok(_) => driver.ack_status_stage(&setup_packet),
+                    Err(_) => driver.stall_control_request(),
+                }
+            }
+            (ClassRequest::SetLineCoding, Direction::HostToDevice) => {
+                let data = &control_event.data[..control_event.bytes_read];
+                match LineCoding::read_from(data) {
+                    Some(line_coding) => {
+                        self.line_coding = line_coding;
+                        trace!("  CDC-ACM line coding: {:?}", line_coding);
+                        driver.ack_status_stage(&setup_packet);
+                    }
+                    None => {
+                        warn!("CDC-ACM stall: invalid line coding: {:?}", data);
+                        driver.stall_control_request();
+                    }
+                }
+            }
+            (ClassRequest::SetControlLineState, Direction::HostToDevice) => {
+                self.control_line_state = setup_packet.value;
+                trace!("  CDC-ACM dtr:{} rts:{}", self.dtr(), self.rts());
+                driver.ack_status_stage(&setup_packet);
+            }
+            (ClassRequest::SendBreak, Direction::HostToDevice) => {
+                let duration = BreakDuration::from(setup_packet.value);
+                trace!("  CDC-ACM send break: {:?}", duration);
+                if let Some(cb) = self.cb_send_break {
+                    cb(duration);
+                }
+                driver.ack_status_stage(&setup_packet);
+            }
+            (request, direction) => {
+                warn!(
+                    "CDC-ACM stall: unsupported class request: {:?} {:?}",
+                    request, direction
+                );
+                driver.stall_control_request();
+            }
+        }
+
+        None
+    }
+
+    /// Sends a SERIAL_STATE notification to the host
+    ///
+    /// See the `serial_state` module for bitmap values. Returns the
+    /// driver's error if the notification could not be queued.
+    pub fn set_serial_state<D>(&mut self, driver: &D, serial_state: u16) -> SmolResult<usize>
+    where
+        D: UsbDriver,
+    {
+        self.serial_state = serial_state;
+
+        let interface_number = self.interface_number as u16;
+        let notification = [
+            0xa1, // bmRequestType: Class, Interface, DeviceToHost
+            NOTIFICATION_SERIAL_STATE,
+            0, // wValue
+            0,
+            interface_number as u8, // wIndex
+            (interface_number >> 8) as u8,
+            2, // wLength
+            0,
+            serial_state as u8, // data
+            (serial_state >> 8) as u8,
+        ];
+
+        driver.write(
+            self.notification_endpoint & endpoint::NUMBER_MASK,
+            notification.into_iter(),
+        )
+    }
+}
+
+// - descriptors --------------------------------------------------------------
+
+/// CDC functional descriptors for the communications interface
+#[rustfmt::skip]
+pub const FUNCTIONAL_DESCRIPTORS: &[u8] = &[
+    // Header: CDC 1.10
+    0x05, 0x24, 0x00, 0x10, 0x01,
+    // Call Management: no call management, data interface 1
+    0x05, 0x24, 0x01, 0x00, DATA_INTERFACE,
+    // Abstract Control Management: supports line coding, control line state and send break
+    0x04, 0x24, 0x02, 0x06,
+    // Union: communications interface 0, data interface 1
+    0x05, 0x24, 0x06, COMMUNICATIONS_INTERFACE, DATA_INTERFACE,
+];
+
+pub const INTERFACE_ASSOCIATION_DESCRIPTOR: InterfaceAssociationDescriptor =
+    InterfaceAssociationDescriptor {
+        first_interface: COMMUNICATIONS_INTERFACE,
+        interface_count: 2,
+        function_class: 0x02,    // Communications
+        function_subclass: 0x02, // Abstract Control Model
+        function_protocol: 0x00,
+        function_string_index: 2,
+        ..InterfaceAssociationDescriptor::new()
+    };
+
+pub const DEVICE_DESCRIPTOR: DeviceDescriptor = DeviceDescriptor {
+    descriptor_version: le16(0x0200),
+    device_class: 0xef,    // Miscellaneous
+    device_subclass: 0x02, // Common Class
+    device_protocol: 0x01, // Interface Association Descriptor
+    max_packet_size: 64,
+    vendor_id: le16(VENDOR_ID),
+    product_id: le16(PRODUCT_ID),
+    device_version_number: le16(0x0100),
+    manufacturer_string_index: 1,
+    product_string_index: 2,
+    serial_string_index: 3,
+    num_configurations: 1,
+    ..DeviceDescriptor::new()
+};
+
+pub const DEVICE_QUALIFIER_DESCRIPTOR: DeviceQualifierDescriptor = DeviceQualifierDescriptor {
+    descriptor_version: le16(0x0200),
+    device_class: 0xef,
+    device_subclass: 0x02,
+    device_protocol: 0x01,
+    max_packet_size: 64,
+    num_configurations: 1,
+    reserved: 0,
+    ..DeviceQualifierDescriptor::new()
+};
+
+pub const CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor = ConfigurationDescriptor::new(
+    ConfigurationDescriptorHeader {
+        descriptor_type: DescriptorType::Configuration as u8,
+        configuration_value: 1,
+        configuration_string_index: 1,
+        attributes: ConfigurationAttributes::new().bits(),
+        max_power: 50, // 50 * 2 mA = 100 mA
+        ..ConfigurationDescriptorHeader::new()
+    },
+    &[
+        InterfaceDescriptor::new(
+            InterfaceDescriptorHeader {
+                interface_number: COMMUNICATIONS_INTERFACE,
+                alternate_setting: 0,
+                interface_class: 0x02,    // Communications
+                interface_subclass: 0x02, // Abstract Control Model
+                interface_protocol: 0x00,
+                interface_string_index: 2,
+                ..InterfaceDescriptorHeader::new()
+            },
+            &[EndpointDescriptor {
+                endpoint_address: NOTIFICATION_ENDPOINT, // IN
+                attributes: EndpointAttributes::interrupt().bits(),
+                max_packet_size: le16(16),
+                interval: 8, // 2^(8-1) * 125us = 16ms
+                ..EndpointDescriptor::new()
+            }],
+        )
+        .with_association(INTERFACE_ASSOCIATION_DESCRIPTOR)
+        .with_class_descriptors(FUNCTIONAL_DESCRIPTORS),
+        InterfaceDescriptor::new(
+            InterfaceDescriptorHeader {
+                interface_number: DATA_INTERFACE,
+                alternate_setting: 0,
+                interface_class: 0x0a, // CDC Data
+                interface_subclass: 0x00,
+                interface_protocol: 0x00,
+                interface_string_index: 2,
+                ..InterfaceDescriptorHeader::new()
+            },
+            &[
+                EndpointDescriptor {
+                    endpoint_address: DATA_IN_ENDPOINT, // IN
+                    attributes: EndpointAttributes::bulk().bits(),
+                    max_packet_size: le16(512),
+                    interval: 0,
+                    ..EndpointDescriptor::new()
+                },
+                EndpointDescriptor {
+                    endpoint_address: DATA_OUT_ENDPOINT, // OUT
+                    attributes: EndpointAttributes::bulk().bits(),
+                    max_packet_size: le16(512),
+                    interval: 0,
+                    ..EndpointDescriptor::new()
+                },
+            ],
+        ),
+    ],
+);
+
+pub const OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0: ConfigurationDescriptor =
+    ConfigurationDescriptor::new(
+        ConfigurationDescriptorHeader {
+            descriptor_type: DescriptorType::OtherSpeedConfiguration as u8,
+            configuration_value: 1,
+            configuration_string_index: 1,
+            attributes: ConfigurationAttributes::new().bits(),
+            max_power: 50, // 50 * 2 mA = 100 mA
+            ..ConfigurationDescriptorHeader::new()
+        },
+        &[
+            InterfaceDescriptor::new(
+                InterfaceDescriptorHeader {
+                    interface_number: COMMUNICATIONS_INTERFACE,
+                    alternate_setting: 0,
+                    interface_class: 0x02,    // Communications
+                    interface_subclass: 0x02, // Abstract Control Model
+                    interface_protocol: 0x00,
+                    interface_string_index: 2,
+                    ..InterfaceDescriptorHeader::new()
+                },
+                &[EndpointDescriptor {
+                    endpoint_address: NOTIFICATION_ENDPOINT, // IN
+                    attributes: EndpointAttributes::interrupt().bits(),
+                    max_packet_size: le16(16),
+                    interval: 16, // 16ms
+                    ..EndpointDescriptor::new()
+                }],
+            )
+            .with_association(INTERFACE_ASSOCIATION_DESCRIPTOR)
+            .with_class_descriptors(FUNCTIONAL_DESCRIPTORS),
+            InterfaceDescriptor::new(
+                InterfaceDescriptorHeader {
+                    interface_number: DATA_INTERFACE,
+                    alternate_setting: 0,
+                    interface_class: 0x0a, // CDC Data
+                    interface_subclass: 0x00,
+                    interface_protocol: 0x00,
+                    interface_string_index: 2,
+                    ..InterfaceDescriptorHeader::new()
+                },
+                &[
+                    EndpointDescriptor {
+                        endpoint_address: DATA_IN_ENDPOINT, // IN
+                        attributes: EndpointAttributes::bulk().bits(),
+                        max_packet_size: le16(64),
+                        interval: 0,
+                        ..EndpointDescriptor::new()
+                    },
+                    EndpointDescriptor {
+                        endpoint_address: DATA_OUT_ENDPOINT, // OUT
+                        attributes: EndpointAttributes::bulk().bits(),
+                        max_packet_size: le16(64),
+                        interval: 0,
+                        ..EndpointDescriptor::new()
+                    },
+                ],
+            ),
+        ],
+    );
+
+pub const USB_STRING_DESCRIPTOR_0: StringDescriptorZero =
+    StringDescriptorZero::new(&[LanguageId::EnglishUnitedStates]);
+
+pub const USB_STRING_DESCRIPTOR_1: StringDescriptor = StringDescriptor::new("Great Scott Gadgets");
+pub const USB_STRING_DESCRIPTOR_2: StringDescriptor = StringDescriptor::new("CDC-ACM Serial");
+pub const USB_STRING_DESCRIPTOR_3: StringDescriptor = StringDescriptor::new("100");
+
+pub const USB_STRING_DESCRIPTORS: &[&StringDescriptor] = &[
+    &USB_STRING_DESCRIPTOR_1,
+    &USB_STRING_DESCRIPTOR_2,
+    &USB_STRING_DESCRIPTOR_3,
+];
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_break_duration() {
+        assert_eq!(BreakDuration::from(0x0000), BreakDuration::Off);
+        assert_eq!(BreakDuration::from(0x0001), BreakDuration::Millis(1));
+        assert_eq!(BreakDuration::from(0x01f4), BreakDuration::Millis(500));
+        assert_eq!(BreakDuration::from(0xfffe), BreakDuration::Millis(0xfffe));
+        assert_eq!(BreakDuration::from(0xffff), BreakDuration::Indefinite);
+    }
+}
@@ -0,0 +1,351 @@
+//! HID interface
+//!
+//! A Human Interface Device class interface with an interrupt IN
+//! endpoint for input reports. Output reports are received via
+//! SET_REPORT on the control endpoint. The interface needs no interface
+//! association descriptor so it can be added to a composite device
+//! alongside other functions.
+
+use log::{debug, trace, warn};
+
+use crate::consts::endpoint;
+use crate::control::{self, ControlEvent};
+use crate::error::SmolResult;
+use crate::setup::{Direction, Recipient, Request, RequestType};
+use crate::traits::UsbDriver;
+
+/// HID class descriptor type
+pub const DESCRIPTOR_TYPE_HID: u8 = 0x21;
+/// Report descriptor type
+pub const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+
+/// Largest input report kept for GET_REPORT, the maximum packet size
+/// of a full speed interrupt endpoint
+pub const MAX_REPORT_SIZE: usize = 64;
+
+// - ClassRequest -------------------------------------------------------------
+
+/// HID class requests
+#[derive(Debug, PartialEq)]
+#[repr(u8)]
+pub enum ClassRequest {
+    GetReport = 0x01,
+    GetIdle = 0x02,
+    GetProtocol = 0x03,
+    SetReport = 0x09,
+    SetIdle = 0x0a,
+    SetProtocol = 0x0b,
+    Unknown,
+}
+
+impl From<u8> for ClassRequest {
+    fn from(value: u8) -> Self {
+        match value {
+            0x01 => ClassRequest::GetReport,
+            0x02 => ClassRequest::GetIdle,
+            0x03 => ClassRequest::GetProtocol,
+            0x09 => ClassRequest::SetReport,
+            0x0a => ClassRequest::SetIdle,
+            0x0b => ClassRequest::SetProtocol,
+            _ => ClassRequest::Unknown,
+        }
+    }
+}
+
+// - descriptors --------------------------------------------------------------
+
+/// Returns the HID class descriptor for a report descriptor of
+/// `report_descriptor_length` bytes
+///
+/// The class descriptor follows the interface descriptor, see
+/// [`InterfaceDescriptor::with_class_descriptors`](crate::descriptor::InterfaceDescriptor::with_class_descriptors).
+pub const fn class_descriptor(report_descriptor_length: u16) -> [u8; 9] {
+    let [length_lo, length_hi] = report_descriptor_length.to_le_bytes();
+    [
+        9,                   // bLength
+        DESCRIPTOR_TYPE_HID, // bDescriptorType
+        0x11,                // bcdHID: 1.11
+        0x01,
+        0x00, // bCountryCode: not localized
+        0x01, // bNumDescriptors
+        DESCRIPTOR_TYPE_REPORT,
+        length_lo, // wDescriptorLength
+        length_hi,
+    ]
+}
+
+/// Report descriptor of a vendor-defined interface exchanging 8 byte
+/// input and output reports
+#[rustfmt::skip]
+pub const VENDOR_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xff, // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01,       // Usage (0x01)
+    0xa1, 0x01,       // Collection (Application)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x08,       //   Report Count (8)
+    0x09, 0x01,       //   Usage (0x01)
+    0x81, 0x02,       //   Input (Data,Var,Abs)
+    0x09, 0x01,       //   Usage (0x01)
+    0x91, 0x02,       //   Output (Data,Var,Abs)
+    0xc0,             // End Collection
+];
+
+// - HidDevice ----------------------------------------------------------------
+
+/// Class request handler and state for a HID interface
+pub struct HidDevice<'a> {
+    interface_number: u8,
+    in_endpoint: u8,
+    report_descriptor: &'a [u8],
+    class_descriptor: [u8; 9],
+    idle_rate: u8,
+    protocol: u8,
+    input_report: [u8; MAX_REPORT_SIZE],
+    input_report_length: usize,
+    control_max_packet_size: usize,
+
+    /// Called with the report type, report id and data of a SET_REPORT
+    /// request, e.g. an output report
+    pub cb_set_report: Option<fn(report_type: u8, report_id: u8, data: &[u8])>,
+}
+
+impl<'a> HidDevice<'a> {
+    pub const fn new(interface_number: u8, in_endpoint: u8, report_descriptor: &'a [u8]) -> Self {
+        Self {
+            interface_number,
+            in_endpoint,
+            report_descriptor,
+            class_descriptor: class_descriptor(report_descriptor.len() as u16),
+            idle_rate: 0,
+            protocol: 1, // report protocol
+            input_report: [0; MAX_REPORT_SIZE],
+            input_report_length: 0,
+            control_max_packet_size: control::DEFAULT_MAX_PACKET_SIZE,
+            cb_set_report: None,
+        }
+    }
+
+    /// Idle rate as last set by the host, in units of 4 ms
+    pub fn idle_rate(&self) -> u8 {
+        self.idle_rate
+    }
+
+    /// 0 for the boot protocol, 1 for the report protocol
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    /// Set the control endpoint's max packet size, see
+    /// [`UsbDevice::ep0_max_packet_size`](crate::device::UsbDevice::ep0_max_packet_size)
+    ///
+    /// Descriptor and report responses are split into packets of this
+    /// size. Defaults to 64 bytes.
+    pub fn set_control_max_packet_size(&mut self, max_packet_size: u16) {
+        self.control_max_packet_size = max_packet_size.into();
+    }
+
+    /// Handles HID descriptor and class requests addressed to our interface
+    ///
+    /// Returns the control event if it was not for us.
+    pub fn handle_control_event<'b, D, const MAX_RECEIVE_SIZE: usize>(
+        &mut self,
+        driver: &D,
+        control_event: ControlEvent<'b, MAX_RECEIVE_SIZE>,
+    ) -> Option<ControlEvent<'b, MAX_RECEIVE_SIZE>>
+    where
+        D: UsbDriver,
+    {
+        let setup_packet = control_event.setup_packet;
+
+        if setup_packet.recipient() != Recipient::Interface
+            || setup_packet.index as u8 != self.interface_number
+        {
+            return Some(control_event);
+        }
+
+        let length = setup_packet.length as usize;
+
+        match (setup_packet.request_type(), setup_packet.request()) {
+            (RequestType::Standard, Request::GetDescriptor) => {
+                let [_, descriptor_type] = setup_packet.value.to_le_bytes();
+                let descriptor: &[u8] = match descriptor_type {
+                    DESCRIPTOR_TYPE_HID => &self.class_descriptor,
+                    DESCRIPTOR_TYPE_REPORT => self.report_descriptor,
+                    _ => return Some(control_event),
+                };
+                debug!("  HID get descriptor: 0x{:x}", descriptor_type);
+                match control::write_data_stage(
+                    driver,
+                    descriptor.iter().copied(),
+                    length,
+                    self.control_max_packet_size,
+                ) {
+                    Ok(_) => driver.ack_status_stage(&setup_packet),
+                    Err(_) => driver.stall_control_request(),
+                }
+                return None;
+            }
+            (RequestType::Class, _) => (),
+            _ => return Some(control_event),
+        }
+
+        let request = ClassRequest::from(setup_packet.request);
+        debug!("  HID class_request: {:?}", request);
+
+        match (request, setup_packet.direction()) {
+            (ClassRequest::GetReport, Direction::DeviceToHost) => {
+                let report = &self.input_report[..self.input_report_length];
+                match control::write_data_stage(
+                    driver,
+                    report.iter().copied(),
+                    length,
+                    self.control_max_packet_size,
+                ) {
+                    Ok(_) => driver.ack_status_stage(&setup_packet),
+                    Err(_) => driver.stall_control_request(),
+                }
+            }
+            (ClassRequest::SetReport, Direction::HostToDevice) => {
+                let [report_id, report_type] = setup_packet.value.to_le_bytes();
+                let data = &control_event.data[..control_event.bytes_read];
+                trace!("  HID set report: {} {} {:?}", report_type, report_id, data);
+                if let Some(cb) = self.cb_set_report {
+                    cb(report_type, report_id, data);
+                }
+                driver.ack_status_stage(&setup_packet);
+            }
+            (ClassRequest::GetIdle, Direction::DeviceToHost) => {
+                match driver.write(0, [self.idle_rate].into_iter().take(length)) {
+                    Ok(_) => driver.ack_status_stage(&setup_packet),
+                    Err(_) => driver.stall_control_request(),
+                }
+            }
+            (ClassRequest::SetIdle, Direction::HostToDevice) => {
+                self.idle_rate = (setup_packet.value >> 8) as u8;
+                driver.ack_status_stage(&setup_packet);
+            }
+            (ClassRequest::GetProtocol, Direction::DeviceToHost) => {
+                match driver.write(0, [self.protocol].into_iter().take(length)) {
+                    Ok(_) => driver.ack_status_stage(&setup_packet),
+                    Err(_) => driver.stall_control_request(),
+                }
+            }
+            (ClassRequest::SetProtocol, Direction::HostToDevice) => {
+                self.protocol = setup_packet.value as u8;
+                driver.ack_status_stage(&setup_packet);
+            }
+            (request, direction) => {
+                warn!(
+                    "HID stall: unsupported class request: {:?} {:?}",
+                    request, direction
+                );
+                driver.stall_control_request();
+            }
+        }
+
+        None
+    }
+
+    /// Sends an input report on the interrupt IN endpoint
+    ///
+    /// The report is also returned by subsequent GET_REPORT requests.
+    /// Reports longer than [`MAX_REPORT_SIZE`] are truncated.
+    ///
+    /// Returns the driver's error if the report could not be queued.
+    pub fn send_report<D>(&mut self, driver: &D, report: &[u8]) -> SmolResult<usize>
+    where
+        D: UsbDriver,
+    {
+        let length = report.len().min(MAX_REPORT_SIZE);
+        self.input_report[..length].copy_from_slice(&report[..length]);
+        self.input_report_length = length;
+
+        driver.write(
+            self.in_endpoint & endpoint::NUMBER_MASK,
+            report[..length].iter().copied(),
+        )
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mock::MockDriver;
+    use crate::setup::SetupPacket;
+
+    fn control_event(setup_packet: [u8; 8], data: &[u8]) -> ControlEvent<'static, 8> {
+        let mut event = ControlEvent {
+            endpoint_number: 0,
+            setup_packet: SetupPacket::try_from(setup_packet).unwrap(),
+            data: [0; 8],
+            bytes_read: data.len(),
+            _marker: core::marker::PhantomData,
+        };
+        event.data[..data.len()].copy_from_slice(data);
+        event
+    }
+
+    #[test]
+    fn test_class_descriptor() {
+        assert_eq!(
+            class_descriptor(VENDOR_REPORT_DESCRIPTOR.len() as u16),
+            [0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 25, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_get_report_descriptor() {
+        let driver = MockDriver::new();
+        let mut hid = HidDevice::new(2, 0x83, VENDOR_REPORT_DESCRIPTOR);
+
+        // requests for other interfaces are returned
+        let event = control_event([0x81, 0x06, 0x00, 0x22, 0x01, 0x00, 0xff, 0x00], &[]);
+        assert!(hid.handle_control_event(&driver, event).is_some());
+
+        let event = control_event([0x81, 0x06, 0x00, 0x22, 0x02, 0x00, 0xff, 0x00], &[]);
+        assert!(hid.handle_control_event(&driver, event).is_none());
+        assert_eq!(
+            driver.writes(),
+            vec![(0, VENDOR_REPORT_DESCRIPTOR.to_vec())]
+        );
+
+        // descriptors longer than the control endpoint's packet size
+        // are split into packets
+        let driver = MockDriver::new();
+        hid.set_control_max_packet_size(8);
+        let event = control_event([0x81, 0x06, 0x00, 0x22, 0x02, 0x00, 0xff, 0x00], &[]);
+        assert!(hid.handle_control_event(&driver, event).is_none());
+        let packets: Vec<usize> = driver.writes().iter().map(|(_, data)| data.len()).collect();
+        assert_eq!(packets, [8, 8, 8, 1]);
+    }
+
+    #[test]
+    fn test_class_requests() {
+        let driver = MockDriver::new();
+        let mut hid = HidDevice::new(2, 0x83, VENDOR_REPORT_DESCRIPTOR);
+
+        // SET_IDLE(duration 125, all reports)
+        let event = control_event([0x21, 0x0a, 0x00, 0x7d, 0x02, 0x00, 0x00, 0x00], &[]);
+        assert!(hid.handle_control_event(&driver, event).is_none());
+        assert_eq!(hid.idle_rate(), 0x7d);
+
+        // GET_REPORT returns the last input report
+        hid.send_report(&driver, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        let event = control_event([0xa1, 0x01, 0x00, 0x01, 0x02, 0x00, 0x04, 0x00], &[]);
+        assert!(hid.handle_control_event(&driver, event).is_none());
+        assert_eq!(
+            driver.writes()[1..],
+            [(3, vec![1, 2, 3, 4, 5, 6, 7, 8]), (0, vec![1, 2, 3, 4]),]
+        );
+
+        // unsupported requests are stalled
+        let event = control_event([0xa1, 0x07, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00], &[]);
+        assert!(hid.handle_control_event(&driver, event).is_none());
+        assert_eq!(driver.stalled(), vec![0x80, 0x00]);
+    }
+}
@@ -0,0 +1,123 @@
+//! Standard USB constants
+//!
+//! Numeric values from Chapter 9 of the USB 2.0 specification.
+
+/// Bitfields of the `SetupPacket` `request_type` field
+pub mod request_type {
+    /// Bit 7: data stage direction
+    pub const DIRECTION_MASK: u8 = 0b1000_0000;
+    pub const DIRECTION_OUT: u8 = 0b0000_0000;
+    pub const DIRECTION_IN: u8 = 0b1000_0000;
+
+    /// Bits 5..=6: request type
+    pub const TYPE_MASK: u8 = 0b0110_0000;
+    pub const TYPE_SHIFT: u8 = 5;
+    pub const TYPE_STANDARD: u8 = 0;
+    pub const TYPE_CLASS: u8 = 1;
+    pub const TYPE_VENDOR: u8 = 2;
+    pub const TYPE_RESERVED: u8 = 3;
+
+    /// Bits 0..=4: recipient
+    pub const RECIPIENT_MASK: u8 = 0b0001_1111;
+    pub const RECIPIENT_DEVICE: u8 = 0;
+    pub const RECIPIENT_INTERFACE: u8 = 1;
+    pub const RECIPIENT_ENDPOINT: u8 = 2;
+    pub const RECIPIENT_OTHER: u8 = 3;
+}
+
+/// Standard request numbers
+pub mod request {
+    pub const GET_STATUS: u8 = 0;
+    pub const CLEAR_FEATURE: u8 = 1;
+    pub const SET_FEATURE: u8 = 3;
+    pub const SET_ADDRESS: u8 = 5;
+    pub const GET_DESCRIPTOR: u8 = 6;
+    pub const SET_DESCRIPTOR: u8 = 7;
+    pub const GET_CONFIGURATION: u8 = 8;
+    pub const SET_CONFIGURATION: u8 = 9;
+    pub const GET_INTERFACE: u8 = 10;
+    pub const SET_INTERFACE: u8 = 11;
+    pub const SYNCH_FRAME: u8 = 12;
+}
+
+/// Standard descriptor types
+pub mod descriptor_type {
+    pub const DEVICE: u8 = 1;
+    pub const CONFIGURATION: u8 = 2;
+    pub const STRING: u8 = 3;
+    pub const INTERFACE: u8 = 4;
+    pub const ENDPOINT: u8 = 5;
+    pub const DEVICE_QUALIFIER: u8 = 6;
+    pub const OTHER_SPEED_CONFIGURATION: u8 = 7;
+    pub const INTERFACE_POWER: u8 = 8;
+    pub const OTG: u8 = 9;
+    pub const DEBUG: u8 = 10;
+    pub const INTERFACE_ASSOCIATION: u8 = 11;
+    pub const SECURITY: u8 = 12;
+    pub const KEY: u8 = 13;
+    pub const ENCRYPTION_TYPE: u8 = 14;
+    pub const BOS: u8 = 15;
+    pub const DEVICE_CAPABILITY: u8 = 16;
+    pub const WIRELESS_ENDPOINT_COMPANION: u8 = 17;
+    pub const SUPERSPEED_ENDPOINT_COMPANION: u8 = 48;
+}
+
+/// Standard feature selectors
+pub mod feature {
+    pub const ENDPOINT_HALT: u16 = 0;
+    pub const DEVICE_REMOTE_WAKEUP: u16 = 1;
+    pub const TEST_MODE: u16 = 2;
+}
+
+/// Endpoint address bitfields
+pub mod endpoint {
+    pub const NUMBER_MASK: u8 = 0b0000_1111;
+    pub const DIRECTION_MASK: u8 = 0b1000_0000;
+    pub const DIRECTION_OUT: u8 = 0b0000_0000;
+    pub const DIRECTION_IN: u8 = 0b1000_0000;
+}
+
+/// Endpoint descriptor `attributes` transfer types (bits 0..=1)
+pub mod transfer_type {
+    pub const MASK: u8 = 0b0000_0011;
+    pub const CONTROL: u8 = 0b00;
+    pub const ISOCHRONOUS: u8 = 0b01;
+    pub const BULK: u8 = 0b10;
+    pub const INTERRUPT: u8 = 0b11;
+}
+
+/// Configuration descriptor `attributes` bitfields
+pub mod configuration_attributes {
+    /// Bit 7 is reserved and must always be set
+    pub const RESERVED_ONE: u8 = 0b1000_0000;
+    pub const SELF_POWERED: u8 = 0b0100_0000;
+    pub const REMOTE_WAKEUP: u8 = 0b0010_0000;
+
+    pub const BUS_POWERED: u8 = RESERVED_ONE;
+}
+
+/// Device capability types of BOS device capability descriptors
+pub mod device_capability {
+    pub const USB_2_0_EXTENSION: u8 = 2;
+    pub const SUPERSPEED_USB: u8 = 3;
+}
+
+/// USB 2.0 extension capability `attributes` bitfields
+pub mod usb20_extension_attributes {
+    /// Link power management
+    pub const LPM: u32 = 1 << 1;
+    /// Best effort service latency
+    pub const BESL: u32 = 1 << 2;
+}
+
+/// SuperSpeed USB device capability bitfields
+pub mod superspeed_usb_attributes {
+    /// `attributes`: latency tolerance messages
+    pub const LTM_CAPABLE: u8 = 1 << 1;
+
+    /// `speeds_supported`
+    pub const SPEED_LOW: u16 = 1 << 0;
+    pub const SPEED_FULL: u16 = 1 << 1;
+    pub const SPEED_HIGH: u16 = 1 << 2;
+    pub const SPEED_SUPER: u16 = 1 << 3;
+}
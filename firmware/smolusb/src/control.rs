@@ -1,33 +1,170 @@
 #![allow(dead_code, unused_imports, unused_variables)] // TODO
 
 ///! USB control interface
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
 
 use crate::error::{SmolError, SmolResult};
 use crate::event::UsbEvent;
 use crate::setup::{Direction, SetupPacket};
-use crate::traits::UsbDriver;
+use crate::traits::{UsbDriver, WriteEndpoint};
 
-/// Represents USB control transfer state.
-#[derive(Debug)]
-pub enum State {
+/// Default maximum length of an accepted control OUT data stage.
+pub const DEFAULT_MAX_TRANSFER_SIZE: usize = 4096;
+
+/// Default maximum packet size of the control endpoint.
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 64;
+
+/// Default number of frames the host may take between control OUT
+/// data stage packets before the transfer is abandoned.
+pub const DEFAULT_DATA_STAGE_TIMEOUT_FRAMES: u16 = 500;
+
+// - ControlTransfer ----------------------------------------------------------
+
+/// Stage of a [`ControlTransfer`]
+///
+/// Directions are from the host's point of view so `DataIn` and
+/// `StatusIn` move data from the device to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferStage {
     Idle,
+    DataIn,
+    DataOut,
+    StatusIn,
+    StatusOut,
+}
+
+/// Tracks the stages of a single control transfer
+///
+/// The state machine does not touch the controller so the same logic
+/// drives both the device and the host role:
+///
+/// ```text
+/// setup -+-> DataIn --> StatusOut -+-> Idle
+///        +-> DataOut -> StatusIn --+
+///        +-----------> StatusIn --+
+/// ```
+///
+/// A SETUP packet always starts a new transfer, abandoning any transfer
+/// in progress.
+#[derive(Debug, Clone, Copy)]
+pub struct ControlTransfer {
+    stage: TransferStage,
+    setup_packet: SetupPacket,
+    bytes_transferred: usize,
+}
+
+impl ControlTransfer {
+    pub const fn new() -> Self {
+        Self {
+            stage: TransferStage::Idle,
+            setup_packet: SetupPacket {
+                request_type: 0,
+                request: 0,
+                value: 0,
+                index: 0,
+                length: 0,
+            },
+            bytes_transferred: 0,
+        }
+    }
+
+    pub fn stage(&self) -> TransferStage {
+        self.stage
+    }
+
+    /// Returns true if no transfer is in progress.
+    pub fn is_idle(&self) -> bool {
+        self.stage == TransferStage::Idle
+    }
+
+    /// Returns the setup packet of the current or last transfer.
+    pub fn setup_packet(&self) -> &SetupPacket {
+        &self.setup_packet
+    }
+
+    /// Returns the number of bytes transferred during the data stage.
+    pub fn bytes_transferred(&self) -> usize {
+        self.bytes_transferred
+    }
+
+    /// Start a new transfer and return the stage following the setup stage.
+    pub fn setup(&mut self, setup_packet: SetupPacket) -> TransferStage {
+        self.setup_packet = setup_packet;
+        self.bytes_transferred = 0;
+        self.stage = match (setup_packet.direction(), setup_packet.length) {
+            // without a data stage the status stage is always IN
+            (_, 0) => TransferStage::StatusIn,
+            (Direction::DeviceToHost, _) => TransferStage::DataIn,
+            (Direction::HostToDevice, _) => TransferStage::DataOut,
+        };
+        self.stage
+    }
+
+    /// Record a data stage packet of `length` bytes
+    ///
+    /// The data stage ends once `wLength` bytes have been transferred
+    /// or with a packet shorter than `max_packet_size`.
+    pub fn data(&mut self, length: usize, max_packet_size: usize) -> SmolResult<TransferStage> {
+        if !matches!(self.stage, TransferStage::DataIn | TransferStage::DataOut) {
+            return Err(SmolError::InvalidControlStage);
+        }
+
+        self.bytes_transferred += length;
+        if self.bytes_transferred >= self.setup_packet.length as usize || length < max_packet_size
+        {
+            return self.end_data_stage();
+        }
+
+        Ok(self.stage)
+    }
 
-    SetupStage,
+    /// End the data stage early, e.g. when the host moves on to the
+    /// status stage before `wLength` bytes were transferred.
+    pub fn end_data_stage(&mut self) -> SmolResult<TransferStage> {
+        self.stage = match self.stage {
+            // the status stage is in the opposite direction
+            TransferStage::DataIn => TransferStage::StatusOut,
+            TransferStage::DataOut => TransferStage::StatusIn,
+            _ => return Err(SmolError::InvalidControlStage),
+        };
+        Ok(self.stage)
+    }
 
-    OutDataStage(SetupPacket),
+    /// Complete the status stage, ending the transfer.
+    pub fn status(&mut self) -> SmolResult<TransferStage> {
+        match self.stage {
+            TransferStage::StatusIn | TransferStage::StatusOut => {
+                self.stage = TransferStage::Idle;
+                Ok(self.stage)
+            }
+            _ => Err(SmolError::InvalidControlStage),
+        }
+    }
 
-    InDataStage,
+    /// Abandon the transfer, e.g. after a stall or bus reset.
+    pub fn abort(&mut self) {
+        self.stage = TransferStage::Idle;
+        self.bytes_transferred = 0;
+    }
+}
 
-    /// Error(endpoint_number: u8)
-    Error(u8),
+impl Default for ControlTransfer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-/// Performs USB control transfers.
+// - Control ------------------------------------------------------------------
+
+/// Performs USB control transfers on behalf of a device.
 pub struct Control<'a, D, const MAX_RECEIVE_SIZE: usize> {
-    state: State,
+    transfer: ControlTransfer,
     rx_buffer: [u8; MAX_RECEIVE_SIZE],
-    rx_buffer_position: usize,
+    max_transfer_size: usize,
+    max_packet_size: usize,
+    data_stage_timeout_frames: u16,
+    /// Frame number of the last control OUT data stage activity
+    data_stage_frame: Option<u16>,
 
     //driver: &'a D,
     _marker: core::marker::PhantomData<&'a D>,
@@ -40,13 +177,41 @@ where
     pub fn new() -> Self {
         Self {
             //driver: driver,
-            state: State::Idle,
+            transfer: ControlTransfer::new(),
             _marker: core::marker::PhantomData,
 
             rx_buffer: [0; MAX_RECEIVE_SIZE],
-            rx_buffer_position: 0,
+            max_transfer_size: DEFAULT_MAX_TRANSFER_SIZE,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            data_stage_timeout_frames: DEFAULT_DATA_STAGE_TIMEOUT_FRAMES,
+            data_stage_frame: None,
         }
     }
+
+    /// Set the maximum length of an accepted control OUT data stage.
+    ///
+    /// Requests with a larger `wLength` are stalled rather than left
+    /// waiting for data. The effective limit is the smaller of this and
+    /// `MAX_RECEIVE_SIZE`.
+    pub fn set_max_transfer_size(&mut self, max_transfer_size: usize) {
+        self.max_transfer_size = max_transfer_size;
+    }
+
+    /// Set the maximum packet size of the control endpoint.
+    ///
+    /// A shorter packet ends a control OUT data stage early.
+    pub fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.max_packet_size = max_packet_size;
+    }
+
+    /// Set the number of frames the host may take between control OUT
+    /// data stage packets, see [`Control::poll_timeout`].
+    ///
+    /// Defaults to [`DEFAULT_DATA_STAGE_TIMEOUT_FRAMES`] and must be
+    /// less than 2048.
+    pub fn set_data_stage_timeout(&mut self, frames: u16) {
+        self.data_stage_timeout_frames = frames.min(0x7ff);
+    }
 }
 
 // - event dispatch -----------------------------------------------------------
@@ -125,13 +290,35 @@ where
         }
     }
 
+    /// Returns true if no control transfer is in progress.
+    pub fn is_idle(&self) -> bool {
+        self.transfer.is_idle()
+    }
+
+    /// Returns the stage of the control transfer in progress.
+    pub fn stage(&self) -> TransferStage {
+        self.transfer.stage()
+    }
+
+    /// Returns the setup packet of the current or last control transfer.
+    pub fn setup_packet(&self) -> &SetupPacket {
+        self.transfer.setup_packet()
+    }
+
     pub fn foo(&'a mut self) -> &'a [u8] {
         &self.rx_buffer
     }
 
+    /// Abandon any control transfer in progress.
+    pub fn reset(&mut self) {
+        self.transfer.abort();
+    }
+
     // USBx
-    pub fn handle_usb_bus_reset(&self, driver: &D) -> SmolResult<()> {
+    pub fn handle_usb_bus_reset(&mut self, driver: &D) -> SmolResult<()> {
         trace!("CONTROL handle_usb_bus_reset");
+        // abandon any control transfer in progress
+        self.reset();
         driver.bus_reset();
         Ok(())
     }
@@ -159,47 +346,45 @@ where
         let direction = setup_packet.direction();
         let length: usize = setup_packet.length as usize;
 
-        self.state = State::SetupStage;
+        // the host has abandoned the previous transfer's IN data stage
+        if self.transfer.stage() == TransferStage::DataIn {
+            driver.flush_in(endpoint_number);
+        }
+
+        let stage = self.transfer.setup(setup_packet);
 
-        trace!("CONTROL handle_receive_setup_packet(endpoint_number: {}) state:{:?} direction:{:?} length:{}",
-               endpoint_number, self.state, direction, length);
+        trace!("CONTROL handle_receive_setup_packet(endpoint_number: {}) stage:{:?} direction:{:?} length:{}",
+               endpoint_number, stage, direction, length);
 
         // make sure endpoint is not stalled
         driver.unstall_endpoint_out(endpoint_number);
 
-        // OUT transfer
-        if direction == Direction::HostToDevice {
-            trace!("  OUT {} bytes", length);
-
-            if length > MAX_RECEIVE_SIZE {
+        match stage {
+            TransferStage::DataOut if length > self.max_transfer_size => {
+                // has data stage, but exceeds the configured limit
+                error!(
+                    "  data stage exceeds maximum transfer size: {} > {}",
+                    length, self.max_transfer_size
+                );
+                self.set_error(driver, endpoint_number);
+                Ok(None) // TODO return error
+            }
+            TransferStage::DataOut if length > MAX_RECEIVE_SIZE => {
                 // has data stage, but too big too receive
                 error!("  data stage too big: {}", length);
                 self.set_error(driver, endpoint_number);
-                return Ok(None); // TODO return error
-            } else if length > 0 {
+                Ok(None) // TODO return error
+            }
+            TransferStage::DataOut => {
                 // has data stage
-                self.state = State::OutDataStage(setup_packet);
+                self.data_stage_frame = driver.frame_number();
                 driver.ack(0, Direction::HostToDevice);
-                return Ok(None); // handle_receive_packet will return it
-            } else {
-                // no data stage, we're done
-                self.state = State::Idle;
-                return Ok(Some(setup_packet));
+                Ok(None) // handle_receive_packet will return it
             }
-
-        // IN transfer - ack ?
-        } else {
-            trace!("  IN {} bytes", length);
-
-            if length > 0 {
-                // has data stage
-                self.state = State::InDataStage;
-            } else {
-                // no data stage, we're done
-                self.state = State::Idle;
+            _ => {
+                // no data stage or the caller responds with the IN data stage
+                Ok(Some(setup_packet))
             }
-
-            return Ok(Some(setup_packet));
         }
     }
 
@@ -210,14 +395,51 @@ where
         endpoint_number: u8,
     ) -> SmolResult<Option<(SetupPacket, &[u8])>> {
         trace!(
-            "CONTROL handle_receive_packet(endpoint_number: {}) state:{:?}",
+            "CONTROL handle_receive_packet(endpoint_number: {}) stage:{:?}",
             endpoint_number,
-            self.state
+            self.transfer.stage()
         );
 
-        let offset = self.rx_buffer_position;
-        let bytes_read = driver.read(endpoint_number, &mut self.rx_buffer[offset..]);
-        driver.ep_out_prime_receive(endpoint_number);
+        if self.transfer.stage() == TransferStage::DataOut {
+            return self.handle_receive_data_out(driver, endpoint_number);
+        }
+
+        let bytes_read = driver.read_and_reprime(endpoint_number, &mut self.rx_buffer);
+        trace!("  read {} bytes", bytes_read);
+
+        match self.transfer.stage() {
+            // it's the host's status stage ack, which may cut the IN data stage short
+            TransferStage::DataIn | TransferStage::StatusOut => {
+                trace!("  ACK bytes_read:{}", bytes_read);
+                if self.transfer.stage() == TransferStage::DataIn {
+                    self.transfer.end_data_stage()?;
+                }
+                self.transfer.status()?;
+                Ok(None)
+            }
+
+            _ => {
+                trace!("  ACK bytes_read:{}", bytes_read);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Receive the next packet of a control OUT data stage
+    ///
+    /// The packet is appended to the receive buffer and the endpoint
+    /// re-primed until `wLength` bytes have arrived or the host sends a
+    /// short packet. A packet carrying more than the rest of `wLength`
+    /// stalls the transfer and returns [`SmolError::FifoOverflow`].
+    fn handle_receive_data_out(
+        &mut self,
+        driver: &D,
+        endpoint_number: u8,
+    ) -> SmolResult<Option<(SetupPacket, &[u8])>> {
+        let offset = self.transfer.bytes_transferred();
+        let length = self.transfer.setup_packet().length as usize;
+        let (bytes_read, overflow) =
+            driver.read_with_overflow(endpoint_number, &mut self.rx_buffer[offset..length]);
 
         trace!(
             "  read {} bytes, buffer position: {}",
@@ -226,42 +448,72 @@ where
         );
         trace!("  {:?}", &self.rx_buffer[offset..offset + bytes_read]);
 
-        match self.state {
-            State::OutDataStage(setup_packet) => {
-                if bytes_read == 0 {
-                    trace!("  ACK TODO early abort bytes_read:{}", bytes_read);
-                }
-
-                let length = setup_packet.length as usize;
+        if overflow > 0 {
+            warn!(
+                "CONTROL data stage overflow at offset {}: {} bytes past wLength {}",
+                offset, overflow, length
+            );
+            self.set_error(driver, endpoint_number);
+            return Err(SmolError::FifoOverflow);
+        }
+        driver.ep_out_prime_receive(endpoint_number);
 
-                self.rx_buffer_position += bytes_read;
-                if self.rx_buffer_position >= length {
-                    self.rx_buffer_position = 0;
-                    self.state = State::Idle;
-                    return Ok(Some((setup_packet, &self.rx_buffer[..length])));
-                } else {
-                    // more data awaits
-                    return Ok(None);
-                }
+        match self.transfer.data(bytes_read, self.max_packet_size)? {
+            TransferStage::StatusIn => {
+                self.data_stage_frame = None;
+                let setup_packet = *self.transfer.setup_packet();
+                let length = self.transfer.bytes_transferred();
+                Ok(Some((setup_packet, &self.rx_buffer[..length])))
             }
-
-            // it's an ack
+            // more data awaits
             _ => {
-                trace!("  ACK bytes_read:{}", bytes_read);
+                self.data_stage_frame = driver.frame_number();
+                Ok(None)
             }
         }
+    }
 
-        Ok(None)
+    /// Abandon a control OUT data stage the host has stopped sending.
+    ///
+    /// Call this periodically, it stalls the transfer and returns true
+    /// if no data stage packet has arrived for the configured number of
+    /// frames. Drivers that don't report frame numbers never time out.
+    pub fn poll_timeout(&mut self, driver: &D) -> bool {
+        if self.transfer.stage() != TransferStage::DataOut {
+            return false;
+        }
+        let (start, now) = match (self.data_stage_frame, driver.frame_number()) {
+            (Some(start), Some(now)) => (start, now),
+            _ => return false,
+        };
+        let elapsed = now.wrapping_sub(start) & 0x7ff;
+        if elapsed < self.data_stage_timeout_frames {
+            return false;
+        }
+
+        warn!(
+            "CONTROL data stage timeout after {} frames, {} of {} bytes received",
+            elapsed,
+            self.transfer.bytes_transferred(),
+            self.transfer.setup_packet().length
+        );
+        self.set_error(driver, 0);
+        true
     }
 
     // USBx_EP_IN n
     pub fn handle_send_complete(&mut self, driver: &D, endpoint_number: u8) -> SmolResult<()> {
         trace!(
-            "CONTROL handle_send_complete(endpoint_number: {}) state:{:?}",
+            "CONTROL handle_send_complete(endpoint_number: {}) stage:{:?}",
             endpoint_number,
-            self.state
+            self.transfer.stage()
         );
 
+        // IN data stage packets complete without changing the stage
+        if self.transfer.stage() == TransferStage::StatusIn {
+            self.transfer.status()?;
+        }
+
         Ok(())
     }
 }
@@ -273,8 +525,294 @@ where
     D: UsbDriver,
 {
     fn set_error(&mut self, driver: &D, endpoint_number: u8) {
-        self.state = State::Error(endpoint_number);
+        self.transfer.abort();
+        self.data_stage_frame = None;
         driver.stall_endpoint_out(endpoint_number);
         driver.stall_endpoint_in(endpoint_number);
     }
 }
+
+/// Write the data stage of an IN control request
+///
+/// At most `requested_length` bytes of `iter` are written to the
+/// control endpoint in packets of `max_packet_size`. A response
+/// shorter than `requested_length` which ends on a packet boundary is
+/// terminated with a zero length packet, the host stops asking once it
+/// has `requested_length` bytes.
+///
+/// Returns the number of bytes written.
+pub fn write_data_stage<D, I>(
+    driver: &D,
+    iter: I,
+    requested_length: usize,
+    max_packet_size: usize,
+) -> SmolResult<usize>
+where
+    D: WriteEndpoint,
+    I: Iterator<Item = u8> + Clone,
+{
+    let length = iter.clone().take(requested_length).count();
+    let iter = iter.take(length);
+    if length < requested_length {
+        driver.write_all(0, iter, max_packet_size)
+    } else {
+        driver.write_packets(0, iter, max_packet_size)
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::descriptor::DeviceDescriptor;
+    use crate::mock::MockDriver;
+    use crate::traits::AsByteSliceIterator;
+
+    const GET_DESCRIPTOR: [u8; 8] = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+    const SET_ADDRESS: [u8; 8] = [0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+    const VENDOR_OUT: [u8; 8] = [0x40, 0x01, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00];
+
+    fn setup_packet(bytes: [u8; 8]) -> SetupPacket {
+        SetupPacket::try_from(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_transfer_stages() {
+        let mut transfer = ControlTransfer::new();
+        assert!(transfer.is_idle());
+        assert_eq!(transfer.status(), Err(SmolError::InvalidControlStage));
+
+        // no data stage
+        assert_eq!(
+            transfer.setup(setup_packet(SET_ADDRESS)),
+            TransferStage::StatusIn
+        );
+        assert_eq!(transfer.data(0, 8), Err(SmolError::InvalidControlStage));
+        assert_eq!(transfer.status(), Ok(TransferStage::Idle));
+
+        // IN data stage ends once wLength bytes are transferred
+        assert_eq!(
+            transfer.setup(setup_packet(GET_DESCRIPTOR)),
+            TransferStage::DataIn
+        );
+        assert_eq!(transfer.data(8, 8), Ok(TransferStage::DataIn));
+        assert_eq!(transfer.data(8, 8), Ok(TransferStage::DataIn));
+        assert_eq!(transfer.data(2, 8), Ok(TransferStage::StatusOut));
+        assert_eq!(transfer.bytes_transferred(), 18);
+        assert_eq!(transfer.status(), Ok(TransferStage::Idle));
+
+        // OUT data stage ends early with a short packet
+        assert_eq!(
+            transfer.setup(setup_packet(VENDOR_OUT)),
+            TransferStage::DataOut
+        );
+        assert_eq!(transfer.data(4, 8), Ok(TransferStage::StatusIn));
+        assert_eq!(transfer.status(), Ok(TransferStage::Idle));
+
+        // a new setup packet restarts the transfer
+        transfer.setup(setup_packet(GET_DESCRIPTOR));
+        transfer.data(8, 8).unwrap();
+        assert_eq!(
+            transfer.setup(setup_packet(VENDOR_OUT)),
+            TransferStage::DataOut
+        );
+        assert_eq!(transfer.bytes_transferred(), 0);
+
+        transfer.abort();
+        assert!(transfer.is_idle());
+    }
+
+    #[test]
+    fn test_control_out_data_stage() {
+        let driver = MockDriver::new();
+        let mut control: Control<MockDriver, 64> = Control::new();
+        control.set_max_packet_size(8);
+
+        driver.receive_setup_packet(VENDOR_OUT);
+        assert!(control
+            .dispatch(&driver, UsbEvent::ReceiveControl(0))
+            .unwrap()
+            .is_none());
+        assert_eq!(control.stage(), TransferStage::DataOut);
+
+        driver.receive_packet(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        assert!(control
+            .dispatch(&driver, UsbEvent::ReceivePacket(0))
+            .unwrap()
+            .is_none());
+        assert_eq!(control.stage(), TransferStage::DataOut);
+
+        driver.receive_packet(&[8, 9]);
+        let event = control
+            .dispatch(&driver, UsbEvent::ReceivePacket(0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.bytes_read, 10);
+        assert_eq!(&event.data[..10], &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(control.stage(), TransferStage::StatusIn);
+
+        // status stage zlp sent by the caller
+        control
+            .dispatch(&driver, UsbEvent::SendComplete(0))
+            .unwrap();
+        assert!(control.is_idle());
+    }
+
+    #[test]
+    fn test_control_in_data_stage() {
+        let driver = MockDriver::new();
+        let mut control: Control<MockDriver, 64> = Control::new();
+
+        driver.receive_setup_packet(GET_DESCRIPTOR);
+        let event = control
+            .dispatch(&driver, UsbEvent::ReceiveControl(0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.setup_packet.length, 0x12);
+        assert_eq!(control.stage(), TransferStage::DataIn);
+
+        // data stage packets sent by the caller don't change the stage
+        control
+            .dispatch(&driver, UsbEvent::SendComplete(0))
+            .unwrap();
+        assert_eq!(control.stage(), TransferStage::DataIn);
+
+        // host acks with a zlp
+        driver.receive_packet(&[]);
+        control
+            .dispatch(&driver, UsbEvent::ReceivePacket(0))
+            .unwrap();
+        assert!(control.is_idle());
+    }
+
+    #[test]
+    fn test_control_abandoned_in_data_stage() {
+        let driver = MockDriver::new();
+        let mut control: Control<MockDriver, 64> = Control::new();
+
+        driver.receive_setup_packet(GET_DESCRIPTOR);
+        control
+            .dispatch(&driver, UsbEvent::ReceiveControl(0))
+            .unwrap();
+        driver.try_write(0, [0x12, 0x01].into_iter()).unwrap();
+
+        // a new setup packet discards the queued response
+        driver.receive_setup_packet(SET_ADDRESS);
+        control
+            .dispatch(&driver, UsbEvent::ReceiveControl(0))
+            .unwrap();
+        assert!(driver.writes().is_empty());
+        assert!(!driver.is_in_flight(0));
+    }
+
+    #[test]
+    fn test_control_oversized_out_stalls() {
+        let driver = MockDriver::new();
+        let mut control: Control<MockDriver, 8> = Control::new();
+
+        driver.receive_setup_packet(VENDOR_OUT);
+        assert!(control
+            .dispatch(&driver, UsbEvent::ReceiveControl(0))
+            .unwrap()
+            .is_none());
+        assert!(control.is_idle());
+        assert_eq!(driver.stalled(), vec![0x00, 0x80]);
+    }
+
+    #[test]
+    fn test_control_out_data_stage_overflow() {
+        let driver = MockDriver::new();
+        let mut control: Control<MockDriver, 64> = Control::new();
+        control.set_max_packet_size(8);
+
+        driver.receive_setup_packet(VENDOR_OUT);
+        control
+            .dispatch(&driver, UsbEvent::ReceiveControl(0))
+            .unwrap();
+        driver.receive_packet(&[0; 8]);
+        control
+            .dispatch(&driver, UsbEvent::ReceivePacket(0))
+            .unwrap();
+
+        // the second packet carries more than the remaining 2 bytes of wLength
+        driver.receive_packet(&[0; 8]);
+        assert_eq!(
+            control
+                .dispatch(&driver, UsbEvent::ReceivePacket(0))
+                .unwrap_err(),
+            SmolError::FifoOverflow
+        );
+        assert!(control.is_idle());
+        assert_eq!(driver.stalled(), vec![0x00, 0x80]);
+    }
+
+    #[test]
+    fn test_control_out_data_stage_timeout() {
+        let driver = MockDriver::new();
+        let mut control: Control<MockDriver, 64> = Control::new();
+        control.set_max_packet_size(8);
+        control.set_data_stage_timeout(10);
+
+        driver.set_frame_number(0x7fc);
+        driver.receive_setup_packet(VENDOR_OUT);
+        control
+            .dispatch(&driver, UsbEvent::ReceiveControl(0))
+            .unwrap();
+        driver.set_frame_number(0x7fe);
+        driver.receive_packet(&[0; 8]);
+        control
+            .dispatch(&driver, UsbEvent::ReceivePacket(0))
+            .unwrap();
+
+        // each packet restarts the timeout, across frame number wrap-around
+        driver.set_frame_number(0x007);
+        assert!(!control.poll_timeout(&driver));
+        assert_eq!(control.stage(), TransferStage::DataOut);
+
+        driver.set_frame_number(0x008);
+        assert!(control.poll_timeout(&driver));
+        assert!(control.is_idle());
+        assert_eq!(driver.stalled(), vec![0x00, 0x80]);
+        assert!(!control.poll_timeout(&driver));
+    }
+
+    #[test]
+    fn test_write_data_stage() {
+        let data = [0xaa; 16];
+        let packets = |driver: &MockDriver| -> Vec<usize> {
+            driver.writes().iter().map(|(_, data)| data.len()).collect()
+        };
+
+        // a short response ending on a packet boundary needs a ZLP
+        let driver = MockDriver::new();
+        assert_eq!(
+            write_data_stage(&driver, data.iter().copied(), 64, 8),
+            Ok(16)
+        );
+        assert_eq!(packets(&driver), [8, 8, 0]);
+
+        // the host stops asking once it has wLength bytes
+        let driver = MockDriver::new();
+        assert_eq!(
+            write_data_stage(&driver, data.iter().copied(), 16, 8),
+            Ok(16)
+        );
+        assert_eq!(packets(&driver), [8, 8]);
+
+        // responses are truncated to wLength
+        let driver = MockDriver::new();
+        assert_eq!(
+            write_data_stage(&driver, data.iter().copied(), 12, 8),
+            Ok(12)
+        );
+        assert_eq!(packets(&driver), [8, 4]);
+
+        let driver = MockDriver::new();
+        assert_eq!(
+            write_data_stage(&driver, data.iter().copied(), 64, 0),
+            Err(SmolError::InvalidArgument)
+        );
+        assert!(driver.writes().is_empty());
+    }
+}
@@ -0,0 +1,274 @@
+//! Control transfer capture format
+//!
+//! With the `control-capture` feature enabled `UsbDevice` records the
+//! SETUP packet and OUT data stage of each control request it
+//! processes. The records can be read back by the host and replayed
+//! against another device with [`host::replay`](crate::host::replay).
+//!
+//! Each record is serialized as:
+//!
+//! ```text
+//! setup packet (8 bytes)
+//! data length (u16, little-endian)
+//! data (data length bytes)
+//! ```
+//!
+//! IN requests are recorded without data, their response is not
+//! captured.
+
+use core::mem::size_of;
+
+use crate::setup::SetupPacket;
+
+/// Largest data stage kept per record, longer data stages are truncated
+pub const MAX_RECORD_DATA: usize = 64;
+
+/// Size of a serialized record without its data
+pub const RECORD_HEADER_SIZE: usize = 8 + size_of::<u16>();
+
+/// Number of records held by a `UsbDevice`'s capture
+pub const CAPTURE_RECORDS: usize = 16;
+
+// - ControlRecord ------------------------------------------------------------
+
+/// A captured control request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlRecord {
+    /// The SETUP packet as sent on the wire
+    pub setup: [u8; 8],
+    data_length: usize,
+    data: [u8; MAX_RECORD_DATA],
+}
+
+impl ControlRecord {
+    /// Record a SETUP packet and its OUT data stage, truncating the
+    /// data to [`MAX_RECORD_DATA`] bytes
+    pub fn new(setup: [u8; 8], data: &[u8]) -> Self {
+        let data_length = data.len().min(MAX_RECORD_DATA);
+        let mut record = Self {
+            setup,
+            data_length,
+            data: [0; MAX_RECORD_DATA],
+        };
+        record.data[..data_length].copy_from_slice(&data[..data_length]);
+        record
+    }
+
+    /// Returns the decoded SETUP packet
+    pub fn setup_packet(&self) -> SetupPacket {
+        // conversion from an 8 byte array can't fail
+        SetupPacket::try_from(self.setup).unwrap_or_default()
+    }
+
+    /// Returns the recorded data stage
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.data_length]
+    }
+
+    /// Returns the size of the serialized record
+    pub fn serialized_length(&self) -> usize {
+        RECORD_HEADER_SIZE + self.data_length
+    }
+
+    /// Returns an iterator over the serialized record
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        self.setup
+            .into_iter()
+            .chain((self.data_length as u16).to_le_bytes())
+            .chain(self.data().iter().copied())
+    }
+
+    /// Parse a record from the start of `bytes` and return it with the
+    /// remaining bytes
+    ///
+    /// Returns `None` if `bytes` holds no complete record.
+    pub fn parse(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let header = bytes.get(..RECORD_HEADER_SIZE)?;
+        let setup: [u8; 8] = header[..8].try_into().ok()?;
+        let data_length = u16::from_le_bytes([header[8], header[9]]) as usize;
+        if data_length > MAX_RECORD_DATA {
+            return None;
+        }
+
+        let end = RECORD_HEADER_SIZE + data_length;
+        let data = bytes.get(RECORD_HEADER_SIZE..end)?;
+        Some((Self::new(setup, data), &bytes[end..]))
+    }
+}
+
+// - ControlCapture -----------------------------------------------------------
+
+/// A ring of the most recent control requests
+///
+/// Once full the oldest record is discarded for each new one.
+pub struct ControlCapture<const N: usize> {
+    enabled: bool,
+    records: [Option<ControlRecord>; N],
+    head: usize,
+    len: usize,
+    dropped: usize,
+}
+
+impl<const N: usize> ControlCapture<N> {
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            records: [None; N],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Start or stop recording, existing records are kept
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the number of records waiting to be read
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of records discarded because the ring was full
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Discard all records and reset the dropped count
+    pub fn clear(&mut self) {
+        self.records = [None; N];
+        self.head = 0;
+        self.len = 0;
+        self.dropped = 0;
+    }
+
+    /// Record a control request if recording is enabled
+    pub fn record(&mut self, setup_packet: &SetupPacket, data: &[u8]) {
+        if !self.enabled || N == 0 {
+            return;
+        }
+
+        if self.len == N {
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+            self.dropped += 1;
+        }
+        let index = (self.head + self.len) % N;
+        self.records[index] = Some(ControlRecord::new(
+            SetupPacket::as_bytes(*setup_packet),
+            data,
+        ));
+        self.len += 1;
+    }
+
+    /// Returns the oldest record without removing it
+    pub fn peek(&self) -> Option<&ControlRecord> {
+        if self.len == 0 {
+            return None;
+        }
+        self.records[self.head].as_ref()
+    }
+
+    /// Remove and return the oldest record
+    pub fn pop(&mut self) -> Option<ControlRecord> {
+        if self.len == 0 {
+            return None;
+        }
+        let record = self.records[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        record
+    }
+
+    /// Serialize as many whole records as fit into `buffer`, oldest
+    /// first, and remove them from the ring
+    ///
+    /// Returns the number of bytes written.
+    pub fn drain_into(&mut self, buffer: &mut [u8]) -> usize {
+        let mut offset = 0;
+        while let Some(record) = self.peek() {
+            let end = offset + record.serialized_length();
+            if end > buffer.len() {
+                break;
+            }
+            for (dest, byte) in buffer[offset..end].iter_mut().zip(record.iter()) {
+                *dest = byte;
+            }
+            offset = end;
+            self.pop();
+        }
+        offset
+    }
+}
+
+impl<const N: usize> Default for ControlCapture<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GET_DEVICE_DESCRIPTOR: [u8; 8] = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+    const VENDOR_OUT: [u8; 8] = [0x40, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00];
+
+    fn setup_packet(bytes: [u8; 8]) -> SetupPacket {
+        SetupPacket::try_from(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_record_format() {
+        let record = ControlRecord::new(VENDOR_OUT, &[0xca, 0xfe]);
+        let bytes: std::vec::Vec<u8> = record.iter().collect();
+        assert_eq!(
+            bytes,
+            [0x40, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x02, 0x00, 0xca, 0xfe]
+        );
+        assert_eq!(bytes.len(), record.serialized_length());
+
+        let (parsed, rest) = ControlRecord::parse(&bytes).unwrap();
+        assert_eq!(parsed, record);
+        assert!(rest.is_empty());
+
+        // truncated records are not parsed
+        assert_eq!(ControlRecord::parse(&bytes[..11]), None);
+    }
+
+    #[test]
+    fn test_capture_ring() {
+        let mut capture = ControlCapture::<2>::new();
+
+        // nothing is recorded until enabled
+        capture.record(&setup_packet(GET_DEVICE_DESCRIPTOR), &[]);
+        assert!(capture.is_empty());
+
+        capture.set_enabled(true);
+        capture.record(&setup_packet(GET_DEVICE_DESCRIPTOR), &[]);
+        capture.record(&setup_packet(VENDOR_OUT), &[1, 2]);
+        capture.record(&setup_packet(VENDOR_OUT), &[3, 4]);
+        assert_eq!(capture.len(), 2);
+        assert_eq!(capture.dropped(), 1);
+
+        // only whole records are drained
+        let mut buffer = [0; 16];
+        assert_eq!(capture.drain_into(&mut buffer), 12);
+        assert_eq!(&buffer[10..12], &[1, 2]);
+        assert_eq!(capture.len(), 1);
+
+        assert_eq!(capture.pop().unwrap().data(), &[3, 4]);
+        assert_eq!(capture.pop(), None);
+    }
+}
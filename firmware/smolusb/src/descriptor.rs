@@ -1,5 +1,8 @@
 #![allow(dead_code, unused_imports, unused_variables, unused_mut)] // TODO
 
+use crate::consts::{
+    configuration_attributes, descriptor_type, device_capability, endpoint, transfer_type,
+};
 use crate::traits::AsByteSliceIterator;
 use crate::SmolError;
 
@@ -14,28 +17,59 @@ use core::slice;
 
 ///! USB Descriptors
 
+// - little-endian helpers ----------------------------------------------------
+
+/// Returns `value` in USB wire order for storing in a multi-byte
+/// descriptor field
+///
+/// Descriptors are serialized straight from their memory layout so
+/// multi-byte fields hold their little-endian wire representation,
+/// making the serialized bytes the same on any host:
+///
+/// ```
+/// use smolusb::descriptor::{le16, EndpointDescriptor};
+///
+/// const ENDPOINT: EndpointDescriptor = EndpointDescriptor {
+///     max_packet_size: le16(512),
+///     ..EndpointDescriptor::new()
+/// };
+/// ```
+pub const fn le16(value: u16) -> u16 {
+    value.to_le()
+}
+
+/// Returns the host value of a multi-byte descriptor field
+pub const fn from_le16(value: u16) -> u16 {
+    u16::from_le(value)
+}
+
+/// Returns `value` in USB wire order, see [`le16`]
+pub const fn le32(value: u32) -> u32 {
+    value.to_le()
+}
+
 /// DescriptorType
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum DescriptorType {
-    Device = 1,
-    Configuration = 2,
-    String = 3,
-    Interface = 4,
-    Endpoint = 5,
-    DeviceQualifier = 6,
-    OtherSpeedConfiguration = 7,
-    InterfacePower = 8,
-    OnTheGo = 9,
-    Debug = 10,
-    InterfaceAssociation = 11,
-    Security = 12,
-    Key = 13,
-    EncryptionType = 14,
-    BinaryDeviceObjectStore = 15,
-    DeviceCapability = 16,
-    WirelessEndpointCompanion = 17,
-    SuperSpeedEndpointCompanion = 48,
+    Device = descriptor_type::DEVICE,
+    Configuration = descriptor_type::CONFIGURATION,
+    String = descriptor_type::STRING,
+    Interface = descriptor_type::INTERFACE,
+    Endpoint = descriptor_type::ENDPOINT,
+    DeviceQualifier = descriptor_type::DEVICE_QUALIFIER,
+    OtherSpeedConfiguration = descriptor_type::OTHER_SPEED_CONFIGURATION,
+    InterfacePower = descriptor_type::INTERFACE_POWER,
+    OnTheGo = descriptor_type::OTG,
+    Debug = descriptor_type::DEBUG,
+    InterfaceAssociation = descriptor_type::INTERFACE_ASSOCIATION,
+    Security = descriptor_type::SECURITY,
+    Key = descriptor_type::KEY,
+    EncryptionType = descriptor_type::ENCRYPTION_TYPE,
+    BinaryDeviceObjectStore = descriptor_type::BOS,
+    DeviceCapability = descriptor_type::DEVICE_CAPABILITY,
+    WirelessEndpointCompanion = descriptor_type::WIRELESS_ENDPOINT_COMPANION,
+    SuperSpeedEndpointCompanion = descriptor_type::SUPERSPEED_ENDPOINT_COMPANION,
 }
 
 impl TryFrom<u8> for DescriptorType {
@@ -43,24 +77,28 @@ impl TryFrom<u8> for DescriptorType {
 
     fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
         let result = match value {
-            1 => DescriptorType::Device,
-            2 => DescriptorType::Configuration,
-            3 => DescriptorType::String,
-            4 => DescriptorType::Interface,
-            5 => DescriptorType::Endpoint,
-            6 => DescriptorType::DeviceQualifier,
-            7 => DescriptorType::OtherSpeedConfiguration,
-            8 => DescriptorType::InterfacePower,
-            9 => DescriptorType::OnTheGo,
-            10 => DescriptorType::Debug,
-            11 => DescriptorType::InterfaceAssociation,
-            12 => DescriptorType::Security,
-            13 => DescriptorType::Key,
-            14 => DescriptorType::EncryptionType,
-            15 => DescriptorType::BinaryDeviceObjectStore,
-            16 => DescriptorType::DeviceCapability,
-            17 => DescriptorType::WirelessEndpointCompanion,
-            48 => DescriptorType::SuperSpeedEndpointCompanion,
+            descriptor_type::DEVICE => DescriptorType::Device,
+            descriptor_type::CONFIGURATION => DescriptorType::Configuration,
+            descriptor_type::STRING => DescriptorType::String,
+            descriptor_type::INTERFACE => DescriptorType::Interface,
+            descriptor_type::ENDPOINT => DescriptorType::Endpoint,
+            descriptor_type::DEVICE_QUALIFIER => DescriptorType::DeviceQualifier,
+            descriptor_type::OTHER_SPEED_CONFIGURATION => DescriptorType::OtherSpeedConfiguration,
+            descriptor_type::INTERFACE_POWER => DescriptorType::InterfacePower,
+            descriptor_type::OTG => DescriptorType::OnTheGo,
+            descriptor_type::DEBUG => DescriptorType::Debug,
+            descriptor_type::INTERFACE_ASSOCIATION => DescriptorType::InterfaceAssociation,
+            descriptor_type::SECURITY => DescriptorType::Security,
+            descriptor_type::KEY => DescriptorType::Key,
+            descriptor_type::ENCRYPTION_TYPE => DescriptorType::EncryptionType,
+            descriptor_type::BOS => DescriptorType::BinaryDeviceObjectStore,
+            descriptor_type::DEVICE_CAPABILITY => DescriptorType::DeviceCapability,
+            descriptor_type::WIRELESS_ENDPOINT_COMPANION => {
+                DescriptorType::WirelessEndpointCompanion
+            }
+            descriptor_type::SUPERSPEED_ENDPOINT_COMPANION => {
+                DescriptorType::SuperSpeedEndpointCompanion
+            }
             _ => return Err(SmolError::FailedConversion),
         };
         Ok(result)
@@ -71,6 +109,8 @@ impl TryFrom<u8> for DescriptorType {
 
 /// USB device descriptor
 ///
+/// Multi-byte fields are little-endian, see [`le16`].
+///
 /// TODO consider renaming descriptor fields according to LUNA / industry-standard names
 #[derive(AsBytes, FromBytes, Clone, Copy)]
 #[repr(C, packed)]
@@ -98,7 +138,7 @@ impl DeviceDescriptor {
         Self {
             _length: size_of::<Self>() as u8,
             _descriptor_type: DescriptorType::Device as u8,
-            descriptor_version: 0x0200,
+            descriptor_version: le16(0x0200),
             device_class: 0,
             device_subclass: 0,
             device_protocol: 0,
@@ -120,6 +160,28 @@ impl Default for DeviceDescriptor {
     }
 }
 
+impl DeviceDescriptor {
+    /// Returns `descriptor_version` (bcdUSB) in host byte order
+    pub const fn descriptor_version(&self) -> u16 {
+        from_le16(self.descriptor_version)
+    }
+
+    /// Returns `vendor_id` in host byte order
+    pub const fn vendor_id(&self) -> u16 {
+        from_le16(self.vendor_id)
+    }
+
+    /// Returns `product_id` in host byte order
+    pub const fn product_id(&self) -> u16 {
+        from_le16(self.product_id)
+    }
+
+    /// Returns `device_version_number` (bcdDevice) in host byte order
+    pub const fn device_version_number(&self) -> u16 {
+        from_le16(self.device_version_number)
+    }
+}
+
 // - DeviceQualifierDescriptor ------------------------------------------------
 
 /// USB device qualifier descriptor
@@ -161,6 +223,190 @@ impl Default for DeviceQualifierDescriptor {
     }
 }
 
+// - DebugDescriptor ----------------------------------------------------------
+
+/// USB debug descriptor
+///
+/// Identifies the endpoints of a debug device, see the USB 2.0 Debug
+/// Device Functional Specification.
+#[derive(AsBytes, FromBytes, Clone, Copy)]
+#[repr(C, packed)]
+pub struct DebugDescriptor {
+    pub _length: u8,          // 4
+    pub _descriptor_type: u8, // 10 = Debug
+    pub debug_in_endpoint_address: u8,
+    pub debug_out_endpoint_address: u8,
+}
+
+impl AsByteSliceIterator for DebugDescriptor {}
+
+impl DebugDescriptor {
+    pub const fn new() -> Self {
+        Self {
+            _length: size_of::<Self>() as u8,
+            _descriptor_type: DescriptorType::Debug as u8,
+            debug_in_endpoint_address: 0,
+            debug_out_endpoint_address: 0,
+        }
+    }
+}
+
+impl Default for DebugDescriptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// - BosDescriptor ------------------------------------------------------------
+
+/// USB binary device object store descriptor header
+#[derive(AsBytes, FromBytes, Clone, Copy)]
+#[repr(C, packed)]
+pub struct BosDescriptorHeader {
+    pub _length: u8,          // 5
+    pub _descriptor_type: u8, // 15 = BinaryDeviceObjectStore
+    pub _total_length: u16,
+    pub _num_device_capabilities: u8,
+}
+
+impl AsByteSliceIterator for BosDescriptorHeader {}
+
+/// USB 2.0 extension device capability descriptor
+///
+/// Reports link power management support to USB 2.0 hosts.
+#[derive(AsBytes, FromBytes, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Usb20ExtensionDescriptor {
+    pub _length: u8,          // 7
+    pub _descriptor_type: u8, // 16 = DeviceCapability
+    pub _capability_type: u8, // 2 = USB 2.0 Extension
+    /// See [`usb20_extension_attributes`](crate::consts::usb20_extension_attributes)
+    pub attributes: u32,
+}
+
+impl AsByteSliceIterator for Usb20ExtensionDescriptor {}
+
+impl Usb20ExtensionDescriptor {
+    pub const fn new() -> Self {
+        Self {
+            _length: size_of::<Self>() as u8,
+            _descriptor_type: DescriptorType::DeviceCapability as u8,
+            _capability_type: device_capability::USB_2_0_EXTENSION,
+            attributes: 0,
+        }
+    }
+}
+
+impl Default for Usb20ExtensionDescriptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// SuperSpeed USB device capability descriptor
+///
+/// Reports the speeds a SuperSpeed device supports and its U1/U2 exit
+/// latencies.
+#[derive(AsBytes, FromBytes, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SuperSpeedUsbDescriptor {
+    pub _length: u8,          // 10
+    pub _descriptor_type: u8, // 16 = DeviceCapability
+    pub _capability_type: u8, // 3 = SuperSpeed USB
+    /// See [`superspeed_usb_attributes`](crate::consts::superspeed_usb_attributes)
+    pub attributes: u8,
+    /// See [`superspeed_usb_attributes`](crate::consts::superspeed_usb_attributes)
+    pub speeds_supported: u16,
+    /// Lowest speed at which all functionality is available
+    pub functionality_support: u8,
+    /// U1 device exit latency in µs
+    pub u1_device_exit_latency: u8,
+    /// U2 device exit latency in µs
+    pub u2_device_exit_latency: u16,
+}
+
+impl AsByteSliceIterator for SuperSpeedUsbDescriptor {}
+
+impl SuperSpeedUsbDescriptor {
+    pub const fn new() -> Self {
+        Self {
+            _length: size_of::<Self>() as u8,
+            _descriptor_type: DescriptorType::DeviceCapability as u8,
+            _capability_type: device_capability::SUPERSPEED_USB,
+            attributes: 0,
+            speeds_supported: 0,
+            functionality_support: 0,
+            u1_device_exit_latency: 0,
+            u2_device_exit_latency: 0,
+        }
+    }
+}
+
+impl Default for SuperSpeedUsbDescriptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// USB binary device object store descriptor
+///
+/// Each device capability descriptor is only included if set. The
+/// header's total length and capability count are calculated when the
+/// descriptor is serialized.
+#[derive(Clone, Copy, Default)]
+pub struct BosDescriptor {
+    pub usb20_extension: Option<Usb20ExtensionDescriptor>,
+    pub superspeed_usb: Option<SuperSpeedUsbDescriptor>,
+}
+
+impl BosDescriptor {
+    pub const fn new() -> Self {
+        Self {
+            usb20_extension: None,
+            superspeed_usb: None,
+        }
+    }
+
+    /// Returns the BOS descriptor header for the included capabilities.
+    pub fn header(&self) -> BosDescriptorHeader {
+        let mut total_length = size_of::<BosDescriptorHeader>();
+        let mut num_device_capabilities = 0;
+        if self.usb20_extension.is_some() {
+            total_length += size_of::<Usb20ExtensionDescriptor>();
+            num_device_capabilities += 1;
+        }
+        if self.superspeed_usb.is_some() {
+            total_length += size_of::<SuperSpeedUsbDescriptor>();
+            num_device_capabilities += 1;
+        }
+        BosDescriptorHeader {
+            _length: size_of::<BosDescriptorHeader>() as u8,
+            _descriptor_type: DescriptorType::BinaryDeviceObjectStore as u8,
+            _total_length: le16(total_length as u16),
+            _num_device_capabilities: num_device_capabilities,
+        }
+    }
+
+    /// Returns an iterator over the serialized BOS descriptor.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + Clone + '_ {
+        let mut header = [0; size_of::<BosDescriptorHeader>()];
+        header.copy_from_slice(self.header().as_bytes());
+
+        header
+            .into_iter()
+            .chain(
+                self.usb20_extension
+                    .iter()
+                    .flat_map(|descriptor| descriptor.as_iter().copied()),
+            )
+            .chain(
+                self.superspeed_usb
+                    .iter()
+                    .flat_map(|descriptor| descriptor.as_iter().copied()),
+            )
+    }
+}
+
 // - ConfigurationDescriptor --------------------------------------------------
 
 /// USB configuration descriptor header
@@ -188,10 +434,89 @@ impl ConfigurationDescriptorHeader {
             _num_interfaces: 0,
             configuration_value: 0,
             configuration_string_index: 0,
-            attributes: 0,
+            attributes: ConfigurationAttributes::new().bits(),
             max_power: 0,
         }
     }
+
+    /// Returns the decoded `attributes` field
+    pub fn configuration_attributes(&self) -> ConfigurationAttributes {
+        ConfigurationAttributes::from(self.attributes)
+    }
+}
+
+/// Configuration descriptor `attributes` field
+///
+/// ```text
+/// bit 7 reserved, must be set
+/// bit 6 self-powered
+/// bit 5 remote wakeup
+/// ```
+///
+/// Built up from a bus-powered configuration without remote wakeup:
+///
+/// ```
+/// use smolusb::descriptor::{ConfigurationAttributes, ConfigurationDescriptorHeader};
+///
+/// const HEADER: ConfigurationDescriptorHeader = ConfigurationDescriptorHeader {
+///     attributes: ConfigurationAttributes::new().self_powered().bits(),
+///     ..ConfigurationDescriptorHeader::new()
+/// };
+/// ```
+///
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ConfigurationAttributes(u8);
+
+impl ConfigurationAttributes {
+    /// A bus-powered configuration without remote wakeup
+    pub const fn new() -> Self {
+        Self(configuration_attributes::RESERVED_ONE)
+    }
+
+    pub const fn self_powered(self) -> Self {
+        Self(self.0 | configuration_attributes::SELF_POWERED)
+    }
+
+    pub const fn remote_wakeup(self) -> Self {
+        Self(self.0 | configuration_attributes::REMOTE_WAKEUP)
+    }
+
+    /// Returns the raw attributes byte
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub const fn is_self_powered(&self) -> bool {
+        self.0 & configuration_attributes::SELF_POWERED != 0
+    }
+
+    pub const fn is_remote_wakeup(&self) -> bool {
+        self.0 & configuration_attributes::REMOTE_WAKEUP != 0
+    }
+
+    /// Returns true if the reserved bit 7 is set and bits 0..=4 are clear
+    pub const fn is_valid(&self) -> bool {
+        self.0 & !(configuration_attributes::SELF_POWERED | configuration_attributes::REMOTE_WAKEUP)
+            == configuration_attributes::RESERVED_ONE
+    }
+}
+
+impl Default for ConfigurationAttributes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<u8> for ConfigurationAttributes {
+    fn from(value: u8) -> Self {
+        Self(value)
+    }
+}
+
+impl From<ConfigurationAttributes> for u8 {
+    fn from(attributes: ConfigurationAttributes) -> Self {
+        attributes.0
+    }
 }
 
 /// USB configuration descriptor
@@ -215,27 +540,84 @@ impl<'a> ConfigurationDescriptor<'a> {
     /// Calculate and update the descriptor total length field
     pub fn set_total_length(&mut self) -> usize {
         let total_length = self.iter().count();
-        self.head._total_length = total_length as u16;
+        self.head._total_length = le16(total_length as u16);
         total_length
     }
 
-    pub fn iter(&self) -> ConfigurationDescriptorIterator {
-        ConfigurationDescriptorIterator::new(self)
+    pub fn iter(&self) -> ConfigurationDescriptorIterator<'_> {
+        ConfigurationDescriptorIterator::new(self, false)
+    }
+
+    /// Returns an iterator that includes the SuperSpeed endpoint companion descriptors
+    pub fn iter_superspeed(&self) -> ConfigurationDescriptorIterator<'_> {
+        ConfigurationDescriptorIterator::new(self, true)
+    }
+
+    /// Returns a copy of the descriptor with the total length field
+    /// updated to include the SuperSpeed endpoint companion descriptors
+    pub fn to_superspeed(&self) -> Self {
+        let mut descriptor = *self;
+        descriptor.head._total_length = le16(self.iter_superspeed().count() as u16);
+        descriptor
+    }
+
+    /// Returns the number of per-endpoint slots needed for this
+    /// configuration, i.e. its highest endpoint number plus one
+    ///
+    /// This is a `const fn` so firmware can check that its
+    /// `MAX_ENDPOINTS` and queue sizes fit its descriptors at compile
+    /// time:
+    ///
+    /// ```ignore
+    /// const _: () = assert!(CONFIGURATION_DESCRIPTOR_0.endpoint_slots() <= MAX_ENDPOINTS);
+    /// ```
+    pub const fn endpoint_slots(&self) -> usize {
+        // the control endpoint is always present
+        let mut slots = 1;
+        let mut i = 0;
+        while i < self.tail.len() {
+            let endpoints = self.tail[i].tail;
+            let mut j = 0;
+            while j < endpoints.len() {
+                let endpoint_number = endpoints[j].endpoint_address & endpoint::NUMBER_MASK;
+                if endpoint_number as usize + 1 > slots {
+                    slots = endpoint_number as usize + 1;
+                }
+                j += 1;
+            }
+            i += 1;
+        }
+        slots
+    }
+
+    /// Returns an iterator over the endpoint descriptors of all interfaces
+    pub fn endpoints(&self) -> impl Iterator<Item = &'a EndpointDescriptor> {
+        self.tail
+            .iter()
+            .flat_map(|interface| interface.endpoints().iter())
     }
 }
 
 /// USB configuration descriptor iterator
+#[derive(Clone)]
 pub struct ConfigurationDescriptorIterator<'a> {
     chain: iter::Chain<slice::Iter<'a, u8>, ConfigurationDescriptorTailIterator<'a>>,
 }
 
 impl<'a> ConfigurationDescriptorIterator<'a> {
-    pub fn new(descriptor: &'a ConfigurationDescriptor) -> Self {
+    pub fn new(descriptor: &'a ConfigurationDescriptor, superspeed: bool) -> Self {
         let head_iter: slice::Iter<'a, u8> = descriptor.head.as_iter();
-        let tail_iter: ConfigurationDescriptorTailIterator = descriptor
-            .tail
-            .iter()
-            .flat_map(&|x: &'a InterfaceDescriptor| x.iter());
+        let tail_iter: ConfigurationDescriptorTailIterator = if superspeed {
+            descriptor
+                .tail
+                .iter()
+                .flat_map(&|x: &'a InterfaceDescriptor| x.iter_superspeed())
+        } else {
+            descriptor
+                .tail
+                .iter()
+                .flat_map(&|x: &'a InterfaceDescriptor| x.iter())
+        };
         let chain: iter::Chain<slice::Iter<'a, u8>, ConfigurationDescriptorTailIterator<'a>> =
             head_iter.chain(tail_iter);
 
@@ -251,14 +633,55 @@ impl<'a> Iterator for ConfigurationDescriptorIterator<'a> {
 }
 
 // type aliases for sanity
-pub type InterfaceDescriptorIterator<'a> =
-    CompositeIterator<'a, InterfaceDescriptorHeader, EndpointDescriptor>;
 pub type ConfigurationDescriptorTailIterator<'a> = iter::FlatMap<
     slice::Iter<'a, InterfaceDescriptor<'a>>,
     InterfaceDescriptorIterator<'a>,
     &'a dyn Fn(&'a InterfaceDescriptor<'a>) -> InterfaceDescriptorIterator<'a>,
 >;
 
+// - InterfaceAssociationDescriptor -------------------------------------------
+
+/// USB interface association descriptor
+///
+/// Groups consecutive interfaces belonging to a single function of a
+/// composite device, e.g. the communications and data interfaces of a
+/// CDC-ACM function.
+#[derive(AsBytes, FromBytes, Clone, Copy)]
+#[repr(C, packed)]
+pub struct InterfaceAssociationDescriptor {
+    pub _length: u8,          // 8
+    pub _descriptor_type: u8, // 11 = InterfaceAssociation
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub function_string_index: u8,
+}
+
+impl AsByteSliceIterator for InterfaceAssociationDescriptor {}
+
+impl InterfaceAssociationDescriptor {
+    pub const fn new() -> Self {
+        Self {
+            _length: size_of::<Self>() as u8,
+            _descriptor_type: DescriptorType::InterfaceAssociation as u8,
+            first_interface: 0,
+            interface_count: 0,
+            function_class: 0,
+            function_subclass: 0,
+            function_protocol: 0,
+            function_string_index: 0,
+        }
+    }
+}
+
+impl Default for InterfaceAssociationDescriptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // - InterfaceDescriptor ------------------------------------------------------
 
 /// USB interface descriptor header
@@ -295,27 +718,179 @@ impl InterfaceDescriptorHeader {
 }
 
 /// USB interface descriptor
+///
+/// Serializes as:
+///
+/// ```text
+/// [interface association descriptor]
+/// interface descriptor
+/// [class-specific descriptors]
+/// endpoint descriptors [each followed by its SuperSpeed endpoint companion descriptor]
+/// ```
+///
+#[derive(Clone, Copy)]
 pub struct InterfaceDescriptor<'a> {
+    association: Option<InterfaceAssociationDescriptor>,
     head: InterfaceDescriptorHeader,
+    class_descriptors: &'a [u8],
     tail: &'a [EndpointDescriptor],
+    companions: &'a [SuperSpeedEndpointCompanionDescriptor],
 }
 
 impl<'a> InterfaceDescriptor<'a> {
     pub const fn new(mut head: InterfaceDescriptorHeader, tail: &'a [EndpointDescriptor]) -> Self {
         head._length = size_of::<InterfaceDescriptorHeader>() as u8;
         head._num_endpoints = tail.len() as u8;
-        Self { head, tail }
+        Self {
+            association: None,
+            head,
+            class_descriptors: &[],
+            tail,
+            companions: &[],
+        }
     }
 
-    pub fn iter(&'a self) -> CompositeIterator<'a, InterfaceDescriptorHeader, EndpointDescriptor> {
-        let iter = CompositeIterator::new(&self.head, self.tail);
-        iter
+    /// Precede this interface with an interface association descriptor
+    pub const fn with_association(mut self, association: InterfaceAssociationDescriptor) -> Self {
+        self.association = Some(association);
+        self
+    }
+
+    /// Follow this interface with the given class-specific descriptors
+    ///
+    /// `class_descriptors` must contain complete, serialized descriptors.
+    pub const fn with_class_descriptors(mut self, class_descriptors: &'a [u8]) -> Self {
+        self.class_descriptors = class_descriptors;
+        self
+    }
+
+    /// Attach SuperSpeed endpoint companion descriptors to this interface's endpoints
+    ///
+    /// `companions[n]` follows endpoint descriptor `n` when the
+    /// interface is serialized for a device operating at SuperSpeed.
+    pub const fn with_superspeed_companions(
+        mut self,
+        companions: &'a [SuperSpeedEndpointCompanionDescriptor],
+    ) -> Self {
+        self.companions = companions;
+        self
+    }
+
+    pub fn iter(&'a self) -> InterfaceDescriptorIterator<'a> {
+        InterfaceDescriptorIterator::new(self, false)
+    }
+
+    /// Returns an iterator that includes the SuperSpeed endpoint companion descriptors
+    pub fn iter_superspeed(&'a self) -> InterfaceDescriptorIterator<'a> {
+        InterfaceDescriptorIterator::new(self, true)
+    }
+
+    /// Returns the interface descriptor header
+    pub fn header(&self) -> &InterfaceDescriptorHeader {
+        &self.head
+    }
+
+    /// Returns the endpoint descriptors belonging to this interface
+    pub fn endpoints(&self) -> &'a [EndpointDescriptor] {
+        self.tail
+    }
+
+    /// Returns the interface association descriptor preceding this interface, if any
+    pub fn association(&self) -> Option<&InterfaceAssociationDescriptor> {
+        self.association.as_ref()
+    }
+
+    /// Returns the serialized class-specific descriptors following this interface
+    pub fn class_descriptors(&self) -> &'a [u8] {
+        self.class_descriptors
+    }
+}
+
+/// Association, interface and class-specific descriptor bytes
+type InterfaceHeadIterator<'a> =
+    iter::Chain<iter::Chain<slice::Iter<'a, u8>, slice::Iter<'a, u8>>, slice::Iter<'a, u8>>;
+
+/// USB interface descriptor iterator
+#[derive(Clone)]
+pub struct InterfaceDescriptorIterator<'a> {
+    chain: iter::Chain<InterfaceHeadIterator<'a>, EndpointDescriptorIterator<'a>>,
+}
+
+impl<'a> InterfaceDescriptorIterator<'a> {
+    pub fn new(descriptor: &'a InterfaceDescriptor, superspeed: bool) -> Self {
+        let association_iter: slice::Iter<'a, u8> = match &descriptor.association {
+            Some(association) => association.as_iter(),
+            None => [].iter(),
+        };
+        let head_iter: slice::Iter<'a, u8> = descriptor.head.as_iter();
+        let class_iter: slice::Iter<'a, u8> = descriptor.class_descriptors.iter();
+        let tail_iter = EndpointDescriptorIterator::new(
+            descriptor.tail,
+            if superspeed {
+                descriptor.companions
+            } else {
+                &[]
+            },
+        );
+        let chain = association_iter
+            .chain(head_iter)
+            .chain(class_iter)
+            .chain(tail_iter);
+
+        Self { chain }
+    }
+}
+
+impl<'a> Iterator for InterfaceDescriptorIterator<'a> {
+    type Item = &'a u8;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chain.next()
+    }
+}
+
+/// Iterator over endpoint descriptors, each followed by its companion descriptor if any
+#[derive(Clone)]
+pub struct EndpointDescriptorIterator<'a> {
+    endpoints: slice::Iter<'a, EndpointDescriptor>,
+    companions: slice::Iter<'a, SuperSpeedEndpointCompanionDescriptor>,
+    current: iter::Chain<slice::Iter<'a, u8>, slice::Iter<'a, u8>>,
+}
+
+impl<'a> EndpointDescriptorIterator<'a> {
+    pub fn new(
+        endpoints: &'a [EndpointDescriptor],
+        companions: &'a [SuperSpeedEndpointCompanionDescriptor],
+    ) -> Self {
+        Self {
+            endpoints: endpoints.iter(),
+            companions: companions.iter(),
+            current: [].iter().chain([].iter()),
+        }
+    }
+}
+
+impl<'a> Iterator for EndpointDescriptorIterator<'a> {
+    type Item = &'a u8;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(byte) = self.current.next() {
+                return Some(byte);
+            }
+            let endpoint = self.endpoints.next()?;
+            let companion_iter = match self.companions.next() {
+                Some(companion) => companion.as_iter(),
+                None => [].iter(),
+            };
+            self.current = endpoint.as_iter().chain(companion_iter);
+        }
     }
 }
 
 // - EndpointDescriptor -------------------------------------------------------
 
 /// USB endpoint descriptor
+///
+/// `max_packet_size` is little-endian, see [`le16`].
 #[derive(AsBytes, FromBytes, Clone, Copy)]
 #[repr(C, packed)]
 pub struct EndpointDescriptor {
@@ -348,6 +923,174 @@ impl Default for EndpointDescriptor {
     }
 }
 
+impl EndpointDescriptor {
+    /// Returns the decoded `attributes` field
+    pub fn endpoint_attributes(&self) -> EndpointAttributes {
+        EndpointAttributes::from(self.attributes)
+    }
+
+    /// Returns `max_packet_size` in host byte order
+    pub const fn max_packet_size(&self) -> u16 {
+        from_le16(self.max_packet_size)
+    }
+}
+
+// - SuperSpeedEndpointCompanionDescriptor -----------------------------------
+
+/// USB SuperSpeed endpoint companion descriptor
+///
+/// Follows each endpoint descriptor in the configuration of a device
+/// operating at SuperSpeed.
+#[derive(AsBytes, FromBytes, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SuperSpeedEndpointCompanionDescriptor {
+    pub _length: u8,          // 6
+    pub _descriptor_type: u8, // 48 = SuperSpeedEndpointCompanion
+    pub max_burst: u8,
+    pub attributes: u8,
+    pub bytes_per_interval: u16,
+}
+
+impl AsByteSliceIterator for SuperSpeedEndpointCompanionDescriptor {}
+
+impl SuperSpeedEndpointCompanionDescriptor {
+    pub const fn new() -> Self {
+        Self {
+            _length: size_of::<Self>() as u8,
+            _descriptor_type: DescriptorType::SuperSpeedEndpointCompanion as u8,
+            max_burst: 0,
+            attributes: 0,
+            bytes_per_interval: 0,
+        }
+    }
+}
+
+impl Default for SuperSpeedEndpointCompanionDescriptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Endpoint transfer type, bits 0..=1 of the endpoint `attributes`
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum TransferType {
+    Control = transfer_type::CONTROL,
+    Isochronous = transfer_type::ISOCHRONOUS,
+    Bulk = transfer_type::BULK,
+    Interrupt = transfer_type::INTERRUPT,
+}
+
+/// Isochronous synchronization type, bits 2..=3 of the endpoint `attributes`
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum SynchronizationType {
+    NoSynchronization = 0b00,
+    Asynchronous = 0b01,
+    Adaptive = 0b10,
+    Synchronous = 0b11,
+}
+
+/// Isochronous usage type, bits 4..=5 of the endpoint `attributes`
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum UsageType {
+    Data = 0b00,
+    Feedback = 0b01,
+    ImplicitFeedbackData = 0b10,
+    Reserved = 0b11,
+}
+
+/// Endpoint descriptor `attributes` field
+///
+/// ```text
+/// bits 0..=1 transfer type
+/// bits 2..=3 synchronization type (isochronous only)
+/// bits 4..=5 usage type (isochronous only)
+/// ```
+///
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EndpointAttributes(u8);
+
+impl EndpointAttributes {
+    pub const fn control() -> Self {
+        Self(TransferType::Control as u8)
+    }
+
+    pub const fn bulk() -> Self {
+        Self(TransferType::Bulk as u8)
+    }
+
+    pub const fn interrupt() -> Self {
+        Self(TransferType::Interrupt as u8)
+    }
+
+    pub const fn isochronous(
+        synchronization_type: SynchronizationType,
+        usage_type: UsageType,
+    ) -> Self {
+        Self(
+            TransferType::Isochronous as u8
+                | (synchronization_type as u8) << 2
+                | (usage_type as u8) << 4,
+        )
+    }
+
+    /// Returns the raw attributes byte
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn transfer_type(&self) -> TransferType {
+        match self.0 & transfer_type::MASK {
+            transfer_type::CONTROL => TransferType::Control,
+            transfer_type::ISOCHRONOUS => TransferType::Isochronous,
+            transfer_type::BULK => TransferType::Bulk,
+            _ => TransferType::Interrupt,
+        }
+    }
+
+    /// Returns the synchronization type of an isochronous endpoint
+    pub fn synchronization_type(&self) -> Option<SynchronizationType> {
+        if self.transfer_type() != TransferType::Isochronous {
+            return None;
+        }
+        let synchronization_type = match (self.0 >> 2) & 0b11 {
+            0b00 => SynchronizationType::NoSynchronization,
+            0b01 => SynchronizationType::Asynchronous,
+            0b10 => SynchronizationType::Adaptive,
+            _ => SynchronizationType::Synchronous,
+        };
+        Some(synchronization_type)
+    }
+
+    /// Returns the usage type of an isochronous endpoint
+    pub fn usage_type(&self) -> Option<UsageType> {
+        if self.transfer_type() != TransferType::Isochronous {
+            return None;
+        }
+        let usage_type = match (self.0 >> 4) & 0b11 {
+            0b00 => UsageType::Data,
+            0b01 => UsageType::Feedback,
+            0b10 => UsageType::ImplicitFeedbackData,
+            _ => UsageType::Reserved,
+        };
+        Some(usage_type)
+    }
+}
+
+impl From<u8> for EndpointAttributes {
+    fn from(value: u8) -> Self {
+        Self(value)
+    }
+}
+
+impl From<EndpointAttributes> for u8 {
+    fn from(attributes: EndpointAttributes) -> Self {
+        attributes.0
+    }
+}
+
 // - StringDescriptorZero -----------------------------------------------------
 
 /// USB string descriptor language id
@@ -533,3 +1276,415 @@ impl<'a, H, T> Iterator for CompositeIterator<'a, H, T> {
         self.chain.next()
     }
 }
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::{superspeed_usb_attributes, usb20_extension_attributes};
+
+    #[test]
+    fn test_endpoint_attributes() {
+        assert_eq!(EndpointAttributes::bulk().bits(), 0b10);
+        assert_eq!(EndpointAttributes::interrupt().bits(), 0b11);
+
+        let attributes =
+            EndpointAttributes::isochronous(SynchronizationType::Adaptive, UsageType::Feedback);
+        assert_eq!(attributes.bits(), 0b01_10_01);
+        assert_eq!(attributes.transfer_type(), TransferType::Isochronous);
+        assert_eq!(
+            attributes.synchronization_type(),
+            Some(SynchronizationType::Adaptive)
+        );
+        assert_eq!(attributes.usage_type(), Some(UsageType::Feedback));
+
+        let attributes = EndpointAttributes::from(0b10);
+        assert_eq!(attributes.transfer_type(), TransferType::Bulk);
+        assert_eq!(attributes.synchronization_type(), None);
+    }
+
+    #[test]
+    fn test_configuration_attributes() {
+        let cases = [
+            (ConfigurationAttributes::new(), 0x80, false, false),
+            (
+                ConfigurationAttributes::new().self_powered(),
+                0xc0,
+                true,
+                false,
+            ),
+            (
+                ConfigurationAttributes::new().remote_wakeup(),
+                0xa0,
+                false,
+                true,
+            ),
+            (
+                ConfigurationAttributes::new()
+                    .self_powered()
+                    .remote_wakeup(),
+                0xe0,
+                true,
+                true,
+            ),
+        ];
+        for (attributes, bits, self_powered, remote_wakeup) in cases {
+            assert_eq!(attributes.bits(), bits);
+            assert!(attributes.is_valid());
+
+            let decoded = ConfigurationAttributes::from(bits);
+            assert_eq!(decoded, attributes);
+            assert_eq!(decoded.is_self_powered(), self_powered);
+            assert_eq!(decoded.is_remote_wakeup(), remote_wakeup);
+        }
+
+        // the reserved bit must be set and the low bits clear
+        assert!(!ConfigurationAttributes::from(0x40).is_valid());
+        assert!(!ConfigurationAttributes::from(0x81).is_valid());
+
+        let head = ConfigurationDescriptorHeader {
+            attributes: ConfigurationAttributes::new().remote_wakeup().bits(),
+            ..ConfigurationDescriptorHeader::new()
+        };
+        assert!(head.configuration_attributes().is_remote_wakeup());
+
+        // headers default to a valid bus-powered configuration
+        let head = ConfigurationDescriptorHeader::new();
+        assert_eq!(
+            head.configuration_attributes(),
+            ConfigurationAttributes::new()
+        );
+    }
+
+    #[test]
+    fn test_little_endian_fields() {
+        let descriptor = DeviceDescriptor {
+            vendor_id: le16(0x1d50),
+            product_id: le16(0x615b),
+            device_version_number: le16(0x0104),
+            ..DeviceDescriptor::new()
+        };
+        let bytes = descriptor.as_bytes();
+        assert_eq!(&bytes[2..4], &[0x00, 0x02]);
+        assert_eq!(&bytes[8..14], &[0x50, 0x1d, 0x5b, 0x61, 0x04, 0x01]);
+        assert_eq!(from_le16(descriptor.vendor_id), 0x1d50);
+        assert_eq!(descriptor.descriptor_version(), 0x0200);
+        assert_eq!(descriptor.vendor_id(), 0x1d50);
+        assert_eq!(descriptor.product_id(), 0x615b);
+        assert_eq!(descriptor.device_version_number(), 0x0104);
+
+        let descriptor = EndpointDescriptor {
+            max_packet_size: le16(512),
+            ..EndpointDescriptor::new()
+        };
+        assert_eq!(&descriptor.as_bytes()[4..6], &[0x00, 0x02]);
+        assert_eq!(descriptor.max_packet_size(), 512);
+
+        static INTERFACES: [InterfaceDescriptor; 1] = [InterfaceDescriptor::new(
+            InterfaceDescriptorHeader::new(),
+            &[],
+        )];
+        let mut configuration =
+            ConfigurationDescriptor::new(ConfigurationDescriptorHeader::new(), &INTERFACES);
+        configuration.set_total_length();
+        assert_eq!(&configuration.head.as_bytes()[2..4], &[18, 0]);
+    }
+
+    #[test]
+    fn test_device_descriptor_bytes() {
+        let descriptor = crate::class::cdc::DEVICE_DESCRIPTOR;
+        assert_eq!(
+            descriptor.as_bytes(),
+            &[
+                18,   // bLength
+                1,    // bDescriptorType
+                0x00, // bcdUSB
+                0x02, //
+                0xff, // bDeviceClass
+                0x00, // bDeviceSubClass
+                0x00, // bDeviceProtocol
+                8,    // bMaxPacketSize0
+                0x86, // idVendor
+                0x1a, //
+                0x23, // idProduct
+                0x75, //
+                0x64, // bcdDevice
+                0x02, //
+                1,    // iManufacturer
+                2,    // iProduct
+                3,    // iSerialNumber
+                1,    // bNumConfigurations
+            ]
+        );
+    }
+
+    #[test]
+    fn test_device_qualifier_descriptor_bytes() {
+        let descriptor = DeviceQualifierDescriptor {
+            descriptor_version: le16(0x0200),
+            device_class: 0xef,
+            device_subclass: 0x02,
+            device_protocol: 0x01,
+            max_packet_size: 64,
+            num_configurations: 2,
+            ..DeviceQualifierDescriptor::new()
+        };
+        assert_eq!(
+            descriptor.as_bytes(),
+            &[10, 6, 0x00, 0x02, 0xef, 0x02, 0x01, 64, 2, 0]
+        );
+    }
+
+    #[test]
+    fn test_small_descriptor_bytes() {
+        let descriptor = DebugDescriptor {
+            debug_in_endpoint_address: 0x81,
+            debug_out_endpoint_address: 0x01,
+            ..DebugDescriptor::new()
+        };
+        assert_eq!(descriptor.as_bytes(), &[4, 10, 0x81, 0x01]);
+
+        let descriptor = InterfaceAssociationDescriptor {
+            first_interface: 1,
+            interface_count: 2,
+            function_class: 0x02,
+            function_subclass: 0x02,
+            function_protocol: 0x01,
+            function_string_index: 4,
+            ..InterfaceAssociationDescriptor::new()
+        };
+        assert_eq!(descriptor.as_bytes(), &[8, 11, 1, 2, 0x02, 0x02, 0x01, 4]);
+
+        let descriptor = EndpointDescriptor {
+            endpoint_address: 0x83,
+            attributes: EndpointAttributes::interrupt().bits(),
+            max_packet_size: le16(0x0140),
+            interval: 4,
+            ..EndpointDescriptor::new()
+        };
+        assert_eq!(descriptor.as_bytes(), &[7, 5, 0x83, 0b11, 0x40, 0x01, 4]);
+
+        let descriptor = SuperSpeedEndpointCompanionDescriptor {
+            max_burst: 3,
+            attributes: 0,
+            bytes_per_interval: le16(0x0400),
+            ..SuperSpeedEndpointCompanionDescriptor::new()
+        };
+        assert_eq!(descriptor.as_bytes(), &[6, 48, 3, 0, 0x00, 0x04]);
+    }
+
+    #[test]
+    fn test_string_descriptor_bytes() {
+        let descriptor = StringDescriptorZero::new(&[
+            LanguageId::EnglishUnitedStates,
+            LanguageId::EnglishUnitedKingdom,
+        ]);
+        let bytes: std::vec::Vec<u8> = descriptor.iter().copied().collect();
+        assert_eq!(bytes, [6, 3, 0x09, 0x04, 0x09, 0x08]);
+
+        let descriptor = StringDescriptor::new("GSG");
+        let bytes: std::vec::Vec<u8> = descriptor.iter().collect();
+        assert_eq!(bytes, [8, 3, b'G', 0, b'S', 0, b'G', 0]);
+    }
+
+    #[test]
+    fn test_bos_descriptor_bytes() {
+        let mut descriptor = BosDescriptor::new();
+        let bytes: std::vec::Vec<u8> = descriptor.iter().collect();
+        assert_eq!(bytes, [5, 15, 5, 0, 0]);
+
+        descriptor.usb20_extension = Some(Usb20ExtensionDescriptor {
+            attributes: le32(usb20_extension_attributes::LPM),
+            ..Usb20ExtensionDescriptor::new()
+        });
+        descriptor.superspeed_usb = Some(SuperSpeedUsbDescriptor {
+            speeds_supported: le16(
+                superspeed_usb_attributes::SPEED_FULL
+                    | superspeed_usb_attributes::SPEED_HIGH
+                    | superspeed_usb_attributes::SPEED_SUPER,
+            ),
+            functionality_support: 1,
+            u1_device_exit_latency: 0x0a,
+            u2_device_exit_latency: le16(0x07ff),
+            ..SuperSpeedUsbDescriptor::new()
+        });
+
+        let bytes: std::vec::Vec<u8> = descriptor.iter().collect();
+        #[rustfmt::skip]
+        let expected: [u8; 22] = [
+            // bos
+            5, 15, 22, 0, 2,
+            // usb 2.0 extension
+            7, 16, 2, 0x02, 0, 0, 0,
+            // superspeed usb
+            10, 16, 3, 0, 0x0e, 0, 1, 0x0a, 0xff, 0x07,
+        ];
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn test_configuration_descriptor_bytes() {
+        let mut descriptor = crate::class::cdc::CONFIGURATION_DESCRIPTOR_0;
+        assert_eq!(descriptor.set_total_length(), 39);
+
+        let bytes: std::vec::Vec<u8> = descriptor.iter().copied().collect();
+        #[rustfmt::skip]
+        let expected: [u8; 39] = [
+            // configuration
+            9, 2, 39, 0, 1, 1, 1, 0x80, 50,
+            // interface 0
+            9, 4, 0, 0, 3, 0xff, 0x01, 0x02, 2,
+            // endpoints
+            7, 5, 0x82, 0b10, 0x00, 0x02, 0,
+            7, 5, 0x02, 0b10, 0x00, 0x02, 0,
+            7, 5, 0x81, 0b11, 8, 0, 1,
+        ];
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn test_configuration_descriptor_endpoint_slots() {
+        const SLOTS: usize = crate::class::cdc::acm::CONFIGURATION_DESCRIPTOR_0.endpoint_slots();
+        assert_eq!(SLOTS, 3);
+
+        let descriptor = ConfigurationDescriptor::new(ConfigurationDescriptorHeader::new(), &[]);
+        assert_eq!(descriptor.endpoint_slots(), 1);
+    }
+
+    #[test]
+    fn test_configuration_descriptor_multiple_interfaces_bytes() {
+        static ENDPOINTS_0: [EndpointDescriptor; 1] = [EndpointDescriptor {
+            endpoint_address: 0x81,
+            attributes: EndpointAttributes::interrupt().bits(),
+            max_packet_size: le16(16),
+            interval: 8,
+            ..EndpointDescriptor::new()
+        }];
+        static ENDPOINTS_1: [EndpointDescriptor; 2] = [
+            EndpointDescriptor {
+                endpoint_address: 0x82,
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(512),
+                ..EndpointDescriptor::new()
+            },
+            EndpointDescriptor {
+                endpoint_address: 0x02,
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(512),
+                ..EndpointDescriptor::new()
+            },
+        ];
+        static CLASS_DESCRIPTORS: [u8; 5] = [5, 0x24, 0x00, 0x10, 0x01];
+        static INTERFACES: [InterfaceDescriptor; 2] = [
+            InterfaceDescriptor::new(
+                InterfaceDescriptorHeader {
+                    interface_number: 0,
+                    interface_class: 0x02,
+                    interface_subclass: 0x02,
+                    interface_protocol: 0x01,
+                    ..InterfaceDescriptorHeader::new()
+                },
+                &ENDPOINTS_0,
+            )
+            .with_association(InterfaceAssociationDescriptor {
+                first_interface: 0,
+                interface_count: 2,
+                function_class: 0x02,
+                function_subclass: 0x02,
+                function_protocol: 0x01,
+                ..InterfaceAssociationDescriptor::new()
+            })
+            .with_class_descriptors(&CLASS_DESCRIPTORS),
+            InterfaceDescriptor::new(
+                InterfaceDescriptorHeader {
+                    interface_number: 1,
+                    interface_class: 0x0a,
+                    interface_string_index: 3,
+                    ..InterfaceDescriptorHeader::new()
+                },
+                &ENDPOINTS_1,
+            ),
+        ];
+        let mut descriptor = ConfigurationDescriptor::new(
+            ConfigurationDescriptorHeader {
+                configuration_value: 2,
+                attributes: 0b1010_0000,
+                max_power: 250,
+                ..ConfigurationDescriptorHeader::new()
+            },
+            &INTERFACES,
+        );
+        assert_eq!(descriptor.set_total_length(), 61);
+
+        let bytes: std::vec::Vec<u8> = descriptor.iter().copied().collect();
+        #[rustfmt::skip]
+        let expected: [u8; 61] = [
+            // configuration
+            9, 2, 61, 0, 2, 2, 0, 0b1010_0000, 250,
+            // interface association
+            8, 11, 0, 2, 0x02, 0x02, 0x01, 0,
+            // interface 0
+            9, 4, 0, 0, 1, 0x02, 0x02, 0x01, 0,
+            // class-specific
+            5, 0x24, 0x00, 0x10, 0x01,
+            // interface 0 endpoints
+            7, 5, 0x81, 0b11, 16, 0, 8,
+            // interface 1
+            9, 4, 1, 0, 2, 0x0a, 0, 0, 3,
+            // interface 1 endpoints
+            7, 5, 0x82, 0b10, 0x00, 0x02, 0,
+            7, 5, 0x02, 0b10, 0x00, 0x02, 0,
+        ];
+        assert_eq!(bytes, expected);
+
+        // the other speed configuration only differs in its type
+        descriptor.head.descriptor_type = DescriptorType::OtherSpeedConfiguration as u8;
+        assert_eq!(descriptor.iter().nth(1), Some(&7));
+    }
+
+    #[test]
+    fn test_superspeed_companions() {
+        static ENDPOINTS: [EndpointDescriptor; 2] = [
+            EndpointDescriptor {
+                endpoint_address: 0x01,
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(1024),
+                ..EndpointDescriptor::new()
+            },
+            EndpointDescriptor {
+                endpoint_address: 0x81,
+                attributes: EndpointAttributes::bulk().bits(),
+                max_packet_size: le16(1024),
+                ..EndpointDescriptor::new()
+            },
+        ];
+        static COMPANIONS: [SuperSpeedEndpointCompanionDescriptor; 2] = [
+            SuperSpeedEndpointCompanionDescriptor {
+                max_burst: 15,
+                ..SuperSpeedEndpointCompanionDescriptor::new()
+            },
+            SuperSpeedEndpointCompanionDescriptor::new(),
+        ];
+        static INTERFACES: [InterfaceDescriptor; 1] =
+            [
+                InterfaceDescriptor::new(InterfaceDescriptorHeader::new(), &ENDPOINTS)
+                    .with_superspeed_companions(&COMPANIONS),
+            ];
+        let mut configuration =
+            ConfigurationDescriptor::new(ConfigurationDescriptorHeader::new(), &INTERFACES);
+
+        assert_eq!(configuration.set_total_length(), 9 + 9 + 7 + 7);
+        assert_eq!(
+            configuration.iter_superspeed().count(),
+            9 + 9 + 7 + 6 + 7 + 6
+        );
+
+        let superspeed = configuration.to_superspeed();
+        let bytes: std::vec::Vec<u8> = superspeed.iter_superspeed().copied().collect();
+        assert_eq!(u16::from_le_bytes([bytes[2], bytes[3]]), 44);
+        assert_eq!(&bytes[25..31], &[6, 48, 15, 0, 0, 0]);
+        assert_eq!(bytes[31..33], [7, 5]);
+        assert_eq!(&bytes[38..44], &[6, 48, 0, 0, 0, 0]);
+    }
+}
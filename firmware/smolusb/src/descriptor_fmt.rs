@@ -0,0 +1,318 @@
+//! Human-readable descriptor dumps
+//!
+//! Formats a device descriptor and its configurations as an indented
+//! tree with decoded fields for bring-up and analyzer debugging:
+//!
+//! ```text
+//! Device
+//!   bcdUSB             2.00
+//!   class              0xff subclass 0x00 protocol 0x00
+//!   ...
+//! Configuration 1
+//!   ...
+//!   Interface 0 alt 0
+//!     ...
+//!     Endpoint 0x81 IN 1
+//!       ...
+//! ```
+//!
+//! Nothing is allocated, use [`format_descriptors`] to write the dump
+//! into a caller-provided buffer or [`DescriptorTree`] with any
+//! [`core::fmt::Write`] implementation.
+
+use core::fmt;
+
+use crate::consts::endpoint;
+use crate::descriptor::{
+    from_le16, ConfigurationDescriptor, DescriptorType, DeviceDescriptor, EndpointDescriptor,
+    InterfaceDescriptor, TransferType,
+};
+use crate::setup::Direction;
+
+/// Formats a device descriptor and its configurations as an indented
+/// tree
+pub struct DescriptorTree<'a> {
+    pub device: &'a DeviceDescriptor,
+    pub configurations: &'a [ConfigurationDescriptor<'a>],
+}
+
+impl<'a> DescriptorTree<'a> {
+    pub fn new(
+        device: &'a DeviceDescriptor,
+        configurations: &'a [ConfigurationDescriptor<'a>],
+    ) -> Self {
+        Self {
+            device,
+            configurations,
+        }
+    }
+}
+
+/// Write the descriptor tree into `buffer` and return it as a string
+///
+/// Returns an error if `buffer` is too small to hold the whole tree.
+pub fn format_descriptors<'b>(
+    buffer: &'b mut [u8],
+    device: &DeviceDescriptor,
+    configurations: &[ConfigurationDescriptor],
+) -> Result<&'b str, fmt::Error> {
+    let mut writer = SliceWriter { buffer, cursor: 0 };
+    fmt::write(
+        &mut writer,
+        format_args!("{}", DescriptorTree::new(device, configurations)),
+    )?;
+    let SliceWriter { buffer, cursor } = writer;
+    // only complete str's are ever written
+    core::str::from_utf8(&buffer[..cursor]).map_err(|_| fmt::Error)
+}
+
+// - trait: Display -----------------------------------------------------------
+
+impl<'a> fmt::Display for DescriptorTree<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_device(f, self.device)?;
+        for configuration in self.configurations {
+            write_configuration(f, configuration)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_device(f: &mut fmt::Formatter<'_>, device: &DeviceDescriptor) -> fmt::Result {
+    writeln!(f, "Device")?;
+    writeln!(
+        f,
+        "  bcdUSB             {}",
+        Bcd(from_le16(device.descriptor_version))
+    )?;
+    writeln!(
+        f,
+        "  class              {:#04x} subclass {:#04x} protocol {:#04x}",
+        device.device_class, device.device_subclass, device.device_protocol
+    )?;
+    writeln!(f, "  bMaxPacketSize0    {}", device.max_packet_size)?;
+    writeln!(
+        f,
+        "  idVendor:idProduct {:04x}:{:04x}",
+        from_le16(device.vendor_id),
+        from_le16(device.product_id)
+    )?;
+    writeln!(
+        f,
+        "  bcdDevice          {}",
+        Bcd(from_le16(device.device_version_number))
+    )?;
+    writeln!(
+        f,
+        "  strings            manufacturer {} product {} serial {}",
+        device.manufacturer_string_index, device.product_string_index, device.serial_string_index
+    )?;
+    writeln!(f, "  configurations     {}", device.num_configurations)
+}
+
+fn write_configuration(
+    f: &mut fmt::Formatter<'_>,
+    configuration: &ConfigurationDescriptor,
+) -> fmt::Result {
+    let head = &configuration.head;
+    if head.descriptor_type == DescriptorType::OtherSpeedConfiguration as u8 {
+        writeln!(f, "Other speed configuration {}", head.configuration_value)?;
+    } else {
+        writeln!(f, "Configuration {}", head.configuration_value)?;
+    }
+    writeln!(f, "  total length       {}", configuration.iter().count())?;
+    writeln!(
+        f,
+        "  string             {}",
+        head.configuration_string_index
+    )?;
+
+    let attributes = head.configuration_attributes();
+    write!(
+        f,
+        "  attributes         {:#04x} {}",
+        attributes.bits(),
+        if attributes.is_self_powered() {
+            "self-powered"
+        } else {
+            "bus-powered"
+        }
+    )?;
+    if attributes.is_remote_wakeup() {
+        write!(f, " remote-wakeup")?;
+    }
+    if !attributes.is_valid() {
+        write!(f, " (invalid)")?;
+    }
+    writeln!(f)?;
+    // bMaxPower is in units of 2 mA
+    writeln!(f, "  max power          {} mA", head.max_power as u16 * 2)?;
+
+    for interface in configuration.tail {
+        write_interface(f, interface)?;
+    }
+    Ok(())
+}
+
+fn write_interface(f: &mut fmt::Formatter<'_>, interface: &InterfaceDescriptor) -> fmt::Result {
+    if let Some(association) = interface.association() {
+        writeln!(
+            f,
+            "  Association interfaces {}..{} class {:#04x} subclass {:#04x} protocol {:#04x}",
+            association.first_interface,
+            association.first_interface as u16 + association.interface_count as u16,
+            association.function_class,
+            association.function_subclass,
+            association.function_protocol
+        )?;
+    }
+
+    let head = interface.header();
+    writeln!(
+        f,
+        "  Interface {} alt {}",
+        head.interface_number, head.alternate_setting
+    )?;
+    writeln!(
+        f,
+        "    class            {:#04x} subclass {:#04x} protocol {:#04x}",
+        head.interface_class, head.interface_subclass, head.interface_protocol
+    )?;
+    writeln!(f, "    string           {}", head.interface_string_index)?;
+    if !interface.class_descriptors().is_empty() {
+        writeln!(
+            f,
+            "    class-specific   {} bytes",
+            interface.class_descriptors().len()
+        )?;
+    }
+
+    for endpoint in interface.endpoints() {
+        write_endpoint(f, endpoint)?;
+    }
+    Ok(())
+}
+
+fn write_endpoint(f: &mut fmt::Formatter<'_>, descriptor: &EndpointDescriptor) -> fmt::Result {
+    let address = descriptor.endpoint_address;
+    let direction = match Direction::from_endpoint_address(address) {
+        Direction::HostToDevice => "OUT",
+        Direction::DeviceToHost => "IN",
+    };
+    writeln!(
+        f,
+        "    Endpoint {:#04x} {} {}",
+        address,
+        direction,
+        address & endpoint::NUMBER_MASK
+    )?;
+
+    let attributes = descriptor.endpoint_attributes();
+    let transfer_type = match attributes.transfer_type() {
+        TransferType::Control => "control",
+        TransferType::Isochronous => "isochronous",
+        TransferType::Bulk => "bulk",
+        TransferType::Interrupt => "interrupt",
+    };
+    write!(f, "      type           {}", transfer_type)?;
+    if let (Some(synchronization_type), Some(usage_type)) =
+        (attributes.synchronization_type(), attributes.usage_type())
+    {
+        write!(f, " {:?} {:?}", synchronization_type, usage_type)?;
+    }
+    writeln!(f)?;
+    writeln!(f, "      max packet     {}", descriptor.max_packet_size())?;
+    writeln!(f, "      interval       {}", descriptor.interval)
+}
+
+/// Formats a binary-coded decimal version number, e.g. 0x0210 as "2.10"
+struct Bcd(u16);
+
+impl fmt::Display for Bcd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:x}.{:02x}", self.0 >> 8, self.0 & 0xff)
+    }
+}
+
+// - SliceWriter --------------------------------------------------------------
+
+/// `fmt::Write` into a byte slice, fails once the slice is full
+struct SliceWriter<'b> {
+    buffer: &'b mut [u8],
+    cursor: usize,
+}
+
+impl<'b> fmt::Write for SliceWriter<'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.cursor + s.len();
+        let dest = self.buffer.get_mut(self.cursor..end).ok_or(fmt::Error)?;
+        dest.copy_from_slice(s.as_bytes());
+        self.cursor = end;
+        Ok(())
+    }
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::class::cdc::acm;
+
+    const ACM_TREE: &str = "\
+Device
+  bcdUSB             2.00
+  class              0xef subclass 0x02 protocol 0x01
+  bMaxPacketSize0    64
+  idVendor:idProduct 1209:0002
+  bcdDevice          1.00
+  strings            manufacturer 1 product 2 serial 3
+  configurations     1
+Configuration 1
+  total length       75
+  string             1
+  attributes         0x80 bus-powered
+  max power          100 mA
+  Association interfaces 0..2 class 0x02 subclass 0x02 protocol 0x00
+  Interface 0 alt 0
+    class            0x02 subclass 0x02 protocol 0x00
+    string           2
+    class-specific   19 bytes
+    Endpoint 0x81 IN 1
+      type           interrupt
+      max packet     16
+      interval       8
+  Interface 1 alt 0
+    class            0x0a subclass 0x00 protocol 0x00
+    string           2
+    Endpoint 0x82 IN 2
+      type           bulk
+      max packet     512
+      interval       0
+    Endpoint 0x02 OUT 2
+      type           bulk
+      max packet     512
+      interval       0
+";
+
+    #[test]
+    fn test_format_descriptors() {
+        let mut buffer = [0; 2048];
+        let tree = format_descriptors(
+            &mut buffer,
+            &acm::DEVICE_DESCRIPTOR,
+            &[acm::CONFIGURATION_DESCRIPTOR_0],
+        )
+        .unwrap();
+        assert_eq!(tree, ACM_TREE);
+
+        // a short buffer is an error rather than a truncated tree
+        let mut buffer = [0; 64];
+        assert!(format_descriptors(
+            &mut buffer,
+            &acm::DEVICE_DESCRIPTOR,
+            &[acm::CONFIGURATION_DESCRIPTOR_0],
+        )
+        .is_err());
+    }
+}
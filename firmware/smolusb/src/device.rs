@@ -1,17 +1,59 @@
 #![allow(dead_code, unused_imports, unused_variables)] // TODO
 
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use core::sync::atomic::{AtomicU8, Ordering};
 
 use log::{debug, error, info, trace, warn};
 
-use crate::control::{Control, ControlEvent};
+use crate::consts::endpoint;
+use crate::control::{Control, ControlEvent, TransferStage};
+#[cfg(feature = "control-capture")]
+use crate::control_capture::{ControlCapture, CAPTURE_RECORDS};
 use crate::descriptor::*;
-use crate::error::{SmolError, SmolResult};
-use crate::event::UsbEvent;
+use crate::error::{ConnectError, SmolError, SmolResult};
+use crate::event::{InterruptSource, UsbEvent};
+use crate::policy::{RecoveryPolicy, TransferPolicy};
 use crate::setup::{Direction, Feature, Recipient, Request, RequestType, SetupPacket};
 use crate::traits::AsByteSliceIterator;
+use crate::traits::InterruptControl;
+use crate::traits::PollEvent;
+use crate::traits::ReadEndpoint;
 use crate::traits::UsbDriver;
+use crate::{EP_MAX_ENDPOINTS, EP_MAX_PACKET_SIZE};
+
+use zerocopy::AsBytes;
+
+/// Maximum current draw a bus-powered USB 2.0 device may advertise, in mA
+pub const MAX_POWER_MA: u16 = 500;
+
+/// Maximum size of the serialized configuration descriptor cache
+///
+/// Configuration descriptors larger than this are serialized on every
+/// request.
+#[cfg(feature = "descriptor-cache")]
+pub const DESCRIPTOR_CACHE_SIZE: usize = 512;
+
+/// Serialized configuration descriptor
+#[cfg(feature = "descriptor-cache")]
+type DescriptorCache = heapless::Vec<u8, DESCRIPTOR_CACHE_SIZE>;
+
+/// Serialize a configuration descriptor for the descriptor cache
+///
+/// Returns `None` if the descriptor does not fit.
+#[cfg(feature = "descriptor-cache")]
+fn serialize(configuration_descriptor: &ConfigurationDescriptor) -> Option<DescriptorCache> {
+    let mut cache = DescriptorCache::new();
+    for byte in configuration_descriptor.iter() {
+        if cache.push(*byte).is_err() {
+            warn!(
+                "DEVICE configuration descriptor exceeds cache size of {} bytes",
+                DESCRIPTOR_CACHE_SIZE
+            );
+            return None;
+        }
+    }
+    Some(cache)
+}
 
 ///! `smolusb` device implementation for Luna USB peripheral
 ///!
@@ -24,7 +66,7 @@ use crate::traits::UsbDriver;
 /// Note: These match the gateware peripheral so the mapping isn't particularly meaningful in other contexts.
 ///
 /// TODO also, these don't match what I'm seeing from the host side ???
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum Speed {
     Low = 2,        // 1.5 Mbps
@@ -45,6 +87,32 @@ impl From<u8> for Speed {
     }
 }
 
+/// Result of a class, vendor or string request callback
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ControlOutcome {
+    /// The callback responded to the request
+    Handled,
+    /// The request is not supported, `UsbDevice` will stall it
+    Stall,
+}
+
+/// What `UsbDevice` does with control requests no handler claims
+///
+/// See [`UsbDevice::set_unknown_request_policy`].
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum UnknownRequestPolicy {
+    /// Stall the request, as required by the USB specification
+    #[default]
+    StallUnknown,
+    /// Complete the request without doing anything
+    ///
+    /// IN requests are answered with a zero length data stage and OUT
+    /// requests have their status stage acknowledged. This is not
+    /// compliant: hosts treat the request as having succeeded. It is
+    /// useful when emulating or fuzzing to see how far a host gets.
+    IgnoreUnknown,
+}
+
 /// USB device state
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum DeviceState {
@@ -52,48 +120,302 @@ pub enum DeviceState {
     Reset,
     Addressed,
     Configured,
-    Suspended, // TODO first need to add suspend signal to eptri
+    /// Entered after the bus has been idle for the idle timeout, see
+    /// [`UsbDevice::set_idle_timeout`]
+    Suspended,
+}
+
+/// Per-endpoint transfer statistics
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EndpointStats {
+    /// Number of packets transferred
+    pub packets: u32,
+    /// Number of bytes read with [`UsbDevice::read_packet`], OUT endpoints only
+    pub bytes: u32,
+    /// Number of packets longer than the endpoint's maximum packet size
+    pub babble: u32,
+    /// Number of bytes discarded by [`UsbDevice::read_packet`] because
+    /// they did not fit the packet, OUT endpoints only
+    pub overflow: u32,
+}
+
+/// Per-endpoint state tracked by `UsbDevice`
+///
+/// All arrays are indexed by endpoint number.
+#[derive(Debug, Clone, Copy)]
+pub struct EndpointState<const MAX_ENDPOINTS: usize> {
+    /// IN endpoints with the halt feature set
+    pub halted_in: [bool; MAX_ENDPOINTS],
+    /// OUT endpoints with the halt feature set
+    pub halted_out: [bool; MAX_ENDPOINTS],
+    /// OUT endpoints primed to receive a packet
+    ///
+    /// Only kept accurate by the `UsbDevice` methods, priming or
+    /// reading an endpoint through the driver directly bypasses it.
+    pub primed_out: [bool; MAX_ENDPOINTS],
+    /// IN endpoints paused via [`UsbDevice::pause_endpoint`]
+    pub paused_in: [bool; MAX_ENDPOINTS],
+    /// OUT endpoints paused via [`UsbDevice::pause_endpoint`]
+    pub paused_out: [bool; MAX_ENDPOINTS],
+    /// IN endpoint statistics
+    pub stats_in: [EndpointStats; MAX_ENDPOINTS],
+    /// OUT endpoint statistics
+    pub stats_out: [EndpointStats; MAX_ENDPOINTS],
+    /// Interface number owning each IN endpoint
+    pub owner_in: [Option<u8>; MAX_ENDPOINTS],
+    /// Interface number owning each OUT endpoint
+    pub owner_out: [Option<u8>; MAX_ENDPOINTS],
+    /// Maximum packet size of each IN endpoint
+    pub max_packet_size_in: [Option<u16>; MAX_ENDPOINTS],
+    /// Maximum packet size of each OUT endpoint
+    pub max_packet_size_out: [Option<u16>; MAX_ENDPOINTS],
+}
+
+impl<const MAX_ENDPOINTS: usize> EndpointState<MAX_ENDPOINTS> {
+    pub const fn new() -> Self {
+        Self {
+            halted_in: [false; MAX_ENDPOINTS],
+            halted_out: [false; MAX_ENDPOINTS],
+            primed_out: [false; MAX_ENDPOINTS],
+            paused_in: [false; MAX_ENDPOINTS],
+            paused_out: [false; MAX_ENDPOINTS],
+            stats_in: [EndpointStats {
+                packets: 0,
+                bytes: 0,
+                babble: 0,
+                overflow: 0,
+            }; MAX_ENDPOINTS],
+            stats_out: [EndpointStats {
+                packets: 0,
+                bytes: 0,
+                babble: 0,
+                overflow: 0,
+            }; MAX_ENDPOINTS],
+            owner_in: [None; MAX_ENDPOINTS],
+            owner_out: [None; MAX_ENDPOINTS],
+            max_packet_size_in: [None; MAX_ENDPOINTS],
+            max_packet_size_out: [None; MAX_ENDPOINTS],
+        }
+    }
+
+    /// Record the owning interface of every endpoint in the given
+    /// configuration.
+    pub fn set_owners(&mut self, configuration_descriptor: &ConfigurationDescriptor) {
+        self.owner_in = [None; MAX_ENDPOINTS];
+        self.owner_out = [None; MAX_ENDPOINTS];
+        for interface in configuration_descriptor.tail {
+            let interface_number = interface.header().interface_number;
+            for endpoint in interface.endpoints() {
+                let endpoint_address = endpoint.endpoint_address;
+                let endpoint_number = (endpoint_address & endpoint::NUMBER_MASK) as usize;
+                let owner = match Direction::from_endpoint_address(endpoint_address) {
+                    Direction::HostToDevice => self.owner_out.get_mut(endpoint_number),
+                    Direction::DeviceToHost => self.owner_in.get_mut(endpoint_number),
+                };
+                if let Some(owner) = owner {
+                    *owner = Some(interface_number);
+                }
+            }
+        }
+    }
+
+    /// Record the maximum packet size of every endpoint in the given
+    /// configuration.
+    ///
+    /// An endpoint declared by several alternate settings keeps the
+    /// size of the first.
+    pub fn set_max_packet_sizes(&mut self, configuration_descriptor: &ConfigurationDescriptor) {
+        self.max_packet_size_in = [None; MAX_ENDPOINTS];
+        self.max_packet_size_out = [None; MAX_ENDPOINTS];
+        for endpoint in configuration_descriptor.endpoints() {
+            let endpoint_address = endpoint.endpoint_address;
+            let endpoint_number = (endpoint_address & endpoint::NUMBER_MASK) as usize;
+            let max_packet_size = match Direction::from_endpoint_address(endpoint_address) {
+                Direction::HostToDevice => self.max_packet_size_out.get_mut(endpoint_number),
+                Direction::DeviceToHost => self.max_packet_size_in.get_mut(endpoint_number),
+            };
+            if let Some(max_packet_size @ None) = max_packet_size {
+                *max_packet_size = Some(endpoint.max_packet_size());
+            }
+        }
+    }
+
+    /// Clear halt and primed state for all endpoints
+    pub fn reset(&mut self) {
+        self.halted_in = [false; MAX_ENDPOINTS];
+        self.halted_out = [false; MAX_ENDPOINTS];
+        self.primed_out = [false; MAX_ENDPOINTS];
+    }
+
+    /// Clear the statistics for all endpoints
+    pub fn reset_stats(&mut self) {
+        self.stats_in = [EndpointStats::default(); MAX_ENDPOINTS];
+        self.stats_out = [EndpointStats::default(); MAX_ENDPOINTS];
+    }
+}
+
+impl<const MAX_ENDPOINTS: usize> Default for EndpointState<MAX_ENDPOINTS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A complete set of device descriptors
+///
+/// Used to replace the descriptors of a running `UsbDevice`, see
+/// [`UsbDevice::update_descriptors`].
+pub struct DescriptorSet<'a> {
+    pub device_descriptor: DeviceDescriptor,
+    pub configuration_descriptor: ConfigurationDescriptor<'a>,
+    pub device_qualifier_descriptor: Option<DeviceQualifierDescriptor>,
+    pub other_speed_configuration_descriptor: Option<ConfigurationDescriptor<'a>>,
+    pub string_descriptor_zero: StringDescriptorZero<'a>,
+    pub string_descriptors: &'a [&'a StringDescriptor<'a>],
 }
 
+/// Handler for class, vendor and string requests, called with the
+/// request's `request` byte or the string descriptor index
+pub type RequestCallback<
+    'a,
+    D,
+    const MAX_RECEIVE_SIZE: usize,
+    const MAX_ENDPOINTS: usize,
+    const MAX_PACKET_SIZE: usize,
+> = fn(
+    device: &UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>,
+    setup_packet: &SetupPacket,
+    request: u8,
+) -> ControlOutcome;
+
+/// Called once a transfer on an endpoint completes
+pub type EndpointCompleteCallback<
+    'a,
+    D,
+    const MAX_RECEIVE_SIZE: usize,
+    const MAX_ENDPOINTS: usize,
+    const MAX_PACKET_SIZE: usize,
+> = fn(
+    device: &UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>,
+    endpoint_number: u8,
+);
+
 /// A USB device
 ///
 /// `UsbDevice` implements the control portion of the USB
 /// specification and consists of:
 ///
-///     * a hal driver
-///     * a device descriptor
-///     * a configuration descriptor
-///     * a set of string descriptors
+/// * a hal driver
+/// * a device descriptor
+/// * one or more configuration descriptors
+/// * a set of string descriptors
+///
+/// `MAX_RECEIVE_SIZE` sizes the buffer holding the data stage of
+/// control OUT requests, e.g. `SET_DESCRIPTOR` payloads, and limits
+/// the largest OUT data stage the device accepts. IN responses are
+/// written from the caller's data without being buffered, so their
+/// length is not limited by it.
+///
+/// `MAX_ENDPOINTS` sizes the per-endpoint state and defaults to
+/// [`EP_MAX_ENDPOINTS`]. Firmware using fewer endpoints can reduce
+/// it to save RAM.
 ///
-pub struct UsbDevice<'a, D, const MAX_RECEIVE_SIZE: usize> {
+/// `MAX_PACKET_SIZE` sizes the buffers returned by
+/// [`UsbDevice::read_packet`] and defaults to [`EP_MAX_PACKET_SIZE`].
+/// Firmware with only small endpoints can reduce it to save RAM.
+///
+/// With the `descriptor-cache` feature enabled the configuration
+/// descriptor is serialized once when the descriptors are set rather
+/// than on every `GET_DESCRIPTOR` request. The device descriptor is
+/// already stored in wire format.
+///
+/// With the `control-capture` feature enabled the control requests
+/// processed by [`UsbDevice::dispatch_control`] are recorded to
+/// `capture` once it is enabled, see [`crate::control_capture`].
+pub struct UsbDevice<
+    'a,
+    D,
+    const MAX_RECEIVE_SIZE: usize,
+    const MAX_ENDPOINTS: usize = { EP_MAX_ENDPOINTS },
+    const MAX_PACKET_SIZE: usize = { EP_MAX_PACKET_SIZE },
+> {
     pub hal_driver: D,
 
     device_descriptor: DeviceDescriptor,
     configuration_descriptor: ConfigurationDescriptor<'a>,
     device_qualifier_descriptor: Option<DeviceQualifierDescriptor>,
     other_speed_configuration_descriptor: Option<ConfigurationDescriptor<'a>>,
+    additional_configuration_descriptors: &'a [ConfigurationDescriptor<'a>],
     string_descriptor_zero: StringDescriptorZero<'a>,
     string_descriptors: &'a [&'a StringDescriptor<'a>],
+    debug_descriptor: Option<DebugDescriptor>,
+    bos_descriptor: Option<BosDescriptor>,
+    #[cfg(feature = "descriptor-cache")]
+    configuration_descriptor_cache: Option<DescriptorCache>,
 
     pub control: Control<'a, D, MAX_RECEIVE_SIZE>,
 
     pub state: RefCell<DeviceState>,
+    speed: Cell<Speed>,
+    pub endpoints: RefCell<EndpointState<MAX_ENDPOINTS>>,
+    #[cfg(feature = "control-capture")]
+    pub capture: RefCell<ControlCapture<CAPTURE_RECORDS>>,
     pub current_configuration: AtomicU8,
-    pub feature_remote_wakeup: bool,
+    /// Set by the host with `SET_FEATURE(DEVICE_REMOTE_WAKEUP)`,
+    /// cleared by `CLEAR_FEATURE` and bus resets
+    pub feature_remote_wakeup: Cell<bool>,
+    high_speed_capable: bool,
+    self_powered: bool,
+    transfer_policy: TransferPolicy,
+    unknown_request_policy: UnknownRequestPolicy,
+    recovery_policy: RecoveryPolicy,
+    /// Number of errors returned by `dispatch_control` since the last
+    /// completed control transfer
+    control_errors: Cell<usize>,
+    /// Number of reconnects made by the recovery policy
+    recoveries: Cell<usize>,
     pub quirk_set_address_before_status: bool,
-
-    pub cb_class_request: Option<
-        fn(device: &UsbDevice<'a, D, MAX_RECEIVE_SIZE>, setup_packet: &SetupPacket, request: u8),
-    >,
-    pub cb_vendor_request: Option<
-        fn(device: &UsbDevice<'a, D, MAX_RECEIVE_SIZE>, setup_packet: &SetupPacket, request: u8),
-    >,
-    pub cb_string_request: Option<
-        fn(device: &UsbDevice<'a, D, MAX_RECEIVE_SIZE>, setup_packet: &SetupPacket, index: u8),
-    >,
+    /// Address requested by a SET_ADDRESS received while already addressed
+    readdressed: Cell<Option<u8>>,
+    /// Address to activate once the SET_ADDRESS status stage is sent
+    pending_address: Cell<Option<u8>>,
+    idle_timeout_ms: Option<u32>,
+    /// Milliseconds since the last bus activity
+    idle_ms: Cell<u32>,
+    /// State to return to when waking from suspend
+    suspended_from: Cell<Option<DeviceState>>,
+
+    pub cb_class_request:
+        Option<RequestCallback<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>>,
+    pub cb_vendor_request:
+        Option<RequestCallback<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>>,
+    pub cb_string_request:
+        Option<RequestCallback<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>>,
+    /// Called on entering suspend, e.g. to enter a low-power state
+    pub cb_suspend:
+        Option<fn(device: &UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>)>,
+    /// Called when bus activity wakes the device from suspend
+    pub cb_resume:
+        Option<fn(device: &UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>)>,
+    /// Called once the device has reconnected to recover from control
+    /// errors, e.g. to re-enable the interrupts disabled by the reconnect
+    pub cb_recovery:
+        Option<fn(device: &UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>)>,
+
+    cb_endpoint_complete_in: [Option<
+        EndpointCompleteCallback<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>,
+    >; MAX_ENDPOINTS],
+    cb_endpoint_complete_out: [Option<
+        EndpointCompleteCallback<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>,
+    >; MAX_ENDPOINTS],
 }
 
-impl<'a, D, const MAX_RECEIVE_SIZE: usize> UsbDevice<'a, D, MAX_RECEIVE_SIZE>
+impl<
+        'a,
+        D,
+        const MAX_RECEIVE_SIZE: usize,
+        const MAX_ENDPOINTS: usize,
+        const MAX_PACKET_SIZE: usize,
+    > UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>
 where
     D: UsbDriver,
 {
@@ -108,6 +430,15 @@ where
         // TODO this ain't great but it will do for now
         let mut configuration_descriptor = configuration_descriptor.clone();
         let total_length = configuration_descriptor.set_total_length();
+        let self_powered = configuration_descriptor
+            .head
+            .configuration_attributes()
+            .is_self_powered();
+        let mut endpoints = EndpointState::new();
+        endpoints.set_owners(&configuration_descriptor);
+        endpoints.set_max_packet_sizes(&configuration_descriptor);
+        #[cfg(feature = "descriptor-cache")]
+        let configuration_descriptor_cache = serialize(&configuration_descriptor);
 
         Self {
             hal_driver,
@@ -116,19 +447,46 @@ where
             configuration_descriptor,
             device_qualifier_descriptor: None,
             other_speed_configuration_descriptor: None,
+            additional_configuration_descriptors: &[],
             string_descriptor_zero,
             string_descriptors,
+            debug_descriptor: None,
+            bos_descriptor: None,
+            #[cfg(feature = "descriptor-cache")]
+            configuration_descriptor_cache,
 
             control: Control::new(),
 
             state: DeviceState::None.into(),
+            speed: Cell::new(Speed::High),
+            endpoints: endpoints.into(),
+            #[cfg(feature = "control-capture")]
+            capture: RefCell::new(ControlCapture::new()),
             current_configuration: 0.into(),
-            feature_remote_wakeup: false,
+            feature_remote_wakeup: Cell::new(false),
+            high_speed_capable: true,
+            self_powered,
+            transfer_policy: TransferPolicy::DEFAULT,
+            unknown_request_policy: UnknownRequestPolicy::StallUnknown,
+            recovery_policy: RecoveryPolicy::DISABLED,
+            control_errors: Cell::new(0),
+            recoveries: Cell::new(0),
             quirk_set_address_before_status: false,
+            readdressed: Cell::new(None),
+            pending_address: Cell::new(None),
+            idle_timeout_ms: None,
+            idle_ms: Cell::new(0),
+            suspended_from: Cell::new(None),
 
             cb_class_request: None,
             cb_vendor_request: None,
             cb_string_request: None,
+            cb_suspend: None,
+            cb_resume: None,
+            cb_recovery: None,
+
+            cb_endpoint_complete_in: [None; MAX_ENDPOINTS],
+            cb_endpoint_complete_out: [None; MAX_ENDPOINTS],
         }
     }
 
@@ -136,6 +494,36 @@ where
         *self.state.borrow()
     }
 
+    /// Returns the speed the device was last enumerated at
+    pub fn speed(&self) -> Speed {
+        self.speed.get()
+    }
+
+    /// Returns the maximum packet size of the control endpoint at the
+    /// current speed
+    ///
+    /// At full speed this is the size advertised by the device
+    /// descriptor, one of 8, 16, 32 or 64 bytes. The size is fixed at
+    /// 8 bytes for low speed, 64 bytes for high speed and 512 bytes for
+    /// SuperSpeed.
+    pub fn ep0_max_packet_size(&self) -> u16 {
+        match self.speed() {
+            Speed::Low => 8,
+            Speed::Full => match self.device_descriptor.max_packet_size {
+                max_packet_size @ (8 | 16 | 32 | 64) => max_packet_size.into(),
+                max_packet_size => {
+                    warn!(
+                        "DEVICE invalid full speed ep0 max packet size: {}",
+                        max_packet_size
+                    );
+                    64
+                }
+            },
+            Speed::High => 64,
+            Speed::SuperSpeed => 512,
+        }
+    }
+
     pub fn set_device_qualifier_descriptor(
         &mut self,
         device_qualifier_descriptor: DeviceQualifierDescriptor,
@@ -143,6 +531,249 @@ where
         self.device_qualifier_descriptor = Some(device_qualifier_descriptor);
     }
 
+    /// Set whether the device is capable of operating at high speed.
+    ///
+    /// Devices that are not high-speed capable stall requests for the
+    /// device qualifier and other speed configuration descriptors
+    /// even if these have been set. Defaults to `true`.
+    pub fn set_high_speed_capable(&mut self, high_speed_capable: bool) {
+        self.high_speed_capable = high_speed_capable;
+    }
+
+    /// Set the maximum length of an accepted control OUT data stage.
+    ///
+    /// Requests with a larger `wLength` are stalled. Defaults to
+    /// [`DEFAULT_MAX_TRANSFER_SIZE`](crate::control::DEFAULT_MAX_TRANSFER_SIZE).
+    pub fn set_max_control_transfer_size(&mut self, max_transfer_size: usize) {
+        self.control.set_max_transfer_size(max_transfer_size);
+    }
+
+    /// Set the retry and timeout limits used by the device's transfer
+    /// helpers, e.g. when priming OUT endpoints.
+    ///
+    /// Defaults to [`TransferPolicy::DEFAULT`].
+    pub fn set_transfer_policy(&mut self, transfer_policy: TransferPolicy) {
+        self.transfer_policy = transfer_policy;
+    }
+
+    /// Returns the retry and timeout limits used by the device's
+    /// transfer helpers.
+    pub fn transfer_policy(&self) -> TransferPolicy {
+        self.transfer_policy
+    }
+
+    /// Set what happens to control requests no handler claims.
+    ///
+    /// This covers class and vendor requests whose callback returns
+    /// [`ControlOutcome::Stall`] and control events [`UsbDevice::poll`]
+    /// can't handle. Malformed standard requests are always stalled.
+    /// Defaults to [`UnknownRequestPolicy::StallUnknown`].
+    pub fn set_unknown_request_policy(&mut self, policy: UnknownRequestPolicy) {
+        self.unknown_request_policy = policy;
+    }
+
+    /// Returns the policy for control requests no handler claims.
+    pub fn unknown_request_policy(&self) -> UnknownRequestPolicy {
+        self.unknown_request_policy
+    }
+
+    /// Set how the device recovers from repeated control errors.
+    ///
+    /// Defaults to [`RecoveryPolicy::DISABLED`].
+    pub fn set_recovery_policy(&mut self, recovery_policy: RecoveryPolicy) {
+        self.recovery_policy = recovery_policy;
+    }
+
+    /// Returns the policy for recovering from repeated control errors.
+    pub fn recovery_policy(&self) -> RecoveryPolicy {
+        self.recovery_policy
+    }
+
+    /// Returns the number of times the device reconnected to recover
+    /// from repeated control errors.
+    ///
+    /// Reconnecting disables the driver's events, interrupt-driven
+    /// firmware can re-enable its interrupts from `cb_recovery`.
+    pub fn recoveries(&self) -> usize {
+        self.recoveries.get()
+    }
+
+    /// Stall or ignore a control request no handler claims, depending on
+    /// the device's [`UnknownRequestPolicy`].
+    pub fn unknown_control_request(&self, setup_packet: &SetupPacket) {
+        match self.unknown_request_policy {
+            UnknownRequestPolicy::StallUnknown => self.hal_driver.stall_control_request(),
+            UnknownRequestPolicy::IgnoreUnknown => {
+                if setup_packet.direction() == Direction::DeviceToHost
+                    && setup_packet.length > 0
+                    && self.hal_driver.write(0, [].into_iter()).is_err()
+                {
+                    self.hal_driver.stall_control_request();
+                    return;
+                }
+                self.hal_driver.ack_status_stage(setup_packet);
+            }
+        }
+    }
+
+    /// Respond to the IN control request in progress with `data` and
+    /// complete its status stage.
+    ///
+    /// The response is truncated to the request's `wLength` and sent in
+    /// packets of [`UsbDevice::ep0_max_packet_size`]. A response shorter
+    /// than `wLength` which ends on a packet boundary is terminated with
+    /// a zero length packet.
+    ///
+    /// Class and vendor request handlers should prefer this to writing
+    /// to the control endpoint directly:
+    ///
+    /// ```ignore
+    /// fn handle_vendor_request(
+    ///     device: &UsbDevice<Usb0, 8>,
+    ///     _setup_packet: &SetupPacket,
+    ///     request: u8,
+    /// ) -> ControlOutcome {
+    ///     match request {
+    ///         0x01 => match device.control_respond(b"firmware v1.0") {
+    ///             Ok(_) => ControlOutcome::Handled,
+    ///             Err(_) => ControlOutcome::Stall,
+    ///         },
+    ///         _ => ControlOutcome::Stall,
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// Returns the number of bytes written or
+    /// [`SmolError::InvalidControlStage`] if no IN control request is
+    /// waiting for a response.
+    pub fn control_respond(&self, data: &[u8]) -> SmolResult<usize> {
+        let setup_packet = self.control.setup_packet();
+
+        match self.control.stage() {
+            TransferStage::DataIn => (),
+            // an IN request without a data stage only needs its status stage
+            TransferStage::StatusIn if setup_packet.direction() == Direction::DeviceToHost => {
+                self.hal_driver.ack_status_stage(setup_packet);
+                return Ok(0);
+            }
+            stage => {
+                warn!("DEVICE control_respond() in stage {:?}", stage);
+                return Err(SmolError::InvalidControlStage);
+            }
+        }
+
+        let length =
+            self.write_data_stage(data.iter().copied(), usize::from(setup_packet.length))?;
+        self.hal_driver.ack_status_stage(setup_packet);
+
+        Ok(length)
+    }
+
+    /// Returns and clears the address of the last SET_ADDRESS request
+    /// received while the device was already addressed.
+    ///
+    /// Hosts set the address once per reset, a repeated SET_ADDRESS
+    /// points at host misbehaviour. It is accepted in the Addressed
+    /// state and stalled in the Configured state, where the USB
+    /// specification leaves the device's behaviour undefined.
+    pub fn take_readdressed(&self) -> Option<u8> {
+        self.readdressed.take()
+    }
+
+    /// Set the power source reported by `GET_STATUS(Device)`.
+    ///
+    /// Defaults to the self-powered bit of the configuration descriptor
+    /// attributes but may be changed at runtime to reflect the device's
+    /// current power source.
+    pub fn set_self_powered(&mut self, self_powered: bool) {
+        self.self_powered = self_powered;
+    }
+
+    /// Returns true if the host has enabled remote wakeup, as reported
+    /// by `GET_STATUS(Device)`.
+    ///
+    /// The host can only enable remote wakeup if the active
+    /// configuration advertises it, see [`UsbDevice::set_power`].
+    pub fn remote_wakeup_enabled(&self) -> bool {
+        self.feature_remote_wakeup.get()
+    }
+
+    /// Set the current draw and power attributes advertised by the
+    /// configuration descriptor.
+    ///
+    /// Patches `bMaxPower` and `bmAttributes` of the first configuration
+    /// and of the other speed configuration, if set, so this needs to be
+    /// called before the host enumerates the device. The power source
+    /// reported by `GET_STATUS(Device)` is updated to match.
+    ///
+    /// `bMaxPower` counts units of 2 mA, odd values of `max_power_ma`
+    /// are rounded down.
+    ///
+    /// Configurations passed to
+    /// [`UsbDevice::set_additional_configuration_descriptors`] are
+    /// borrowed and keep the power attributes they were declared with.
+    ///
+    /// Returns [`SmolError::InvalidArgument`] if `max_power_ma` exceeds
+    /// [`MAX_POWER_MA`].
+    pub fn set_power(
+        &mut self,
+        max_power_ma: u16,
+        self_powered: bool,
+        remote_wakeup: bool,
+    ) -> SmolResult<()> {
+        if max_power_ma > MAX_POWER_MA {
+            warn!(
+                "DEVICE set_power() {} mA exceeds {} mA",
+                max_power_ma, MAX_POWER_MA
+            );
+            return Err(SmolError::InvalidArgument);
+        }
+
+        let mut attributes = ConfigurationAttributes::new();
+        if self_powered {
+            attributes = attributes.self_powered();
+        }
+        if remote_wakeup {
+            attributes = attributes.remote_wakeup();
+        }
+        // bMaxPower is in units of 2 mA
+        let max_power = (max_power_ma / 2) as u8;
+
+        let other_speed = self
+            .other_speed_configuration_descriptor
+            .as_mut()
+            .map(|descriptor| &mut descriptor.head);
+        for head in [Some(&mut self.configuration_descriptor.head), other_speed]
+            .into_iter()
+            .flatten()
+        {
+            head.attributes = attributes.bits();
+            head.max_power = max_power;
+        }
+        #[cfg(feature = "descriptor-cache")]
+        {
+            self.configuration_descriptor_cache = serialize(&self.configuration_descriptor);
+        }
+
+        self.self_powered = self_powered;
+
+        Ok(())
+    }
+
+    /// Set the descriptor returned by `GET_DESCRIPTOR(Debug)`.
+    ///
+    /// Requests for the debug descriptor are stalled if it is not set.
+    pub fn set_debug_descriptor(&mut self, debug_descriptor: DebugDescriptor) {
+        self.debug_descriptor = Some(debug_descriptor);
+    }
+
+    /// Set the BOS descriptor returned for `GET_DESCRIPTOR(BOS)`.
+    ///
+    /// Requests for the BOS descriptor are stalled if it is not set.
+    pub fn set_bos_descriptor(&mut self, bos_descriptor: BosDescriptor) {
+        self.bos_descriptor = Some(bos_descriptor);
+    }
+
     pub fn set_other_speed_configuration_descriptor(
         &mut self,
         other_speed_configuration_descriptor: ConfigurationDescriptor<'a>,
@@ -153,86 +784,1095 @@ where
         other_speed_configuration_descriptor.set_total_length();
         self.other_speed_configuration_descriptor = Some(other_speed_configuration_descriptor);
     }
+
+    /// Set the device's configurations following the one passed to
+    /// [`UsbDevice::new`].
+    ///
+    /// The host selects a configuration descriptor by its index, the
+    /// configuration passed to `new` has index 0 and these follow in
+    /// order. The device descriptor's `num_configurations` should
+    /// count all of them.
+    pub fn set_additional_configuration_descriptors(
+        &mut self,
+        configuration_descriptors: &'a [ConfigurationDescriptor<'a>],
+    ) {
+        self.additional_configuration_descriptors = configuration_descriptors;
+    }
+
+    /// Returns the configuration descriptor with the given index
+    fn configuration_descriptor_at(&self, index: u8) -> Option<ConfigurationDescriptor<'a>> {
+        if index == 0 {
+            return Some(self.configuration_descriptor);
+        }
+        self.additional_configuration_descriptors
+            .get(index as usize - 1)
+            .copied()
+    }
+
+    /// Returns the configuration descriptor with the given index and
+    /// its total length field updated, as sent to the host
+    fn configuration_descriptor_with_length(
+        &self,
+        index: u8,
+    ) -> Option<ConfigurationDescriptor<'a>> {
+        // the first configuration's total length is set when it is installed
+        let mut descriptor = self.configuration_descriptor_at(index)?;
+        if index != 0 {
+            descriptor.set_total_length();
+        }
+        Some(descriptor)
+    }
+
+    /// Returns the configuration descriptor with the given
+    /// `configuration_value`
+    fn configuration_descriptor_by_value(
+        &self,
+        configuration_value: u8,
+    ) -> Option<ConfigurationDescriptor<'a>> {
+        (0..=self.additional_configuration_descriptors.len() as u8)
+            .filter_map(|index| self.configuration_descriptor_at(index))
+            .find(|descriptor| descriptor.head.configuration_value == configuration_value)
+    }
+
+    /// Returns the configuration descriptor selected by the host, or
+    /// the first configuration if the device is not configured
+    fn active_configuration_descriptor(&self) -> ConfigurationDescriptor<'a> {
+        let configuration_value = self.current_configuration.load(Ordering::Relaxed);
+        self.configuration_descriptor_by_value(configuration_value)
+            .unwrap_or(self.configuration_descriptor)
+    }
+
+    /// Returns the device descriptor as sent to the host.
+    pub fn device_descriptor_bytes(&self) -> &[u8] {
+        self.device_descriptor.as_bytes()
+    }
+
+    /// Serialize the configuration descriptor with the given index into
+    /// `buffer` as it would be sent to the host at the current speed.
+    ///
+    /// Like a `GET_DESCRIPTOR` request with `wLength` set to the length
+    /// of `buffer`, the descriptor is truncated if it does not fit.
+    /// Returns the number of bytes written or
+    /// [`SmolError::InvalidArgument`] if there is no configuration with
+    /// the given index.
+    pub fn configuration_descriptor_bytes(
+        &self,
+        index: u8,
+        buffer: &mut [u8],
+    ) -> SmolResult<usize> {
+        let descriptor = self
+            .configuration_descriptor_with_length(index)
+            .ok_or(SmolError::InvalidArgument)?;
+
+        let mut length = 0;
+        let mut copy = |bytes: ConfigurationDescriptorIterator| {
+            for (slot, byte) in buffer.iter_mut().zip(bytes) {
+                *slot = *byte;
+                length += 1;
+            }
+        };
+        if self.speed() == Speed::SuperSpeed {
+            let descriptor = descriptor.to_superspeed();
+            copy(descriptor.iter_superspeed());
+        } else {
+            copy(descriptor.iter());
+        }
+
+        Ok(length)
+    }
+
+    /// Replace the device's descriptors without reconnecting.
+    ///
+    /// The host will only see the new descriptors the next time it
+    /// requests them. Use [`UsbDevice::update_descriptors_and_reenumerate`]
+    /// to force the host to re-enumerate the device.
+    ///
+    /// Any additional configuration descriptors are removed.
+    ///
+    /// Returns [`SmolError::ControlTransferInProgress`] and leaves the
+    /// current descriptors in place if a control transfer is underway.
+    pub fn update_descriptors(&mut self, descriptors: DescriptorSet<'a>) -> SmolResult<()> {
+        if !self.control.is_idle() {
+            warn!("DEVICE update_descriptors: control transfer in progress");
+            return Err(SmolError::ControlTransferInProgress);
+        }
+
+        let DescriptorSet {
+            device_descriptor,
+            configuration_descriptor,
+            device_qualifier_descriptor,
+            other_speed_configuration_descriptor,
+            string_descriptor_zero,
+            string_descriptors,
+        } = descriptors;
+
+        let mut configuration_descriptor = configuration_descriptor;
+        configuration_descriptor.set_total_length();
+
+        let mut endpoints = self.endpoints.borrow_mut();
+        endpoints.set_owners(&configuration_descriptor);
+        endpoints.set_max_packet_sizes(&configuration_descriptor);
+        drop(endpoints);
+        self.device_descriptor = device_descriptor;
+        self.configuration_descriptor = configuration_descriptor;
+        self.additional_configuration_descriptors = &[];
+        self.device_qualifier_descriptor = device_qualifier_descriptor;
+        self.other_speed_configuration_descriptor = None;
+        if let Some(other_speed_configuration_descriptor) = other_speed_configuration_descriptor {
+            self.set_other_speed_configuration_descriptor(other_speed_configuration_descriptor);
+        }
+        self.string_descriptor_zero = string_descriptor_zero;
+        self.string_descriptors = string_descriptors;
+
+        #[cfg(feature = "descriptor-cache")]
+        {
+            self.configuration_descriptor_cache = serialize(&self.configuration_descriptor);
+        }
+
+        debug!("DEVICE update_descriptors: descriptors updated");
+
+        Ok(())
+    }
+
+    /// Replace the device's descriptors and force the host to
+    /// re-enumerate the device.
+    ///
+    /// The device is disconnected, `delay` is called to give the host
+    /// time to notice the disconnect and the device is then reconnected.
+    /// As with [`UsbDevice::connect`] the caller is responsible for
+    /// re-enabling interrupts afterwards.
+    pub fn update_descriptors_and_reenumerate<F>(
+        &mut self,
+        descriptors: DescriptorSet<'a>,
+        delay: F,
+    ) -> SmolResult<Speed>
+    where
+        F: FnOnce(),
+    {
+        self.update_descriptors(descriptors)?;
+
+        self.disconnect();
+        self.state.replace(DeviceState::None);
+        self.endpoints.borrow_mut().reset();
+        self.current_configuration.store(0, Ordering::Relaxed);
+
+        delay();
+
+        let speed = self.connect()?;
+        debug!("DEVICE update_descriptors: re-enumerating at {:?}", speed);
+
+        Ok(speed)
+    }
 }
 
 // Device connection
-impl<'a, D, const MAX_RECEIVE_SIZE: usize> UsbDevice<'a, D, MAX_RECEIVE_SIZE>
+impl<
+        'a,
+        D,
+        const MAX_RECEIVE_SIZE: usize,
+        const MAX_ENDPOINTS: usize,
+        const MAX_PACKET_SIZE: usize,
+    > UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>
 where
     D: UsbDriver,
 {
-    pub fn connect(&self) -> Speed {
-        self.hal_driver.connect().into()
+    /// Connect the device to the bus
+    ///
+    /// Returns the speed reported by the controller.
+    pub fn connect(&self) -> Result<Speed, ConnectError> {
+        let speed = self.hal_driver.connect()?.into();
+        self.speed.set(speed);
+        Ok(speed)
     }
 
     pub fn disconnect(&self) {
         self.hal_driver.disconnect()
     }
 
+    /// Take the device offline
+    ///
+    /// Disconnects from the bus, which also disables the controller's
+    /// events and discards any buffered data, and abandons any control
+    /// transfer in progress. The device returns to its unconfigured
+    /// state so [`UsbDevice::connect`] is required to bring it back
+    /// online.
+    pub fn shutdown(&mut self) {
+        self.hal_driver.disconnect();
+        self.control.reset();
+        self.state.replace(DeviceState::None);
+        self.endpoints.borrow_mut().reset();
+        self.current_configuration.store(0, Ordering::Relaxed);
+        self.pending_address.set(None);
+        self.idle_ms.set(0);
+        self.suspended_from.set(None);
+        debug!("DEVICE shutdown");
+    }
+
     pub fn reset(&self) -> Speed {
         let speed = self.hal_driver.reset().into();
+        self.speed.set(speed);
         self.state.replace(DeviceState::Reset.into());
+        self.endpoints.borrow_mut().reset();
         speed
     }
 
     pub fn bus_reset(&self) -> Speed {
         let speed = self.hal_driver.bus_reset().into();
+        self.speed.set(speed);
         self.state.replace(DeviceState::Reset.into());
+        self.endpoints.borrow_mut().reset();
         speed
     }
 }
 
-// Control dispatch
-impl<'a, D, const MAX_RECEIVE_SIZE: usize> UsbDevice<'a, D, MAX_RECEIVE_SIZE>
+// Endpoint state
+impl<
+        'a,
+        D,
+        const MAX_RECEIVE_SIZE: usize,
+        const MAX_ENDPOINTS: usize,
+        const MAX_PACKET_SIZE: usize,
+    > UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>
 where
     D: UsbDriver,
 {
-    /// Dispatches USB events for handling by Control
+    /// Prepare the given OUT endpoint to receive a single packet and
+    /// record it as primed.
     ///
-    /// Returns unhandled Control responses for further handling by the caller
-    pub fn dispatch_control(
-        &mut self,
-        event: UsbEvent,
-    ) -> SmolResult<Option<ControlEvent<'a, MAX_RECEIVE_SIZE>>> {
-        trace!("DEVICE dispatch_control({:?})", event);
+    /// The prime is retried if it did not take effect, see
+    /// [`UsbDevice::set_transfer_policy`]. Returns
+    /// [`SmolError::PrimeFailed`] if the endpoint could not be primed,
+    /// in which case no further packets will be received on it until
+    /// it is primed again.
+    pub fn ep_out_prime_receive(&self, endpoint_number: u8) -> SmolResult<()> {
+        self.check_endpoint(endpoint_number, Direction::HostToDevice)?;
+        // paused endpoints are primed again when they are resumed
+        if self.is_paused(endpoint_number) {
+            trace!("DEVICE OUT{} paused, not priming", endpoint_number);
+            return Ok(());
+        }
+        if let Err(e) = self
+            .hal_driver
+            .ep_out_prime_receive_retry(endpoint_number, &self.transfer_policy)
+        {
+            warn!("DEVICE failed to prime OUT endpoint {}", endpoint_number);
+            return Err(e);
+        }
+        if let Some(primed) = self
+            .endpoints
+            .borrow_mut()
+            .primed_out
+            .get_mut(endpoint_number as usize)
+        {
+            *primed = true;
+        }
+        Ok(())
+    }
 
-        //let response = self.control.dispatch(&self.hal_driver, event)?;
-        //trace!("  {:?} got response: {:?}", event, response);
+    /// Prime every OUT endpoint declared in the active configuration
+    /// descriptor.
+    ///
+    /// This is called automatically after a `SetConfiguration` request.
+    pub fn prime_out_endpoints(&self) {
+        for endpoint in self.active_configuration_descriptor().endpoints() {
+            let endpoint_address = endpoint.endpoint_address;
+            if Direction::from_endpoint_address(endpoint_address) != Direction::HostToDevice {
+                continue;
+            }
+            let endpoint_number = endpoint_address & endpoint::NUMBER_MASK;
+            if endpoint_number == 0 {
+                continue;
+            }
+            let max_packet_size = endpoint.max_packet_size();
+            trace!(
+                "  priming OUT endpoint: {} max_packet_size: {}",
+                endpoint_number,
+                max_packet_size
+            );
+            if max_packet_size as usize > MAX_PACKET_SIZE {
+                warn!(
+                    "  OUT endpoint {} max_packet_size {} exceeds buffer size {}",
+                    endpoint_number, max_packet_size, MAX_PACKET_SIZE
+                );
+            }
+            let _ = self.ep_out_prime_receive(endpoint_number);
+        }
+    }
 
-        match self.control.dispatch(&self.hal_driver, event)? {
-            Some(
-                response @ ControlEvent {
-                    endpoint_number,
-                    setup_packet,
-                    //data,
-                    bytes_read,
-                    //_marker,
-                    ..
-                },
-            ) => {
-                // probably a standard request that can be handled by UsbDevice
-                // TODO check direction and split setup_request into in/out
-                if bytes_read == 0 {
-                    // try to handle the request but return packet to caller if we can't
-                    match self.setup_request(endpoint_number, &setup_packet)? {
-                        Some(_setup_packet) => Ok(Some(response)),
-                        None => Ok(None),
-                    }
+    /// Read the packet received on the given OUT endpoint and re-prime it.
+    ///
+    /// Returns the number of bytes read and the packet buffer.
+    ///
+    /// Packets longer than the endpoint's maximum packet size are
+    /// host babble, they are truncated to the maximum packet size and
+    /// counted in the endpoint's `babble` statistic. Packets are also
+    /// truncated to `MAX_PACKET_SIZE`. Truncated bytes are counted in
+    /// the endpoint's `overflow` statistic.
+    ///
+    /// Paused endpoints are left untouched and read as empty, see
+    /// [`UsbDevice::pause_endpoint`], as are IN endpoints.
+    pub fn read_packet(&self, endpoint_number: u8) -> (usize, [u8; MAX_PACKET_SIZE]) {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        if self
+            .check_endpoint(endpoint_number, Direction::HostToDevice)
+            .is_err()
+            || self.is_paused(endpoint_number)
+        {
+            return (0, buffer);
+        }
+        let (bytes_read, overflow) = self
+            .hal_driver
+            .read_with_overflow(endpoint_number, &mut buffer);
+        let _ = self.ep_out_prime_receive(endpoint_number);
+
+        let max_packet_size = self.out_max_packet_size(endpoint_number);
+        let packet_length = bytes_read + overflow;
+        if packet_length > max_packet_size {
+            warn!(
+                "DEVICE babble on OUT endpoint {}: {} bytes > max packet size {}",
+                endpoint_number, packet_length, max_packet_size
+            );
+            self.update_endpoint_state(&UsbEvent::BabbleDetected(endpoint_number));
+            let bytes_read = bytes_read.min(max_packet_size);
+            self.count_bytes_out(endpoint_number, bytes_read, packet_length - bytes_read);
+            return (bytes_read, buffer);
+        }
 
-                // setup packet has a data stage, probably a class or vendor request
-                } else {
-                    // TODO any scenario where control could be handling this unless we add support
-                    //      for registering class/vendor handlers with UsbDevice?
-                    Ok(Some(response))
-                }
-            }
-            None => Ok(None),
+        self.count_bytes_out(endpoint_number, bytes_read, overflow);
+        (bytes_read, buffer)
+    }
+
+    fn count_bytes_out(&self, endpoint_number: u8, bytes_read: usize, overflow: usize) {
+        let mut endpoints = self.endpoints.borrow_mut();
+        if let Some(stats) = endpoints.stats_out.get_mut(endpoint_number as usize) {
+            stats.bytes = stats.bytes.wrapping_add(bytes_read as u32);
+            stats.overflow = stats.overflow.wrapping_add(overflow as u32);
         }
     }
-}
 
-// SETUP request
-impl<'a, D, const MAX_RECEIVE_SIZE: usize> UsbDevice<'a, D, MAX_RECEIVE_SIZE>
-where
+    /// Returns the maximum packet size of the given OUT endpoint in the
+    /// active configuration, or `MAX_PACKET_SIZE` if it isn't declared.
+    fn out_max_packet_size(&self, endpoint_number: u8) -> usize {
+        self.endpoints
+            .borrow()
+            .max_packet_size_out
+            .get(endpoint_number as usize)
+            .copied()
+            .flatten()
+            .map(usize::from)
+            .unwrap_or(MAX_PACKET_SIZE)
+    }
+
+    /// Check that the given endpoint number can be used in `direction`.
+    ///
+    /// The controller only sees the endpoint number so an IN endpoint
+    /// number passed to an OUT operation, or the other way around,
+    /// silently addresses the wrong endpoint. This catches the two
+    /// common mistakes with a warning and
+    /// [`SmolError::InvalidArgument`]:
+    ///
+    /// * passing an endpoint address, e.g. `0x81`, instead of an
+    ///   endpoint number.
+    /// * passing the number of an endpoint the active configuration
+    ///   only declares in the opposite direction.
+    ///
+    /// Endpoints the configuration doesn't declare at all are accepted.
+    ///
+    /// OUT operations on the device check their endpoint, firmware
+    /// writing to IN endpoints via the driver can call this first.
+    pub fn check_endpoint(&self, endpoint_number: u8, direction: Direction) -> SmolResult<()> {
+        if endpoint_number & !endpoint::NUMBER_MASK != 0 {
+            warn!(
+                "DEVICE expected {:?} endpoint number, got address 0x{:x}",
+                direction, endpoint_number
+            );
+            return Err(SmolError::InvalidArgument);
+        }
+        if endpoint_number == 0 {
+            return Ok(());
+        }
+
+        let endpoints = self.endpoints.borrow();
+        let index = endpoint_number as usize;
+        let declared_in = endpoints.owner_in.get(index).copied().flatten().is_some();
+        let declared_out = endpoints.owner_out.get(index).copied().flatten().is_some();
+        let misused = match direction {
+            Direction::HostToDevice => declared_in && !declared_out,
+            Direction::DeviceToHost => declared_out && !declared_in,
+        };
+        if misused {
+            warn!(
+                "DEVICE endpoint {} is not a {:?} endpoint",
+                endpoint_number, direction
+            );
+            return Err(SmolError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Reset the FIFO of a single endpoint, e.g. to recover from an overflow.
+    ///
+    /// Unlike [`UsbDevice::reset`] this leaves the device address,
+    /// configuration and other endpoints' state intact, including a
+    /// packet waiting in the shared OUT FIFO for another endpoint.
+    ///
+    /// An OUT endpoint that was primed is re-primed afterwards. Priming
+    /// resets the shared OUT FIFO so in that case a packet waiting for
+    /// another endpoint is discarded as well.
+    pub fn reset_endpoint(&self, endpoint_address: u8) {
+        let endpoint_number = endpoint_address & endpoint::NUMBER_MASK;
+        let direction = Direction::from_endpoint_address(endpoint_address);
+        trace!(
+            "DEVICE reset_endpoint({}, {:?})",
+            endpoint_number,
+            direction
+        );
+
+        self.hal_driver.reset_endpoint(endpoint_number, direction);
+
+        if direction == Direction::HostToDevice && self.is_primed_out(endpoint_number) {
+            let _ = self.ep_out_prime_receive(endpoint_number);
+        }
+    }
+
+    /// Stop servicing the given endpoint address without stalling it.
+    ///
+    /// The host is answered with NAK until the endpoint is resumed with
+    /// [`UsbDevice::resume_endpoint`]. Paused OUT endpoints are not
+    /// re-primed and [`UsbDevice::read_packet`] leaves any packet in
+    /// the FIFO, writes to paused IN endpoints return
+    /// [`SmolError::WouldBlock`].
+    ///
+    /// Returns [`SmolError::InvalidArgument`] for the control endpoint.
+    pub fn pause_endpoint(&self, endpoint_address: u8) -> SmolResult<()> {
+        let endpoint_number = endpoint_address & endpoint::NUMBER_MASK;
+        let direction = Direction::from_endpoint_address(endpoint_address);
+        self.hal_driver.pause_endpoint(endpoint_number, direction)?;
+        self.set_paused(endpoint_address, true);
+        debug!("DEVICE pause_endpoint(0x{:x})", endpoint_address);
+        Ok(())
+    }
+
+    /// Resume servicing an endpoint paused with
+    /// [`UsbDevice::pause_endpoint`].
+    ///
+    /// OUT endpoints are re-primed without resetting the FIFO. A
+    /// packet received before the endpoint was paused is kept and
+    /// the endpoint is re-primed once it has been read.
+    ///
+    /// Returns [`SmolError::InvalidArgument`] for the control endpoint.
+    pub fn resume_endpoint(&self, endpoint_address: u8) -> SmolResult<()> {
+        let endpoint_number = endpoint_address & endpoint::NUMBER_MASK;
+        let direction = Direction::from_endpoint_address(endpoint_address);
+        self.hal_driver
+            .resume_endpoint(endpoint_number, direction)?;
+        self.set_paused(endpoint_address, false);
+        if direction == Direction::HostToDevice {
+            if let Some(primed) = self
+                .endpoints
+                .borrow_mut()
+                .primed_out
+                .get_mut(endpoint_number as usize)
+            {
+                *primed = true;
+            }
+        }
+        debug!("DEVICE resume_endpoint(0x{:x})", endpoint_address);
+        Ok(())
+    }
+
+    /// Returns true if the given endpoint address is paused.
+    pub fn is_endpoint_paused(&self, endpoint_address: u8) -> bool {
+        let endpoints = self.endpoints.borrow();
+        let endpoint_number = (endpoint_address & endpoint::NUMBER_MASK) as usize;
+        let paused = match Direction::from_endpoint_address(endpoint_address) {
+            Direction::HostToDevice => endpoints.paused_out.get(endpoint_number),
+            Direction::DeviceToHost => endpoints.paused_in.get(endpoint_number),
+        };
+        paused.copied().unwrap_or(false)
+    }
+
+    /// Returns true if the given OUT endpoint number is paused.
+    fn is_paused(&self, endpoint_number: u8) -> bool {
+        self.is_endpoint_paused(endpoint_number & endpoint::NUMBER_MASK)
+    }
+
+    fn set_paused(&self, endpoint_address: u8, paused: bool) {
+        let mut endpoints = self.endpoints.borrow_mut();
+        let endpoint_number = (endpoint_address & endpoint::NUMBER_MASK) as usize;
+        let entry = match Direction::from_endpoint_address(endpoint_address) {
+            Direction::HostToDevice => endpoints.paused_out.get_mut(endpoint_number),
+            Direction::DeviceToHost => endpoints.paused_in.get_mut(endpoint_number),
+        };
+        if let Some(entry) = entry {
+            *entry = paused;
+        }
+    }
+
+    /// Enable or disable double-buffering of the given OUT endpoint.
+    ///
+    /// Falls back to single-buffering if the controller does not
+    /// support it. Returns `true` if the endpoint is double-buffered.
+    pub fn set_double_buffered(&self, endpoint_number: u8, enabled: bool) -> bool {
+        if self
+            .check_endpoint(endpoint_number, Direction::HostToDevice)
+            .is_err()
+        {
+            return false;
+        }
+        let double_buffered = self
+            .hal_driver
+            .set_double_buffered(endpoint_number, enabled);
+        if enabled && !double_buffered {
+            debug!(
+                "DEVICE OUT{} is single-buffered on this controller",
+                endpoint_number
+            );
+        }
+        double_buffered
+    }
+
+    /// Register a callback for transfers completing on the given endpoint
+    ///
+    /// The callback is invoked by [`UsbDevice::dispatch_endpoint_complete`]
+    /// for each `SendComplete` on an IN endpoint or `ReceivePacket` on
+    /// an OUT endpoint, after the endpoint state has been updated. OUT
+    /// callbacks are expected to call [`UsbDevice::read_packet`].
+    ///
+    /// Pass `None` to remove the callback. Events on endpoints without
+    /// a callback are returned to the caller of [`UsbDevice::poll`] as
+    /// before.
+    ///
+    /// For example, to send a response too large for a single write
+    /// one part at a time:
+    ///
+    /// ```ignore
+    /// static PART: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// fn send_next_part(device: &UsbDevice<'_, Usb0, 64>, endpoint_number: u8) {
+    ///     let part = PART.fetch_add(1, Ordering::Relaxed);
+    ///     if let Some(data) = RESPONSE.chunks(512).nth(part) {
+    ///         device.hal_driver.write(endpoint_number, data.iter().copied());
+    ///     }
+    /// }
+    ///
+    /// device.on_endpoint_complete(1, Direction::DeviceToHost, Some(send_next_part))?;
+    /// send_next_part(&device, 1);
+    /// ```
+    pub fn on_endpoint_complete(
+        &mut self,
+        endpoint_number: u8,
+        direction: Direction,
+        callback: Option<
+            EndpointCompleteCallback<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>,
+        >,
+    ) -> SmolResult<()> {
+        self.check_endpoint(endpoint_number, direction)?;
+        let entry = match direction {
+            Direction::HostToDevice => self
+                .cb_endpoint_complete_out
+                .get_mut(endpoint_number as usize),
+            Direction::DeviceToHost => self
+                .cb_endpoint_complete_in
+                .get_mut(endpoint_number as usize),
+        };
+        match entry {
+            Some(entry) => {
+                *entry = callback;
+                Ok(())
+            }
+            None => {
+                warn!(
+                    "DEVICE on_endpoint_complete: endpoint {} exceeds MAX_ENDPOINTS {}",
+                    endpoint_number, MAX_ENDPOINTS
+                );
+                Err(SmolError::InvalidArgument)
+            }
+        }
+    }
+
+    /// Invoke the callback registered for the event's endpoint, see
+    /// [`UsbDevice::on_endpoint_complete`].
+    ///
+    /// Called by [`UsbDevice::poll`], firmware dispatching events from
+    /// an interrupt queue calls this for non-control endpoint events.
+    ///
+    /// Returns true if a callback handled the event.
+    pub fn dispatch_endpoint_complete(&self, event: &UsbEvent) -> bool {
+        let callback = match *event {
+            UsbEvent::SendComplete(endpoint_number) if endpoint_number != 0 => self
+                .cb_endpoint_complete_in
+                .get(endpoint_number as usize)
+                .copied()
+                .flatten()
+                .map(|callback| (callback, endpoint_number)),
+            UsbEvent::ReceivePacket(endpoint_number) if endpoint_number != 0 => self
+                .cb_endpoint_complete_out
+                .get(endpoint_number as usize)
+                .copied()
+                .flatten()
+                .map(|callback| (callback, endpoint_number)),
+            _ => None,
+        };
+        match callback {
+            Some((callback, endpoint_number)) => {
+                callback(self, endpoint_number);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the number of the interface owning the given endpoint address.
+    pub fn endpoint_owner(&self, endpoint_address: u8) -> Option<u8> {
+        let endpoints = self.endpoints.borrow();
+        let endpoint_number = (endpoint_address & endpoint::NUMBER_MASK) as usize;
+        let owner = match Direction::from_endpoint_address(endpoint_address) {
+            Direction::HostToDevice => endpoints.owner_out.get(endpoint_number),
+            Direction::DeviceToHost => endpoints.owner_in.get(endpoint_number),
+        };
+        owner.copied().flatten()
+    }
+
+    /// Prime the OUT endpoints owned by the given interface.
+    pub fn prime_interface_out_endpoints(&self, interface_number: u8) {
+        let owned: heapless::Vec<u8, MAX_ENDPOINTS> = self
+            .endpoints
+            .borrow()
+            .owner_out
+            .iter()
+            .enumerate()
+            .filter(|(endpoint_number, owner)| {
+                *endpoint_number != 0 && **owner == Some(interface_number)
+            })
+            .map(|(endpoint_number, _)| endpoint_number as u8)
+            .collect();
+        for endpoint_number in owned {
+            trace!(
+                "  priming interface {} OUT endpoint: {}",
+                interface_number,
+                endpoint_number
+            );
+            let _ = self.ep_out_prime_receive(endpoint_number);
+        }
+    }
+
+    /// Returns true if the given OUT endpoint is primed to receive.
+    ///
+    /// The primed state is only tracked for endpoints primed with
+    /// [`UsbDevice::ep_out_prime_receive`] and read with
+    /// [`UsbDevice::read_packet`], calling the driver's
+    /// `ep_out_prime_receive` or `read_and_reprime` directly leaves it
+    /// stale.
+    pub fn is_primed_out(&self, endpoint_number: u8) -> bool {
+        self.endpoints
+            .borrow()
+            .primed_out
+            .get(endpoint_number as usize)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Returns true if the given endpoint address has its halt feature set.
+    pub fn is_halted(&self, endpoint_address: u8) -> bool {
+        let endpoints = self.endpoints.borrow();
+        let endpoint_number = (endpoint_address & endpoint::NUMBER_MASK) as usize;
+        let halted = match Direction::from_endpoint_address(endpoint_address) {
+            Direction::HostToDevice => endpoints.halted_out.get(endpoint_number),
+            Direction::DeviceToHost => endpoints.halted_in.get(endpoint_number),
+        };
+        halted.copied().unwrap_or(false)
+    }
+
+    fn set_halted(&self, endpoint_address: u8, halted: bool) {
+        let mut endpoints = self.endpoints.borrow_mut();
+        let endpoint_number = (endpoint_address & endpoint::NUMBER_MASK) as usize;
+        let entry = match Direction::from_endpoint_address(endpoint_address) {
+            Direction::HostToDevice => endpoints.halted_out.get_mut(endpoint_number),
+            Direction::DeviceToHost => endpoints.halted_in.get_mut(endpoint_number),
+        };
+        if let Some(entry) = entry {
+            *entry = halted;
+        }
+    }
+
+    /// Update endpoint state and statistics for an incoming event.
+    fn update_endpoint_state(&self, event: &UsbEvent) {
+        let mut endpoints = self.endpoints.borrow_mut();
+        match *event {
+            UsbEvent::BusReset => endpoints.reset(),
+            UsbEvent::ReceivePacket(endpoint_number) => {
+                let endpoint_number = endpoint_number as usize;
+                if let Some(primed) = endpoints.primed_out.get_mut(endpoint_number) {
+                    *primed = false;
+                }
+                if let Some(stats) = endpoints.stats_out.get_mut(endpoint_number) {
+                    stats.packets = stats.packets.wrapping_add(1);
+                }
+            }
+            UsbEvent::SendComplete(endpoint_number) => {
+                if let Some(stats) = endpoints.stats_in.get_mut(endpoint_number as usize) {
+                    stats.packets = stats.packets.wrapping_add(1);
+                }
+            }
+            UsbEvent::BabbleDetected(endpoint_number) => {
+                if let Some(stats) = endpoints.stats_out.get_mut(endpoint_number as usize) {
+                    stats.babble = stats.babble.wrapping_add(1);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+// Control dispatch
+impl<
+        'a,
+        D,
+        const MAX_RECEIVE_SIZE: usize,
+        const MAX_ENDPOINTS: usize,
+        const MAX_PACKET_SIZE: usize,
+    > UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>
+where
+    D: UsbDriver,
+{
+    /// Dispatches USB events for handling by Control
+    ///
+    /// Returns unhandled Control responses for further handling by the caller
+    ///
+    /// Errors are returned to the caller. Once the device's
+    /// [`RecoveryPolicy`] limit of errors without a completed control
+    /// transfer in between is reached the
+    /// device is also disconnected and reconnected so the host
+    /// re-enumerates it, see [`UsbDevice::recoveries`].
+    pub fn dispatch_control(
+        &mut self,
+        event: UsbEvent,
+    ) -> SmolResult<Option<ControlEvent<'a, MAX_RECEIVE_SIZE>>> {
+        let result = self.dispatch_control_event(event);
+
+        match result {
+            // a completed transfer shows the device and host are in step
+            Ok(_) if self.control.is_idle() => self.control_errors.set(0),
+            Ok(_) => (),
+            Err(error) => {
+                let control_errors = self.control_errors.get() + 1;
+                self.control_errors.set(control_errors);
+
+                match self.recovery_policy.max_control_errors {
+                    Some(max_control_errors) if control_errors >= max_control_errors => {
+                        warn!(
+                            "DEVICE reconnecting after {} control errors, last: {:?}",
+                            control_errors, error
+                        );
+                        self.reconnect();
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Take the device off the bus and bring it back to recover from
+    /// repeated control errors.
+    fn reconnect(&mut self) {
+        self.shutdown();
+        if let Err(error) = self.connect() {
+            error!("DEVICE failed to reconnect: {:?}", error);
+        }
+        self.control_errors.set(0);
+        self.recoveries.set(self.recoveries.get() + 1);
+
+        if let Some(cb) = self.cb_recovery {
+            cb(self);
+        }
+    }
+
+    fn dispatch_control_event(
+        &mut self,
+        event: UsbEvent,
+    ) -> SmolResult<Option<ControlEvent<'a, MAX_RECEIVE_SIZE>>> {
+        trace!("DEVICE dispatch_control({:?})", event);
+
+        self.bus_activity();
+        self.update_endpoint_state(&event);
+        match event {
+            UsbEvent::BusReset => {
+                self.state.replace(DeviceState::Reset);
+                self.current_configuration.store(0, Ordering::Relaxed);
+                self.feature_remote_wakeup.set(false);
+                self.pending_address.set(None);
+            }
+            // the SET_ADDRESS status stage was sent with the old address
+            UsbEvent::SendComplete(0) => {
+                if let Some(address) = self.pending_address.take() {
+                    self.activate_address(address);
+                }
+            }
+            _ => (),
+        }
+
+        // ep0's packet size depends on the negotiated speed
+        self.control
+            .set_max_packet_size(self.ep0_max_packet_size().into());
+
+        //let response = self.control.dispatch(&self.hal_driver, event)?;
+        //trace!("  {:?} got response: {:?}", event, response);
+
+        match self.control.dispatch(&self.hal_driver, event)? {
+            Some(
+                response @ ControlEvent {
+                    endpoint_number,
+                    setup_packet,
+                    //data,
+                    bytes_read,
+                    //_marker,
+                    ..
+                },
+            ) => {
+                #[cfg(feature = "control-capture")]
+                self.capture
+                    .borrow_mut()
+                    .record(&setup_packet, &response.data[..bytes_read]);
+
+                // probably a standard request that can be handled by UsbDevice
+                // TODO check direction and split setup_request into in/out
+                if bytes_read == 0 {
+                    // try to handle the request but return packet to caller if we can't
+                    match self.setup_request(endpoint_number, &setup_packet)? {
+                        Some(_setup_packet) => Ok(Some(response)),
+                        None => Ok(None),
+                    }
+
+                // setup packet has a data stage, probably a class or vendor request
+                } else {
+                    // TODO any scenario where control could be handling this unless we add support
+                    //      for registering class/vendor handlers with UsbDevice?
+                    Ok(Some(response))
+                }
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+// Interrupt sources
+impl<
+        'a,
+        D,
+        const MAX_RECEIVE_SIZE: usize,
+        const MAX_ENDPOINTS: usize,
+        const MAX_PACKET_SIZE: usize,
+    > UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>
+where
+    D: UsbDriver + InterruptControl,
+{
+    /// Choose whether events from the given source are interrupt-driven
+    /// or polled.
+    ///
+    /// Interrupts give the lowest latency but every event costs an
+    /// interrupt entry and exit, and anything the handler logs delays
+    /// the next event. A main loop streaming IN data as fast as it can
+    /// may do better with [`InterruptSource::EndpointIn`] disabled,
+    /// checking for `SendComplete` events itself, while bus resets and
+    /// control requests stay interrupt-driven.
+    ///
+    /// Events from a disabled source remain pending in the controller
+    /// and need to be collected with [`UsbDevice::poll`] or the
+    /// driver's [`PollEvent`] implementation.
+    pub fn set_interrupt_source(&self, source: InterruptSource, enabled: bool) {
+        debug!("DEVICE set_interrupt_source({:?}, {})", source, enabled);
+        self.hal_driver.set_interrupt_source(source, enabled);
+    }
+
+    /// Returns true if events from the given source are interrupt-driven
+    pub fn is_interrupt_source_enabled(&self, source: InterruptSource) -> bool {
+        self.hal_driver.is_interrupt_source_enabled(source)
+    }
+}
+
+// Idle suspend
+impl<
+        'a,
+        D,
+        const MAX_RECEIVE_SIZE: usize,
+        const MAX_ENDPOINTS: usize,
+        const MAX_PACKET_SIZE: usize,
+    > UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>
+where
+    D: UsbDriver,
+{
+    /// Suspend the device once the bus has been idle for
+    /// `idle_timeout_ms` milliseconds, or never if `None`
+    ///
+    /// The controller does not report SOFs or the bus suspend signal so
+    /// idle time is measured between the events seen by the device,
+    /// with the time supplied by firmware through
+    /// [`UsbDevice::idle_tick`].
+    pub fn set_idle_timeout(&mut self, idle_timeout_ms: Option<u32>) {
+        self.idle_timeout_ms = idle_timeout_ms;
+        self.idle_ms.set(0);
+    }
+
+    pub fn idle_timeout(&self) -> Option<u32> {
+        self.idle_timeout_ms
+    }
+
+    /// Advance the idle watchdog by `elapsed_ms` milliseconds
+    ///
+    /// Call this periodically, e.g. from a timer interrupt. Once the
+    /// idle timeout has passed without bus activity the device enters
+    /// [`DeviceState::Suspended`], calls `cb_suspend` and returns
+    /// [`UsbEvent::Suspend`].
+    pub fn idle_tick(&self, elapsed_ms: u32) -> Option<UsbEvent> {
+        let idle_timeout_ms = self.idle_timeout_ms?;
+        let state = self.state();
+        if matches!(state, DeviceState::None | DeviceState::Suspended) {
+            return None;
+        }
+
+        let idle_ms = self.idle_ms.get().saturating_add(elapsed_ms);
+        self.idle_ms.set(idle_ms);
+        if idle_ms < idle_timeout_ms {
+            return None;
+        }
+
+        debug!("DEVICE suspending after {} ms idle", idle_ms);
+        self.suspended_from.set(Some(state));
+        self.state.replace(DeviceState::Suspended);
+        if let Some(cb) = self.cb_suspend {
+            cb(self);
+        }
+
+        Some(UsbEvent::Suspend)
+    }
+
+    /// Record bus activity, waking the device if it was suspended
+    ///
+    /// Events passed to [`UsbDevice::dispatch_control`] and
+    /// [`UsbDevice::poll`] already count as activity. Firmware handling
+    /// endpoint events itself should call this for them too.
+    pub fn bus_activity(&self) {
+        self.idle_ms.set(0);
+
+        if let Some(state) = self.suspended_from.take() {
+            debug!("DEVICE resuming to {:?}", state);
+            self.state.replace(state);
+            if let Some(cb) = self.cb_resume {
+                cb(self);
+            }
+        }
+    }
+}
+
+// Polled operation
+impl<
+        'a,
+        D,
+        const MAX_RECEIVE_SIZE: usize,
+        const MAX_ENDPOINTS: usize,
+        const MAX_PACKET_SIZE: usize,
+    > UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>
+where
+    D: UsbDriver + PollEvent,
+{
+    /// Poll the driver for the next event without relying on interrupts
+    ///
+    /// Control endpoint events are dispatched to `Control` and
+    /// consumed. Control requests `UsbDevice` can't handle are passed to
+    /// [`UsbDevice::unknown_control_request`] so use `cb_class_request`
+    /// and `cb_vendor_request` to handle them.
+    ///
+    /// Endpoint events with a callback registered via
+    /// [`UsbDevice::on_endpoint_complete`] are passed to it and consumed.
+    ///
+    /// Returns any other event for handling by the caller.
+    pub fn poll(&mut self) -> Option<UsbEvent> {
+        self.control.poll_timeout(&self.hal_driver);
+        let event = self.hal_driver.poll_event()?;
+
+        match event {
+            UsbEvent::BusReset
+            | UsbEvent::ReceiveControl(0)
+            | UsbEvent::ReceivePacket(0)
+            | UsbEvent::SendComplete(0) => {
+                match self.dispatch_control(event) {
+                    Ok(Some(control_event)) => {
+                        warn!("DEVICE poll unhandled control event: {:?}", control_event);
+                        self.unknown_control_request(&control_event.setup_packet);
+                    }
+                    Ok(None) => (),
+                    Err(e) => {
+                        error!("DEVICE poll error handling control event: {:?}", e);
+                    }
+                }
+                None
+            }
+            event => {
+                self.bus_activity();
+                self.update_endpoint_state(&event);
+                if self.dispatch_endpoint_complete(&event) {
+                    return None;
+                }
+                Some(event)
+            }
+        }
+    }
+
+    /// Connect the device to the bus and wait for the host's first bus
+    /// reset
+    ///
+    /// A host always resets the bus before enumerating a newly
+    /// connected device, and the reset clears any endpoint primed
+    /// before it. The recommended polled bring-up sequence is:
+    ///
+    /// 1. configure descriptors and policies on the `UsbDevice`.
+    /// 2. call `connect_and_wait_reset`.
+    /// 3. prime any OUT endpoints that must be ready before the
+    ///    device is configured.
+    /// 4. enter the main loop calling [`UsbDevice::poll`].
+    ///
+    /// Events other than `BusReset` that arrive while waiting are
+    /// discarded. The driver is polled at most `timeout_cycles` times
+    /// before giving up with [`SmolError::Timeout`], in which case the
+    /// device is left connected.
+    ///
+    /// Returns the speed negotiated during the reset.
+    pub fn connect_and_wait_reset(&mut self, timeout_cycles: usize) -> SmolResult<Speed> {
+        self.connect()?;
+
+        for _ in 0..timeout_cycles {
+            match self.hal_driver.poll_event() {
+                Some(UsbEvent::BusReset) => {
+                    self.control.reset();
+                    self.current_configuration.store(0, Ordering::Relaxed);
+                    let speed = self.bus_reset();
+                    debug!("DEVICE connected at {:?}", speed);
+                    return Ok(speed);
+                }
+                Some(event) => {
+                    debug!("DEVICE discarding {:?} before first bus reset", event);
+                }
+                None => (),
+            }
+        }
+
+        warn!("DEVICE timed out waiting for bus reset");
+        Err(SmolError::Timeout)
+    }
+}
+
+// SETUP request
+impl<
+        'a,
+        D,
+        const MAX_RECEIVE_SIZE: usize,
+        const MAX_ENDPOINTS: usize,
+        const MAX_PACKET_SIZE: usize,
+    > UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>
+where
     D: UsbDriver,
 {
     pub fn setup_request(
@@ -240,14 +1880,14 @@ where
         _endpoint_number: u8,
         setup_packet: &SetupPacket,
     ) -> SmolResult<Option<SetupPacket>> {
-        let request_type = setup_packet.request_type();
+        let (direction, request_type, recipient) = setup_packet.request_type_byte().parts();
         let request = setup_packet.request();
 
         if matches!(request_type, RequestType::Standard) {
             debug!(
                 "SETUP {:?} {:?} {:?} {:?} 0x{:x} 0x{:x} {}",
-                setup_packet.recipient(),
-                setup_packet.direction(),
+                recipient,
+                direction,
                 request_type,
                 request,
                 setup_packet.value,
@@ -257,15 +1897,28 @@ where
         }
 
         match (&request_type, &request) {
+            (RequestType::Standard, Request::GetStatus) => {
+                self.setup_get_status(setup_packet)?;
+            }
             (RequestType::Standard, Request::SetAddress) => {
                 self.setup_set_address(setup_packet)?;
             }
+            // class descriptors, e.g. HID report descriptors, are
+            // requested from the interface they belong to
+            (RequestType::Standard, Request::GetDescriptor)
+                if recipient == Recipient::Interface =>
+            {
+                return Ok(Some(*setup_packet));
+            }
             (RequestType::Standard, Request::GetDescriptor) => {
                 self.setup_get_descriptor(setup_packet)?;
             }
             (RequestType::Standard, Request::SetConfiguration) => {
                 self.setup_set_configuration(setup_packet)?;
             }
+            (RequestType::Standard, Request::SetInterface) => {
+                self.setup_set_interface(setup_packet)?;
+            }
             (RequestType::Standard, Request::GetConfiguration) => {
                 self.setup_get_configuration(setup_packet)?;
             }
@@ -278,7 +1931,10 @@ where
             (RequestType::Class, Request::ClassOrVendor(request)) => {
                 // if we have a callback handler, invoke it
                 if let Some(cb) = self.cb_class_request {
-                    cb(self, setup_packet, *request);
+                    if cb(self, setup_packet, *request) == ControlOutcome::Stall {
+                        warn!("SETUP unsupported class request {}", request);
+                        self.unknown_control_request(setup_packet);
+                    }
 
                 // otherwise return the setup packet for the caller to handle
                 } else {
@@ -288,7 +1944,10 @@ where
             (RequestType::Vendor, Request::ClassOrVendor(request)) => {
                 // if we have a callback handler, invoke it
                 if let Some(cb) = self.cb_vendor_request {
-                    cb(self, setup_packet, *request);
+                    if cb(self, setup_packet, *request) == ControlOutcome::Stall {
+                        warn!("SETUP unsupported vendor request {}", request);
+                        self.unknown_control_request(setup_packet);
+                    }
                 } else {
                     // otherwise return the setup packet for the caller to handle
                     return Ok(Some(*setup_packet));
@@ -303,16 +1962,30 @@ where
         Ok(None)
     }
 
-    // TODO move tx_ack_active flag logic to control.rs
     fn setup_set_address(&self, setup_packet: &SetupPacket) -> SmolResult<()> {
 
         let address: u8 = (setup_packet.value & 0x7f) as u8;
 
+        // a well-behaved host only sets the address once after a reset
+        match self.state() {
+            DeviceState::Addressed if address != 0 => {
+                warn!("SETUP host re-addressed device to {}", address);
+                self.readdressed.set(Some(address));
+            }
+            DeviceState::Configured => {
+                // device behaviour is not specified, refuse the request
+                warn!("SETUP stall: SetAddress({}) while configured", address);
+                self.readdressed.set(Some(address));
+                self.hal_driver.stall_control_request();
+                return Ok(());
+            }
+            _ => (),
+        }
+
         if self.quirk_set_address_before_status {
             warn!("UsbDevice::setup_set_address({}) quirk_set_address_before_status", address);
             // activate new address
-            self.hal_driver.set_address(address);
-            self.state.replace(DeviceState::Addressed.into());
+            self.activate_address(address);
 
             // ack status
             self.hal_driver.ack(0, Direction::HostToDevice);
@@ -320,27 +1993,17 @@ where
         } else {
             trace!("UsbDevice::setup_set_address({})", address);
 
-            // set tx_ack_active flag
+            // set tx_ack_active flag, cleared with the SendComplete event
             // TODO a slighty safer approach would be nice
             unsafe {
                 self.hal_driver.set_tx_ack_active();
             }
 
-            // respond with ack status first before changing device address
+            // respond with ack status first before changing device address,
+            // the new address is activated by the SendComplete event so
+            // polled firmware is not blocked waiting for it
+            self.pending_address.set(Some(address));
             self.hal_driver.ack(0, Direction::HostToDevice);
-
-            // wait for the response packet to get sent
-            // TODO a slightly safer approach would be nice
-            loop {
-                let active = unsafe { self.hal_driver.is_tx_ack_active() };
-                if active == false {
-                    break;
-                }
-            }
-
-            // activate new address
-            self.hal_driver.set_address(address);
-            self.state.replace(DeviceState::Addressed.into());
         }
 
         debug!(
@@ -352,6 +2015,16 @@ where
         Ok(())
     }
 
+    fn activate_address(&self, address: u8) {
+        self.hal_driver.set_address(address);
+        // address zero returns the device to the default state
+        let state = match address {
+            0 => DeviceState::Reset,
+            _ => DeviceState::Addressed,
+        };
+        self.state.replace(state);
+    }
+
     fn setup_get_descriptor(&self, setup_packet: &SetupPacket) -> SmolResult<()> {
         // extract the descriptor type and number from our SETUP request
         let [descriptor_number, descriptor_type_bits] = setup_packet.value.to_le_bytes();
@@ -379,39 +2052,90 @@ where
         );
 
         match (&descriptor_type, descriptor_number) {
-            (DescriptorType::Device, 0) => self
-                .hal_driver
-                .write_ref(0, self.device_descriptor.as_iter().take(requested_length)),
-            (DescriptorType::Configuration, 0) => self.hal_driver.write_ref(
-                0,
-                self.configuration_descriptor.iter().take(requested_length),
-            ),
-            (DescriptorType::DeviceQualifier, 0) => {
-                if let Some(descriptor) = &self.device_qualifier_descriptor {
-                    self.hal_driver
-                        .write_ref(0, descriptor.as_iter().take(requested_length));
-                } else {
+            (DescriptorType::Device, 0) => {
+                self.write_data_stage(self.device_descriptor.as_iter().copied(), requested_length)?;
+            }
+            (DescriptorType::Configuration, index) => match self
+                .configuration_descriptor_with_length(index)
+            {
+                Some(descriptor) if self.speed() == Speed::SuperSpeed => {
+                    let descriptor = descriptor.to_superspeed();
+                    self.write_data_stage(descriptor.iter_superspeed().copied(), requested_length)?;
+                }
+                Some(_) if index == 0 => self.write_configuration_descriptor(requested_length)?,
+                Some(descriptor) => {
+                    self.write_data_stage(descriptor.iter().copied(), requested_length)?;
+                }
+                None => {
+                    warn!("SETUP stall: unknown configuration descriptor {}", index);
+                    self.hal_driver.stall_control_request();
+                    return Ok(());
+                }
+            },
+            (DescriptorType::DeviceQualifier, 0) => match &self.device_qualifier_descriptor {
+                Some(descriptor) if self.high_speed_capable => {
+                    self.write_data_stage(descriptor.as_iter().copied(), requested_length)?;
+                }
+                Some(_) => {
+                    warn!("SETUP stall: device qualifier requested from full speed only device");
+                    self.hal_driver.stall_control_request();
+                    return Ok(());
+                }
+                None => {
                     warn!("SETUP stall: no device qualifier descriptor configured");
-                    // TODO stall?
+                    self.hal_driver.stall_control_request();
                     return Ok(());
                 }
-            }
-            (DescriptorType::OtherSpeedConfiguration, 0) => {
-                if let Some(descriptor) = self.other_speed_configuration_descriptor {
-                    self.hal_driver
-                        .write_ref(0, descriptor.iter().take(requested_length));
-                } else {
-                    warn!("SETUP stall: no other speed configuration descriptor configured");
-                    // TODO stall?
+            },
+            (DescriptorType::Debug, 0) => match &self.debug_descriptor {
+                Some(descriptor) => {
+                    self.write_data_stage(descriptor.as_iter().copied(), requested_length)?;
+                }
+                None => {
+                    warn!("SETUP stall: no debug descriptor configured");
+                    self.hal_driver.stall_control_request();
                     return Ok(());
                 }
+            },
+            (DescriptorType::BinaryDeviceObjectStore, 0) => match &self.bos_descriptor {
+                Some(descriptor) => {
+                    self.write_data_stage(descriptor.iter(), requested_length)?;
+                }
+                None => {
+                    warn!("SETUP stall: no BOS descriptor configured");
+                    self.hal_driver.stall_control_request();
+                    return Ok(());
+                }
+            },
+            (DescriptorType::OtherSpeedConfiguration, 0) => {
+                match self.other_speed_configuration_descriptor {
+                    Some(descriptor) if self.high_speed_capable => {
+                        self.write_data_stage(descriptor.iter().copied(), requested_length)?;
+                    }
+                    Some(_) => {
+                        warn!(
+                            "SETUP stall: other speed configuration requested from full speed only device"
+                        );
+                        self.hal_driver.stall_control_request();
+                        return Ok(());
+                    }
+                    None => {
+                        warn!("SETUP stall: no other speed configuration descriptor configured");
+                        self.hal_driver.stall_control_request();
+                        return Ok(());
+                    }
+                }
+            }
+            (DescriptorType::String, 0) => {
+                self.hal_driver
+                    .write_ref(0, self.string_descriptor_zero.iter().take(requested_length))?;
             }
-            (DescriptorType::String, 0) => self
-                .hal_driver
-                .write_ref(0, self.string_descriptor_zero.iter().take(requested_length)),
             (DescriptorType::String, index) => {
                 if let Some(cb) = self.cb_string_request {
-                    cb(self, setup_packet, index);
+                    if cb(self, setup_packet, index) == ControlOutcome::Stall {
+                        warn!("SETUP stall: unsupported string descriptor {}", index);
+                        self.hal_driver.stall_control_request();
+                    }
                     return Ok(());
                 }
 
@@ -422,12 +2146,10 @@ where
                     return Ok(());
                 }
 
-                self.hal_driver.write(
-                    0,
-                    self.string_descriptors[offset_index]
-                        .iter()
-                        .take(requested_length),
-                )
+                self.write_data_stage(
+                    self.string_descriptors[offset_index].iter(),
+                    requested_length,
+                )?;
             }
             _ => {
                 warn!(
@@ -444,9 +2166,38 @@ where
         Ok(())
     }
 
-    fn setup_set_configuration(&self, setup_packet: &SetupPacket) -> SmolResult<()> {
-        self.hal_driver.ack_status_stage(setup_packet);
+    /// Write the data stage of the IN control request in progress in
+    /// packets of the control endpoint's max packet size, see
+    /// [`control::write_data_stage`](crate::control::write_data_stage)
+    fn write_data_stage<I>(&self, iter: I, requested_length: usize) -> SmolResult<usize>
+    where
+        I: Iterator<Item = u8> + Clone,
+    {
+        crate::control::write_data_stage(
+            &self.hal_driver,
+            iter,
+            requested_length,
+            usize::from(self.ep0_max_packet_size()),
+        )
+    }
 
+    /// Write the configuration descriptor, from the descriptor cache if enabled
+    fn write_configuration_descriptor(&self, requested_length: usize) -> SmolResult<()> {
+        #[cfg(feature = "descriptor-cache")]
+        if let Some(cache) = &self.configuration_descriptor_cache {
+            self.write_data_stage(cache.iter().copied(), requested_length)?;
+            return Ok(());
+        }
+
+        self.write_data_stage(
+            self.configuration_descriptor.iter().copied(),
+            requested_length,
+        )?;
+
+        Ok(())
+    }
+
+    fn setup_set_configuration(&self, setup_packet: &SetupPacket) -> SmolResult<()> {
         let configuration: u8 = setup_packet.value as u8;
 
         trace!(
@@ -454,17 +2205,89 @@ where
             configuration
         );
 
-        // TODO support multiple configurations
-        if configuration > 1 {
-            warn!("SETUP stall: unknown configuration {}", configuration);
-            self.hal_driver.stall_control_request();
-            return Ok(());
+        if configuration != 0 {
+            match self.configuration_descriptor_by_value(configuration) {
+                Some(descriptor) => {
+                    let mut endpoints = self.endpoints.borrow_mut();
+                    endpoints.set_owners(&descriptor);
+                    endpoints.set_max_packet_sizes(&descriptor);
+                }
+                None => {
+                    warn!("SETUP stall: unknown configuration {}", configuration);
+                    self.hal_driver.stall_control_request();
+                    return Ok(());
+                }
+            }
         }
 
+        self.hal_driver.ack_status_stage(setup_packet);
+
         self.current_configuration
             .store(configuration, Ordering::Relaxed);
         self.state.replace(DeviceState::Configured.into());
 
+        // prime the OUT endpoints of the new configuration
+        if configuration != 0 {
+            self.prime_out_endpoints();
+        }
+
+        Ok(())
+    }
+
+    fn setup_set_interface(&self, setup_packet: &SetupPacket) -> SmolResult<()> {
+        let interface_number = setup_packet.index as u8;
+        let alternate_setting = setup_packet.value as u8;
+
+        trace!(
+            "SETUP setup_set_interface() interface:{} alternate_setting:{}",
+            interface_number,
+            alternate_setting
+        );
+
+        let exists = self
+            .active_configuration_descriptor()
+            .tail
+            .iter()
+            .any(|interface| {
+                let header = interface.header();
+                header.interface_number == interface_number
+                    && header.alternate_setting == alternate_setting
+            });
+        if !exists {
+            warn!(
+                "SETUP stall: unknown interface {} alternate setting {}",
+                interface_number, alternate_setting
+            );
+            self.hal_driver.stall_control_request();
+            return Ok(());
+        }
+
+        self.hal_driver.ack_status_stage(setup_packet);
+
+        // selecting an alternate setting clears the halt feature of the
+        // interface's endpoints
+        {
+            let mut endpoints = self.endpoints.borrow_mut();
+            let endpoints = &mut *endpoints;
+            for (halted, owner) in endpoints
+                .halted_in
+                .iter_mut()
+                .zip(endpoints.owner_in.iter())
+                .chain(
+                    endpoints
+                        .halted_out
+                        .iter_mut()
+                        .zip(endpoints.owner_out.iter()),
+                )
+            {
+                if *owner == Some(interface_number) {
+                    *halted = false;
+                }
+            }
+        }
+
+        self.prime_interface_out_endpoints(interface_number);
+
         Ok(())
     }
 
@@ -478,7 +2301,74 @@ where
 
         let current_configuration = self.current_configuration.load(Ordering::Relaxed);
 
-        self.hal_driver.write_ref(0, [current_configuration].iter());
+        self.hal_driver
+            .write_ref(0, [current_configuration].iter())?;
+        self.hal_driver.ack_status_stage(setup_packet);
+
+        Ok(())
+    }
+
+    fn setup_get_status(&self, setup_packet: &SetupPacket) -> SmolResult<()> {
+        let recipient = setup_packet.recipient();
+        let requested_length = setup_packet.length as usize;
+
+        let status: u16 = match recipient {
+            Recipient::Device => {
+                // D0: self powered, D1: remote wakeup
+                let mut status = 0;
+                if self.self_powered {
+                    status |= 0b01;
+                }
+                if self.feature_remote_wakeup.get() {
+                    status |= 0b10;
+                }
+                status
+            }
+            Recipient::Interface => {
+                // all bits reserved
+                let interface_number = setup_packet.index as u8;
+                let exists = self
+                    .active_configuration_descriptor()
+                    .tail
+                    .iter()
+                    .any(|interface| interface.header().interface_number == interface_number);
+                if !exists {
+                    warn!(
+                        "SETUP stall: get status for unknown interface: {}",
+                        interface_number
+                    );
+                    self.hal_driver.stall_control_request();
+                    return Ok(());
+                }
+                0
+            }
+            Recipient::Endpoint => {
+                // D0: halt
+                let endpoint_address = setup_packet.endpoint_address();
+                if self.is_halted(endpoint_address) {
+                    0b01
+                } else {
+                    0
+                }
+            }
+            _ => {
+                warn!(
+                    "SETUP stall: unhandled get status recipient: {:?}",
+                    recipient
+                );
+                self.hal_driver.stall_control_request();
+                return Ok(());
+            }
+        };
+
+        trace!(
+            "SETUP setup_get_status() recipient:{:?} status:{:#06b}",
+            recipient,
+            status
+        );
+
+        self.hal_driver
+            .write(0, status.to_le_bytes().into_iter().take(requested_length))?;
         self.hal_driver.ack_status_stage(setup_packet);
 
         Ok(())
@@ -499,12 +2389,15 @@ where
 
         match (&recipient, &feature) {
             (Recipient::Device, Feature::DeviceRemoteWakeup) => {
-                // TODO self.feature_remote_wakeup = false;
+                self.feature_remote_wakeup.set(false);
+                self.hal_driver.ack_status_stage(setup_packet);
+                trace!("SETUP setup_clear_feature DeviceRemoteWakeup");
             }
             (Recipient::Endpoint, Feature::EndpointHalt) => {
-                let endpoint_address = setup_packet.index as u8;
+                let endpoint_address = setup_packet.endpoint_address();
                 self.hal_driver
                     .clear_feature_endpoint_halt(endpoint_address);
+                self.set_halted(endpoint_address, false);
                 self.hal_driver.ack_status_stage(setup_packet);
                 trace!(
                     "SETUP setup_clear_feature EndpointHalt: 0x{:x}",
@@ -543,7 +2436,32 @@ where
 
         match (&recipient, &feature) {
             (Recipient::Device, Feature::DeviceRemoteWakeup) => {
-                // TODO self.feature_remote_wakeup = true;
+                let attributes = self
+                    .active_configuration_descriptor()
+                    .head
+                    .configuration_attributes();
+                if !attributes.is_remote_wakeup() {
+                    warn!("SETUP stall: remote wakeup not supported by configuration");
+                    self.hal_driver.stall_control_request();
+                    return Ok(());
+                }
+                self.feature_remote_wakeup.set(true);
+                self.hal_driver.ack_status_stage(setup_packet);
+                trace!("SETUP setup_set_feature DeviceRemoteWakeup");
+            }
+            (Recipient::Endpoint, Feature::EndpointHalt) => {
+                let endpoint_address = setup_packet.endpoint_address();
+                let endpoint_number = endpoint_address & endpoint::NUMBER_MASK;
+                match Direction::from_endpoint_address(endpoint_address) {
+                    Direction::HostToDevice => self.hal_driver.stall_endpoint_out(endpoint_number),
+                    Direction::DeviceToHost => self.hal_driver.stall_endpoint_in(endpoint_number),
+                }
+                self.set_halted(endpoint_address, true);
+                self.hal_driver.ack_status_stage(setup_packet);
+                trace!(
+                    "SETUP setup_set_feature EndpointHalt: 0x{:x}",
+                    endpoint_address
+                );
             }
             _ => {
                 warn!(
@@ -560,7 +2478,17 @@ where
 }
 
 // Helpers
-impl<'a, D, const MAX_RECEIVE_SIZE: usize> UsbDevice<'a, D, MAX_RECEIVE_SIZE> where D: UsbDriver {}
+impl<
+        'a,
+        D,
+        const MAX_RECEIVE_SIZE: usize,
+        const MAX_ENDPOINTS: usize,
+        const MAX_PACKET_SIZE: usize,
+    > UsbDevice<'a, D, MAX_RECEIVE_SIZE, MAX_ENDPOINTS, MAX_PACKET_SIZE>
+where
+    D: UsbDriver,
+{
+}
 
 /*
 # Reference enumeration process (quirks merged from Linux, macOS, and Windows):
@@ -577,3 +2505,1460 @@ impl<'a, D, const MAX_RECEIVE_SIZE: usize> UsbDevice<'a, D, MAX_RECEIVE_SIZE> wh
 # - Read back configuration number and validate.
 
 */
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::class::cdc;
+    use crate::class::cdc::acm;
+    use crate::mock::MockDriver;
+    use crate::traits::{UnsafeUsbDriverOperations, WriteEndpoint, WriteRefEndpoint};
+    use zerocopy::AsBytes;
+
+    fn test_device<'a>() -> UsbDevice<'a, MockDriver, 8> {
+        let mut device = UsbDevice::new(
+            MockDriver::new(),
+            cdc::DEVICE_DESCRIPTOR,
+            cdc::CONFIGURATION_DESCRIPTOR_0,
+            cdc::USB_STRING_DESCRIPTOR_0,
+            cdc::USB_STRING_DESCRIPTORS,
+        );
+        device.set_device_qualifier_descriptor(cdc::DEVICE_QUALIFIER_DESCRIPTOR);
+        device
+    }
+
+    fn get_descriptor(descriptor_type: DescriptorType, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: 0b1000_0000, // DeviceToHost, Standard, Device
+            request: 6,                // GetDescriptor
+            value: u16::from_le_bytes([0, descriptor_type as u8]),
+            index: 0,
+            length,
+        }
+    }
+
+    /// Split a control IN response into the packets written to ep0 at high speed
+    fn ep0_packets(bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        bytes
+            .chunks(64)
+            .map(|packet| (0, packet.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_device_qualifier_high_speed() {
+        let mut device = test_device();
+
+        let setup_packet = get_descriptor(DescriptorType::DeviceQualifier, 10);
+        device.setup_request(0, &setup_packet).unwrap();
+
+        let expected = cdc::DEVICE_QUALIFIER_DESCRIPTOR.as_bytes().to_vec();
+        assert_eq!(device.hal_driver.writes(), vec![(0, expected)]);
+        assert!(device.hal_driver.stalled().is_empty());
+    }
+
+    #[test]
+    fn test_ep0_max_packet_size() {
+        let mut device = test_device();
+        device.speed.set(Speed::Full);
+
+        for max_packet_size in [8, 16, 32, 64] {
+            device.device_descriptor.max_packet_size = max_packet_size;
+            assert_eq!(device.ep0_max_packet_size(), max_packet_size.into());
+        }
+
+        // invalid sizes fall back to 64
+        device.device_descriptor.max_packet_size = 12;
+        assert_eq!(device.ep0_max_packet_size(), 64);
+
+        // other speeds ignore the descriptor
+        device.device_descriptor.max_packet_size = 8;
+        device.speed.set(Speed::Low);
+        assert_eq!(device.ep0_max_packet_size(), 8);
+        device.speed.set(Speed::High);
+        assert_eq!(device.ep0_max_packet_size(), 64);
+        device.speed.set(Speed::SuperSpeed);
+        assert_eq!(device.ep0_max_packet_size(), 512);
+    }
+
+    #[test]
+    fn test_device_descriptor_ep0_max_packet_size() {
+        let mut device = test_device();
+        device.speed.set(Speed::Full);
+        device.device_descriptor.max_packet_size = 8;
+
+        // descriptors longer than ep0's max packet size span several packets
+        let setup_packet = get_descriptor(DescriptorType::Device, 18);
+        device.setup_request(0, &setup_packet).unwrap();
+
+        let expected = device.device_descriptor.as_bytes();
+        assert_eq!(
+            device.hal_driver.writes(),
+            vec![
+                (0, expected[..8].to_vec()),
+                (0, expected[8..16].to_vec()),
+                (0, expected[16..].to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_status_self_powered() {
+        let mut device = test_device();
+        let setup_packet = SetupPacket {
+            request_type: 0b1000_0000, // DeviceToHost, Standard, Device
+            request: 0,                // GetStatus
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+
+        // cdc descriptor advertises bus-powered
+        device.setup_request(0, &setup_packet).unwrap();
+        device.set_self_powered(true);
+        device.setup_request(0, &setup_packet).unwrap();
+
+        assert_eq!(
+            device.hal_driver.writes(),
+            vec![(0, vec![0b00, 0]), (0, vec![0b01, 0])]
+        );
+    }
+
+    #[test]
+    fn test_get_status_remote_wakeup() {
+        let mut device = test_device();
+        let get_status = SetupPacket {
+            request_type: 0b1000_0000, // DeviceToHost, Standard, Device
+            request: 0,                // GetStatus
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+        let feature = |request: u8| SetupPacket {
+            request_type: 0b0000_0000, // HostToDevice, Standard, Device
+            request,
+            value: 1, // DeviceRemoteWakeup
+            index: 0,
+            length: 0,
+        };
+        let status = |device: &mut UsbDevice<MockDriver, 8>| {
+            device.setup_request(0, &get_status).unwrap();
+            device.hal_driver.writes().last().unwrap().1.clone()
+        };
+
+        // cdc descriptor does not advertise remote wakeup
+        device.setup_request(0, &feature(3)).unwrap();
+        assert_eq!(device.hal_driver.stalled(), vec![0x80, 0x00]);
+        assert!(!device.remote_wakeup_enabled());
+        assert_eq!(status(&mut device), vec![0b00, 0]);
+
+        // SetFeature(DeviceRemoteWakeup)
+        device.set_power(100, false, true).unwrap();
+        device.setup_request(0, &feature(3)).unwrap();
+        assert!(device.remote_wakeup_enabled());
+        assert_eq!(status(&mut device), vec![0b10, 0]);
+
+        // combined with self powered
+        device.set_self_powered(true);
+        assert_eq!(status(&mut device), vec![0b11, 0]);
+
+        // ClearFeature(DeviceRemoteWakeup)
+        device.setup_request(0, &feature(1)).unwrap();
+        assert!(!device.remote_wakeup_enabled());
+        assert_eq!(status(&mut device), vec![0b01, 0]);
+
+        // cleared by a bus reset
+        device.setup_request(0, &feature(3)).unwrap();
+        assert!(device.remote_wakeup_enabled());
+        device.dispatch_control(UsbEvent::BusReset).unwrap();
+        assert!(!device.remote_wakeup_enabled());
+    }
+
+    #[test]
+    fn test_get_status_interface() {
+        let mut device = test_device();
+        let mut setup_packet = SetupPacket {
+            request_type: 0b1000_0001, // DeviceToHost, Standard, Interface
+            request: 0,                // GetStatus
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+
+        device.setup_request(0, &setup_packet).unwrap();
+        assert_eq!(device.hal_driver.writes(), vec![(0, vec![0, 0])]);
+        assert!(device.hal_driver.stalled().is_empty());
+
+        // unknown interfaces are stalled
+        setup_packet.index = 7;
+        device.setup_request(0, &setup_packet).unwrap();
+        assert_eq!(device.hal_driver.writes().len(), 1);
+        assert_eq!(device.hal_driver.stalled(), vec![0x80, 0x00]);
+    }
+
+    #[test]
+    fn test_endpoint_halt_direction() {
+        let mut device = test_device();
+        let feature = |request: u8, endpoint_address: u8| SetupPacket {
+            request_type: 0b0000_0010, // HostToDevice, Standard, Endpoint
+            request,
+            value: 0, // EndpointHalt
+            index: endpoint_address.into(),
+            length: 0,
+        };
+        let get_status = |endpoint_address: u8| SetupPacket {
+            request_type: 0b1000_0010, // DeviceToHost, Standard, Endpoint
+            request: 0,                // GetStatus
+            value: 0,
+            index: endpoint_address.into(),
+            length: 2,
+        };
+
+        // SetFeature(EndpointHalt) on IN endpoint 1 leaves OUT endpoint 1
+        // alone, the status stage is acked with a zero length write
+        device.setup_request(0, &feature(3, 0x81)).unwrap();
+        assert_eq!(device.hal_driver.stalled(), vec![0x81]);
+        assert!(device.is_halted(0x81));
+        assert!(!device.is_halted(0x01));
+
+        device.setup_request(0, &get_status(0x81)).unwrap();
+        device.setup_request(0, &get_status(0x01)).unwrap();
+        assert_eq!(
+            device.hal_driver.writes()[1..],
+            [(0, vec![0b01, 0]), (0, vec![0b00, 0])]
+        );
+
+        // ClearFeature(EndpointHalt) on OUT endpoint 1 leaves IN endpoint 1 halted
+        device.setup_request(0, &feature(1, 0x01)).unwrap();
+        assert_eq!(device.hal_driver.stalled(), vec![0x81]);
+        assert!(device.is_halted(0x81));
+
+        device.setup_request(0, &feature(1, 0x81)).unwrap();
+        assert!(device.hal_driver.stalled().is_empty());
+        assert!(!device.is_halted(0x81));
+    }
+
+    #[test]
+    fn test_endpoint_owner() {
+        let mut device: UsbDevice<'_, MockDriver, 8> = UsbDevice::new(
+            MockDriver::new(),
+            acm::DEVICE_DESCRIPTOR,
+            acm::CONFIGURATION_DESCRIPTOR_0,
+            acm::USB_STRING_DESCRIPTOR_0,
+            acm::USB_STRING_DESCRIPTORS,
+        );
+
+        assert_eq!(device.endpoint_owner(0x81), Some(0));
+        assert_eq!(device.endpoint_owner(0x82), Some(1));
+        assert_eq!(device.endpoint_owner(0x02), Some(1));
+        assert_eq!(device.endpoint_owner(0x01), None);
+
+        // SetInterface(interface: 1, alternate_setting: 0)
+        let setup_packet = SetupPacket {
+            request_type: 0b0000_0001, // HostToDevice, Standard, Interface
+            request: 11,               // SetInterface
+            value: 0,
+            index: 1,
+            length: 0,
+        };
+        device.setup_request(0, &setup_packet).unwrap();
+
+        assert_eq!(device.hal_driver.primed(), vec![2]);
+        assert!(device.is_primed_out(2));
+    }
+
+    #[test]
+    fn test_connect() {
+        let device = test_device();
+        assert_eq!(device.connect(), Ok(Speed::High));
+
+        device.hal_driver.fail_connect(ConnectError::NoVbus);
+        assert_eq!(device.connect(), Err(ConnectError::NoVbus));
+    }
+
+    #[test]
+    fn test_connect_and_wait_reset() {
+        let mut device = test_device();
+
+        // events before the reset are discarded
+        device.hal_driver.queue_event(UsbEvent::SendComplete(1));
+        device.hal_driver.queue_event(UsbEvent::BusReset);
+        device.hal_driver.queue_event(UsbEvent::ReceiveControl(0));
+        assert_eq!(device.connect_and_wait_reset(10), Ok(Speed::High));
+        assert_eq!(device.hal_driver.bus_resets(), 1);
+        assert_eq!(device.state(), DeviceState::Reset);
+
+        // events after the reset are left for the main loop
+        assert!(matches!(
+            device.hal_driver.poll_event(),
+            Some(UsbEvent::ReceiveControl(0))
+        ));
+
+        // no reset
+        assert_eq!(device.connect_and_wait_reset(10), Err(SmolError::Timeout));
+        assert_eq!(device.hal_driver.bus_resets(), 1);
+
+        device.hal_driver.fail_connect(ConnectError::NoVbus);
+        device.hal_driver.queue_event(UsbEvent::BusReset);
+        assert_eq!(
+            device.connect_and_wait_reset(10),
+            Err(SmolError::NotConnected)
+        );
+        assert_eq!(device.hal_driver.bus_resets(), 1);
+    }
+
+    #[test]
+    fn test_configuration_descriptor() {
+        let mut device = test_device();
+
+        let mut expected = cdc::CONFIGURATION_DESCRIPTOR_0;
+        expected.set_total_length();
+        let expected: std::vec::Vec<u8> = expected.iter().copied().collect();
+
+        device
+            .setup_request(0, &get_descriptor(DescriptorType::Configuration, 9))
+            .unwrap();
+        device
+            .setup_request(0, &get_descriptor(DescriptorType::Configuration, 0xff))
+            .unwrap();
+        let mut packets = vec![(0, expected[..9].to_vec())];
+        packets.extend(ep0_packets(&expected));
+        assert_eq!(device.hal_driver.writes(), packets);
+
+        // replacing the descriptors also replaces any cached copy
+        device
+            .update_descriptors(DescriptorSet {
+                device_descriptor: acm::DEVICE_DESCRIPTOR,
+                configuration_descriptor: acm::CONFIGURATION_DESCRIPTOR_0,
+                device_qualifier_descriptor: None,
+                other_speed_configuration_descriptor: None,
+                string_descriptor_zero: acm::USB_STRING_DESCRIPTOR_0,
+                string_descriptors: acm::USB_STRING_DESCRIPTORS,
+            })
+            .unwrap();
+
+        let mut expected = acm::CONFIGURATION_DESCRIPTOR_0;
+        expected.set_total_length();
+        let expected: std::vec::Vec<u8> = expected.iter().copied().collect();
+
+        device
+            .setup_request(0, &get_descriptor(DescriptorType::Configuration, 0xff))
+            .unwrap();
+        assert_eq!(
+            device.hal_driver.writes()[packets.len()..],
+            ep0_packets(&expected)
+        );
+    }
+
+    #[test]
+    fn test_configuration_descriptor_index() {
+        const CONFIGURATION_DESCRIPTOR_1: ConfigurationDescriptor = ConfigurationDescriptor::new(
+            ConfigurationDescriptorHeader {
+                configuration_value: 2,
+                ..acm::CONFIGURATION_DESCRIPTOR_0.head
+            },
+            acm::CONFIGURATION_DESCRIPTOR_0.tail,
+        );
+
+        let mut device = test_device();
+        device.set_additional_configuration_descriptors(&[CONFIGURATION_DESCRIPTOR_1]);
+
+        let mut expected_0 = cdc::CONFIGURATION_DESCRIPTOR_0;
+        expected_0.set_total_length();
+        let expected_0: std::vec::Vec<u8> = expected_0.iter().copied().collect();
+        let mut expected_1 = CONFIGURATION_DESCRIPTOR_1;
+        expected_1.set_total_length();
+        let expected_1: std::vec::Vec<u8> = expected_1.iter().copied().collect();
+
+        // the low byte of wValue is the configuration index
+        for index in 0..3 {
+            let mut setup_packet = get_descriptor(DescriptorType::Configuration, 0xff);
+            setup_packet.value |= index;
+            device.setup_request(0, &setup_packet).unwrap();
+        }
+        let mut packets = ep0_packets(&expected_0);
+        packets.extend(ep0_packets(&expected_1));
+        assert_eq!(device.hal_driver.writes(), packets);
+        assert_eq!(device.hal_driver.stalled(), vec![0x80, 0x00]);
+
+        // SetConfiguration selects by configuration value, not index
+        let setup_packet = SetupPacket {
+            request_type: 0b0000_0000, // HostToDevice, Standard, Device
+            request: 9,                // SetConfiguration
+            value: 2,
+            index: 0,
+            length: 0,
+        };
+        device.setup_request(0, &setup_packet).unwrap();
+        assert_eq!(device.current_configuration.load(Ordering::Relaxed), 2);
+        assert_eq!(device.endpoint_owner(0x82), Some(1));
+    }
+
+    #[test]
+    fn test_descriptor_bytes() {
+        let mut device = test_device();
+        assert_eq!(
+            device.device_descriptor_bytes(),
+            cdc::DEVICE_DESCRIPTOR.as_bytes()
+        );
+
+        // matches what GET_DESCRIPTOR sends
+        let mut buffer = [0; 128];
+        let length = device
+            .configuration_descriptor_bytes(0, &mut buffer)
+            .unwrap();
+        device
+            .setup_request(0, &get_descriptor(DescriptorType::Configuration, 0xff))
+            .unwrap();
+        assert_eq!(
+            device.hal_driver.writes(),
+            vec![(0, buffer[..length].to_vec())]
+        );
+
+        // truncated to the buffer
+        let mut short = [0; 9];
+        assert_eq!(device.configuration_descriptor_bytes(0, &mut short), Ok(9));
+        assert_eq!(short, buffer[..9]);
+        assert_eq!(
+            device.configuration_descriptor_bytes(1, &mut buffer),
+            Err(SmolError::InvalidArgument)
+        );
+
+        // reflects runtime descriptor changes
+        device
+            .update_descriptors(DescriptorSet {
+                device_descriptor: acm::DEVICE_DESCRIPTOR,
+                configuration_descriptor: acm::CONFIGURATION_DESCRIPTOR_0,
+                device_qualifier_descriptor: None,
+                other_speed_configuration_descriptor: None,
+                string_descriptor_zero: acm::USB_STRING_DESCRIPTOR_0,
+                string_descriptors: acm::USB_STRING_DESCRIPTORS,
+            })
+            .unwrap();
+        let mut expected = acm::CONFIGURATION_DESCRIPTOR_0;
+        expected.set_total_length();
+        let expected: std::vec::Vec<u8> = expected.iter().copied().collect();
+        let length = device
+            .configuration_descriptor_bytes(0, &mut buffer)
+            .unwrap();
+        assert_eq!(&buffer[..length], &expected[..]);
+        assert_eq!(
+            device.device_descriptor_bytes(),
+            acm::DEVICE_DESCRIPTOR.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_debug_descriptor() {
+        let mut device = test_device();
+
+        // stalls if unset
+        let setup_packet = get_descriptor(DescriptorType::Debug, 4);
+        device.setup_request(0, &setup_packet).unwrap();
+        assert!(device.hal_driver.writes().is_empty());
+        assert_eq!(device.hal_driver.stalled(), vec![0x80, 0x00]);
+
+        device.set_debug_descriptor(DebugDescriptor {
+            debug_in_endpoint_address: 0x81,
+            debug_out_endpoint_address: 0x01,
+            ..DebugDescriptor::new()
+        });
+        device.setup_request(0, &setup_packet).unwrap();
+        assert_eq!(
+            device.hal_driver.writes(),
+            vec![(0, vec![4, 10, 0x81, 0x01])]
+        );
+    }
+
+    #[test]
+    fn test_bos_descriptor() {
+        let mut device = test_device();
+
+        // stalls if unset
+        let setup_packet = get_descriptor(DescriptorType::BinaryDeviceObjectStore, 5);
+        device.setup_request(0, &setup_packet).unwrap();
+        assert_eq!(device.hal_driver.stalled(), vec![0x80, 0x00]);
+
+        // hosts read the header first, then the total length
+        device.set_bos_descriptor(BosDescriptor {
+            usb20_extension: Some(Usb20ExtensionDescriptor::new()),
+            ..BosDescriptor::new()
+        });
+        device.setup_request(0, &setup_packet).unwrap();
+        let setup_packet = get_descriptor(DescriptorType::BinaryDeviceObjectStore, 12);
+        device.setup_request(0, &setup_packet).unwrap();
+        assert_eq!(
+            device.hal_driver.writes(),
+            vec![
+                (0, vec![5, 15, 12, 0, 1]),
+                (0, vec![5, 15, 12, 0, 1, 7, 16, 2, 0, 0, 0, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_device_qualifier_full_speed_only() {
+        let mut device = test_device();
+        device.set_high_speed_capable(false);
+
+        let setup_packet = get_descriptor(DescriptorType::DeviceQualifier, 10);
+        device.setup_request(0, &setup_packet).unwrap();
+
+        assert!(device.hal_driver.writes().is_empty());
+        assert_eq!(device.hal_driver.stalled(), vec![0x80, 0x00]);
+    }
+
+    #[test]
+    fn test_control_out_exceeds_max_transfer_size() {
+        let mut device: UsbDevice<MockDriver, 64> = UsbDevice::new(
+            MockDriver::new(),
+            cdc::DEVICE_DESCRIPTOR,
+            cdc::CONFIGURATION_DESCRIPTOR_0,
+            cdc::USB_STRING_DESCRIPTOR_0,
+            cdc::USB_STRING_DESCRIPTORS,
+        );
+        device.set_max_control_transfer_size(16);
+
+        // HostToDevice, Vendor, Device with a 16 byte data stage
+        let setup_packet = [0x40, 0x01, 0x00, 0x00, 0x00, 0x00, 16, 0x00];
+        device.hal_driver.receive_setup_packet(setup_packet);
+        device
+            .dispatch_control(UsbEvent::ReceiveControl(0))
+            .unwrap();
+        assert!(device.hal_driver.stalled().is_empty());
+
+        // ... and with a 17 byte data stage
+        let setup_packet = [0x40, 0x01, 0x00, 0x00, 0x00, 0x00, 17, 0x00];
+        device.hal_driver.receive_setup_packet(setup_packet);
+        device
+            .dispatch_control(UsbEvent::ReceiveControl(0))
+            .unwrap();
+        assert_eq!(device.hal_driver.stalled(), vec![0x00, 0x80]);
+    }
+
+    /// Deliver a SETUP packet to the control endpoint
+    fn receive_setup<'a>(
+        device: &mut UsbDevice<'a, MockDriver, 8>,
+        setup_packet: [u8; 8],
+    ) -> Option<ControlEvent<'a, 8>> {
+        device.hal_driver.receive_setup_packet(setup_packet);
+        device
+            .dispatch_control(UsbEvent::ReceiveControl(0))
+            .unwrap()
+    }
+
+    const SET_ADDRESS: [u8; 8] = [0x00, 0x05, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00];
+    const SET_CONFIGURATION: [u8; 8] = [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+    // HostToDevice, Vendor, Device with a 4 byte data stage
+    const VENDOR_OUT: [u8; 8] = [0x40, 0x01, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00];
+
+    #[test]
+    fn test_recovery_policy() {
+        use core::sync::atomic::AtomicUsize;
+
+        static RECOVERIES: AtomicUsize = AtomicUsize::new(0);
+
+        let mut device = test_device();
+        device.set_recovery_policy(RecoveryPolicy::reconnect_after(2));
+        device.cb_recovery = Some(|_| {
+            RECOVERIES.fetch_add(1, Ordering::Relaxed);
+        });
+
+        // overflow the data stage of a 4 byte control OUT request
+        fn control_error(device: &mut UsbDevice<MockDriver, 8>) -> SmolResult<()> {
+            receive_setup(device, VENDOR_OUT);
+            device.hal_driver.receive_packet(&[0; 8]);
+            device.dispatch_control(UsbEvent::ReceivePacket(0))?;
+            Ok(())
+        }
+
+        // errors are returned but a completed transfer resets the count
+        assert_eq!(control_error(&mut device), Err(SmolError::FifoOverflow));
+        receive_setup(&mut device, SET_ADDRESS);
+        device.dispatch_control(UsbEvent::SendComplete(0)).unwrap();
+        assert!(device.control.is_idle());
+        assert_eq!(control_error(&mut device), Err(SmolError::FifoOverflow));
+        assert_eq!(device.hal_driver.disconnects(), 0);
+        assert_eq!(device.recoveries(), 0);
+        assert_eq!(RECOVERIES.load(Ordering::Relaxed), 0);
+
+        // repeated errors reconnect the device
+        assert_eq!(control_error(&mut device), Err(SmolError::FifoOverflow));
+        assert_eq!(device.hal_driver.disconnects(), 1);
+        assert_eq!(device.recoveries(), 1);
+        assert_eq!(RECOVERIES.load(Ordering::Relaxed), 1);
+        assert_eq!(device.state(), DeviceState::None);
+        assert!(device.control.is_idle());
+
+        // errors are only reported without a policy
+        device.set_recovery_policy(RecoveryPolicy::DISABLED);
+        for _ in 0..4 {
+            assert_eq!(control_error(&mut device), Err(SmolError::FifoOverflow));
+        }
+        assert_eq!(device.recoveries(), 1);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let mut device = test_device();
+        device.state.replace(DeviceState::Configured);
+        device.current_configuration.store(1, Ordering::Relaxed);
+
+        device.shutdown();
+
+        assert_eq!(device.hal_driver.disconnects(), 1);
+        assert_eq!(device.state(), DeviceState::None);
+        assert_eq!(device.current_configuration.load(Ordering::Relaxed), 0);
+        assert!(device.control.is_idle());
+
+        assert!(device.connect().is_ok());
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        use core::sync::atomic::AtomicUsize;
+
+        static SUSPENDS: AtomicUsize = AtomicUsize::new(0);
+        static RESUMES: AtomicUsize = AtomicUsize::new(0);
+
+        let mut device = test_device();
+        device.cb_suspend = Some(|_| {
+            SUSPENDS.fetch_add(1, Ordering::Relaxed);
+        });
+        device.cb_resume = Some(|_| {
+            RESUMES.fetch_add(1, Ordering::Relaxed);
+        });
+        device.state.replace(DeviceState::Configured);
+
+        // disabled by default
+        assert_eq!(device.idle_tick(1000).map(u8::from), None);
+
+        device.set_idle_timeout(Some(3));
+        assert_eq!(device.idle_tick(2).map(u8::from), None);
+
+        // bus activity restarts the idle period
+        device.dispatch_control(UsbEvent::SendComplete(0)).unwrap();
+        assert_eq!(device.idle_tick(2).map(u8::from), None);
+        assert_eq!(device.state(), DeviceState::Configured);
+
+        // idle past the threshold
+        assert_eq!(device.idle_tick(1).map(u8::from), Some(16));
+        assert_eq!(device.state(), DeviceState::Suspended);
+        assert_eq!(SUSPENDS.load(Ordering::Relaxed), 1);
+
+        // only suspends once
+        assert_eq!(device.idle_tick(10).map(u8::from), None);
+        assert_eq!(SUSPENDS.load(Ordering::Relaxed), 1);
+
+        // and wakes on bus activity
+        device.bus_activity();
+        assert_eq!(device.state(), DeviceState::Configured);
+        assert_eq!(RESUMES.load(Ordering::Relaxed), 1);
+        assert_eq!(device.idle_tick(2).map(u8::from), None);
+    }
+
+    #[cfg(feature = "control-capture")]
+    #[test]
+    fn test_control_capture() {
+        let mut device = test_device();
+
+        // nothing is recorded until enabled
+        receive_setup(&mut device, SET_ADDRESS);
+        assert!(device.capture.borrow().is_empty());
+
+        device.capture.borrow_mut().set_enabled(true);
+        receive_setup(&mut device, SET_CONFIGURATION);
+        assert!(receive_setup(&mut device, VENDOR_OUT).is_none());
+        device.hal_driver.receive_packet(&[1, 2, 3, 4]);
+        device.dispatch_control(UsbEvent::ReceivePacket(0)).unwrap();
+
+        let mut capture = device.capture.borrow_mut();
+        assert_eq!(capture.len(), 2);
+        assert_eq!(capture.pop().unwrap().setup, SET_CONFIGURATION);
+        let record = capture.pop().unwrap();
+        assert_eq!(record.setup, VENDOR_OUT);
+        assert_eq!(record.data(), &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_set_address_readdress() {
+        let mut device = test_device();
+        receive_setup(&mut device, SET_ADDRESS);
+
+        // the address is activated once the status stage has been sent
+        assert_eq!(device.hal_driver.address(), 0);
+        device.dispatch_control(UsbEvent::SendComplete(0)).unwrap();
+        assert_eq!(device.state(), DeviceState::Addressed);
+        assert_eq!(device.hal_driver.address(), 7);
+        assert_eq!(device.take_readdressed(), None);
+
+        // accepted while addressed
+        receive_setup(
+            &mut device,
+            [0x00, 0x05, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00],
+        );
+        device.dispatch_control(UsbEvent::SendComplete(0)).unwrap();
+        assert_eq!(device.state(), DeviceState::Addressed);
+        assert_eq!(device.hal_driver.address(), 9);
+        assert_eq!(device.take_readdressed(), Some(9));
+        assert_eq!(device.take_readdressed(), None);
+
+        // stalled while configured
+        receive_setup(&mut device, SET_CONFIGURATION);
+        receive_setup(&mut device, SET_ADDRESS);
+        assert_eq!(device.state(), DeviceState::Configured);
+        assert_eq!(device.hal_driver.address(), 9);
+        assert_eq!(device.hal_driver.stalled(), vec![0x80, 0x00]);
+        assert_eq!(device.take_readdressed(), Some(7));
+
+        // address zero returns to the default state
+        device.dispatch_control(UsbEvent::BusReset).unwrap();
+        receive_setup(&mut device, SET_ADDRESS);
+        device.dispatch_control(UsbEvent::SendComplete(0)).unwrap();
+        receive_setup(
+            &mut device,
+            [0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        );
+        device.dispatch_control(UsbEvent::SendComplete(0)).unwrap();
+        assert_eq!(device.state(), DeviceState::Reset);
+        assert_eq!(device.hal_driver.address(), 0);
+        assert_eq!(device.take_readdressed(), None);
+    }
+
+    #[test]
+    fn test_set_address_polled() {
+        let mut device = test_device();
+
+        // SET_ADDRESS returns without waiting for the status stage
+        device.hal_driver.receive_setup_packet(SET_ADDRESS);
+        device.hal_driver.queue_event(UsbEvent::ReceiveControl(0));
+        assert!(device.poll().is_none());
+        assert_eq!(device.hal_driver.writes(), vec![(0, vec![])]);
+        assert_eq!(device.hal_driver.address(), 0);
+        assert!(unsafe { device.hal_driver.is_tx_ack_active() });
+
+        // and activates the address once it has been sent
+        device.hal_driver.queue_event(UsbEvent::SendComplete(0));
+        assert!(device.poll().is_none());
+        assert!(!unsafe { device.hal_driver.is_tx_ack_active() });
+        assert_eq!(device.hal_driver.address(), 7);
+        assert_eq!(device.state(), DeviceState::Addressed);
+    }
+
+    #[test]
+    fn test_set_configuration_unknown() {
+        let mut device = test_device();
+        receive_setup(&mut device, SET_ADDRESS);
+        device.dispatch_control(UsbEvent::SendComplete(0)).unwrap();
+        let writes = device.hal_driver.writes().len();
+
+        // unknown configurations are stalled without acking the status stage
+        receive_setup(
+            &mut device,
+            [0x00, 0x09, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(device.hal_driver.stalled(), vec![0x80, 0x00]);
+        assert_eq!(device.hal_driver.writes().len(), writes);
+        assert_eq!(device.state(), DeviceState::Addressed);
+        assert_eq!(device.current_configuration.load(Ordering::Relaxed), 0);
+
+        // known configurations are acked
+        receive_setup(&mut device, SET_CONFIGURATION);
+        assert_eq!(device.hal_driver.writes()[writes..], [(0, vec![])]);
+        assert_eq!(device.state(), DeviceState::Configured);
+    }
+
+    #[test]
+    fn test_repeated_bus_reset() {
+        let mut device = test_device();
+
+        for _ in 0..10 {
+            device.dispatch_control(UsbEvent::BusReset).unwrap();
+            assert_eq!(device.state(), DeviceState::Reset);
+            assert_eq!(device.current_configuration.load(Ordering::Relaxed), 0);
+            assert_eq!(device.hal_driver.address(), 0);
+            assert!(!device.is_primed_out(2));
+
+            receive_setup(&mut device, SET_ADDRESS);
+            device.dispatch_control(UsbEvent::SendComplete(0)).unwrap();
+            receive_setup(&mut device, SET_CONFIGURATION);
+            assert_eq!(device.state(), DeviceState::Configured);
+            assert!(device.is_primed_out(2));
+        }
+
+        assert_eq!(device.hal_driver.bus_resets(), 10);
+    }
+
+    #[test]
+    fn test_bus_reset_between_control_stages() {
+        let stages = [
+            UsbEvent::ReceiveControl(0),
+            UsbEvent::ReceivePacket(0),
+            UsbEvent::SendComplete(0),
+        ];
+
+        for reset_after in 0..stages.len() {
+            let mut device = test_device();
+
+            // interrupt the transfer with a bus reset after each stage
+            for (n, &event) in stages.iter().enumerate() {
+                match event {
+                    UsbEvent::ReceiveControl(_) => {
+                        device.hal_driver.receive_setup_packet(VENDOR_OUT)
+                    }
+                    UsbEvent::ReceivePacket(_) => device.hal_driver.receive_packet(&[1, 2, 3, 4]),
+                    _ => (),
+                }
+                let response = device.dispatch_control(event).unwrap();
+                assert!(response.is_none() || n <= reset_after);
+                if n == reset_after {
+                    device.dispatch_control(UsbEvent::BusReset).unwrap();
+                    assert_eq!(device.state(), DeviceState::Reset);
+                    assert!(device.control.is_idle());
+                }
+            }
+            assert!(device.control.is_idle());
+
+            // the device recovers and completes the next transfer
+            assert!(receive_setup(&mut device, VENDOR_OUT).is_none());
+            device.hal_driver.receive_packet(&[1, 2, 3, 4]);
+            let response = device
+                .dispatch_control(UsbEvent::ReceivePacket(0))
+                .unwrap()
+                .unwrap();
+            assert_eq!(&response.data[..response.bytes_read], &[1, 2, 3, 4]);
+        }
+    }
+
+    #[test]
+    fn test_reset_endpoint_reprimes_out_endpoints() {
+        let mut device = test_device();
+        receive_setup(&mut device, SET_CONFIGURATION);
+        let primed = device.hal_driver.primed().len();
+
+        device.reset_endpoint(0x02);
+
+        assert_eq!(device.hal_driver.endpoint_resets(), vec![0x02]);
+        assert_eq!(device.hal_driver.primed()[primed..], [2]);
+        assert_eq!(device.state(), DeviceState::Configured);
+    }
+
+    #[test]
+    fn test_reset_endpoint_keeps_other_out_packet() {
+        let mut device = test_device();
+        receive_setup(&mut device, SET_CONFIGURATION);
+        let primed = device.hal_driver.primed().len();
+        device.hal_driver.receive_packet_on(2, &[1, 2, 3]);
+
+        // resetting an unprimed endpoint leaves the shared FIFO alone
+        device.reset_endpoint(0x01);
+        assert_eq!(device.hal_driver.primed().len(), primed);
+        assert!(device.hal_driver.has_packet());
+
+        // resetting the packet's endpoint discards it and re-primes
+        // only that endpoint
+        device.reset_endpoint(0x02);
+        assert_eq!(device.hal_driver.primed()[primed..], [2]);
+        assert!(!device.hal_driver.has_packet());
+    }
+
+    #[test]
+    fn test_reset_endpoint_flushes_in() {
+        let device = test_device();
+
+        device
+            .hal_driver
+            .try_write(1, [1, 2, 3].into_iter())
+            .unwrap();
+        device
+            .hal_driver
+            .try_write(3, [4, 5, 6].into_iter())
+            .unwrap();
+
+        // only the reset endpoint's queued bytes are discarded
+        device.reset_endpoint(0x81);
+        assert_eq!(device.hal_driver.writes(), vec![(3, vec![4, 5, 6])]);
+        assert!(!device.hal_driver.is_in_flight(1));
+        assert!(device.hal_driver.is_in_flight(3));
+    }
+
+    #[test]
+    fn test_ep_out_prime_retry() {
+        let mut device = test_device();
+
+        // a failed prime is retried
+        device.hal_driver.fail_primes(1);
+        assert_eq!(device.ep_out_prime_receive(2), Ok(()));
+        assert_eq!(device.hal_driver.primed(), vec![2]);
+        assert!(device.is_primed_out(2));
+
+        // but only a bounded number of times
+        device.set_transfer_policy(TransferPolicy {
+            max_retries: 1,
+            ..TransferPolicy::DEFAULT
+        });
+        device.hal_driver.fail_primes(2);
+        assert_eq!(device.ep_out_prime_receive(3), Err(SmolError::PrimeFailed));
+        assert_eq!(device.hal_driver.primed(), vec![2]);
+        assert!(!device.is_primed_out(3));
+    }
+
+    #[test]
+    fn test_endpoint_direction_misuse() {
+        let device = test_device();
+
+        // endpoint 1 is only declared as IN
+        assert_eq!(
+            device.ep_out_prime_receive(1),
+            Err(SmolError::InvalidArgument)
+        );
+        // an endpoint address instead of a number
+        assert_eq!(
+            device.ep_out_prime_receive(0x82),
+            Err(SmolError::InvalidArgument)
+        );
+        assert!(device.hal_driver.primed().is_empty());
+        assert!(!device.is_primed_out(1));
+
+        // reading an IN endpoint leaves the driver untouched
+        device.hal_driver.receive_packet(&[1, 2, 3]);
+        assert_eq!(device.read_packet(1).0, 0);
+        assert!(device.hal_driver.has_packet());
+        assert!(device.hal_driver.primed().is_empty());
+        assert!(!device.set_double_buffered(1, true));
+
+        assert_eq!(device.check_endpoint(2, Direction::HostToDevice), Ok(()));
+        assert_eq!(device.check_endpoint(2, Direction::DeviceToHost), Ok(()));
+        assert_eq!(device.check_endpoint(1, Direction::DeviceToHost), Ok(()));
+        assert_eq!(device.check_endpoint(0, Direction::HostToDevice), Ok(()));
+        assert_eq!(
+            device.check_endpoint(0x81, Direction::DeviceToHost),
+            Err(SmolError::InvalidArgument)
+        );
+        // undeclared endpoints are accepted
+        assert_eq!(device.check_endpoint(5, Direction::HostToDevice), Ok(()));
+    }
+
+    #[test]
+    fn test_zero_length_data_stage() {
+        let mut device = test_device();
+
+        // a SET request without a data stage is returned straight away
+        // and acked with a ZLP
+        assert!(receive_setup(&mut device, SET_CONFIGURATION).is_none());
+        assert_eq!(device.hal_driver.writes(), vec![(0, vec![])]);
+        assert!(device
+            .hal_driver
+            .primed()
+            .iter()
+            .all(|&endpoint| endpoint != 0));
+        assert_eq!(device.control.stage(), TransferStage::StatusIn);
+        device.dispatch_control(UsbEvent::SendComplete(0)).unwrap();
+        assert!(device.control.is_idle());
+
+        // a GET request sends its data stage and waits for the host's ZLP
+        let primed = device.hal_driver.primed().len();
+        receive_setup(
+            &mut device,
+            [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00],
+        );
+        assert_eq!(device.hal_driver.writes()[1].1.len(), 18);
+        assert_eq!(device.hal_driver.primed()[primed..], [0]);
+        assert_eq!(device.control.stage(), TransferStage::DataIn);
+        device.hal_driver.receive_packet(&[]);
+        device.dispatch_control(UsbEvent::ReceivePacket(0)).unwrap();
+        assert!(device.control.is_idle());
+
+        // an IN request without a data stage also gets an IN status stage
+        device.set_unknown_request_policy(UnknownRequestPolicy::IgnoreUnknown);
+        let primed = device.hal_driver.primed().len();
+        receive_setup(
+            &mut device,
+            [0xc0, 0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(device.control.stage(), TransferStage::StatusIn);
+        device.unknown_control_request(&SetupPacket {
+            request_type: 0xc0,
+            request: 0x42,
+            value: 0,
+            index: 0,
+            length: 0,
+        });
+        assert_eq!(device.hal_driver.writes()[2], (0, vec![]));
+        assert_eq!(device.hal_driver.primed().len(), primed);
+        device.dispatch_control(UsbEvent::SendComplete(0)).unwrap();
+        assert!(device.control.is_idle());
+    }
+
+    #[test]
+    fn test_on_endpoint_complete() {
+        use core::sync::atomic::AtomicUsize;
+
+        static PART: AtomicUsize = AtomicUsize::new(0);
+        const RESPONSE: [u8; 10] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+        fn send_next_part(device: &UsbDevice<MockDriver, 8>, endpoint_number: u8) {
+            let part = PART.fetch_add(1, Ordering::Relaxed);
+            if let Some(data) = RESPONSE.chunks(4).nth(part) {
+                device
+                    .hal_driver
+                    .write(endpoint_number, data.iter().copied())
+                    .unwrap();
+            }
+        }
+
+        let mut device = test_device();
+        assert_eq!(
+            device.on_endpoint_complete(2, Direction::DeviceToHost, Some(send_next_part)),
+            Ok(())
+        );
+        // endpoint 1 is only declared as IN
+        assert_eq!(
+            device.on_endpoint_complete(1, Direction::HostToDevice, Some(send_next_part)),
+            Err(SmolError::InvalidArgument)
+        );
+
+        // the multi-part IN transfer is chained from the callback
+        send_next_part(&device, 2);
+        for _ in 0..3 {
+            device.hal_driver.queue_event(UsbEvent::SendComplete(2));
+            assert!(device.poll().is_none());
+        }
+        assert_eq!(
+            device.hal_driver.writes(),
+            vec![
+                (2, vec![0, 1, 2, 3]),
+                (2, vec![4, 5, 6, 7]),
+                (2, vec![8, 9]),
+            ]
+        );
+        assert_eq!(device.endpoints.borrow().stats_in[2].packets, 3);
+
+        // endpoints without a callback are returned to the caller
+        device.hal_driver.queue_event(UsbEvent::ReceivePacket(2));
+        assert!(matches!(device.poll(), Some(UsbEvent::ReceivePacket(2))));
+
+        device
+            .on_endpoint_complete(2, Direction::DeviceToHost, None)
+            .unwrap();
+        device.hal_driver.queue_event(UsbEvent::SendComplete(2));
+        assert!(matches!(device.poll(), Some(UsbEvent::SendComplete(2))));
+    }
+
+    #[test]
+    fn test_vendor_request_stall() {
+        fn handle_vendor_request(
+            device: &UsbDevice<MockDriver, 8>,
+            _setup_packet: &SetupPacket,
+            request: u8,
+        ) -> ControlOutcome {
+            match request {
+                0x5f => {
+                    device.hal_driver.write(0, [0, 0].into_iter()).unwrap();
+                    ControlOutcome::Handled
+                }
+                _ => ControlOutcome::Stall,
+            }
+        }
+
+        let mut device = test_device();
+        device.cb_vendor_request = Some(handle_vendor_request);
+
+        let mut setup_packet = SetupPacket {
+            request_type: 0b1100_0000, // DeviceToHost, Vendor, Device
+            request: 0x5f,
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+        assert!(matches!(device.setup_request(0, &setup_packet), Ok(None)));
+        assert_eq!(device.hal_driver.writes(), vec![(0, vec![0, 0])]);
+        assert!(device.hal_driver.stalled().is_empty());
+
+        // unsupported requests are stalled rather than spoofed
+        setup_packet.request = 0x60;
+        assert!(matches!(device.setup_request(0, &setup_packet), Ok(None)));
+        assert_eq!(device.hal_driver.writes().len(), 1);
+        assert_eq!(device.hal_driver.stalled(), vec![0x80, 0x00]);
+    }
+
+    #[test]
+    fn test_interface_get_descriptor() {
+        let mut device = test_device();
+
+        // a HID report descriptor is left to the interface's class handler
+        let event = receive_setup(
+            &mut device,
+            [0x81, 0x06, 0x00, 0x22, 0x02, 0x00, 0x40, 0x00],
+        );
+        assert!(matches!(
+            event,
+            Some(ControlEvent {
+                setup_packet: SetupPacket { index: 2, .. },
+                ..
+            })
+        ));
+        assert!(device.hal_driver.writes().is_empty());
+        assert!(device.hal_driver.stalled().is_empty());
+    }
+
+    #[test]
+    fn test_control_respond() {
+        const RESPONSE: [u8; 20] = [
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19,
+        ];
+
+        // respond with the number of bytes given by bRequest
+        fn handle_vendor_request(
+            device: &UsbDevice<MockDriver, 8>,
+            _setup_packet: &SetupPacket,
+            request: u8,
+        ) -> ControlOutcome {
+            match device.control_respond(&RESPONSE[..request.into()]) {
+                Ok(_) => ControlOutcome::Handled,
+                Err(_) => ControlOutcome::Stall,
+            }
+        }
+
+        let mut device = test_device();
+        device.speed.set(Speed::Full);
+        device.device_descriptor.max_packet_size = 8;
+        device.cb_vendor_request = Some(handle_vendor_request);
+
+        // the response is split into packets and the status stage primed
+        let primed = device.hal_driver.primed().len();
+        receive_setup(&mut device, [0xc0, 20, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00]);
+        assert_eq!(
+            device.hal_driver.writes(),
+            vec![
+                (0, RESPONSE[..8].to_vec()),
+                (0, RESPONSE[8..16].to_vec()),
+                (0, RESPONSE[16..].to_vec()),
+            ]
+        );
+        assert_eq!(device.hal_driver.primed()[primed..], [0]);
+        assert!(device.hal_driver.stalled().is_empty());
+        device.hal_driver.receive_packet(&[]);
+        device.dispatch_control(UsbEvent::ReceivePacket(0)).unwrap();
+        assert!(device.control.is_idle());
+
+        // truncated to wLength without a terminating ZLP
+        receive_setup(&mut device, [0xc0, 20, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00]);
+        assert_eq!(
+            device.hal_driver.writes()[3..],
+            [(0, RESPONSE[..8].to_vec()), (0, RESPONSE[8..16].to_vec())]
+        );
+        device.hal_driver.receive_packet(&[]);
+        device.dispatch_control(UsbEvent::ReceivePacket(0)).unwrap();
+
+        // short responses ending on a packet boundary get a ZLP
+        receive_setup(&mut device, [0xc0, 16, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00]);
+        assert_eq!(
+            device.hal_driver.writes()[5..],
+            [
+                (0, RESPONSE[..8].to_vec()),
+                (0, RESPONSE[8..16].to_vec()),
+                (0, vec![]),
+            ]
+        );
+        device.hal_driver.receive_packet(&[]);
+        device.dispatch_control(UsbEvent::ReceivePacket(0)).unwrap();
+
+        // there is nothing to respond to once the transfer is complete
+        assert!(matches!(
+            device.control_respond(&RESPONSE),
+            Err(SmolError::InvalidControlStage)
+        ));
+    }
+
+    #[test]
+    fn test_set_interrupt_source() {
+        let device = test_device();
+
+        for source in [
+            InterruptSource::Controller,
+            InterruptSource::EndpointControl,
+            InterruptSource::EndpointIn,
+            InterruptSource::EndpointOut,
+        ] {
+            device.set_interrupt_source(source, true);
+        }
+
+        // poll IN completions but leave the other sources enabled
+        device.set_interrupt_source(InterruptSource::EndpointIn, false);
+        assert!(!device.is_interrupt_source_enabled(InterruptSource::EndpointIn));
+        assert!(device.is_interrupt_source_enabled(InterruptSource::Controller));
+        assert!(device.is_interrupt_source_enabled(InterruptSource::EndpointControl));
+        assert!(device.is_interrupt_source_enabled(InterruptSource::EndpointOut));
+
+        device.set_interrupt_source(InterruptSource::EndpointIn, true);
+        assert!(device.is_interrupt_source_enabled(InterruptSource::EndpointIn));
+    }
+
+    #[test]
+    fn test_unknown_request_policy() {
+        fn handle_class_request(
+            _device: &UsbDevice<MockDriver, 8>,
+            _setup_packet: &SetupPacket,
+            _request: u8,
+        ) -> ControlOutcome {
+            ControlOutcome::Stall
+        }
+
+        let mut device = test_device();
+        device.cb_class_request = Some(handle_class_request);
+        assert_eq!(
+            device.unknown_request_policy(),
+            UnknownRequestPolicy::StallUnknown
+        );
+
+        let setup_in = SetupPacket {
+            request_type: 0b1010_0001, // DeviceToHost, Class, Interface
+            request: 0x21,
+            value: 0,
+            index: 0,
+            length: 8,
+        };
+        let setup_out = SetupPacket {
+            request_type: 0b0010_0001, // HostToDevice, Class, Interface
+            request: 0x22,
+            value: 0,
+            index: 0,
+            length: 0,
+        };
+
+        // stalled by default
+        device.setup_request(0, &setup_in).unwrap();
+        assert_eq!(device.hal_driver.stalled(), vec![0x80, 0x00]);
+        assert!(device.hal_driver.writes().is_empty());
+
+        // ignored requests complete without data
+        device.set_unknown_request_policy(UnknownRequestPolicy::IgnoreUnknown);
+        let primed = device.hal_driver.primed().len();
+        device.setup_request(0, &setup_in).unwrap();
+        assert_eq!(device.hal_driver.writes(), vec![(0, vec![])]);
+        assert_eq!(device.hal_driver.primed()[primed..], [0]);
+
+        device.setup_request(0, &setup_out).unwrap();
+        assert_eq!(device.hal_driver.writes(), vec![(0, vec![]), (0, vec![])]);
+        assert_eq!(device.hal_driver.stalled().len(), 2);
+    }
+
+    #[test]
+    fn test_set_power() {
+        let mut device = test_device();
+        device.set_other_speed_configuration_descriptor(cdc::CONFIGURATION_DESCRIPTOR_0);
+
+        assert_eq!(
+            device.set_power(501, false, false),
+            Err(SmolError::InvalidArgument)
+        );
+        assert_eq!(device.set_power(101, true, true), Ok(()));
+
+        // bmAttributes and bMaxPower of both configurations
+        device
+            .setup_request(0, &get_descriptor(DescriptorType::Configuration, 9))
+            .unwrap();
+        device
+            .setup_request(
+                0,
+                &get_descriptor(DescriptorType::OtherSpeedConfiguration, 9),
+            )
+            .unwrap();
+        for (_, descriptor) in device.hal_driver.writes() {
+            assert_eq!(&descriptor[7..9], &[0b1110_0000, 50]);
+        }
+
+        // GET_STATUS(Device) reports self-powered
+        let setup_packet = SetupPacket {
+            request_type: 0b1000_0000, // DeviceToHost, Standard, Device
+            request: 0,                // GetStatus
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+        device.setup_request(0, &setup_packet).unwrap();
+        assert_eq!(device.hal_driver.writes()[2].1[0] & 0b1, 0b1);
+    }
+
+    #[test]
+    fn test_read_packet_babble() {
+        let device: UsbDevice<MockDriver<64>, 8, 4, 64> = UsbDevice::new(
+            MockDriver::default(),
+            acm::DEVICE_DESCRIPTOR,
+            // full speed configuration with 64 byte bulk endpoints
+            acm::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0,
+            acm::USB_STRING_DESCRIPTOR_0,
+            acm::USB_STRING_DESCRIPTORS,
+        );
+
+        // a full packet is not babble
+        device.hal_driver.receive_packet(&[0xaa; 64]);
+        assert_eq!(device.read_packet(2).0, 64);
+        assert_eq!(device.endpoints.borrow().stats_out[2].babble, 0);
+
+        // but one byte more is
+        device.hal_driver.receive_packet(&[0xaa; 65]);
+        let (bytes_read, _) = device.read_packet(2);
+        assert_eq!(bytes_read, 64);
+        assert_eq!(device.endpoints.borrow().stats_out[2].babble, 1);
+        assert_eq!(device.endpoints.borrow().stats_out[2].bytes, 128);
+        assert_eq!(device.endpoints.borrow().stats_out[2].overflow, 1);
+
+        device.endpoints.borrow_mut().reset_stats();
+        assert_eq!(device.endpoints.borrow().stats_out[2].babble, 0);
+        assert_eq!(device.endpoints.borrow().stats_out[2].bytes, 0);
+        assert_eq!(device.endpoints.borrow().stats_out[2].overflow, 0);
+    }
+
+    #[test]
+    fn test_max_packet_size_set_configuration() {
+        // full speed configuration with 64 byte bulk endpoints
+        const CONFIGURATION_DESCRIPTOR_1: ConfigurationDescriptor = ConfigurationDescriptor::new(
+            ConfigurationDescriptorHeader {
+                configuration_value: 2,
+                ..acm::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0.head
+            },
+            acm::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0.tail,
+        );
+
+        let mut device: UsbDevice<MockDriver<512>, 8, 4, 512> = UsbDevice::new(
+            MockDriver::default(),
+            acm::DEVICE_DESCRIPTOR,
+            acm::CONFIGURATION_DESCRIPTOR_0,
+            acm::USB_STRING_DESCRIPTOR_0,
+            acm::USB_STRING_DESCRIPTORS,
+        );
+        device.set_additional_configuration_descriptors(&[CONFIGURATION_DESCRIPTOR_1]);
+        assert_eq!(device.endpoints.borrow().max_packet_size_out[2], Some(512));
+        assert_eq!(device.endpoints.borrow().max_packet_size_in[2], Some(512));
+
+        let setup_packet = SetupPacket {
+            request_type: 0b0000_0000, // HostToDevice, Standard, Device
+            request: 9,                // SetConfiguration
+            value: 2,
+            index: 0,
+            length: 0,
+        };
+        device.setup_request(0, &setup_packet).unwrap();
+        assert_eq!(device.endpoints.borrow().max_packet_size_out[2], Some(64));
+        assert_eq!(device.endpoints.borrow().max_packet_size_in[2], Some(64));
+        assert_eq!(device.endpoints.borrow().max_packet_size_out[3], None);
+
+        // packets are checked against the selected configuration
+        device.hal_driver.receive_packet(&[0xaa; 65]);
+        assert_eq!(device.read_packet(2).0, 64);
+        assert_eq!(device.endpoints.borrow().stats_out[2].babble, 1);
+    }
+
+    #[test]
+    fn test_read_packet_overflow() {
+        let device: UsbDevice<MockDriver<64>, 8, 4, 8> = UsbDevice::new(
+            MockDriver::default(),
+            acm::DEVICE_DESCRIPTOR,
+            acm::OTHER_SPEED_CONFIGURATION_DESCRIPTOR_0,
+            acm::USB_STRING_DESCRIPTOR_0,
+            acm::USB_STRING_DESCRIPTORS,
+        );
+
+        // bytes past MAX_PACKET_SIZE are discarded
+        device.hal_driver.receive_packet(&[0xaa; 12]);
+        assert_eq!(device.read_packet(2).0, 8);
+        device.hal_driver.receive_packet(&[0xaa; 10]);
+        assert_eq!(device.read_packet(2).0, 8);
+
+        let stats = device.endpoints.borrow().stats_out[2];
+        assert_eq!(stats.bytes, 16);
+        assert_eq!(stats.overflow, 6);
+        assert_eq!(stats.babble, 0);
+    }
+
+    #[test]
+    fn test_pause_endpoint_out() {
+        let mut device = test_device();
+        receive_setup(&mut device, SET_CONFIGURATION);
+        let primed = device.hal_driver.primed().len();
+
+        assert_eq!(device.pause_endpoint(0x02), Ok(()));
+        assert!(device.is_endpoint_paused(0x02));
+        assert!(!device.is_endpoint_paused(0x82));
+
+        // the packet stays in the FIFO and the endpoint isn't re-primed
+        device.hal_driver.receive_packet_on(2, &[1, 2, 3, 4]);
+        assert_eq!(device.read_packet(2).0, 0);
+        assert!(device.hal_driver.has_packet());
+        assert_eq!(device.ep_out_prime_receive(2), Ok(()));
+        assert_eq!(device.hal_driver.primed().len(), primed);
+        assert!(device.hal_driver.stalled().is_empty());
+
+        // resuming keeps the packet queued while paused and the
+        // endpoint is re-primed once it has been read
+        assert_eq!(device.resume_endpoint(0x02), Ok(()));
+        assert!(!device.is_endpoint_paused(0x02));
+        assert!(device.hal_driver.has_packet());
+        assert_eq!(device.hal_driver.primed().len(), primed);
+        assert!(device.is_primed_out(2));
+        let (bytes_read, buffer) = device.read_packet(2);
+        assert_eq!(&buffer[..bytes_read], &[1, 2, 3, 4]);
+        assert_eq!(device.hal_driver.primed()[primed..], [2]);
+
+        // without a packet waiting resuming re-primes the endpoint
+        assert_eq!(device.pause_endpoint(0x02), Ok(()));
+        assert_eq!(device.resume_endpoint(0x02), Ok(()));
+        assert_eq!(device.hal_driver.primed()[primed..], [2, 2]);
+    }
+
+    #[test]
+    fn test_pause_endpoint_in() {
+        let mut device = test_device();
+        receive_setup(&mut device, SET_CONFIGURATION);
+
+        // writes to a paused IN endpoint are refused rather than discarded
+        assert_eq!(device.pause_endpoint(0x82), Ok(()));
+        assert_eq!(
+            device.hal_driver.write(2, [1, 2, 3].into_iter()),
+            Err(SmolError::WouldBlock)
+        );
+        assert_eq!(
+            device.hal_driver.try_write(2, [1, 2, 3].into_iter()),
+            Err(SmolError::WouldBlock)
+        );
+        let writes = device.hal_driver.writes().len();
+
+        assert_eq!(device.resume_endpoint(0x82), Ok(()));
+        assert_eq!(device.hal_driver.write(2, [1, 2, 3].into_iter()), Ok(3));
+        assert_eq!(device.hal_driver.writes()[writes..], [(2, vec![1, 2, 3])]);
+
+        // the control endpoint can't be paused
+        assert_eq!(device.pause_endpoint(0x80), Err(SmolError::InvalidArgument));
+        assert_eq!(device.pause_endpoint(0x00), Err(SmolError::InvalidArgument));
+        assert!(!device.is_endpoint_paused(0x80));
+    }
+
+    #[test]
+    fn test_read_packet_max_packet_size() {
+        let device: UsbDevice<MockDriver<64>, 8, 4, 64> = UsbDevice::new(
+            MockDriver::default(),
+            cdc::DEVICE_DESCRIPTOR,
+            cdc::CONFIGURATION_DESCRIPTOR_0,
+            cdc::USB_STRING_DESCRIPTOR_0,
+            cdc::USB_STRING_DESCRIPTORS,
+        );
+
+        // packets are truncated to the buffer size
+        device.hal_driver.receive_packet(&[0xaa; 100]);
+        let (bytes_read, buffer) = device.read_packet(2);
+        assert_eq!(bytes_read, 64);
+        assert_eq!(buffer, [0xaa; 64]);
+        assert_eq!(device.hal_driver.primed(), vec![2]);
+        assert!(device.is_primed_out(2));
+
+        // the mock driver rejects writes larger than its packet size
+        let result = device.hal_driver.try_write(1, [0; 65].into_iter());
+        assert_eq!(result, Err(SmolError::FifoOverflow));
+        let result = device.hal_driver.write_ref(1, [0; 65].iter());
+        assert_eq!(result, Err(SmolError::FifoOverflow));
+    }
+}
@@ -2,6 +2,15 @@
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum SmolError {
     FailedConversion,
+    ControlTransferInProgress,
+    InvalidControlStage,
+    TxInProgress,
+    WouldBlock,
+    FifoOverflow,
+    InvalidArgument,
+    PrimeFailed,
+    Timeout,
+    NotConnected,
 }
 
 // trait:: core::fmt::Display
@@ -11,6 +20,13 @@ impl core::fmt::Display for SmolError {
     }
 }
 
+// trait: core::convert::From<ConnectError>
+impl core::convert::From<ConnectError> for SmolError {
+    fn from(_error: ConnectError) -> Self {
+        SmolError::NotConnected
+    }
+}
+
 // trait: core::convert::From<core::num::TryFromIntError>
 impl core::convert::From<core::num::TryFromIntError> for SmolError {
     fn from(_error: core::num::TryFromIntError) -> Self {
@@ -34,9 +50,34 @@ impl core::error::Error for SmolError {
         use SmolError::*;
         match self {
             FailedConversion => "Failed to convert packet value",
+            ControlTransferInProgress => "Control transfer in progress",
+            InvalidControlStage => "Event not valid in the current control transfer stage",
+            TxInProgress => "IN endpoint FIFO still holds unsent data",
+            WouldBlock => "IN endpoint is paused or has a transfer in flight",
+            FifoOverflow => "Write exceeded the endpoint FIFO size",
+            InvalidArgument => "Argument out of range",
+            PrimeFailed => "OUT endpoint failed to prime",
+            Timeout => "Operation timed out",
+            NotConnected => "Device failed to connect",
         }
     }
 }
 
 /// Result<T>
 pub type SmolResult<T> = core::result::Result<T, SmolError>;
+
+/// Reasons a device can fail to connect to the bus
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ConnectError {
+    /// VBUS is not present, only reported by drivers able to sense it
+    NoVbus,
+    /// The device controller did not enable its pull-up
+    Disconnected,
+}
+
+// trait:: core::fmt::Display
+impl core::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(&self, f)
+    }
+}
@@ -0,0 +1,1029 @@
+//! USB host role
+//!
+//! Host-side transfers are built on top of the token-level
+//! [`HostDriver`] trait which must be implemented by a host-capable
+//! peripheral.
+
+use log::{trace, warn};
+
+use heapless::{String, Vec};
+
+use crate::consts::{descriptor_type, endpoint, request, request_type};
+use crate::control::{ControlTransfer, TransferStage};
+use crate::control_capture::ControlRecord;
+use crate::error::{SmolError, SmolResult};
+use crate::policy::TransferPolicy;
+use crate::setup::{Direction, SetupPacket};
+
+// - Handshake ----------------------------------------------------------------
+
+/// Handshake returned by the device for a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handshake {
+    Ack,
+    Nak,
+    Stall,
+    /// No response from the device
+    Timeout,
+}
+
+// - HostError ----------------------------------------------------------------
+
+/// Errors returned by host-role requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostError {
+    /// The device stalled the request
+    Stall(ControlStage),
+    /// The device stalled the bulk endpoint with the given address
+    Halted(u8),
+    /// The device returned a malformed descriptor
+    InvalidDescriptor,
+    /// The request is not valid for the given arguments
+    InvalidRequest,
+    /// Transaction-level error
+    Transfer(SmolError),
+}
+
+impl From<SmolError> for HostError {
+    fn from(error: SmolError) -> Self {
+        HostError::Transfer(error)
+    }
+}
+
+// trait:: core::fmt::Display
+impl core::fmt::Display for HostError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(&self, f)
+    }
+}
+
+/// Result<T> for host-role requests
+pub type HostResult<T> = core::result::Result<T, HostError>;
+
+// - HostDriver ---------------------------------------------------------------
+
+/// Token-level operations for a USB host controller
+pub trait HostDriver {
+    /// Maximum packet size of the target's control endpoint
+    fn control_max_packet_size(&self) -> usize;
+
+    /// Send a SETUP token followed by an 8 byte DATA0 packet
+    fn send_setup(&self, endpoint_number: u8, packet: &[u8; 8]) -> Handshake;
+
+    /// Send an OUT token followed by a DATA0/DATA1 packet
+    fn send_out(&self, endpoint_number: u8, data: &[u8], data1: bool) -> Handshake;
+
+    /// Send an IN token and receive a DATA0/DATA1 packet into `buffer`
+    ///
+    /// Returns the number of bytes received or the handshake if the
+    /// device did not respond with data.
+    fn receive_in(
+        &self,
+        endpoint_number: u8,
+        buffer: &mut [u8],
+        data1: bool,
+    ) -> Result<usize, Handshake>;
+
+    /// Reset the controller's transfer state for `endpoint_number` and
+    /// flush any data left in its FIFOs
+    fn abort(&self, endpoint_number: u8);
+}
+
+// - control transfers --------------------------------------------------------
+
+/// Stage of a control transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlStage {
+    Setup,
+    Data,
+    Status,
+}
+
+/// Result of a host control transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlResponse {
+    /// Number of bytes transferred during the data stage
+    pub bytes_transferred: usize,
+    /// The stage at which the device stalled the transfer, if any
+    pub stalled: Option<ControlStage>,
+}
+
+/// Send an arbitrary 8 byte SETUP packet and perform the data and status stages
+///
+/// The packet is transmitted verbatim. Bit 7 of the first byte selects
+/// the direction of the data stage and bytes 6..8 its length.
+///
+/// For IN requests the response is read into `data`, for OUT requests
+/// the data stage is sent from `data`. In both cases the data stage is
+/// limited to the length of `data`.
+///
+/// Transactions the device NAKs are retried up to
+/// `policy.max_nak_retries` times before [`SmolError::Timeout`] is
+/// returned.
+///
+/// A transfer that stalls or fails part way through is aborted with
+/// [`abort_transfer`] before returning so the next transfer starts
+/// from a clean state.
+pub fn send_raw_setup<D>(
+    driver: &D,
+    endpoint_number: u8,
+    setup_packet: [u8; 8],
+    data: &mut [u8],
+    policy: &TransferPolicy,
+) -> SmolResult<ControlResponse>
+where
+    D: HostDriver,
+{
+    let result = control_transfer(driver, endpoint_number, setup_packet, data, policy);
+    if !matches!(result, Ok(ControlResponse { stalled: None, .. })) {
+        abort_transfer(driver, endpoint_number);
+    }
+    result
+}
+
+/// Abort the control transfer in progress on `endpoint_number`
+///
+/// Resets the host controller's transfer state and flushes its FIFOs,
+/// e.g. after the device stopped responding part way through a
+/// transfer. [`send_raw_setup`] already does this for any transfer
+/// that does not complete.
+pub fn abort_transfer<D>(driver: &D, endpoint_number: u8)
+where
+    D: HostDriver,
+{
+    warn!("HOST abort_transfer({})", endpoint_number);
+    driver.abort(endpoint_number);
+}
+
+/// Replay a sequence of control requests captured by a device
+///
+/// `records` holds serialized [`ControlRecord`]s, see
+/// [`crate::control_capture`]. Each request is sent with
+/// [`send_raw_setup`] using `buffer` for its data stage: OUT requests
+/// send the recorded data, IN responses are read into `buffer`.
+///
+/// `on_response` is called with each record, its response and the data
+/// received. A stalled request does not end the replay.
+///
+/// Returns the number of requests sent or
+/// [`SmolError::FailedConversion`] if `records` ends with a malformed
+/// record.
+pub fn replay<D, F>(
+    driver: &D,
+    endpoint_number: u8,
+    records: &[u8],
+    buffer: &mut [u8],
+    policy: &TransferPolicy,
+    mut on_response: F,
+) -> SmolResult<usize>
+where
+    D: HostDriver,
+    F: FnMut(&ControlRecord, &ControlResponse, &[u8]),
+{
+    let mut records = records;
+    let mut count = 0;
+
+    while !records.is_empty() {
+        let (record, rest) = match ControlRecord::parse(records) {
+            Some(parsed) => parsed,
+            None => {
+                warn!("HOST replay: malformed record after {} requests", count);
+                return Err(SmolError::FailedConversion);
+            }
+        };
+        records = rest;
+
+        let data = match Direction::from(record.setup[0]) {
+            Direction::HostToDevice => {
+                let length = record.data().len().min(buffer.len());
+                buffer[..length].copy_from_slice(&record.data()[..length]);
+                &mut buffer[..length]
+            }
+            Direction::DeviceToHost => &mut buffer[..],
+        };
+        let response = send_raw_setup(driver, endpoint_number, record.setup, data, policy)?;
+        on_response(&record, &response, &data[..response.bytes_transferred]);
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Perform the stages of a control transfer for [`send_raw_setup`]
+fn control_transfer<D>(
+    driver: &D,
+    endpoint_number: u8,
+    setup_packet: [u8; 8],
+    data: &mut [u8],
+    policy: &TransferPolicy,
+) -> SmolResult<ControlResponse>
+where
+    D: HostDriver,
+{
+    let direction = Direction::from(setup_packet[0]);
+    let length = u16::from_le_bytes([setup_packet[6], setup_packet[7]]) as usize;
+    let length = length.min(data.len());
+
+    trace!(
+        "HOST send_raw_setup({}, {:?}) direction:{:?} length:{}",
+        endpoint_number,
+        setup_packet,
+        direction,
+        length
+    );
+
+    let stalled = |stage: ControlStage, bytes_transferred: usize| {
+        warn!("HOST stall: control transfer stalled in {:?} stage", stage);
+        Ok(ControlResponse {
+            bytes_transferred,
+            stalled: Some(stage),
+        })
+    };
+
+    // setup stage
+    let mut transfer = ControlTransfer::new();
+    transfer.setup(SetupPacket::try_from(setup_packet)?);
+    let handshake = retry_nak(policy, || driver.send_setup(endpoint_number, &setup_packet))?;
+    if handshake == Handshake::Stall {
+        return stalled(ControlStage::Setup, 0);
+    }
+
+    // data stage
+    let max_packet_size = driver.control_max_packet_size();
+    let mut data1 = true;
+    while matches!(
+        transfer.stage(),
+        TransferStage::DataIn | TransferStage::DataOut
+    ) {
+        let offset = transfer.bytes_transferred();
+        if offset >= length {
+            // the caller's buffer is shorter than wLength
+            transfer.end_data_stage()?;
+            break;
+        }
+        let end = (offset + max_packet_size).min(length);
+        let packet_length = if transfer.stage() == TransferStage::DataIn {
+            match retry_nak_in(policy, || {
+                driver.receive_in(endpoint_number, &mut data[offset..end], data1)
+            })? {
+                Ok(bytes_read) => bytes_read,
+                Err(Handshake::Stall) => return stalled(ControlStage::Data, offset),
+                Err(_) => 0,
+            }
+        } else {
+            match retry_nak(policy, || {
+                driver.send_out(endpoint_number, &data[offset..end], data1)
+            })? {
+                Handshake::Stall => return stalled(ControlStage::Data, offset),
+                _ => end - offset,
+            }
+        };
+        transfer.data(packet_length, max_packet_size)?;
+        data1 = !data1;
+    }
+    let bytes_transferred = transfer.bytes_transferred();
+
+    // status stage is always DATA1
+    let status = match transfer.stage() {
+        TransferStage::StatusOut => {
+            retry_nak(policy, || driver.send_out(endpoint_number, &[], true))?
+        }
+        _ => match retry_nak_in(policy, || driver.receive_in(endpoint_number, &mut [], true))? {
+            Ok(_) => Handshake::Ack,
+            Err(handshake) => handshake,
+        },
+    };
+    if status == Handshake::Stall {
+        return stalled(ControlStage::Status, bytes_transferred);
+    }
+    transfer.status()?;
+
+    Ok(ControlResponse {
+        bytes_transferred,
+        stalled: None,
+    })
+}
+
+/// Perform a control request against the device and return the
+/// number of bytes transferred during the data stage
+///
+/// This is the general form of the request-specific helpers in this
+/// module and can issue any standard, class or vendor request.
+///
+/// For IN requests up to `setup_packet.length` bytes of the response
+/// are read into `data`. For OUT requests `data` must hold at least
+/// `setup_packet.length` bytes, which are sent as the data stage.
+///
+/// A stall in any stage is returned as [`HostError::Stall`].
+pub fn control_request<D>(
+    driver: &D,
+    endpoint_number: u8,
+    setup_packet: SetupPacket,
+    data: &mut [u8],
+    policy: &TransferPolicy,
+) -> HostResult<usize>
+where
+    D: HostDriver,
+{
+    let length = setup_packet.length as usize;
+    if setup_packet.direction() == Direction::HostToDevice && data.len() < length {
+        warn!(
+            "HOST control_request: {} bytes of data for an OUT request of length {}",
+            data.len(),
+            length
+        );
+        return Err(HostError::InvalidRequest);
+    }
+
+    let response = send_raw_setup(
+        driver,
+        endpoint_number,
+        SetupPacket::as_bytes(setup_packet),
+        data,
+        policy,
+    )?;
+    match response.stalled {
+        Some(stage) => Err(HostError::Stall(stage)),
+        None => Ok(response.bytes_transferred),
+    }
+}
+
+// - bulk transfers -----------------------------------------------------------
+
+/// Host-side state of a target's bulk endpoint
+///
+/// The data toggle carries over from one transfer to the next, reset
+/// it with [`BulkEndpoint::reset_toggle`] after the endpoint's halt
+/// has been cleared or the device has been configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulkEndpoint {
+    /// Endpoint address, bit 7 selects the direction
+    pub address: u8,
+    pub max_packet_size: usize,
+    /// Data toggle of the next transaction
+    pub data1: bool,
+}
+
+impl BulkEndpoint {
+    pub const fn new(address: u8, max_packet_size: usize) -> Self {
+        Self {
+            address,
+            max_packet_size,
+            data1: false,
+        }
+    }
+
+    pub fn number(&self) -> u8 {
+        self.address & endpoint::NUMBER_MASK
+    }
+
+    pub fn direction(&self) -> Direction {
+        Direction::from_endpoint_address(self.address)
+    }
+
+    /// Start the next transaction with DATA0
+    pub fn reset_toggle(&mut self) {
+        self.data1 = false;
+    }
+}
+
+/// Perform a bulk transfer and return the number of bytes transferred
+///
+/// The transfer direction follows the endpoint's address. OUT
+/// transfers send `data` in packets of up to `max_packet_size` bytes,
+/// followed by a zero length packet if the last packet was full, so
+/// the device can tell where the transfer ends. IN transfers receive
+/// packets into `data` until the device sends a short packet or
+/// `data` is full. A packet longer than the space left in `data` is
+/// truncated so IN buffers should be a multiple of `max_packet_size`.
+///
+/// Transactions the device NAKs are retried up to
+/// `policy.max_nak_retries` times before a [`SmolError::Timeout`] is
+/// returned. A stall is returned as
+/// [`HostError::Halted`].
+pub fn bulk_transfer<D>(
+    driver: &D,
+    endpoint: &mut BulkEndpoint,
+    data: &mut [u8],
+    policy: &TransferPolicy,
+) -> HostResult<usize>
+where
+    D: HostDriver,
+{
+    trace!(
+        "HOST bulk_transfer(0x{:x}) length:{}",
+        endpoint.address,
+        data.len()
+    );
+
+    let max_packet_size = endpoint.max_packet_size;
+    if max_packet_size == 0 {
+        return Err(HostError::InvalidRequest);
+    }
+    let endpoint_number = endpoint.number();
+    let mut offset = 0;
+
+    match endpoint.direction() {
+        Direction::HostToDevice => loop {
+            let end = (offset + max_packet_size).min(data.len());
+            let packet = &data[offset..end];
+            match retry_nak(policy, || {
+                driver.send_out(endpoint_number, packet, endpoint.data1)
+            })? {
+                Handshake::Stall => return Err(HostError::Halted(endpoint.address)),
+                _ => endpoint.data1 = !endpoint.data1,
+            }
+            offset = end;
+
+            // a short packet, including the terminating zlp, ends the transfer
+            if packet.len() < max_packet_size {
+                break;
+            }
+        },
+        Direction::DeviceToHost => {
+            while offset < data.len() {
+                let end = (offset + max_packet_size).min(data.len());
+                let packet_length = match retry_nak_in(policy, || {
+                    driver.receive_in(endpoint_number, &mut data[offset..end], endpoint.data1)
+                })? {
+                    Ok(packet_length) => packet_length,
+                    Err(Handshake::Stall) => return Err(HostError::Halted(endpoint.address)),
+                    Err(_) => 0,
+                };
+                endpoint.data1 = !endpoint.data1;
+                offset += packet_length;
+
+                // a short packet ends the transfer
+                if packet_length < max_packet_size {
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(offset)
+}
+
+// - string descriptors -------------------------------------------------------
+
+/// Language used if the device does not report any: English (United States)
+pub const DEFAULT_LANGUAGE_ID: u16 = 0x0409;
+
+/// Maximum number of language ids returned by [`get_language_ids`]
+pub const MAX_LANGUAGE_IDS: usize = 16;
+
+/// Fetch string descriptor zero and return the language ids supported by the device
+pub fn get_language_ids<D>(
+    driver: &D,
+    endpoint_number: u8,
+    policy: &TransferPolicy,
+) -> HostResult<Vec<u16, MAX_LANGUAGE_IDS>>
+where
+    D: HostDriver,
+{
+    let mut buffer = [0; 255];
+    let payload = get_string_descriptor(driver, endpoint_number, 0, 0, &mut buffer, policy)?;
+
+    Ok(payload
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .take(MAX_LANGUAGE_IDS)
+        .collect())
+}
+
+/// Fetch string descriptor `index` and decode it to UTF-8
+///
+/// If `language_id` is `None` the first language reported by the
+/// device's string descriptor zero is used. Index zero holds the
+/// language ids rather than a string, use [`get_language_ids`] instead.
+///
+/// Strings longer than `N` bytes are truncated at the last character
+/// that fits and invalid UTF-16 is replaced with `U+FFFD`.
+pub fn get_string<D, const N: usize>(
+    driver: &D,
+    endpoint_number: u8,
+    index: u8,
+    language_id: Option<u16>,
+    policy: &TransferPolicy,
+) -> HostResult<String<N>>
+where
+    D: HostDriver,
+{
+    if index == 0 {
+        warn!("HOST get_string: index 0 is the language id descriptor");
+        return Err(HostError::InvalidRequest);
+    }
+
+    let language_id = match language_id {
+        Some(language_id) => language_id,
+        None => get_language_ids(driver, endpoint_number, policy)?
+            .first()
+            .copied()
+            .unwrap_or(DEFAULT_LANGUAGE_ID),
+    };
+
+    let mut buffer = [0; 255];
+    let payload = get_string_descriptor(
+        driver,
+        endpoint_number,
+        index,
+        language_id,
+        &mut buffer,
+        policy,
+    )?;
+
+    let code_units = payload
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+    let mut string = String::new();
+    for c in char::decode_utf16(code_units) {
+        if string
+            .push(c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .is_err()
+        {
+            trace!("HOST get_string: truncated string descriptor {}", index);
+            break;
+        }
+    }
+
+    Ok(string)
+}
+
+/// Fetch a string descriptor and return its payload
+fn get_string_descriptor<'b, D>(
+    driver: &D,
+    endpoint_number: u8,
+    index: u8,
+    language_id: u16,
+    buffer: &'b mut [u8; 255],
+    policy: &TransferPolicy,
+) -> HostResult<&'b [u8]>
+where
+    D: HostDriver,
+{
+    let setup_packet = SetupPacket {
+        request_type: request_type::DIRECTION_IN,
+        request: request::GET_DESCRIPTOR,
+        value: u16::from_le_bytes([index, descriptor_type::STRING]),
+        index: language_id,
+        length: buffer.len() as u16,
+    };
+
+    let bytes_transferred = control_request(driver, endpoint_number, setup_packet, buffer, policy)?;
+    if bytes_transferred < 2 || buffer[1] != descriptor_type::STRING {
+        warn!(
+            "HOST get_string: invalid string descriptor {}: {:?}",
+            index,
+            &buffer[..bytes_transferred]
+        );
+        return Err(HostError::InvalidDescriptor);
+    }
+    let length = (buffer[0] as usize).min(bytes_transferred);
+
+    Ok(&buffer[2..length.max(2)])
+}
+
+/// Retry a transaction for as long as the device NAKs it
+fn retry_nak<F>(policy: &TransferPolicy, mut transaction: F) -> SmolResult<Handshake>
+where
+    F: FnMut() -> Handshake,
+{
+    for _ in 0..=policy.max_nak_retries {
+        match transaction() {
+            Handshake::Nak => continue,
+            Handshake::Timeout => return Err(SmolError::Timeout),
+            handshake => return Ok(handshake),
+        }
+    }
+    Err(SmolError::Timeout)
+}
+
+/// Retry an IN transaction for as long as the device NAKs it
+///
+/// Returns the number of bytes received or a stall handshake.
+fn retry_nak_in<F>(
+    policy: &TransferPolicy,
+    mut transaction: F,
+) -> SmolResult<Result<usize, Handshake>>
+where
+    F: FnMut() -> Result<usize, Handshake>,
+{
+    for _ in 0..=policy.max_nak_retries {
+        match transaction() {
+            Err(Handshake::Nak) => continue,
+            Err(Handshake::Timeout) => return Err(SmolError::Timeout),
+            result => return Ok(result),
+        }
+    }
+    Err(SmolError::Timeout)
+}
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockHost, MockReply, MockTransaction};
+    use std::vec::Vec;
+
+    const GET_DEVICE_DESCRIPTOR: [u8; 8] = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+
+    #[test]
+    fn test_send_raw_setup_in() {
+        let host = MockHost::new(8);
+        host.reply(MockReply::Handshake(Handshake::Ack));
+        host.reply(MockReply::Handshake(Handshake::Nak));
+        host.reply(MockReply::Data((0..8).collect()));
+        host.reply(MockReply::Data((8..16).collect()));
+        host.reply(MockReply::Data((16..18).collect()));
+
+        let mut buffer = [0; 64];
+        let response = send_raw_setup(
+            &host,
+            0,
+            GET_DEVICE_DESCRIPTOR,
+            &mut buffer,
+            &TransferPolicy::DEFAULT,
+        )
+        .unwrap();
+
+        assert_eq!(response.bytes_transferred, 18);
+        assert_eq!(response.stalled, None);
+        assert_eq!(&buffer[..18], &(0..18).collect::<Vec<u8>>()[..]);
+        assert_eq!(
+            host.transactions(),
+            vec![
+                MockTransaction::Setup(GET_DEVICE_DESCRIPTOR),
+                MockTransaction::In(true),
+                MockTransaction::In(true),
+                MockTransaction::In(false),
+                MockTransaction::In(true),
+                MockTransaction::Out(vec![], true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_send_raw_setup_out() {
+        let host = MockHost::new(64);
+
+        let setup_packet = [0x40, 0x01, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00];
+        let mut data = [1, 2, 3];
+        let response =
+            send_raw_setup(&host, 0, setup_packet, &mut data, &TransferPolicy::DEFAULT).unwrap();
+
+        assert_eq!(response.bytes_transferred, 3);
+        assert_eq!(
+            host.transactions(),
+            vec![
+                MockTransaction::Setup(setup_packet),
+                MockTransaction::Out(vec![1, 2, 3], true),
+                MockTransaction::In(true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_send_raw_setup_stall() {
+        let host = MockHost::new(64);
+        host.reply(MockReply::Handshake(Handshake::Ack));
+        host.reply(MockReply::Handshake(Handshake::Stall));
+
+        let mut buffer = [0; 64];
+        let response = send_raw_setup(
+            &host,
+            0,
+            GET_DEVICE_DESCRIPTOR,
+            &mut buffer,
+            &TransferPolicy::DEFAULT,
+        )
+        .unwrap();
+
+        assert_eq!(response.stalled, Some(ControlStage::Data));
+        assert_eq!(response.bytes_transferred, 0);
+    }
+
+    #[test]
+    fn test_send_raw_setup_timeout() {
+        let host = MockHost::new(64);
+        host.reply(MockReply::Handshake(Handshake::Nak));
+        host.reply(MockReply::Handshake(Handshake::Nak));
+
+        let policy = TransferPolicy {
+            max_nak_retries: 1,
+            ..TransferPolicy::DEFAULT
+        };
+        let mut buffer = [0; 64];
+        let result = send_raw_setup(&host, 0, GET_DEVICE_DESCRIPTOR, &mut buffer, &policy);
+
+        assert_eq!(result, Err(SmolError::Timeout));
+        assert_eq!(host.transactions().len(), 2);
+        assert_eq!(host.aborts(), vec![0]);
+    }
+
+    #[test]
+    fn test_abort_transfer() {
+        // the device stalls part way through the data stage
+        let host = MockHost::new(8);
+        host.reply(MockReply::Handshake(Handshake::Ack));
+        host.reply(MockReply::Data((0..8).collect()));
+        host.reply(MockReply::Handshake(Handshake::Stall));
+
+        let mut buffer = [0; 64];
+        let response = send_raw_setup(
+            &host,
+            0,
+            GET_DEVICE_DESCRIPTOR,
+            &mut buffer,
+            &TransferPolicy::DEFAULT,
+        )
+        .unwrap();
+        assert_eq!(response.stalled, Some(ControlStage::Data));
+        assert_eq!(response.bytes_transferred, 8);
+        assert_eq!(host.aborts(), vec![0]);
+
+        // the next transfer starts from the setup stage again
+        host.reply(MockReply::Handshake(Handshake::Ack));
+        host.reply(MockReply::Data((0..2).collect()));
+        let response = send_raw_setup(
+            &host,
+            0,
+            GET_DEVICE_DESCRIPTOR,
+            &mut buffer,
+            &TransferPolicy::DEFAULT,
+        )
+        .unwrap();
+        assert_eq!(response.stalled, None);
+        assert_eq!(response.bytes_transferred, 2);
+        assert_eq!(host.aborts(), vec![0]);
+        assert_eq!(
+            host.transactions()[3..],
+            [
+                MockTransaction::Setup(GET_DEVICE_DESCRIPTOR),
+                MockTransaction::In(true),
+                MockTransaction::Out(vec![], true),
+            ]
+        );
+
+        // aborts can also be made by the caller
+        abort_transfer(&host, 0);
+        assert_eq!(host.aborts(), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_replay() {
+        let vendor_out = [0x40, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00];
+        let mut records: Vec<u8> = ControlRecord::new(vendor_out, &[0xca, 0xfe])
+            .iter()
+            .collect();
+        records.extend(ControlRecord::new(GET_DEVICE_DESCRIPTOR, &[]).iter());
+
+        // the OUT request is stalled, the IN request returns 2 bytes
+        let host = MockHost::new(64);
+        host.reply(MockReply::Handshake(Handshake::Ack));
+        host.reply(MockReply::Handshake(Handshake::Stall));
+        host.reply(MockReply::Handshake(Handshake::Ack));
+        host.reply(MockReply::Data(vec![0x12, 0x01]));
+
+        let mut responses = Vec::new();
+        let mut buffer = [0; 64];
+        let count = replay(
+            &host,
+            0,
+            &records,
+            &mut buffer,
+            &TransferPolicy::DEFAULT,
+            |record, response, data| {
+                responses.push((record.setup, response.stalled, data.to_vec()));
+            },
+        )
+        .unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(
+            responses,
+            vec![
+                (vendor_out, Some(ControlStage::Data), vec![]),
+                (GET_DEVICE_DESCRIPTOR, None, vec![0x12, 0x01]),
+            ]
+        );
+        assert_eq!(
+            host.transactions()[1],
+            MockTransaction::Out(vec![0xca, 0xfe], true)
+        );
+
+        // malformed records end the replay
+        let result = replay(
+            &host,
+            0,
+            &records[..4],
+            &mut buffer,
+            &TransferPolicy::DEFAULT,
+            |_, _, _| (),
+        );
+        assert_eq!(result, Err(SmolError::FailedConversion));
+    }
+
+    #[test]
+    fn test_bulk_transfer_out() {
+        let host = MockHost::new(64);
+        let mut endpoint = BulkEndpoint::new(0x02, 4);
+
+        // exact multiple of max packet size is terminated with a zlp
+        let mut data = [1, 2, 3, 4, 5, 6, 7, 8];
+        let policy = TransferPolicy::DEFAULT;
+        assert_eq!(
+            bulk_transfer(&host, &mut endpoint, &mut data, &policy),
+            Ok(8)
+        );
+        assert_eq!(
+            host.transactions(),
+            vec![
+                MockTransaction::Out(vec![1, 2, 3, 4], false),
+                MockTransaction::Out(vec![5, 6, 7, 8], true),
+                MockTransaction::Out(vec![], false),
+            ]
+        );
+        assert!(endpoint.data1);
+
+        // the data toggle continues from the previous transfer
+        let mut data = [1, 2, 3, 4, 5, 6];
+        assert_eq!(
+            bulk_transfer(&host, &mut endpoint, &mut data, &policy),
+            Ok(6)
+        );
+        assert_eq!(
+            host.transactions()[3..],
+            [
+                MockTransaction::Out(vec![1, 2, 3, 4], true),
+                MockTransaction::Out(vec![5, 6], false),
+            ]
+        );
+
+        // stalls are returned with the endpoint address
+        host.reply(MockReply::Handshake(Handshake::Stall));
+        assert_eq!(
+            bulk_transfer(&host, &mut endpoint, &mut data, &policy),
+            Err(HostError::Halted(0x02))
+        );
+    }
+
+    #[test]
+    fn test_bulk_transfer_in() {
+        let host = MockHost::new(64);
+        let mut endpoint = BulkEndpoint::new(0x81, 4);
+        let policy = TransferPolicy::DEFAULT;
+
+        // a full buffer ends the transfer
+        host.reply(MockReply::Data(vec![1, 2, 3, 4]));
+        host.reply(MockReply::Handshake(Handshake::Nak));
+        host.reply(MockReply::Data(vec![5, 6, 7, 8]));
+        let mut buffer = [0; 8];
+        assert_eq!(
+            bulk_transfer(&host, &mut endpoint, &mut buffer, &policy),
+            Ok(8)
+        );
+        assert_eq!(buffer, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(
+            host.transactions(),
+            vec![
+                MockTransaction::In(false),
+                MockTransaction::In(true),
+                MockTransaction::In(true),
+            ]
+        );
+
+        // a short packet ends the transfer
+        host.reply(MockReply::Data(vec![1, 2, 3, 4]));
+        host.reply(MockReply::Data(vec![5, 6]));
+        let mut buffer = [0; 16];
+        endpoint.reset_toggle();
+        assert_eq!(
+            bulk_transfer(&host, &mut endpoint, &mut buffer, &policy),
+            Ok(6)
+        );
+        assert_eq!(&buffer[..6], &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(host.transactions().len(), 5);
+
+        // as does a zlp
+        host.reply(MockReply::Data(vec![1, 2, 3, 4]));
+        host.reply(MockReply::Data(vec![]));
+        assert_eq!(
+            bulk_transfer(&host, &mut endpoint, &mut buffer, &policy),
+            Ok(4)
+        );
+        assert_eq!(host.transactions().len(), 7);
+    }
+
+    #[test]
+    fn test_control_request() {
+        // vendor IN request
+        let host = MockHost::new(64);
+        host.reply(MockReply::Handshake(Handshake::Ack));
+        host.reply(MockReply::Data(vec![0xca, 0xfe]));
+        let setup_packet = SetupPacket {
+            request_type: 0b1100_0000, // DeviceToHost, Vendor, Device
+            request: 0x42,
+            value: 0x1234,
+            index: 0,
+            length: 4,
+        };
+        let mut buffer = [0; 4];
+        let result = control_request(
+            &host,
+            0,
+            setup_packet,
+            &mut buffer,
+            &TransferPolicy::DEFAULT,
+        );
+        assert_eq!(result, Ok(2));
+        assert_eq!(&buffer[..2], &[0xca, 0xfe]);
+        assert_eq!(
+            host.transactions()[0],
+            MockTransaction::Setup([0xc0, 0x42, 0x34, 0x12, 0x00, 0x00, 0x04, 0x00])
+        );
+
+        // class OUT request
+        let host = MockHost::new(64);
+        let setup_packet = SetupPacket {
+            request_type: 0b0010_0001, // HostToDevice, Class, Interface
+            request: 0x20,
+            value: 0,
+            index: 1,
+            length: 3,
+        };
+        let mut data = [1, 2, 3];
+        let result = control_request(&host, 0, setup_packet, &mut data, &TransferPolicy::DEFAULT);
+        assert_eq!(result, Ok(3));
+        assert_eq!(
+            host.transactions()[1],
+            MockTransaction::Out(vec![1, 2, 3], true)
+        );
+
+        // OUT data shorter than wLength is rejected before anything is sent
+        let host = MockHost::new(64);
+        let result = control_request(
+            &host,
+            0,
+            setup_packet,
+            &mut data[..2],
+            &TransferPolicy::DEFAULT,
+        );
+        assert_eq!(result, Err(HostError::InvalidRequest));
+        assert!(host.transactions().is_empty());
+    }
+
+    #[test]
+    fn test_control_request_stall() {
+        let host = MockHost::new(64);
+        host.reply(MockReply::Handshake(Handshake::Stall));
+        let setup_packet = SetupPacket {
+            request_type: 0b0100_0000, // HostToDevice, Vendor, Device
+            request: 0x01,
+            value: 0,
+            index: 0,
+            length: 0,
+        };
+        let result = control_request(&host, 0, setup_packet, &mut [], &TransferPolicy::DEFAULT);
+        assert_eq!(result, Err(HostError::Stall(ControlStage::Setup)));
+    }
+
+    fn string_descriptor(s: &str) -> Vec<u8> {
+        let mut descriptor = vec![0, 3];
+        descriptor.extend(s.encode_utf16().flat_map(|c| c.to_le_bytes()));
+        descriptor[0] = descriptor.len() as u8;
+        descriptor
+    }
+
+    #[test]
+    fn test_get_string() {
+        let host = MockHost::new(64);
+        // string descriptor zero
+        host.reply(MockReply::Handshake(Handshake::Ack));
+        host.reply(MockReply::Data(vec![6, 3, 0x07, 0x04, 0x09, 0x04]));
+        host.reply(MockReply::Handshake(Handshake::Ack));
+        // string descriptor 2
+        host.reply(MockReply::Handshake(Handshake::Ack));
+        host.reply(MockReply::Data(string_descriptor("Cynthion ⚡")));
+
+        let string: String<32> = get_string(&host, 0, 2, None, &TransferPolicy::DEFAULT).unwrap();
+
+        assert_eq!(string, "Cynthion ⚡");
+        assert_eq!(
+            host.transactions()[3],
+            MockTransaction::Setup([0x80, 0x06, 0x02, 0x03, 0x07, 0x04, 0xff, 0x00])
+        );
+    }
+
+    #[test]
+    fn test_get_string_truncated() {
+        let host = MockHost::new(64);
+        host.reply(MockReply::Handshake(Handshake::Ack));
+        host.reply(MockReply::Data(string_descriptor("abc⚡def")));
+
+        let string: String<5> =
+            get_string(&host, 0, 1, Some(0x0409), &TransferPolicy::DEFAULT).unwrap();
+
+        assert_eq!(string, "abc");
+    }
+}
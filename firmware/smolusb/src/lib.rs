@@ -4,16 +4,27 @@
 
 //! Simple peripheral-level USB stack
 
+pub mod capture;
 pub mod class;
+pub mod consts;
 pub mod control;
+pub mod control_capture;
 pub mod descriptor;
+pub mod descriptor_fmt;
 pub mod device;
 pub mod error;
+pub mod host;
+#[cfg(test)]
+pub(crate) mod mock;
+pub mod policy;
 pub mod setup;
 pub mod traits;
 
+pub use error::ConnectError;
 pub use error::SmolError;
 pub use error::SmolResult;
+pub use policy::RecoveryPolicy;
+pub use policy::TransferPolicy;
 
 // TODO const template structs
 pub const EP_MAX_ENDPOINTS: usize = 16;
@@ -48,6 +59,16 @@ pub mod event {
         ///
         /// Contents is (endpoint_number)
         SendComplete(u8) = 13,
+
+        /// Received a packet longer than the OUT endpoint's maximum
+        /// packet size
+        ///
+        /// Contents is (endpoint_number)
+        BabbleDetected(u8) = 15,
+
+        /// The bus has been idle for longer than the device's idle
+        /// timeout
+        Suspend = 16,
     }
 
     impl core::fmt::Debug for UsbEvent {
@@ -68,10 +89,32 @@ pub mod event {
                 UsbEvent::SendComplete(endpoint) => {
                     write!(f, "SendComplete({})", endpoint)
                 }
+                UsbEvent::BabbleDetected(endpoint) => {
+                    write!(f, "BabbleDetected({})", endpoint)
+                }
+                UsbEvent::Suspend => {
+                    write!(f, "Suspend")
+                }
             }
         }
     }
 
+    /// Interrupt sources of a USB interface
+    ///
+    /// Each source raises a subset of [`UsbEvent`]s and can be switched
+    /// between interrupt-driven and polled operation on its own.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum InterruptSource {
+        /// USBx controller: [`UsbEvent::BusReset`]
+        Controller,
+        /// USBx_EP_CONTROL: [`UsbEvent::ReceiveControl`]
+        EndpointControl,
+        /// USBx_EP_IN: [`UsbEvent::SendComplete`]
+        EndpointIn,
+        /// USBx_EP_OUT: [`UsbEvent::ReceivePacket`]
+        EndpointOut,
+    }
+
     impl From<UsbEvent> for u8 {
         fn from(event: UsbEvent) -> u8 {
             match event {
@@ -80,6 +123,8 @@ pub mod event {
                 UsbEvent::ReceiveSetupPacket(_, _) => 14,
                 UsbEvent::ReceivePacket(_) => 12,
                 UsbEvent::SendComplete(_) => 13,
+                UsbEvent::BabbleDetected(_) => 15,
+                UsbEvent::Suspend => 16,
             }
         }
     }
@@ -0,0 +1,638 @@
+//! Mock USB driver for host-side testing
+//!
+//! `MockDriver` records the operations performed on it so tests can
+//! exercise `smolusb` without hardware.
+
+#![allow(dead_code)]
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+use crate::error::{ConnectError, SmolError, SmolResult};
+use crate::event::{InterruptSource, UsbEvent};
+use crate::host::{Handshake, HostDriver};
+use crate::setup::{Direction, SetupPacket};
+use crate::traits::{
+    EndpointStatus, InterruptControl, PollEvent, ReadControl, ReadEndpoint,
+    UnsafeUsbDriverOperations, UsbDriver, UsbDriverOperations, WriteEndpoint, WriteRefEndpoint,
+};
+
+/// Mock device-side driver
+///
+/// Writes larger than `MAX_PACKET_SIZE` fail with `FifoOverflow`.
+#[derive(Default)]
+pub struct MockDriver<const MAX_PACKET_SIZE: usize = { crate::EP_MAX_PACKET_SIZE }> {
+    /// Number of upcoming `try_write` calls that will fail
+    fail_writes: Cell<usize>,
+    /// Number of upcoming `ep_out_prime_receive` calls that will fail
+    fail_primes: Cell<usize>,
+    /// Set if the last `ep_out_prime_receive` call failed
+    prime_failed: Cell<bool>,
+    /// Returned by the next call to `connect`
+    connect_error: Cell<Option<ConnectError>>,
+    in_fifo_resets: Cell<usize>,
+    /// Bitmap of IN endpoints written via `try_write` and not yet
+    /// completed
+    in_flight: Cell<u16>,
+    /// Bitmaps of paused IN and OUT endpoints
+    paused_in: Cell<u16>,
+    paused_out: Cell<u16>,
+    tx_ack_active: Cell<bool>,
+    address: Cell<u8>,
+    /// Returned by the next call to `read_control`
+    setup_packet: Cell<Option<[u8; 8]>>,
+    /// Returned by the next call to `read`
+    packet: RefCell<Vec<u8>>,
+    /// Endpoint `packet` was received on
+    packet_endpoint: Cell<u8>,
+    /// Moved to `packet` in order each time the endpoint is primed
+    /// after the previous packet has been read
+    queued_packets: RefCell<VecDeque<Vec<u8>>>,
+    bus_resets: Cell<usize>,
+    disconnects: Cell<usize>,
+    /// Returned by `frame_number`
+    frame_number: Cell<Option<u16>>,
+    /// Returned in order by `poll_event`
+    events: RefCell<VecDeque<UsbEvent>>,
+    /// Bitmap of enabled `InterruptSource`s
+    interrupts_enabled: Cell<u8>,
+
+    /// (endpoint_number, data) of each packet written
+    writes: RefCell<Vec<(u8, Vec<u8>)>>,
+    /// Endpoints primed via `ep_out_prime_receive`
+    primed: RefCell<Vec<u8>>,
+    /// Endpoint addresses stalled via `stall_endpoint_in/out`
+    stalled: RefCell<Vec<u8>>,
+    /// Endpoint addresses reset via `reset_endpoint`
+    endpoint_resets: RefCell<Vec<u8>>,
+}
+
+impl MockDriver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<const MAX_PACKET_SIZE: usize> MockDriver<MAX_PACKET_SIZE> {
+    /// Make the next `count` calls to `try_write` fail with `TxInProgress`
+    pub fn fail_writes(&self, count: usize) {
+        self.fail_writes.set(count);
+    }
+
+    /// Make the next `count` calls to `ep_out_prime_receive` leave
+    /// the endpoint unprimed
+    pub fn fail_primes(&self, count: usize) {
+        self.fail_primes.set(count);
+    }
+
+    /// Make the next call to `connect` fail with `error`
+    pub fn fail_connect(&self, error: ConnectError) {
+        self.connect_error.set(Some(error));
+    }
+
+    /// Make the next call to `read_control` return `setup_packet`
+    pub fn receive_setup_packet(&self, setup_packet: [u8; 8]) {
+        self.setup_packet.set(Some(setup_packet));
+    }
+
+    /// Make the next call to `read` return `packet`
+    pub fn receive_packet(&self, packet: &[u8]) {
+        self.packet.replace(packet.to_vec());
+    }
+
+    /// Queue `packet` to be returned by `read` after any packets
+    /// already waiting
+    pub fn queue_packet(&self, packet: &[u8]) {
+        if self.has_packet() {
+            self.queued_packets.borrow_mut().push_back(packet.to_vec());
+        } else {
+            self.receive_packet(packet);
+        }
+    }
+
+    /// Make the next call to `read` return `packet` received on the
+    /// given OUT endpoint
+    pub fn receive_packet_on(&self, endpoint_number: u8, packet: &[u8]) {
+        self.packet_endpoint.set(endpoint_number);
+        self.receive_packet(packet);
+    }
+
+    /// Set the frame number of the last SOF packet
+    pub fn set_frame_number(&self, frame_number: u16) {
+        self.frame_number.set(Some(frame_number & 0x7ff));
+    }
+
+    /// Queue an event to be returned by `poll_event`
+    pub fn queue_event(&self, event: UsbEvent) {
+        self.events.borrow_mut().push_back(event);
+    }
+
+    /// Complete the write in flight on the given IN endpoint, as if
+    /// the host had collected it
+    pub fn complete_write(&self, endpoint_number: u8) {
+        self.in_flight
+            .set(self.in_flight.get() & !(1 << (endpoint_number & 0xf)));
+    }
+
+    pub fn disconnects(&self) -> usize {
+        self.disconnects.get()
+    }
+
+    pub fn bus_resets(&self) -> usize {
+        self.bus_resets.get()
+    }
+
+    pub fn in_fifo_resets(&self) -> usize {
+        self.in_fifo_resets.get()
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address.get()
+    }
+
+    pub fn writes(&self) -> Vec<(u8, Vec<u8>)> {
+        self.writes.borrow().clone()
+    }
+
+    pub fn primed(&self) -> Vec<u8> {
+        self.primed.borrow().clone()
+    }
+
+    pub fn stalled(&self) -> Vec<u8> {
+        self.stalled.borrow().clone()
+    }
+
+    pub fn endpoint_resets(&self) -> Vec<u8> {
+        self.endpoint_resets.borrow().clone()
+    }
+
+    /// Returns true if a packet is waiting to be read
+    pub fn has_packet(&self) -> bool {
+        !self.packet.borrow().is_empty()
+    }
+
+    fn paused(&self, direction: Direction) -> &Cell<u16> {
+        match direction {
+            Direction::HostToDevice => &self.paused_out,
+            Direction::DeviceToHost => &self.paused_in,
+        }
+    }
+
+    fn is_paused(&self, endpoint_number: u8, direction: Direction) -> bool {
+        self.paused(direction).get() & (1 << (endpoint_number & 0xf)) != 0
+    }
+
+    /// Writes to paused IN endpoints, or endpoints whose previous
+    /// write the host has not collected yet, fail without writing
+    /// anything
+    fn check_writable(&self, endpoint_number: u8) -> SmolResult<()> {
+        if self.is_paused(endpoint_number, Direction::DeviceToHost)
+            || self.is_in_flight(endpoint_number)
+        {
+            return Err(SmolError::WouldBlock);
+        }
+        Ok(())
+    }
+
+    fn record_write(&self, endpoint_number: u8, data: Vec<u8>) {
+        self.writes.borrow_mut().push((endpoint_number, data));
+    }
+}
+
+impl<const MAX_PACKET_SIZE: usize> UsbDriver for MockDriver<MAX_PACKET_SIZE> {}
+
+impl<const MAX_PACKET_SIZE: usize> UsbDriverOperations for MockDriver<MAX_PACKET_SIZE> {
+    fn connect(&self) -> Result<u8, ConnectError> {
+        match self.connect_error.take() {
+            Some(error) => Err(error),
+            None => Ok(0),
+        }
+    }
+    fn disconnect(&self) {
+        self.disconnects.set(self.disconnects.get() + 1);
+        self.address.set(0);
+    }
+    fn reset(&self) -> u8 {
+        0
+    }
+    fn bus_reset(&self) -> u8 {
+        self.bus_resets.set(self.bus_resets.get() + 1);
+        self.address.set(0);
+        0
+    }
+    fn ack_status_stage(&self, packet: &SetupPacket) {
+        // requests without a data stage always have an IN status stage
+        match (Direction::from(packet.request_type), packet.length) {
+            (Direction::DeviceToHost, 1..) => self.ack(0, Direction::DeviceToHost),
+            _ => self.ack(0, Direction::HostToDevice),
+        }
+    }
+    fn ack(&self, endpoint_number: u8, direction: Direction) {
+        match direction {
+            Direction::DeviceToHost => self.ep_out_prime_receive(endpoint_number),
+            Direction::HostToDevice => {
+                // tx_ack_active stays set until the SendComplete event
+                self.record_write(endpoint_number, Vec::new());
+            }
+        }
+    }
+    fn set_address(&self, address: u8) {
+        self.address.set(address & 0x7f);
+    }
+    fn stall_control_request(&self) {
+        self.stall_endpoint_in(0);
+        self.stall_endpoint_out(0);
+    }
+    fn stall_endpoint_in(&self, endpoint_number: u8) {
+        self.stalled.borrow_mut().push(endpoint_number | 0x80);
+    }
+    fn stall_endpoint_out(&self, endpoint_number: u8) {
+        self.stalled.borrow_mut().push(endpoint_number);
+    }
+    fn unstall_endpoint_in(&self, endpoint_number: u8) {
+        self.stalled
+            .borrow_mut()
+            .retain(|&address| address != endpoint_number | 0x80);
+    }
+    fn unstall_endpoint_out(&self, endpoint_number: u8) {
+        self.stalled
+            .borrow_mut()
+            .retain(|&address| address != endpoint_number);
+    }
+    fn clear_feature_endpoint_halt(&self, endpoint_address: u8) {
+        self.stalled
+            .borrow_mut()
+            .retain(|&address| address != endpoint_address);
+    }
+    fn reset_endpoint(&self, endpoint_number: u8, direction: Direction) {
+        let endpoint_address = match direction {
+            Direction::HostToDevice => endpoint_number,
+            Direction::DeviceToHost => endpoint_number | 0x80,
+        };
+        self.endpoint_resets.borrow_mut().push(endpoint_address);
+        match direction {
+            Direction::HostToDevice => {
+                if self.packet_endpoint.get() == endpoint_number {
+                    self.packet.borrow_mut().clear();
+                }
+            }
+            Direction::DeviceToHost => self.flush_in(endpoint_number),
+        }
+    }
+    /// The host never sees a write that is still in flight so it is
+    /// removed from `writes`
+    fn flush_in(&self, endpoint_number: u8) {
+        if self.is_in_flight(endpoint_number) {
+            let mut writes = self.writes.borrow_mut();
+            if let Some(index) = writes
+                .iter()
+                .rposition(|(endpoint, _)| *endpoint == endpoint_number)
+            {
+                writes.remove(index);
+            }
+        }
+        self.complete_write(endpoint_number);
+    }
+    fn pause_endpoint(&self, endpoint_number: u8, direction: Direction) -> SmolResult<()> {
+        if endpoint_number & 0xf == 0 {
+            return Err(SmolError::InvalidArgument);
+        }
+        let paused = self.paused(direction);
+        paused.set(paused.get() | (1 << (endpoint_number & 0xf)));
+        Ok(())
+    }
+    fn resume_endpoint(&self, endpoint_number: u8, direction: Direction) -> SmolResult<()> {
+        if endpoint_number & 0xf == 0 {
+            return Err(SmolError::InvalidArgument);
+        }
+        let paused = self.paused(direction);
+        paused.set(paused.get() & !(1 << (endpoint_number & 0xf)));
+        // like eptri, a packet still waiting in the FIFO is kept and
+        // the endpoint is re-primed once it has been read
+        if direction == Direction::HostToDevice && !self.out_has_data(endpoint_number) {
+            self.ep_out_prime_receive(endpoint_number);
+        }
+        Ok(())
+    }
+    fn frame_number(&self) -> Option<u16> {
+        self.frame_number.get()
+    }
+}
+
+impl<const MAX_PACKET_SIZE: usize> PollEvent for MockDriver<MAX_PACKET_SIZE> {
+    fn poll_event(&self) -> Option<UsbEvent> {
+        let event = self.events.borrow_mut().pop_front();
+        if let Some(UsbEvent::SendComplete(_)) = event {
+            self.tx_ack_active.set(false);
+        }
+        event
+    }
+}
+
+impl<const MAX_PACKET_SIZE: usize> EndpointStatus for MockDriver<MAX_PACKET_SIZE> {
+    fn in_has_data(&self, endpoint_number: u8) -> bool {
+        self.is_in_flight(endpoint_number)
+    }
+
+    fn in_is_idle(&self, _endpoint_number: u8) -> bool {
+        self.in_flight.get() == 0
+    }
+
+    fn out_has_data(&self, endpoint_number: u8) -> bool {
+        !self.packet.borrow().is_empty() && self.packet_endpoint.get() == endpoint_number
+    }
+}
+
+impl<const MAX_PACKET_SIZE: usize> InterruptControl for MockDriver<MAX_PACKET_SIZE> {
+    fn set_interrupt_source(&self, source: InterruptSource, enabled: bool) {
+        let mask = 1 << source as u8;
+        let interrupts_enabled = self.interrupts_enabled.get();
+        if enabled {
+            self.interrupts_enabled.set(interrupts_enabled | mask);
+        } else {
+            self.interrupts_enabled.set(interrupts_enabled & !mask);
+        }
+    }
+
+    fn is_interrupt_source_enabled(&self, source: InterruptSource) -> bool {
+        self.interrupts_enabled.get() & (1 << source as u8) != 0
+    }
+}
+
+impl<const MAX_PACKET_SIZE: usize> UnsafeUsbDriverOperations for MockDriver<MAX_PACKET_SIZE> {
+    unsafe fn set_tx_ack_active(&self) {
+        self.tx_ack_active.set(true);
+    }
+    unsafe fn clear_tx_ack_active(&self) {
+        self.tx_ack_active.set(false);
+    }
+    unsafe fn is_tx_ack_active(&self) -> bool {
+        self.tx_ack_active.get()
+    }
+}
+
+impl<const MAX_PACKET_SIZE: usize> ReadControl for MockDriver<MAX_PACKET_SIZE> {
+    fn read_control(&self, buffer: &mut [u8]) -> usize {
+        match self.setup_packet.take() {
+            Some(setup_packet) => {
+                buffer[..8].copy_from_slice(&setup_packet);
+                8
+            }
+            None => 0,
+        }
+    }
+}
+
+impl<const MAX_PACKET_SIZE: usize> ReadEndpoint for MockDriver<MAX_PACKET_SIZE> {
+    fn ep_out_prime_receive(&self, endpoint_number: u8) {
+        if self.is_paused(endpoint_number, Direction::HostToDevice) {
+            return;
+        }
+        // like eptri, priming resets the shared OUT FIFO
+        self.packet.borrow_mut().clear();
+        let fail_primes = self.fail_primes.get();
+        self.prime_failed.set(fail_primes > 0);
+        if fail_primes > 0 {
+            self.fail_primes.set(fail_primes - 1);
+            return;
+        }
+        self.primed.borrow_mut().push(endpoint_number);
+        // the host sends its next packet once the endpoint is primed
+        if let Some(next) = self.queued_packets.borrow_mut().pop_front() {
+            self.packet.replace(next);
+        }
+    }
+    fn is_ep_out_primed(&self, endpoint_number: u8) -> bool {
+        self.is_paused(endpoint_number, Direction::HostToDevice) || !self.prime_failed.get()
+    }
+    fn read(&self, endpoint_number: u8, buffer: &mut [u8]) -> usize {
+        self.read_with_overflow(endpoint_number, buffer).0
+    }
+
+    fn read_with_overflow(&self, _endpoint_number: u8, buffer: &mut [u8]) -> (usize, usize) {
+        let packet = self.packet.take();
+        let length = packet.len().min(buffer.len());
+        buffer[..length].copy_from_slice(&packet[..length]);
+        (length, packet.len() - length)
+    }
+}
+
+impl<const MAX_PACKET_SIZE: usize> WriteEndpoint for MockDriver<MAX_PACKET_SIZE> {
+    fn write<I>(&self, endpoint_number: u8, iter: I) -> SmolResult<usize>
+    where
+        I: Iterator<Item = u8>,
+    {
+        self.check_writable(endpoint_number)?;
+        let data: Vec<u8> = iter.collect();
+        if data.len() > MAX_PACKET_SIZE {
+            return Err(SmolError::FifoOverflow);
+        }
+        let bytes_written = data.len();
+        self.record_write(endpoint_number, data);
+        Ok(bytes_written)
+    }
+
+    fn write_all<I>(
+        &self,
+        endpoint_number: u8,
+        iter: I,
+        max_packet_size: usize,
+    ) -> SmolResult<usize>
+    where
+        I: Iterator<Item = u8>,
+    {
+        self.check_writable(endpoint_number)?;
+        if max_packet_size == 0 {
+            return Err(SmolError::InvalidArgument);
+        }
+        let data: Vec<u8> = iter.collect();
+        for packet in data.chunks(max_packet_size) {
+            self.record_write(endpoint_number, packet.to_vec());
+        }
+        // terminate the transfer with a ZLP
+        if data.len() % max_packet_size == 0 {
+            self.record_write(endpoint_number, Vec::new());
+        }
+        Ok(data.len())
+    }
+
+    fn write_packets<I>(
+        &self,
+        endpoint_number: u8,
+        iter: I,
+        packet_size: usize,
+    ) -> SmolResult<usize>
+    where
+        I: Iterator<Item = u8>,
+    {
+        self.check_writable(endpoint_number)?;
+        if packet_size == 0 {
+            return Err(SmolError::InvalidArgument);
+        }
+        let data: Vec<u8> = iter.collect();
+        if data.is_empty() {
+            self.record_write(endpoint_number, data);
+            return Ok(0);
+        }
+        for packet in data.chunks(packet_size) {
+            self.record_write(endpoint_number, packet.to_vec());
+        }
+        Ok(data.len())
+    }
+
+    fn try_write<I>(&self, endpoint_number: u8, iter: I) -> SmolResult<usize>
+    where
+        I: Iterator<Item = u8>,
+    {
+        self.check_writable(endpoint_number)?;
+
+        let fail_writes = self.fail_writes.get();
+        if fail_writes > 0 {
+            self.fail_writes.set(fail_writes - 1);
+            return Err(SmolError::TxInProgress);
+        }
+
+        let data: Vec<u8> = iter.collect();
+        if data.len() > MAX_PACKET_SIZE {
+            return Err(SmolError::FifoOverflow);
+        }
+        let bytes_written = data.len();
+        self.record_write(endpoint_number, data);
+        self.in_flight
+            .set(self.in_flight.get() | (1 << (endpoint_number & 0xf)));
+        Ok(bytes_written)
+    }
+
+    fn is_in_flight(&self, endpoint_number: u8) -> bool {
+        self.in_flight.get() & (1 << (endpoint_number & 0xf)) != 0
+    }
+
+    fn reset_in_fifo(&self) {
+        self.in_fifo_resets.set(self.in_fifo_resets.get() + 1);
+        self.in_flight.set(0);
+    }
+}
+
+impl<const MAX_PACKET_SIZE: usize> WriteRefEndpoint for MockDriver<MAX_PACKET_SIZE> {
+    fn write_ref<'a, I>(&self, endpoint_number: u8, iter: I) -> SmolResult<usize>
+    where
+        I: Iterator<Item = &'a u8>,
+    {
+        self.check_writable(endpoint_number)?;
+        let data: Vec<u8> = iter.copied().collect();
+        if data.len() > MAX_PACKET_SIZE {
+            return Err(SmolError::FifoOverflow);
+        }
+        let bytes_written = data.len();
+        self.record_write(endpoint_number, data);
+        Ok(bytes_written)
+    }
+}
+
+// - MockHost -----------------------------------------------------------------
+
+/// A transaction issued by the host
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockTransaction {
+    Setup([u8; 8]),
+    /// (data, data1)
+    Out(Vec<u8>, bool),
+    /// (data1)
+    In(bool),
+}
+
+/// A scripted reply from the target device
+#[derive(Debug, Clone)]
+pub enum MockReply {
+    Handshake(Handshake),
+    Data(Vec<u8>),
+}
+
+/// Mock host controller with scripted device replies
+///
+/// Transactions are answered with queued replies in order. Once the
+/// queue is empty SETUP and OUT transactions are ACK'd and IN
+/// transactions receive a zero length packet.
+pub struct MockHost {
+    control_max_packet_size: usize,
+    replies: RefCell<VecDeque<MockReply>>,
+    transactions: RefCell<Vec<MockTransaction>>,
+    aborts: RefCell<Vec<u8>>,
+}
+
+impl MockHost {
+    pub fn new(control_max_packet_size: usize) -> Self {
+        Self {
+            control_max_packet_size,
+            replies: RefCell::new(VecDeque::new()),
+            transactions: RefCell::new(Vec::new()),
+            aborts: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Queue a reply for the next transaction
+    pub fn reply(&self, reply: MockReply) {
+        self.replies.borrow_mut().push_back(reply);
+    }
+
+    pub fn transactions(&self) -> Vec<MockTransaction> {
+        self.transactions.borrow().clone()
+    }
+
+    /// Endpoint numbers of the transfers aborted so far
+    pub fn aborts(&self) -> Vec<u8> {
+        self.aborts.borrow().clone()
+    }
+
+    fn handshake(&self) -> Handshake {
+        match self.replies.borrow_mut().pop_front() {
+            Some(MockReply::Handshake(handshake)) => handshake,
+            Some(MockReply::Data(_)) | None => Handshake::Ack,
+        }
+    }
+}
+
+impl HostDriver for MockHost {
+    fn control_max_packet_size(&self) -> usize {
+        self.control_max_packet_size
+    }
+
+    fn send_setup(&self, _endpoint_number: u8, packet: &[u8; 8]) -> Handshake {
+        self.transactions
+            .borrow_mut()
+            .push(MockTransaction::Setup(*packet));
+        self.handshake()
+    }
+
+    fn send_out(&self, _endpoint_number: u8, data: &[u8], data1: bool) -> Handshake {
+        self.transactions
+            .borrow_mut()
+            .push(MockTransaction::Out(data.to_vec(), data1));
+        self.handshake()
+    }
+
+    fn receive_in(
+        &self,
+        _endpoint_number: u8,
+        buffer: &mut [u8],
+        data1: bool,
+    ) -> Result<usize, Handshake> {
+        self.transactions
+            .borrow_mut()
+            .push(MockTransaction::In(data1));
+        match self.replies.borrow_mut().pop_front() {
+            Some(MockReply::Data(data)) => {
+                let length = data.len().min(buffer.len());
+                buffer[..length].copy_from_slice(&data[..length]);
+                Ok(length)
+            }
+            Some(MockReply::Handshake(handshake)) => Err(handshake),
+            None => Ok(0),
+        }
+    }
+
+    fn abort(&self, endpoint_number: u8) {
+        self.aborts.borrow_mut().push(endpoint_number);
+    }
+}
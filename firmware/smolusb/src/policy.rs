@@ -0,0 +1,75 @@
+//! Retry and timeout limits shared by transfer helpers
+
+/// Retry and timeout limits for a transfer
+///
+/// Passed to the device and host transfer helpers so firmware can
+/// configure their timing in one place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferPolicy {
+    /// Number of times an operation is retried after a transient
+    /// failure, e.g. a prime that did not take effect or a busy IN FIFO
+    pub max_retries: usize,
+    /// Number of times a transaction the other end of the bus NAKs is
+    /// retried before giving up, e.g. while a device NAKs a host
+    /// transaction
+    pub max_nak_retries: usize,
+}
+
+impl TransferPolicy {
+    pub const DEFAULT: Self = Self {
+        max_retries: 2,
+        max_nak_retries: 1000,
+    };
+
+    pub const fn new(max_retries: usize, max_nak_retries: usize) -> Self {
+        Self {
+            max_retries,
+            max_nak_retries,
+        }
+    }
+
+    /// Returns the total number of attempts, the first try included.
+    pub const fn attempts(&self) -> usize {
+        self.max_retries + 1
+    }
+}
+
+impl Default for TransferPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// What `UsbDevice` does when control transfers keep failing
+///
+/// Malformed requests, e.g. while fuzzing, can leave the control state
+/// machine out of step with the host. Disconnecting and reconnecting
+/// makes the host reset and re-enumerate the device rather than leaving
+/// it wedged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryPolicy {
+    /// Number of control errors without a completed control transfer
+    /// in between after which the device reconnects, or `None` to only
+    /// report errors
+    pub max_control_errors: Option<usize>,
+}
+
+impl RecoveryPolicy {
+    /// Never reconnect, errors are only returned to the caller
+    pub const DISABLED: Self = Self {
+        max_control_errors: None,
+    };
+
+    /// Reconnect after `max_control_errors` control errors
+    pub const fn reconnect_after(max_control_errors: usize) -> Self {
+        Self {
+            max_control_errors: Some(max_control_errors),
+        }
+    }
+}
+
+impl Default for RecoveryPolicy {
+    fn default() -> Self {
+        Self::DISABLED
+    }
+}
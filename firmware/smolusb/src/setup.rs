@@ -1,4 +1,5 @@
 ///! Types for working with the SETUP packet.
+use crate::consts::{endpoint, feature, request, request_type};
 use crate::error::SmolError;
 
 /// Represents a USB setup packet.
@@ -37,25 +38,110 @@ impl SetupPacket {
 }
 
 impl SetupPacket {
+    /// Returns the `request_type` field for decoding all of its parts at once.
+    pub fn request_type_byte(&self) -> RequestTypeByte {
+        RequestTypeByte(self.request_type)
+    }
+
     pub fn request_type(&self) -> RequestType {
-        RequestType::from(self.request_type)
+        self.request_type_byte().type_()
     }
 
     pub fn recipient(&self) -> Recipient {
-        Recipient::from(self.request_type)
+        self.request_type_byte().recipient()
     }
 
     pub fn direction(&self) -> Direction {
-        Direction::from(self.request_type)
+        self.request_type_byte().direction()
     }
 
     pub fn request(&self) -> Request {
         Request::from(self.request)
     }
+
+    /// Returns the endpoint address held in `wIndex` of an endpoint
+    /// recipient request.
+    ///
+    /// The direction bit is kept so IN and OUT endpoints sharing a
+    /// number can be told apart, reserved bits are cleared.
+    pub fn endpoint_address(&self) -> u8 {
+        self.index as u8 & (endpoint::DIRECTION_MASK | endpoint::NUMBER_MASK)
+    }
+}
+
+/// The `[SetupPacket]` `request_type` field
+///
+/// Decodes the direction, type and recipient bitfields of the byte and
+/// builds new values from them:
+///
+/// ```
+/// use smolusb::setup::{Direction, Recipient, RequestType, RequestTypeByte};
+///
+/// let request_type = RequestTypeByte::new()
+///     .with_direction(Direction::DeviceToHost)
+///     .with_type(RequestType::Vendor)
+///     .with_recipient(Recipient::Interface);
+/// assert_eq!(u8::from(request_type), 0b1100_0001);
+///
+/// let (direction, type_, recipient) = request_type.parts();
+/// assert_eq!(direction, Direction::DeviceToHost);
+/// assert_eq!(type_, RequestType::Vendor);
+/// assert_eq!(recipient, Recipient::Interface);
+/// ```
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct RequestTypeByte(pub u8);
+
+impl RequestTypeByte {
+    /// Returns a `HostToDevice`, `Standard`, `Device` request type.
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    pub const fn with_direction(self, direction: Direction) -> Self {
+        Self((self.0 & !request_type::DIRECTION_MASK) | direction as u8)
+    }
+
+    pub const fn with_type(self, type_: RequestType) -> Self {
+        Self((self.0 & !request_type::TYPE_MASK) | ((type_ as u8) << request_type::TYPE_SHIFT))
+    }
+
+    /// `Recipient::Reserved` is encoded as the first reserved value.
+    pub const fn with_recipient(self, recipient: Recipient) -> Self {
+        Self((self.0 & !request_type::RECIPIENT_MASK) | recipient as u8)
+    }
+
+    pub fn direction(&self) -> Direction {
+        Direction::from(self.0)
+    }
+
+    pub fn type_(&self) -> RequestType {
+        RequestType::from(self.0)
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        Recipient::from(self.0)
+    }
+
+    /// Returns the direction, type and recipient of the request.
+    pub fn parts(&self) -> (Direction, RequestType, Recipient) {
+        (self.direction(), self.type_(), self.recipient())
+    }
+}
+
+impl From<u8> for RequestTypeByte {
+    fn from(value: u8) -> Self {
+        Self(value)
+    }
+}
+
+impl From<RequestTypeByte> for u8 {
+    fn from(value: RequestTypeByte) -> Self {
+        value.0
+    }
 }
 
 /// Represents bits 0..=4 of the `[SetupPacket]` `request_type` field.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Recipient {
     Device = 0,
@@ -67,45 +153,45 @@ pub enum Recipient {
 
 impl From<u8> for Recipient {
     fn from(value: u8) -> Self {
-        match value & 0b0001_1111 {
-            0 => Recipient::Device,
-            1 => Recipient::Interface,
-            2 => Recipient::Endpoint,
-            3 => Recipient::Other,
-            4..=u8::MAX => Recipient::Reserved,
+        match value & request_type::RECIPIENT_MASK {
+            request_type::RECIPIENT_DEVICE => Recipient::Device,
+            request_type::RECIPIENT_INTERFACE => Recipient::Interface,
+            request_type::RECIPIENT_ENDPOINT => Recipient::Endpoint,
+            request_type::RECIPIENT_OTHER => Recipient::Other,
+            _ => Recipient::Reserved,
         }
     }
 }
 
 /// Represents bit 5..=6 of the `[SetupPacket]` `request`_type field.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum RequestType {
-    Standard = 0,
-    Class = 1,
-    Vendor = 2,
-    Reserved = 3,
+    Standard = request_type::TYPE_STANDARD,
+    Class = request_type::TYPE_CLASS,
+    Vendor = request_type::TYPE_VENDOR,
+    Reserved = request_type::TYPE_RESERVED,
 }
 
 impl From<u8> for RequestType {
     fn from(value: u8) -> Self {
-        match (value >> 5) & 0b0000_0011 {
-            0 => RequestType::Standard,
-            1 => RequestType::Class,
-            2 => RequestType::Vendor,
-            3..=u8::MAX => RequestType::Reserved,
+        match (value & request_type::TYPE_MASK) >> request_type::TYPE_SHIFT {
+            request_type::TYPE_STANDARD => RequestType::Standard,
+            request_type::TYPE_CLASS => RequestType::Class,
+            request_type::TYPE_VENDOR => RequestType::Vendor,
+            _ => RequestType::Reserved,
         }
     }
 }
 
 /// Represents bit 7 of the `[SetupPacket]` `request`_type field.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Direction {
     /// Host to device (OUT)
-    HostToDevice = 0x00,
+    HostToDevice = request_type::DIRECTION_OUT,
     /// Device to host (IN)
-    DeviceToHost = 0x80, // 0b1000_0000,
+    DeviceToHost = request_type::DIRECTION_IN,
 }
 
 impl Direction {
@@ -115,7 +201,7 @@ impl Direction {
 
 impl From<u8> for Direction {
     fn from(request_type: u8) -> Self {
-        match (request_type & 0b1000_0000) == 0 {
+        match (request_type & request_type::DIRECTION_MASK) == request_type::DIRECTION_OUT {
             true => Direction::HostToDevice,
             false => Direction::DeviceToHost,
         }
@@ -124,7 +210,7 @@ impl From<u8> for Direction {
 
 impl Direction {
     pub fn from_endpoint_address(endpoint_address: u8) -> Self {
-        match (endpoint_address & 0b10000000) == 0 {
+        match (endpoint_address & endpoint::DIRECTION_MASK) == endpoint::DIRECTION_OUT {
             true => Direction::HostToDevice,
             false => Direction::DeviceToHost,
         }
@@ -135,17 +221,17 @@ impl Direction {
 #[derive(Debug, PartialEq)]
 #[repr(u8)]
 pub enum Request {
-    GetStatus = 0,
-    ClearFeature = 1,
-    SetFeature = 3,
-    SetAddress = 5,
-    GetDescriptor = 6,
-    SetDescriptor = 7,
-    GetConfiguration = 8,
-    SetConfiguration = 9,
-    GetInterface = 10,
-    SetInterface = 11,
-    SynchronizeFrame = 12,
+    GetStatus = request::GET_STATUS,
+    ClearFeature = request::CLEAR_FEATURE,
+    SetFeature = request::SET_FEATURE,
+    SetAddress = request::SET_ADDRESS,
+    GetDescriptor = request::GET_DESCRIPTOR,
+    SetDescriptor = request::SET_DESCRIPTOR,
+    GetConfiguration = request::GET_CONFIGURATION,
+    SetConfiguration = request::SET_CONFIGURATION,
+    GetInterface = request::GET_INTERFACE,
+    SetInterface = request::SET_INTERFACE,
+    SynchronizeFrame = request::SYNCH_FRAME,
     ClassOrVendor(u8),
     Reserved(u8),
 }
@@ -153,19 +239,19 @@ pub enum Request {
 impl From<u8> for Request {
     fn from(value: u8) -> Self {
         match value {
-            0 => Request::GetStatus,
-            1 => Request::ClearFeature,
+            request::GET_STATUS => Request::GetStatus,
+            request::CLEAR_FEATURE => Request::ClearFeature,
             2 => Request::Reserved(2),
-            3 => Request::SetFeature,
+            request::SET_FEATURE => Request::SetFeature,
             4 => Request::Reserved(4),
-            5 => Request::SetAddress,
-            6 => Request::GetDescriptor,
-            7 => Request::SetDescriptor,
-            8 => Request::GetConfiguration,
-            9 => Request::SetConfiguration,
-            10 => Request::GetInterface,
-            11 => Request::SetInterface,
-            12 => Request::SynchronizeFrame,
+            request::SET_ADDRESS => Request::SetAddress,
+            request::GET_DESCRIPTOR => Request::GetDescriptor,
+            request::SET_DESCRIPTOR => Request::SetDescriptor,
+            request::GET_CONFIGURATION => Request::GetConfiguration,
+            request::SET_CONFIGURATION => Request::SetConfiguration,
+            request::GET_INTERFACE => Request::GetInterface,
+            request::SET_INTERFACE => Request::SetInterface,
+            request::SYNCH_FRAME => Request::SynchronizeFrame,
             13..=u8::MAX => Request::ClassOrVendor(value),
         }
     }
@@ -184,10 +270,90 @@ impl TryFrom<u16> for Feature {
 
     fn try_from(value: u16) -> core::result::Result<Self, Self::Error> {
         let result = match value {
-            0 => Feature::EndpointHalt,
-            1 => Feature::DeviceRemoteWakeup,
+            feature::ENDPOINT_HALT => Feature::EndpointHalt,
+            feature::DEVICE_REMOTE_WAKEUP => Feature::DeviceRemoteWakeup,
             _ => return Err(SmolError::FailedConversion),
         };
         Ok(result)
     }
 }
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_address() {
+        let mut setup_packet = SetupPacket {
+            request_type: 0b0000_0010, // HostToDevice, Standard, Endpoint
+            request: 1,                // ClearFeature
+            value: 0,                  // EndpointHalt
+            index: 0x0081,
+            length: 0,
+        };
+        assert_eq!(setup_packet.endpoint_address(), 0x81);
+        assert_eq!(
+            Direction::from_endpoint_address(setup_packet.endpoint_address()),
+            Direction::DeviceToHost
+        );
+
+        setup_packet.index = 0x0001;
+        assert_eq!(setup_packet.endpoint_address(), 0x01);
+        assert_eq!(
+            Direction::from_endpoint_address(setup_packet.endpoint_address()),
+            Direction::HostToDevice
+        );
+
+        // reserved bits are ignored
+        setup_packet.index = 0xff72;
+        assert_eq!(setup_packet.endpoint_address(), 0x02);
+    }
+
+    #[test]
+    fn test_request_type_byte() {
+        for value in 0..=u8::MAX {
+            let request_type = RequestTypeByte::from(value);
+            let (direction, type_, recipient) = request_type.parts();
+
+            assert_eq!(direction, Direction::from(value));
+            assert_eq!(type_, RequestType::from(value));
+            assert_eq!(recipient, Recipient::from(value));
+            assert_eq!(u8::from(request_type), value);
+
+            // everything but reserved recipients round-trips through the builder
+            let rebuilt = RequestTypeByte::new()
+                .with_direction(direction)
+                .with_type(type_)
+                .with_recipient(recipient);
+            if value & request_type::RECIPIENT_MASK <= Recipient::Reserved as u8 {
+                assert_eq!(rebuilt, request_type);
+            } else {
+                assert_eq!(rebuilt.parts(), request_type.parts());
+            }
+        }
+    }
+
+    #[test]
+    fn test_request_type_byte_builder() {
+        let request_type = RequestTypeByte(0xff)
+            .with_direction(Direction::HostToDevice)
+            .with_type(RequestType::Class)
+            .with_recipient(Recipient::Endpoint);
+        assert_eq!(request_type, RequestTypeByte(0b0010_0010));
+
+        let setup_packet = SetupPacket {
+            request_type: 0b1010_0001,
+            ..SetupPacket::default()
+        };
+        assert_eq!(
+            setup_packet.request_type_byte().parts(),
+            (
+                Direction::DeviceToHost,
+                RequestType::Class,
+                Recipient::Interface
+            )
+        );
+    }
+}
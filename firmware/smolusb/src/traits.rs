@@ -1,3 +1,5 @@
+use crate::error::{ConnectError, SmolError, SmolResult};
+use crate::policy::TransferPolicy;
 use crate::setup::{Direction, SetupPacket};
 
 use zerocopy::AsBytes;
@@ -19,7 +21,9 @@ pub trait UsbDriver:
 
 pub trait UsbDriverOperations {
     /// Connect
-    fn connect(&self) -> u8;
+    ///
+    /// Returns the raw speed bits reported by the controller.
+    fn connect(&self) -> Result<u8, ConnectError>;
     /// Disconnect
     fn disconnect(&self);
     /// Reset
@@ -27,6 +31,11 @@ pub trait UsbDriverOperations {
     /// Bus Reset
     fn bus_reset(&self) -> u8;
     /// Acknowledge the status stage of an incoming control request.
+    ///
+    /// The status stage is OUT, i.e. a ZLP from the host, after an IN
+    /// data stage and IN otherwise. Requests with a `length` of zero
+    /// have no data stage, so they always send a ZLP, whatever their
+    /// direction bit.
     fn ack_status_stage(&self, packet: &SetupPacket);
     fn ack(&self, endpoint_number: u8, direction: Direction);
     fn set_address(&self, address: u8);
@@ -43,6 +52,81 @@ pub trait UsbDriverOperations {
 
     /// Clear any halt condition on the target endpoint, and clear the data toggle bit.
     fn clear_feature_endpoint_halt(&self, endpoint_address: u8);
+
+    /// Discard any data buffered for the given endpoint without
+    /// resetting the rest of the device.
+    ///
+    /// Data buffered for other endpoints is left in place. OUT
+    /// endpoints need to be re-primed afterwards.
+    fn reset_endpoint(&self, endpoint_number: u8, direction: Direction);
+
+    /// Discard data queued on the given IN endpoint that the host has
+    /// not collected yet and clear its in-flight flag.
+    fn flush_in(&self, endpoint_number: u8);
+
+    /// Stop servicing the given endpoint, the host is answered with NAK
+    ///
+    /// Unlike a stall this is not an error condition, the host keeps
+    /// retrying until the endpoint is resumed. OUT endpoints are no
+    /// longer primed and writes to IN endpoints return
+    /// [`SmolError::WouldBlock`] without writing anything.
+    ///
+    /// Returns [`SmolError::InvalidArgument`] for endpoint 0, the
+    /// control endpoint can't be paused without losing control
+    /// transfers.
+    fn pause_endpoint(&self, endpoint_number: u8, direction: Direction) -> SmolResult<()>;
+
+    /// Resume servicing an endpoint paused with
+    /// [`UsbDriverOperations::pause_endpoint`]
+    ///
+    /// OUT endpoints are re-primed without resetting the FIFO. A
+    /// packet received before the endpoint was paused is kept and
+    /// the endpoint is re-primed once it has been read.
+    fn resume_endpoint(&self, endpoint_number: u8, direction: Direction) -> SmolResult<()>;
+
+    /// Returns the 11-bit frame number from the last SOF packet.
+    ///
+    /// High-speed microframes share the frame number of their frame.
+    /// Returns `None` if the controller does not report SOF packets.
+    fn frame_number(&self) -> Option<u16> {
+        None
+    }
+}
+
+/// Endpoint FIFO status
+///
+/// Reading a controller's status registers often depends on which
+/// endpoint is selected, these methods take care of that.
+pub trait EndpointStatus {
+    /// Returns true if data written to the given IN endpoint is still
+    /// waiting for the host to collect it.
+    fn in_has_data(&self, endpoint_number: u8) -> bool;
+
+    /// Returns true if the IN FIFO is empty and no transmission to the
+    /// host is in progress, i.e. the given IN endpoint can be written.
+    fn in_is_idle(&self, endpoint_number: u8) -> bool;
+
+    /// Returns true if the OUT FIFO holds a packet received on the
+    /// given endpoint.
+    fn out_has_data(&self, endpoint_number: u8) -> bool;
+}
+
+/// Synchronous event polling for firmware running without interrupts
+pub trait PollEvent {
+    /// Return and clear the next pending USB event, if any
+    fn poll_event(&self) -> Option<crate::event::UsbEvent>;
+}
+
+/// Per-source control of a USB interface's interrupts
+pub trait InterruptControl {
+    /// Enable or disable interrupts raised by the given source.
+    ///
+    /// Events from a disabled source stay pending and can still be
+    /// collected with [`PollEvent::poll_event`].
+    fn set_interrupt_source(&self, source: crate::event::InterruptSource, enabled: bool);
+
+    /// Returns true if interrupts from the given source are enabled
+    fn is_interrupt_source_enabled(&self, source: crate::event::InterruptSource) -> bool;
 }
 
 pub trait UnsafeUsbDriverOperations {
@@ -64,25 +148,259 @@ pub trait ReadEndpoint {
 
     /// Read a packet from the given endpoint.
     fn read(&self, endpoint_number: u8, buffer: &mut [u8]) -> usize;
+
+    /// Read a packet from the given endpoint.
+    ///
+    /// Returns the number of bytes read and the number of bytes
+    /// discarded because they did not fit in `buffer`. Controllers
+    /// that can't tell always report no discarded bytes, which is the
+    /// default.
+    fn read_with_overflow(&self, endpoint_number: u8, buffer: &mut [u8]) -> (usize, usize) {
+        (self.read(endpoint_number, buffer), 0)
+    }
+
+    /// Returns true if the given OUT endpoint is primed to receive a
+    /// packet or has already received one.
+    ///
+    /// Controllers that can't read back their prime state always
+    /// return `true`, which is the default.
+    fn is_ep_out_primed(&self, _endpoint_number: u8) -> bool {
+        true
+    }
+
+    /// Prime the given OUT endpoint, retrying if the prime did not
+    /// take effect
+    ///
+    /// A prime racing with e.g. a bus reset can be lost, which would
+    /// otherwise silently stop reception. The prime is retried up to
+    /// `policy.max_retries` times before [`SmolError::PrimeFailed`] is
+    /// returned.
+    fn ep_out_prime_receive_retry(
+        &self,
+        endpoint_number: u8,
+        policy: &TransferPolicy,
+    ) -> SmolResult<()> {
+        for _ in 0..policy.attempts() {
+            self.ep_out_prime_receive(endpoint_number);
+            if self.is_ep_out_primed(endpoint_number) {
+                return Ok(());
+            }
+            log::debug!("  RX OUT{} prime retry", endpoint_number);
+        }
+        Err(SmolError::PrimeFailed)
+    }
+
+    /// Enable or disable double-buffering of the given OUT endpoint.
+    ///
+    /// With double-buffering the host can fill one buffer while
+    /// firmware drains the other. Returns `true` if the endpoint is
+    /// now double-buffered.
+    ///
+    /// Controllers without ping-pong buffers keep the endpoint
+    /// single-buffered and return `false`, which is the default.
+    fn set_double_buffered(&self, _endpoint_number: u8, _enabled: bool) -> bool {
+        false
+    }
+
+    /// Read a packet from the given endpoint and prime it to receive the next one.
+    ///
+    /// Use [`ReadEndpoint::read`] and [`ReadEndpoint::ep_out_prime_receive`]
+    /// separately if re-priming needs to be deferred, e.g. for flow control.
+    fn read_and_reprime(&self, endpoint_number: u8, buffer: &mut [u8]) -> usize {
+        let bytes_read = self.read(endpoint_number, buffer);
+        self.ep_out_prime_receive(endpoint_number);
+        bytes_read
+    }
+}
+
+/// Read all packets waiting on an OUT endpoint in one call
+///
+/// Implemented for every driver that can read an endpoint and report
+/// whether it holds a packet.
+pub trait ReadAvailable: ReadEndpoint + EndpointStatus {
+    /// Read the packets received on the given OUT endpoint into
+    /// `buffer`, re-priming the endpoint after each one.
+    ///
+    /// Reading stops once no packet is waiting or `buffer` has less
+    /// than `max_packet_size` bytes left so a packet is never
+    /// truncated. Returns the total number of bytes read.
+    ///
+    /// The packets are concatenated, use
+    /// [`ReadAvailable::read_available_packets`] if their boundaries
+    /// matter.
+    fn read_all_available(
+        &self,
+        endpoint_number: u8,
+        buffer: &mut [u8],
+        max_packet_size: usize,
+    ) -> usize {
+        self.read_available_packets(endpoint_number, buffer, max_packet_size, |_| ())
+    }
+
+    /// Like [`ReadAvailable::read_all_available`] but calls
+    /// `on_packet` with each packet as it is read.
+    fn read_available_packets<F>(
+        &self,
+        endpoint_number: u8,
+        buffer: &mut [u8],
+        max_packet_size: usize,
+        mut on_packet: F,
+    ) -> usize
+    where
+        F: FnMut(&[u8]),
+    {
+        let mut bytes_read = 0;
+        while buffer.len() - bytes_read >= max_packet_size && self.out_has_data(endpoint_number) {
+            let packet = &mut buffer[bytes_read..bytes_read + max_packet_size];
+            let packet_length = self.read_and_reprime(endpoint_number, packet);
+            on_packet(&packet[..packet_length]);
+            bytes_read += packet_length;
+        }
+        bytes_read
+    }
 }
 
+impl<T> ReadAvailable for T where T: ReadEndpoint + EndpointStatus {}
+
 // These two should be one trait
-// TODO return bytes_written
 
 pub trait WriteEndpoint {
     /// Write iterator to a single packet
-    fn write<'a, I>(&self, endpoint_number: u8, iter: I)
+    ///
+    /// Writing more than [`EP_MAX_PACKET_SIZE`](crate::EP_MAX_PACKET_SIZE)
+    /// bytes returns [`SmolError::FifoOverflow`] rather than sending an
+    /// oversized packet. Use [`WriteEndpoint::write_all`] for responses
+    /// which may be longer than the endpoint's max packet size.
+    ///
+    /// Returns the number of bytes written or
+    /// [`SmolError::WouldBlock`] if the endpoint is paused or the host
+    /// has not collected its previous write yet.
+    fn write<I>(&self, endpoint_number: u8, iter: I) -> SmolResult<usize>
+    where
+        I: Iterator<Item = u8>;
+
+    /// Write iterator as packets of at most `max_packet_size` bytes
+    ///
+    /// The transfer is terminated with a zero length packet if the
+    /// total number of bytes written is a multiple of
+    /// `max_packet_size`, including when the iterator is empty.
+    ///
+    /// Returns the number of bytes written, [`SmolError::WouldBlock`]
+    /// if the endpoint is paused or the host has not collected its
+    /// previous write yet, [`SmolError::Timeout`] if the host
+    /// stops collecting packets part way through the transfer or
+    /// [`SmolError::InvalidArgument`] if `max_packet_size` is zero.
+    fn write_all<I>(
+        &self,
+        endpoint_number: u8,
+        iter: I,
+        max_packet_size: usize,
+    ) -> SmolResult<usize>
     where
         I: Iterator<Item = u8>;
 
     /// Write iterator to multiple packets
-    fn write_packets<'a, I>(&self, endpoint_number: u8, iter: I, packet_size: usize)
+    ///
+    /// Unlike [`WriteEndpoint::write_all`] no zero length packet is
+    /// sent after a final full packet, only when the iterator is empty.
+    ///
+    /// Returns the number of bytes written, [`SmolError::WouldBlock`]
+    /// if the endpoint is paused or the host has not collected its
+    /// previous write yet, [`SmolError::Timeout`] if the host
+    /// stops collecting packets part way through the transfer or
+    /// [`SmolError::InvalidArgument`] if `packet_size` is zero.
+    fn write_packets<I>(
+        &self,
+        endpoint_number: u8,
+        iter: I,
+        packet_size: usize,
+    ) -> SmolResult<usize>
     where
         I: Iterator<Item = u8>;
+
+    /// Write iterator to a single packet
+    ///
+    /// Unlike [`WriteEndpoint::write`] this does not clear a busy IN
+    /// FIFO but returns [`SmolError::TxInProgress`] instead, or
+    /// [`SmolError::WouldBlock`] if the host has not collected the
+    /// endpoint's previous write yet. Writing more than a single
+    /// packet returns [`SmolError::FifoOverflow`].
+    ///
+    /// Returns the number of bytes written.
+    fn try_write<I>(&self, endpoint_number: u8, iter: I) -> SmolResult<usize>
+    where
+        I: Iterator<Item = u8>;
+
+    /// Returns true if a previous write to the IN endpoint has not
+    /// been collected by the host yet
+    ///
+    /// The flag is set when the endpoint is primed and cleared on the
+    /// endpoint's `SendComplete` event or when the IN FIFO is reset.
+    fn is_in_flight(&self, endpoint_number: u8) -> bool;
+
+    /// Discard any data waiting in the IN FIFO
+    fn reset_in_fifo(&self);
+
+    /// Write a single packet, resetting the IN FIFO and retrying on
+    /// transient failures
+    ///
+    /// The write is retried up to `policy.max_retries` times before the
+    /// last error is returned. [`SmolError::WouldBlock`] is returned
+    /// immediately as resetting the FIFO would discard the write in
+    /// flight.
+    fn write_retry(
+        &self,
+        endpoint_number: u8,
+        data: &[u8],
+        policy: &TransferPolicy,
+    ) -> SmolResult<usize> {
+        let mut result = Err(SmolError::TxInProgress);
+        for _ in 0..policy.attempts() {
+            result = self.try_write(endpoint_number, data.iter().copied());
+            match result {
+                Err(SmolError::TxInProgress) | Err(SmolError::FifoOverflow) => {
+                    log::debug!("  TX IN{} retry: {:?}", endpoint_number, result);
+                    self.reset_in_fifo();
+                }
+                _ => break,
+            }
+        }
+        result
+    }
+
+    /// Queue a single packet on an IN endpoint ahead of the host's
+    /// next poll
+    ///
+    /// The data is written to the IN FIFO and the endpoint primed
+    /// without waiting for the host, so the next IN token is answered
+    /// straight away. This suits interrupt endpoints such as HID
+    /// reports where the report should be ready before the host polls.
+    ///
+    /// Only one packet can be queued per endpoint. The endpoint stays
+    /// [in flight](WriteEndpoint::is_in_flight) until the host has
+    /// collected it, when the driver raises `SendComplete` for the
+    /// endpoint. Call `preload_in` again from the `SendComplete`
+    /// handler to have the next packet waiting for the following poll.
+    ///
+    /// Returns [`SmolError::WouldBlock`] if the previous packet has not
+    /// been collected yet and otherwise fails like
+    /// [`WriteEndpoint::try_write`].
+    fn preload_in(&self, endpoint_number: u8, data: &[u8]) -> SmolResult<usize> {
+        self.try_write(endpoint_number, data.iter().copied())
+    }
 }
 
 pub trait WriteRefEndpoint {
-    fn write_ref<'a, I>(&self, endpoint_number: u8, iter: I)
+    /// Write iterator to a single packet
+    ///
+    /// Writing more than [`EP_MAX_PACKET_SIZE`](crate::EP_MAX_PACKET_SIZE)
+    /// bytes returns [`SmolError::FifoOverflow`] rather than sending an
+    /// oversized packet.
+    ///
+    /// Returns the number of bytes written or
+    /// [`SmolError::WouldBlock`] if the endpoint is paused or the host
+    /// has not collected its previous write yet.
+    fn write_ref<'a, I>(&self, endpoint_number: u8, iter: I) -> SmolResult<usize>
     where
         I: Iterator<Item = &'a u8>;
 }
@@ -105,3 +423,152 @@ trait AsIterator<'a> {
     type AsIter: Iterator<Item = Self::Item>;
     fn as_iter(&'a self) -> Self::AsIter;
 }
+
+// - tests --------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDriver;
+
+    #[test]
+    fn test_endpoint_status() {
+        let driver = MockDriver::new();
+        assert!(driver.in_is_idle(1));
+        assert!(!driver.in_has_data(1));
+        assert!(!driver.out_has_data(2));
+
+        // IN data waits until the host collects it
+        driver.try_write(1, [1, 2, 3].into_iter()).unwrap();
+        assert!(driver.in_has_data(1));
+        assert!(!driver.in_has_data(3));
+        assert!(!driver.in_is_idle(1));
+        driver.complete_write(1);
+        assert!(!driver.in_has_data(1));
+        assert!(driver.in_is_idle(1));
+
+        // OUT data is only reported for the endpoint it arrived on
+        driver.receive_packet_on(2, &[4, 5, 6]);
+        assert!(driver.out_has_data(2));
+        assert!(!driver.out_has_data(1));
+        let mut buffer = [0; 8];
+        assert_eq!(driver.read(2, &mut buffer), 3);
+        assert!(!driver.out_has_data(2));
+    }
+
+    #[test]
+    fn test_write_retry() {
+        let driver = MockDriver::new();
+        driver.fail_writes(1);
+
+        let result = driver.write_retry(1, &[1, 2, 3], &TransferPolicy::DEFAULT);
+
+        assert_eq!(result, Ok(3));
+        assert_eq!(driver.in_fifo_resets(), 1);
+        assert_eq!(driver.writes(), vec![(1, vec![1, 2, 3])]);
+    }
+
+    #[test]
+    fn test_write_retry_bounded() {
+        let driver = MockDriver::new();
+        driver.fail_writes(5);
+
+        let result = driver.write_retry(1, &[1, 2, 3], &TransferPolicy::DEFAULT);
+
+        assert_eq!(result, Err(SmolError::TxInProgress));
+        assert_eq!(driver.in_fifo_resets(), 3);
+        assert!(driver.writes().is_empty());
+    }
+
+    #[test]
+    fn test_try_write_in_flight() {
+        let driver = MockDriver::new();
+
+        assert_eq!(driver.try_write(1, [1, 2, 3].into_iter()), Ok(3));
+        assert!(driver.is_in_flight(1));
+        assert!(!driver.is_in_flight(2));
+
+        // previous write not collected yet
+        assert_eq!(
+            driver.try_write(1, [4].into_iter()),
+            Err(SmolError::WouldBlock)
+        );
+        assert_eq!(
+            driver.write_retry(1, &[4], &TransferPolicy::DEFAULT),
+            Err(SmolError::WouldBlock)
+        );
+        assert_eq!(driver.write(1, [4].into_iter()), Err(SmolError::WouldBlock));
+        assert_eq!(driver.in_fifo_resets(), 0);
+
+        // other endpoints are unaffected
+        assert_eq!(driver.try_write(2, [5].into_iter()), Ok(1));
+
+        driver.complete_write(1);
+        assert_eq!(driver.try_write(1, [4].into_iter()), Ok(1));
+        assert_eq!(
+            driver.writes(),
+            vec![(1, vec![1, 2, 3]), (2, vec![5]), (1, vec![4])]
+        );
+    }
+
+    #[test]
+    fn test_preload_in() {
+        let driver = MockDriver::new();
+
+        // report is primed before the host has polled the endpoint
+        assert_eq!(driver.preload_in(1, &[0xaa, 0xbb]), Ok(2));
+        assert!(driver.is_in_flight(1));
+        assert_eq!(driver.writes(), vec![(1, vec![0xaa, 0xbb])]);
+
+        // the next report has to wait for SendComplete
+        assert_eq!(driver.preload_in(1, &[0xcc]), Err(SmolError::WouldBlock));
+
+        driver.complete_write(1);
+        assert_eq!(driver.preload_in(1, &[0xcc]), Ok(1));
+        assert_eq!(
+            driver.writes(),
+            vec![(1, vec![0xaa, 0xbb]), (1, vec![0xcc])]
+        );
+    }
+
+    #[test]
+    fn test_read_and_reprime() {
+        let driver = MockDriver::new();
+        let mut buffer = [0; 8];
+
+        driver.read_and_reprime(2, &mut buffer);
+
+        assert_eq!(driver.primed(), vec![2]);
+    }
+
+    #[test]
+    fn test_read_all_available() {
+        let driver = MockDriver::new();
+        let queue_packets = || {
+            driver.queue_packet(&[1, 2, 3, 4]);
+            driver.queue_packet(&[5, 6, 7, 8]);
+            driver.queue_packet(&[9]);
+        };
+
+        // all three packets fit
+        queue_packets();
+        let mut buffer = [0; 16];
+        let mut packets = Vec::new();
+        let bytes_read = driver
+            .read_available_packets(0, &mut buffer, 4, |packet| packets.push(packet.to_vec()));
+        assert_eq!(bytes_read, 9);
+        assert_eq!(&buffer[..9], &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(packets, vec![vec![1, 2, 3, 4], vec![5, 6, 7, 8], vec![9]]);
+        assert_eq!(driver.primed(), vec![0, 0, 0]);
+        assert!(!driver.has_packet());
+
+        // packets that may not fit are left for the next call
+        queue_packets();
+        let mut buffer = [0; 10];
+        assert_eq!(driver.read_all_available(0, &mut buffer, 4), 8);
+        assert_eq!(&buffer[..8], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(driver.read_all_available(0, &mut buffer, 4), 1);
+        assert_eq!(buffer[0], 9);
+        assert_eq!(driver.read_all_available(0, &mut buffer, 4), 0);
+    }
+}